@@ -36,6 +36,31 @@ fn bench_compression(c: &mut Criterion) {
                 decompress_block(black_box(&compressed)).unwrap()
             })
         });
+
+        group.bench_function(format!("compress_gorilla_{}", size), |b| {
+            b.iter(|| {
+                compress_block_with(black_box(&points), CompressionType::Gorilla).unwrap()
+            })
+        });
+
+        let gorilla = compress_block_with(&points, CompressionType::Gorilla).unwrap();
+
+        group.bench_function(format!("decompress_gorilla_{}", size), |b| {
+            b.iter(|| {
+                decompress_block_with(black_box(&gorilla), CompressionType::Gorilla).unwrap()
+            })
+        });
+
+        let lz4_stats = compression_stats(&points, &compressed);
+        let gorilla_stats = compression_stats(&points, &gorilla);
+        println!(
+            "{} points: lz4 {:.1}x ({:.2} B/point), gorilla {:.1}x ({:.2} B/point)",
+            size,
+            lz4_stats.ratio,
+            lz4_stats.bytes_per_point(),
+            gorilla_stats.ratio,
+            gorilla_stats.bytes_per_point()
+        );
     }
 
     group.finish();
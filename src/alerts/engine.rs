@@ -0,0 +1,580 @@
+//! Alert Engine
+//!
+//! Evaluates alert rules against the points published to the WebSocket
+//! hub, checks missing-data rules on a timer, and delivers what fires to
+//! the hub's `alerts` topic and to the rules' webhooks.
+//!
+//! Rules, their tracking state and the alert history are kept in
+//! `alerts.json` in the data directory. Rule edits and fired alerts are
+//! written at once; other tracking state changes are written every
+//! `persist_interval_ms` and by `flush`.
+
+use crate::alerts::error::{AlertError, AlertResult};
+use crate::alerts::rule::{Alert, AlertRule, Condition};
+use crate::storage::{JsonStore, Record, StorageEngine, StorageError, StoreState, TimeRange};
+use crate::websocket::{ConnectionHub, ServerMessage, WsEvent};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+/// Name of the alert file in the data directory
+const ALERTS_FILE: &str = "alerts.json";
+
+/// Alert engine configuration
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    /// How often missing-data rules are checked (default: 1 minute)
+    pub check_interval_ms: u64,
+    /// Alerts kept in the history (default: 1000)
+    pub history_limit: usize,
+    /// Webhook request timeout (default: 5 seconds)
+    pub webhook_timeout_ms: u64,
+    /// How often unsaved tracking state is written (default: 10 seconds)
+    pub persist_interval_ms: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            check_interval_ms: 60_000,
+            history_limit: 1000,
+            webhook_timeout_ms: 5000,
+            persist_interval_ms: 10_000,
+        }
+    }
+}
+
+/// Everything persisted in the alert file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AlertState {
+    next_rule_id: u64,
+    next_alert_id: u64,
+    rules: Vec<AlertRule>,
+    /// Oldest first
+    history: Vec<Alert>,
+    /// Tracking state changed since the last write
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl StoreState for AlertState {
+    type Record = AlertRule;
+
+    fn records(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    fn records_mut(&mut self) -> &mut Vec<AlertRule> {
+        &mut self.rules
+    }
+
+    fn last_id(&mut self) -> &mut u64 {
+        &mut self.next_rule_id
+    }
+}
+
+impl Record for AlertRule {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn created_at(&self) -> i64 {
+        self.created_at
+    }
+
+    fn identify(&mut self, id: u64, created_at: i64) {
+        self.id = id;
+        self.created_at = created_at;
+    }
+}
+
+/// Evaluates alert rules and keeps their history
+pub struct AlertEngine {
+    storage: Arc<StorageEngine>,
+    config: AlertConfig,
+    store: JsonStore<AlertState>,
+    client: reqwest::Client,
+}
+
+impl AlertEngine {
+    /// Open the alert file of the storage engine's data directory
+    pub fn open(storage: Arc<StorageEngine>, config: AlertConfig) -> AlertResult<Self> {
+        let store = JsonStore::open(storage.data_dir(), ALERTS_FILE)?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.webhook_timeout_ms))
+            .build()
+            .unwrap_or_default();
+
+        Ok(Self {
+            storage,
+            config,
+            store,
+            client,
+        })
+    }
+
+    /// All rules, in creation order
+    pub async fn rules(&self) -> Vec<AlertRule> {
+        self.store.records().await
+    }
+
+    /// A rule by ID
+    pub async fn rule(&self, id: u64) -> Option<AlertRule> {
+        self.store.get(id).await
+    }
+
+    /// Add a rule, which starts out not firing
+    pub async fn create_rule(&self, mut rule: AlertRule) -> AlertResult<AlertRule> {
+        rule.validate()?;
+        rule.state = Default::default();
+        Ok(self.store.insert(rule, Utc::now().timestamp_millis()).await?)
+    }
+
+    /// Change a rule, which starts over as if it had never fired
+    pub async fn update_rule(&self, id: u64, mut rule: AlertRule) -> AlertResult<AlertRule> {
+        rule.validate()?;
+        rule.state = Default::default();
+        self.store.replace(id, rule).await?.ok_or(AlertError::RuleNotFound(id))
+    }
+
+    /// Delete a rule (its alerts stay in the history)
+    pub async fn delete_rule(&self, id: u64) -> AlertResult<()> {
+        if !self.store.remove(id).await? {
+            return Err(AlertError::RuleNotFound(id));
+        }
+        Ok(())
+    }
+
+    /// Write tracking state changes that haven't been saved yet
+    pub async fn flush(&self) -> AlertResult<()> {
+        let mut state = self.store.lock().await;
+        if state.dirty {
+            self.save(&mut state)?;
+        }
+        Ok(())
+    }
+
+    /// Most recent alerts first, optionally of one rule only
+    pub async fn history(&self, rule_id: Option<u64>, limit: usize) -> Vec<Alert> {
+        let state = self.store.lock().await;
+        state
+            .history
+            .iter()
+            .rev()
+            .filter(|alert| rule_id.is_none_or(|id| alert.rule_id == id))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Evaluate the threshold and anomaly rules of a metric against a new point
+    ///
+    /// A point also re-arms the metric's missing-data rules. Rules ignore
+    /// points older than their window at `now` (see `AlertRule::is_live`).
+    /// Returns the alerts that fired, already added to the history.
+    pub async fn process_point(
+        &self,
+        metric: &str,
+        value: f64,
+        timestamp: i64,
+        now: i64,
+    ) -> AlertResult<Vec<Alert>> {
+        let baselines = self.read_baselines(metric, timestamp, now).await;
+
+        let mut state = self.store.lock().await;
+        let mut fired = Vec::new();
+        let mut changed = false;
+
+        for rule in state.rules.iter_mut() {
+            if !rule.enabled || rule.metric != metric || !rule.is_live(timestamp, now) {
+                continue;
+            }
+            let before = rule.state.clone();
+
+            match rule.condition {
+                Condition::Threshold {
+                    operator,
+                    value: threshold,
+                    for_ms,
+                } => {
+                    if operator.holds(value, threshold) {
+                        let start = *rule.state.breach_start.get_or_insert(timestamp);
+                        if !rule.state.firing && timestamp - start >= for_ms {
+                            rule.state.firing = true;
+                            fired.push((rule.id, rule.message(Some(value), None), Some(value)));
+                        }
+                    } else {
+                        rule.state = Default::default();
+                    }
+                }
+                Condition::Anomaly {
+                    method,
+                    threshold,
+                    min_points,
+                    ..
+                } => {
+                    // Rules whose baseline couldn't be read are left as they are
+                    let Some(baseline) = baselines.get(&rule.id) else {
+                        continue;
+                    };
+                    let score = (baseline.len() >= min_points)
+                        .then(|| method.score(value, baseline))
+                        .flatten();
+
+                    match score {
+                        Some(score) if score > threshold => {
+                            if !rule.state.firing {
+                                rule.state.firing = true;
+                                let message = rule.message(Some(value), Some(score));
+                                fired.push((rule.id, message, Some(value)));
+                            }
+                        }
+                        _ => rule.state.firing = false,
+                    }
+                }
+                Condition::MissingData { .. } => rule.state.firing = false,
+            }
+
+            changed |= rule.state != before;
+        }
+
+        let alerts = self.record(&mut state, fired, timestamp);
+        state.dirty |= changed;
+        if !alerts.is_empty() {
+            self.save(&mut state)?;
+        }
+        Ok(alerts)
+    }
+
+    /// Read the baselines of a metric's anomaly rules for a point, by rule ID
+    ///
+    /// The reads happen without holding the state lock. Rules whose read
+    /// fails are left out.
+    async fn read_baselines(
+        &self,
+        metric: &str,
+        timestamp: i64,
+        now: i64,
+    ) -> HashMap<u64, Vec<f64>> {
+        let rules: Vec<(u64, String, i64)> = {
+            let state = self.store.lock().await;
+            state
+                .rules
+                .iter()
+                .filter(|rule| rule.enabled && rule.metric == metric)
+                .filter(|rule| rule.is_live(timestamp, now))
+                .filter_map(|rule| match rule.condition {
+                    Condition::Anomaly { baseline_ms, .. } => {
+                        Some((rule.id, rule.name.clone(), baseline_ms))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mut baselines = HashMap::new();
+        for (id, name, baseline_ms) in rules {
+            let range = TimeRange::new(timestamp.saturating_sub(baseline_ms), timestamp);
+            match self.storage.query_metric(metric, range).await {
+                Ok(points) => {
+                    baselines.insert(id, points.iter().map(|p| p.value).collect());
+                }
+                Err(StorageError::MetricNotFound(_)) => {
+                    baselines.insert(id, Vec::new());
+                }
+                Err(e) => tracing::warn!(rule = %name, error = %e, "Baseline read failed"),
+            }
+        }
+        baselines
+    }
+
+    /// Fire the missing-data rules whose metric had no point in their window
+    ///
+    /// A rule doesn't fire before it has existed for its whole window.
+    pub async fn check_missing(&self, now: i64) -> AlertResult<Vec<Alert>> {
+        let due: Vec<(u64, String, String, i64)> = {
+            let state = self.store.lock().await;
+            state
+                .rules
+                .iter()
+                .filter(|rule| rule.enabled && !rule.state.firing)
+                .filter_map(|rule| match rule.condition {
+                    Condition::MissingData { for_ms } if now - rule.created_at >= for_ms => {
+                        Some((rule.id, rule.name.clone(), rule.metric.clone(), for_ms))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Read without the state lock, then fire the rules still due
+        let mut missing = Vec::new();
+        for (id, name, metric, for_ms) in due {
+            let range = TimeRange::new(now - for_ms, now + 1);
+            match self.storage.query_metric(&metric, range).await {
+                Ok(points) if points.is_empty() => missing.push(id),
+                Ok(_) => {}
+                Err(StorageError::MetricNotFound(_)) => missing.push(id),
+                Err(e) => tracing::warn!(rule = %name, error = %e, "Missing-data check failed"),
+            }
+        }
+
+        let mut state = self.store.lock().await;
+        let mut fired = Vec::new();
+        for rule in state.rules.iter_mut() {
+            if missing.contains(&rule.id) && rule.enabled && !rule.state.firing {
+                rule.state.firing = true;
+                fired.push((rule.id, rule.message(None, None), None));
+            }
+        }
+
+        let alerts = self.record(&mut state, fired, now);
+        if !alerts.is_empty() {
+            self.save(&mut state)?;
+        }
+        Ok(alerts)
+    }
+
+    /// Evaluate rules against the points published to `hub`, check
+    /// missing-data rules every `check_interval_ms`, and deliver what fires
+    pub fn start(self: &Arc<Self>, hub: Arc<ConnectionHub>) -> JoinHandle<()> {
+        let engine = Arc::clone(self);
+        let mut events = hub.subscribe_broadcast();
+
+        tokio::spawn(async move {
+            let interval = Duration::from_millis(engine.config.check_interval_ms.max(1));
+            let mut ticker = tokio::time::interval(interval);
+            let interval = Duration::from_millis(engine.config.persist_interval_ms.max(1));
+            let mut persist = tokio::time::interval(interval);
+
+            loop {
+                let result = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(WsEvent {
+                            message: ServerMessage::DataPoint { metric, value, timestamp, .. },
+                            ..
+                        }) => {
+                            let now = Utc::now().timestamp_millis();
+                            engine.process_point(&metric, value, timestamp, now).await
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(skipped, "Alert engine fell behind, points skipped");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = ticker.tick() => engine.check_missing(Utc::now().timestamp_millis()).await,
+                    _ = persist.tick() => engine.flush().await.map(|_| Vec::new()),
+                };
+
+                match result {
+                    Ok(alerts) => {
+                        for alert in alerts {
+                            engine.deliver(&hub, alert).await;
+                        }
+                    }
+                    Err(e) => tracing::error!(error = %e, "Alert evaluation failed"),
+                }
+            }
+        })
+    }
+
+    /// Publish an alert to the hub and POST it to its rule's webhook
+    async fn deliver(&self, hub: &ConnectionHub, alert: Alert) {
+        tracing::info!(rule = %alert.rule, "Alert: {}", alert.message);
+        hub.publish(WsEvent::alert(&alert));
+
+        let webhook = self.rule(alert.rule_id).await.and_then(|rule| rule.webhook);
+        if let Some(url) = webhook {
+            let request = self.client.post(&url).json(&alert);
+            tokio::spawn(async move {
+                match request.send().await {
+                    Ok(response) if !response.status().is_success() => {
+                        let status = response.status();
+                        tracing::warn!(url = %url, status = %status, "Webhook rejected alert");
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!(url = %url, error = %e, "Webhook delivery failed"),
+                }
+            });
+        }
+    }
+
+    /// Add fired rules to the history as alerts
+    fn record(
+        &self,
+        state: &mut AlertState,
+        fired: Vec<(u64, String, Option<f64>)>,
+        timestamp: i64,
+    ) -> Vec<Alert> {
+        let triggered_at = Utc::now().timestamp_millis();
+        let mut alerts = Vec::with_capacity(fired.len());
+
+        for (rule_id, message, value) in fired {
+            let Some(rule) = state.rules.iter().find(|rule| rule.id == rule_id) else {
+                continue;
+            };
+            state.next_alert_id += 1;
+            alerts.push(Alert {
+                id: state.next_alert_id,
+                rule_id,
+                rule: rule.name.clone(),
+                metric: rule.metric.clone(),
+                message,
+                value,
+                timestamp,
+                triggered_at,
+            });
+        }
+
+        state.history.extend(alerts.iter().cloned());
+        let excess = state.history.len().saturating_sub(self.config.history_limit);
+        state.history.drain(..excess);
+        alerts
+    }
+
+    fn save(&self, state: &mut AlertState) -> AlertResult<()> {
+        self.store.save(state)?;
+        state.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::rule::AnomalyMethod;
+    use crate::query::Comparison;
+    use crate::storage::{AggregationType, Category, DataPoint, Metric, StorageConfig};
+    use tempfile::tempdir;
+
+    const DAY_MS: i64 = 24 * 3600 * 1000;
+
+    async fn create_test_engine() -> (Arc<AlertEngine>, Arc<StorageEngine>, tempfile::TempDir) {
+        let dir = tempdir().unwrap();
+        let storage = Arc::new(StorageEngine::new(StorageConfig::new(dir.path())).await.unwrap());
+        let engine = AlertEngine::open(Arc::clone(&storage), AlertConfig::default()).unwrap();
+        (Arc::new(engine), storage, dir)
+    }
+
+    #[tokio::test]
+    async fn test_threshold_for_duration() {
+        let (engine, storage, _dir) = create_test_engine().await;
+
+        let condition = Condition::Threshold {
+            operator: Comparison::Above,
+            value: 80.0,
+            for_ms: 3 * DAY_MS,
+        };
+        let rule = engine
+            .create_rule(AlertRule::new("High resting HR", "resting_hr", condition))
+            .await
+            .unwrap();
+
+        // Fires once the breach has lasted three days, then stays quiet
+        let readings = [(0, 85.0), (1, 82.0), (2, 90.0), (3, 84.0), (4, 88.0)];
+        let mut fired = Vec::new();
+        for (day, value) in readings {
+            let at = day * DAY_MS;
+            fired.extend(engine.process_point("resting_hr", value, at, at).await.unwrap());
+        }
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].timestamp, 3 * DAY_MS);
+        assert_eq!(fired[0].message, "resting_hr is 84 (> 80) for 3d");
+
+        // A normal reading re-arms it; other metrics don't count
+        engine.process_point("resting_hr", 70.0, 5 * DAY_MS, 5 * DAY_MS).await.unwrap();
+        engine.process_point("mood", 99.0, 6 * DAY_MS, 6 * DAY_MS).await.unwrap();
+        for day in 6..9 {
+            let at = day * DAY_MS;
+            let alerts = engine.process_point("resting_hr", 81.0, at, at).await.unwrap();
+            assert!(alerts.is_empty());
+        }
+        let at = 9 * DAY_MS;
+        let alerts = engine.process_point("resting_hr", 81.0, at, at).await.unwrap();
+        assert_eq!(alerts.len(), 1);
+
+        // Rules, state and history survive a restart
+        let reopened = AlertEngine::open(Arc::clone(&storage), AlertConfig::default()).unwrap();
+        assert!(reopened.rule(rule.id).await.unwrap().state.firing);
+        let history = reopened.history(Some(rule.id), 10).await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].timestamp, 9 * DAY_MS);
+
+        // Tracking state without a firing is written by flush
+        engine.process_point("resting_hr", 70.0, 10 * DAY_MS, 10 * DAY_MS).await.unwrap();
+        engine.process_point("resting_hr", 85.0, 11 * DAY_MS, 11 * DAY_MS).await.unwrap();
+        let reopen = || {
+            AlertEngine::open(Arc::clone(&reopened.storage), AlertConfig::default()).unwrap()
+        };
+        assert!(reopen().rule(rule.id).await.unwrap().state.firing);
+        engine.flush().await.unwrap();
+        let state = reopen().rule(rule.id).await.unwrap().state;
+        assert!(!state.firing);
+        assert_eq!(state.breach_start, Some(11 * DAY_MS));
+    }
+
+    #[tokio::test]
+    async fn test_anomaly_and_missing_data() {
+        let (engine, storage, _dir) = create_test_engine().await;
+
+        let metric_id = storage
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+        let now = Utc::now().timestamp_millis();
+        for (i, value) in [6.0, 7.0, 6.5, 7.0, 6.0, 7.5, 6.5, 7.0, 6.0, 7.0].iter().enumerate() {
+            let timestamp = now - 10 * DAY_MS + i as i64 * DAY_MS;
+            storage.write(DataPoint::with_timestamp(metric_id, *value, timestamp)).await.unwrap();
+        }
+
+        let condition = Condition::Anomaly {
+            method: AnomalyMethod::Mad,
+            baseline_ms: 30 * DAY_MS,
+            threshold: 3.0,
+            min_points: 10,
+        };
+        engine.create_rule(AlertRule::new("Mood swing", "mood", condition)).await.unwrap();
+
+        // A backfilled outlier is not news
+        let backfill = now - 2 * 3600 * 1000;
+        assert!(engine.process_point("mood", 1.0, backfill, now).await.unwrap().is_empty());
+
+        assert!(engine.process_point("mood", 6.8, now, now).await.unwrap().is_empty());
+        let alerts = engine.process_point("mood", 1.0, now, now).await.unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].message.contains("median absolute deviations from its 30d baseline"));
+
+        // Missing data fires once its window has passed since creation
+        let rule = AlertRule::new("No mood", "mood", Condition::MissingData { for_ms: 2 * DAY_MS });
+        let rule = engine.create_rule(rule).await.unwrap();
+        assert!(engine.check_missing(now + DAY_MS).await.unwrap().is_empty());
+        let alerts = engine.check_missing(now + 3 * DAY_MS).await.unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule_id, rule.id);
+        assert!(engine.check_missing(now + 4 * DAY_MS).await.unwrap().is_empty());
+
+        // Disabled rules never fire
+        let disabled = AlertRule::new("No sleep", "sleep", Condition::MissingData { for_ms: 1 })
+            .enabled(false);
+        engine.create_rule(disabled).await.unwrap();
+        assert!(engine.check_missing(now + 4 * DAY_MS).await.unwrap().is_empty());
+
+        assert_eq!(engine.history(None, 10).await.len(), 2);
+
+        // Only a point within the window re-arms a missing-data rule
+        engine.process_point("mood", 7.0, now, now + 4 * DAY_MS).await.unwrap();
+        assert!(engine.rule(rule.id).await.unwrap().state.firing);
+        let at = now + 4 * DAY_MS;
+        engine.process_point("mood", 7.0, at, at).await.unwrap();
+        assert!(!engine.rule(rule.id).await.unwrap().state.firing);
+
+        assert!(matches!(engine.delete_rule(99).await, Err(AlertError::RuleNotFound(99))));
+    }
+}
@@ -0,0 +1,22 @@
+//! Alert error types
+
+use thiserror::Error;
+
+/// Errors that can occur managing alert rules
+#[derive(Error, Debug)]
+pub enum AlertError {
+    /// Rule definition is incomplete or out of range
+    #[error("Invalid alert rule: {0}")]
+    InvalidRule(String),
+
+    /// No rule with this ID
+    #[error("Alert rule not found: {0}")]
+    RuleNotFound(u64),
+
+    /// Reading or writing the alert file failed
+    #[error("Storage error: {0}")]
+    Storage(#[from] crate::storage::StorageError),
+}
+
+/// Result type for alert operations
+pub type AlertResult<T> = Result<T, AlertError>;
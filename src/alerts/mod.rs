@@ -0,0 +1,22 @@
+//! Alerting
+//!
+//! Watches data as it arrives and raises alerts when rules fire.
+//!
+//! ## Architecture
+//!
+//! - **AlertRule**: Threshold, anomaly (z-score/MAD) and missing-data rules
+//! - **AlertEngine**: Evaluates rules against the points published to the
+//!   WebSocket hub and keeps the alert history
+//!
+//! ## Delivery
+//!
+//! Alerts are sent to WebSocket clients subscribed to the `alerts` topic
+//! and POSTed as JSON to the webhook of the rule that fired.
+
+mod engine;
+mod error;
+mod rule;
+
+pub use engine::{AlertConfig, AlertEngine};
+pub use error::{AlertError, AlertResult};
+pub use rule::{Alert, AlertRule, AnomalyMethod, Condition, RuleState};
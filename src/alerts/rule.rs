@@ -0,0 +1,379 @@
+//! Alert Rules
+//!
+//! An `AlertRule` watches one metric for a `Condition`:
+//!
+//! - **Threshold**: every point for at least `for_ms` compares true against
+//!   a value ("resting heart rate > 80 for 3 days")
+//! - **Anomaly**: a point lies more than `threshold` z-scores (or scaled
+//!   median absolute deviations) away from the points of the preceding
+//!   `baseline_ms`
+//! - **MissingData**: no point arrived for `for_ms` ("no mood logged in 48h")
+//!
+//! A rule fires once when its condition starts to hold and re-arms once it
+//! stops holding, so a long breach yields one `Alert`, not one per point.
+
+use crate::alerts::error::{AlertError, AlertResult};
+use crate::query::Comparison;
+use serde::{Deserialize, Serialize};
+
+/// Scale turning a median absolute deviation into a standard deviation
+/// estimate for normally distributed data
+const MAD_SCALE: f64 = 1.4826;
+
+/// Longest anomaly baseline, which every point of the metric reads
+pub const MAX_BASELINE_MS: i64 = 365 * 24 * 3600 * 1000;
+
+/// How late a point may arrive and still count as live, for rules whose
+/// window is shorter
+const LIVE_POINT_MS: i64 = 3600 * 1000;
+
+/// How far from its baseline a point is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyMethod {
+    /// Standard deviations from the mean
+    ZScore,
+    /// Scaled median absolute deviations from the median (robust to outliers)
+    Mad,
+}
+
+impl AnomalyMethod {
+    /// Distance of `value` from `baseline` (None if the baseline is flat)
+    pub fn score(&self, value: f64, baseline: &[f64]) -> Option<f64> {
+        let (center, spread) = match self {
+            Self::ZScore => {
+                let n = baseline.len() as f64;
+                let mean = baseline.iter().sum::<f64>() / n;
+                let variance = baseline.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+                (mean, variance.sqrt())
+            }
+            Self::Mad => {
+                let center = median(baseline.to_vec());
+                let deviations = baseline.iter().map(|v| (v - center).abs()).collect();
+                (center, median(deviations) * MAD_SCALE)
+            }
+        };
+
+        (spread > 0.0 && spread.is_finite()).then(|| (value - center).abs() / spread)
+    }
+
+    fn unit(&self) -> &'static str {
+        match self {
+            Self::ZScore => "standard deviations",
+            Self::Mad => "median absolute deviations",
+        }
+    }
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// What an alert rule watches for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// Values beyond a threshold, for at least `for_ms`
+    Threshold {
+        operator: Comparison,
+        value: f64,
+        /// How long every point must breach (0 = the first breaching point fires)
+        #[serde(default)]
+        for_ms: i64,
+    },
+    /// Values far from the preceding `baseline_ms` of data
+    Anomaly {
+        method: AnomalyMethod,
+        /// Window of points the baseline is computed over
+        baseline_ms: i64,
+        /// Score above which a point is anomalous (default: 3.0)
+        #[serde(default = "default_anomaly_threshold")]
+        threshold: f64,
+        /// Baseline points needed before anything is flagged (default: 10)
+        #[serde(default = "default_min_points")]
+        min_points: usize,
+    },
+    /// No data for `for_ms`
+    MissingData { for_ms: i64 },
+}
+
+fn default_anomaly_threshold() -> f64 {
+    3.0
+}
+
+fn default_min_points() -> usize {
+    10
+}
+
+/// Tracking of a rule's condition between points
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleState {
+    /// Timestamp of the first point of the current threshold breach
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub breach_start: Option<i64>,
+    /// Whether the rule has fired and not re-armed yet
+    #[serde(default)]
+    pub firing: bool,
+}
+
+/// A rule evaluated against the points of one metric
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    /// Unique rule ID (assigned on creation)
+    #[serde(default)]
+    pub id: u64,
+    /// Human-readable name
+    pub name: String,
+    /// Metric the rule watches
+    pub metric: String,
+    /// What fires the rule
+    pub condition: Condition,
+    /// URL alerts are POSTed to as JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    /// Disabled rules are kept but never fire
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Creation time in milliseconds (assigned on creation)
+    #[serde(default)]
+    pub created_at: i64,
+    /// Current tracking state
+    #[serde(default)]
+    pub state: RuleState,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl AlertRule {
+    /// Create an enabled rule without a webhook
+    pub fn new(name: impl Into<String>, metric: impl Into<String>, condition: Condition) -> Self {
+        Self {
+            id: 0,
+            name: name.into(),
+            metric: metric.into(),
+            condition,
+            webhook: None,
+            enabled: true,
+            created_at: 0,
+            state: RuleState::default(),
+        }
+    }
+
+    /// Builder: POST alerts to a webhook
+    pub fn webhook(mut self, url: impl Into<String>) -> Self {
+        self.webhook = Some(url.into());
+        self
+    }
+
+    /// Builder: enable or disable the rule
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Check the rule is complete and its settings are in range
+    pub fn validate(&self) -> AlertResult<()> {
+        let invalid = |message: &str| Err(AlertError::InvalidRule(message.to_string()));
+
+        if self.name.trim().is_empty() {
+            return invalid("name cannot be empty");
+        }
+        if self.metric.trim().is_empty() {
+            return invalid("metric cannot be empty");
+        }
+        if let Some(url) = &self.webhook {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return invalid("webhook must be an http(s) URL");
+            }
+        }
+
+        match self.condition {
+            Condition::Threshold { value, for_ms, .. } => {
+                if !value.is_finite() {
+                    return invalid("threshold value must be finite");
+                }
+                if for_ms < 0 {
+                    return invalid("for_ms cannot be negative");
+                }
+            }
+            Condition::Anomaly {
+                baseline_ms,
+                threshold,
+                min_points,
+                ..
+            } => {
+                if baseline_ms <= 0 {
+                    return invalid("baseline_ms must be positive");
+                }
+                if baseline_ms > MAX_BASELINE_MS {
+                    return invalid("baseline_ms cannot exceed 365 days");
+                }
+                if !(threshold.is_finite() && threshold > 0.0) {
+                    return invalid("anomaly threshold must be positive");
+                }
+                if min_points < 2 {
+                    return invalid("min_points must be at least 2");
+                }
+            }
+            Condition::MissingData { for_ms } => {
+                if for_ms <= 0 {
+                    return invalid("for_ms must be positive");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if a point at `timestamp` is recent enough at `now` to evaluate
+    ///
+    /// Older points are backfills: they neither fire the rule nor re-arm it.
+    pub(crate) fn is_live(&self, timestamp: i64, now: i64) -> bool {
+        let window = match self.condition {
+            Condition::Threshold { for_ms, .. } | Condition::MissingData { for_ms } => for_ms,
+            Condition::Anomaly { .. } => 0,
+        };
+        timestamp >= now.saturating_sub(window.max(LIVE_POINT_MS))
+    }
+
+    /// Description of a firing for a point of `value` (None for missing data)
+    pub(crate) fn message(&self, value: Option<f64>, score: Option<f64>) -> String {
+        match &self.condition {
+            Condition::Threshold {
+                operator,
+                value: threshold,
+                for_ms,
+            } => {
+                let mut message = format!(
+                    "{} is {} ({} {})",
+                    self.metric,
+                    value.unwrap_or(f64::NAN),
+                    operator.as_str(),
+                    threshold
+                );
+                if *for_ms > 0 {
+                    message.push_str(&format!(" for {}", format_duration(*for_ms)));
+                }
+                message
+            }
+            Condition::Anomaly {
+                method,
+                baseline_ms,
+                ..
+            } => format!(
+                "{} is {}, {:.1} {} from its {} baseline",
+                self.metric,
+                value.unwrap_or(f64::NAN),
+                score.unwrap_or(f64::NAN),
+                method.unit(),
+                format_duration(*baseline_ms)
+            ),
+            Condition::MissingData { for_ms } => {
+                format!("No {} data for {}", self.metric, format_duration(*for_ms))
+            }
+        }
+    }
+}
+
+/// A firing of an alert rule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    /// Unique alert ID
+    pub id: u64,
+    /// Rule that fired
+    pub rule_id: u64,
+    /// Name of the rule that fired
+    pub rule: String,
+    /// Metric the rule watches
+    pub metric: String,
+    /// What happened
+    pub message: String,
+    /// Value of the point that fired the rule (None for missing data)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    /// Timestamp of that point, or of the check that found data missing
+    pub timestamp: i64,
+    /// When the alert fired, in milliseconds
+    pub triggered_at: i64,
+}
+
+/// Format milliseconds in the largest whole unit, like "3d" or "48h"
+fn format_duration(ms: i64) -> String {
+    const UNITS: [(i64, &str); 4] = [
+        (24 * 3600 * 1000, "d"),
+        (3600 * 1000, "h"),
+        (60 * 1000, "m"),
+        (1000, "s"),
+    ];
+    UNITS
+        .iter()
+        .find(|(unit, _)| ms % unit == 0 && ms >= *unit)
+        .map(|(unit, suffix)| format!("{}{}", ms / unit, suffix))
+        .unwrap_or_else(|| format!("{}ms", ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anomaly_scores() {
+        let baseline = [10.0, 12.0, 11.0, 9.0, 10.0, 11.0, 10.0, 9.0, 12.0, 10.0];
+        let z = AnomalyMethod::ZScore.score(20.0, &baseline).unwrap();
+        assert!(z > 9.0 && z < 10.0);
+        assert!(AnomalyMethod::ZScore.score(10.5, &baseline).unwrap() < 1.0);
+
+        // A single outlier in the baseline barely moves the MAD
+        let mut skewed = baseline.to_vec();
+        skewed.push(1000.0);
+        assert!(AnomalyMethod::Mad.score(20.0, &skewed).unwrap() > 5.0);
+        assert!(AnomalyMethod::ZScore.score(20.0, &skewed).unwrap() < 1.0);
+
+        assert_eq!(AnomalyMethod::Mad.score(5.0, &[3.0, 3.0, 3.0]), None);
+    }
+
+    #[test]
+    fn test_rule_json() {
+        let rule: AlertRule = serde_json::from_str(
+            r#"{"name": "High resting HR", "metric": "resting_hr",
+                "condition": {"type": "threshold", "operator": ">", "value": 80,
+                              "for_ms": 259200000}}"#,
+        )
+        .unwrap();
+        assert!(rule.enabled);
+        assert_eq!(
+            rule.condition,
+            Condition::Threshold {
+                operator: Comparison::Above,
+                value: 80.0,
+                for_ms: 3 * 24 * 3600 * 1000,
+            }
+        );
+        assert_eq!(rule.message(Some(82.0), None), "resting_hr is 82 (> 80) for 3d");
+        assert!(rule.validate().is_ok());
+        let day_ms = 24 * 3600 * 1000;
+        assert!(rule.is_live(day_ms, 4 * day_ms));
+        assert!(!rule.is_live(day_ms, 4 * day_ms + 1));
+
+        let rule = AlertRule::new("No mood", "mood", Condition::MissingData { for_ms: 0 });
+        assert!(matches!(rule.validate(), Err(AlertError::InvalidRule(_))));
+        let condition = Condition::Anomaly {
+            method: AnomalyMethod::Mad,
+            baseline_ms: MAX_BASELINE_MS + 1,
+            threshold: 3.0,
+            min_points: 10,
+        };
+        let rule = AlertRule::new("Mood swing", "mood", condition);
+        assert!(matches!(rule.validate(), Err(AlertError::InvalidRule(_))));
+        let condition = Condition::MissingData { for_ms: 172_800_000 };
+        let rule = AlertRule::new("No mood", "mood", condition);
+        assert_eq!(rule.message(None, None), "No mood data for 2d");
+    }
+}
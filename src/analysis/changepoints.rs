@@ -0,0 +1,117 @@
+//! Change Point Detection
+//!
+//! Shifts in the mean are found by comparing the `window` values on either
+//! side of each index: the difference of their means, over its standard
+//! error, scores the index. The best index of a segment becomes a change
+//! point if its score reaches the threshold, and both halves are searched
+//! again. Comparing neighbouring windows rather than whole segments keeps
+//! slow, steady trends from reading as shifts.
+//!
+//! The noise level is estimated from the median absolute difference between
+//! consecutive values, so the shifts themselves don't inflate it.
+
+/// Scale of the median absolute difference of normal noise, `0.6745 * sqrt(2)`
+const MAD_DIFF_SCALE: f64 = 0.9539;
+
+/// A detected shift in the mean
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Split {
+    /// Index of the first value after the shift
+    pub index: usize,
+    /// Mean difference over its standard error
+    pub score: f64,
+}
+
+/// Shifts in the mean of `values`, in index order
+///
+/// Change points are at least `window` values apart.
+pub(crate) fn detect(values: &[f64], threshold: f64, window: usize) -> Vec<Split> {
+    let window = window.max(1);
+    if values.len() < 2 * window {
+        return Vec::new();
+    }
+
+    let mut prefix = Vec::with_capacity(values.len() + 1);
+    prefix.push(0.0);
+    for value in values {
+        prefix.push(prefix[prefix.len() - 1] + value);
+    }
+
+    let scale = values.iter().map(|v| v.abs()).sum::<f64>() / values.len() as f64;
+    let sigma = noise_level(values).max(1e-9 * (1.0 + scale));
+
+    let mut splits = Vec::new();
+    let mut segments = vec![(0, values.len())];
+    while let Some((start, end)) = segments.pop() {
+        let Some(split) = best_split(&prefix, start, end, sigma, window) else {
+            continue;
+        };
+        if split.score >= threshold {
+            splits.push(split);
+            segments.push((start, split.index));
+            segments.push((split.index, end));
+        }
+    }
+
+    splits.sort_by_key(|split| split.index);
+    splits
+}
+
+/// Standard deviation of the noise, from consecutive differences
+fn noise_level(values: &[f64]) -> f64 {
+    let mut diffs: Vec<f64> = values.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+    if diffs.is_empty() {
+        return 0.0;
+    }
+    diffs.sort_by(|a, b| a.total_cmp(b));
+    diffs[diffs.len() / 2] / MAD_DIFF_SCALE
+}
+
+/// Highest scoring split of `[start, end)`
+fn best_split(
+    prefix: &[f64],
+    start: usize,
+    end: usize,
+    sigma: f64,
+    window: usize,
+) -> Option<Split> {
+    // Standard error of the difference of two window means
+    let error = sigma * (2.0 / window as f64).sqrt();
+
+    (start + window..=end.checked_sub(window)?)
+        .map(|index| {
+            let before = prefix[index] - prefix[index - window];
+            let after = prefix[index + window] - prefix[index];
+            Split {
+                index,
+                score: (after - before).abs() / window as f64 / error,
+            }
+        })
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic noise in [-0.5, 0.5]
+    fn noise(i: usize) -> f64 {
+        ((i * 37 + 11) % 23) as f64 / 22.0 - 0.5
+    }
+
+    #[test]
+    fn test_mean_shift() {
+        let values: Vec<f64> = (0..60)
+            .map(|i| noise(i) + if i < 25 { 5.0 } else { 8.0 })
+            .collect();
+        let splits = detect(&values, 4.0, 7);
+        assert_eq!(splits.len(), 1);
+        assert_eq!(splits[0].index, 25);
+
+        // Noise alone, and a steady climb
+        let flat: Vec<f64> = (0..60).map(noise).collect();
+        assert!(detect(&flat, 4.0, 7).is_empty());
+        let climb: Vec<f64> = (0..60).map(|i| noise(i) + 0.02 * i as f64).collect();
+        assert!(detect(&climb, 4.0, 7).is_empty());
+    }
+}
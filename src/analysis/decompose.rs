@@ -0,0 +1,566 @@
+//! Seasonal Decomposition
+//!
+//! Classical additive decomposition of a metric's daily aggregates into
+//! `value = trend + yearly + weekly + residual`:
+//!
+//! - **Trend**: centered moving average over a week, or over a year once
+//!   there are two years of days (held flat where the window doesn't fit)
+//! - **Yearly**: mean detrended value per day of the year, smoothed over a
+//!   month; only with two years of days
+//! - **Weekly**: mean of what's left per day of the week
+//! - **Residual**: everything else
+//!
+//! Days without data are interpolated linearly before decomposing, and have
+//! no value or residual in the result. Day-of-week effects are scored
+//! against the residual noise, and change points are searched in the
+//! deseasonalized series (see [`changepoints`](super::changepoints)).
+
+use crate::analysis::changepoints;
+use crate::analysis::error::{AnalysisError, AnalysisResult};
+use crate::query::{AggregationFunc, GroupByClause, GroupByInterval, Query, QueryExecutor};
+use crate::storage::TimeRange;
+use chrono::{DateTime, Datelike, NaiveDate, Weekday};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Days per week
+const WEEK: usize = 7;
+/// Days per year, the last day of leap years folded into the one before
+const YEAR: usize = 365;
+/// Days the yearly profile is smoothed over
+const MONTH: usize = 31;
+/// Fewest days with data a decomposition needs
+const MIN_DAYS: usize = 2 * WEEK;
+/// Most days a decomposition covers
+const MAX_DAYS: u32 = 5 * YEAR as u32;
+/// Days compared on either side of a change point
+const MIN_SEGMENT_DAYS: usize = WEEK;
+/// Score from which a day-of-week effect counts as significant (about 95%)
+const SIGNIFICANT_SCORE: f64 = 2.0;
+
+/// Decomposition settings
+#[derive(Debug, Clone)]
+pub struct DecomposeOptions {
+    /// Days of history to decompose, ending today (default: 90)
+    pub days: u32,
+    /// How a day's points are combined (default: avg)
+    pub aggregation: AggregationFunc,
+    /// Time zone days start in (default: UTC)
+    pub timezone: Tz,
+    /// Score a mean shift needs to be a change point (default: 4.0)
+    pub change_threshold: f64,
+}
+
+impl Default for DecomposeOptions {
+    fn default() -> Self {
+        Self {
+            days: 90,
+            aggregation: AggregationFunc::Avg,
+            timezone: Tz::UTC,
+            change_threshold: 4.0,
+        }
+    }
+}
+
+impl DecomposeOptions {
+    /// Builder: decompose the last `days` days
+    pub fn days(mut self, days: u32) -> Self {
+        self.days = days;
+        self
+    }
+
+    /// Builder: combine a day's points with another aggregation
+    pub fn aggregation(mut self, aggregation: AggregationFunc) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    /// Builder: start days in a time zone
+    pub fn timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    /// Builder: score a mean shift needs to be a change point
+    pub fn change_threshold(mut self, threshold: f64) -> Self {
+        self.change_threshold = threshold;
+        self
+    }
+
+    /// Check the settings are in range
+    pub fn validate(&self) -> AnalysisResult<()> {
+        if self.days < MIN_DAYS as u32 || self.days > MAX_DAYS {
+            return Err(AnalysisError::InvalidRequest(format!(
+                "days must be between {} and {}",
+                MIN_DAYS, MAX_DAYS
+            )));
+        }
+        if !self.change_threshold.is_finite() || self.change_threshold <= 0.0 {
+            return Err(AnalysisError::InvalidRequest(
+                "change_threshold must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A metric's daily aggregates split into components
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Decomposition {
+    /// Time zone days start in
+    pub timezone: String,
+    /// Days decomposed
+    pub days: usize,
+    /// Days that had data
+    pub days_with_data: usize,
+    /// Share of the non-seasonal variance explained by the trend (0 to 1)
+    pub trend_strength: f64,
+    /// Share of the non-trend variance explained by the weekly cycle (0 to 1)
+    pub weekly_strength: f64,
+    /// Share of the non-trend variance explained by the yearly cycle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yearly_strength: Option<f64>,
+    /// Weekly component per day of the week, Monday first
+    pub day_of_week: Vec<DayOfWeekEffect>,
+    /// Shifts in the deseasonalized mean
+    pub change_points: Vec<ChangePoint>,
+    /// Components per day, oldest first
+    pub points: Vec<DecompositionPoint>,
+}
+
+/// Components of one day
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecompositionPoint {
+    /// Start of the day in milliseconds
+    pub timestamp: i64,
+    /// The day's aggregate, if it had data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    /// Trend component
+    pub trend: f64,
+    /// Weekly component
+    pub weekly: f64,
+    /// Yearly component
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yearly: Option<f64>,
+    /// What the components don't explain, if the day had data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub residual: Option<f64>,
+}
+
+/// How a day of the week differs from the weekly average
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DayOfWeekEffect {
+    /// Day of the week
+    pub day: Weekday,
+    /// Difference from the weekly average
+    pub effect: f64,
+    /// Days with data the effect is measured over
+    pub samples: usize,
+    /// Effect over its standard error
+    pub score: f64,
+    /// Whether the effect is unlikely to be noise
+    pub significant: bool,
+}
+
+/// A shift in a metric's deseasonalized mean
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangePoint {
+    /// Start of the first day after the shift
+    pub timestamp: i64,
+    /// Mean since the previous change point
+    pub before: f64,
+    /// Mean until the next change point
+    pub after: f64,
+    /// Shift between the weeks either side, over its standard error
+    pub score: f64,
+}
+
+impl Decomposition {
+    /// Change in the trend from the first day to the last
+    pub fn trend_change(&self) -> f64 {
+        match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => last.trend - first.trend,
+            _ => 0.0,
+        }
+    }
+
+    /// The significant day-of-week effect furthest from the average
+    pub fn strongest_weekday(&self) -> Option<&DayOfWeekEffect> {
+        self.day_of_week
+            .iter()
+            .filter(|effect| effect.significant)
+            .max_by(|a, b| a.effect.abs().total_cmp(&b.effect.abs()))
+    }
+}
+
+/// Decompose a metric's daily aggregates over the last `options.days` days,
+/// starting at the first day with data
+pub async fn decompose_metric(
+    executor: &QueryExecutor,
+    metric: &str,
+    options: &DecomposeOptions,
+    now: i64,
+) -> AnalysisResult<Decomposition> {
+    options.validate()?;
+
+    let days = GroupByClause::new(GroupByInterval::Day).timezone(options.timezone);
+    let today = days.bucket_start(now);
+    let tomorrow = days.next_bucket_start(today);
+    let mut first = today;
+    for _ in 1..options.days {
+        first = days.bucket_start(first - 1);
+    }
+
+    let query = Query::select(&[metric])
+        .time_range(TimeRange::new(first, tomorrow))
+        .group_by_clause(days.clone())
+        .with_aggregation(options.aggregation)
+        .build();
+    let aggregates: HashMap<i64, f64> = executor
+        .execute(query)
+        .await?
+        .rows
+        .iter()
+        .filter_map(|row| Some((row.timestamp, row.get(metric)?)))
+        .collect();
+
+    let mut daily = Vec::new();
+    let mut day = aggregates.keys().copied().min().unwrap_or(today).min(today);
+    while day < tomorrow {
+        daily.push((day, aggregates.get(&day).copied()));
+        day = days.next_bucket_start(day);
+    }
+
+    decompose(&daily, options)
+}
+
+/// Decompose consecutive days, given as their start and aggregate
+pub fn decompose(
+    daily: &[(i64, Option<f64>)],
+    options: &DecomposeOptions,
+) -> AnalysisResult<Decomposition> {
+    let observed: Vec<bool> = daily.iter().map(|(_, value)| value.is_some()).collect();
+    let days_with_data = observed.iter().filter(|o| **o).count();
+    if days_with_data < MIN_DAYS {
+        return Err(AnalysisError::InsufficientData {
+            needed: MIN_DAYS,
+            found: days_with_data,
+        });
+    }
+
+    let values: Vec<Option<f64>> = daily.iter().map(|(_, value)| *value).collect();
+    let filled = interpolate(&values);
+    let dates: Vec<NaiveDate> = daily
+        .iter()
+        .map(|(timestamp, _)| local_date(*timestamp, options.timezone))
+        .collect();
+    let with_yearly = daily.len() >= 2 * YEAR;
+
+    let trend = moving_average(&filled, if with_yearly { YEAR } else { WEEK });
+    let detrended: Vec<f64> = filled.iter().zip(&trend).map(|(v, t)| v - t).collect();
+
+    let yearly = with_yearly.then(|| {
+        let bins: Vec<usize> = dates
+            .iter()
+            .map(|date| (date.ordinal0() as usize).min(YEAR - 1))
+            .collect();
+        let all = vec![true; filled.len()];
+        let (profile, _) = seasonal_profile(&detrended, &bins, &all, YEAR);
+        let profile = centered(circular_average(&profile, MONTH));
+        bins.iter().map(|bin| profile[*bin]).collect::<Vec<f64>>()
+    });
+    let deyearled: Vec<f64> = match &yearly {
+        Some(yearly) => detrended.iter().zip(yearly).map(|(v, y)| v - y).collect(),
+        None => detrended.clone(),
+    };
+
+    let weekdays: Vec<usize> = dates
+        .iter()
+        .map(|date| date.weekday().num_days_from_monday() as usize)
+        .collect();
+    let (profile, samples) = seasonal_profile(&deyearled, &weekdays, &observed, WEEK);
+    let profile = centered(profile);
+    let weekly: Vec<f64> = weekdays.iter().map(|day| profile[*day]).collect();
+
+    let residual: Vec<f64> = deyearled.iter().zip(&weekly).map(|(v, w)| v - w).collect();
+    let observed_residual: Vec<f64> = residual
+        .iter()
+        .zip(&observed)
+        .filter_map(|(r, o)| o.then_some(*r))
+        .collect();
+
+    // Strength of a component: 1 - var(residual) / var(component + residual)
+    let strength = |component: &[f64]| {
+        let combined: Vec<f64> = component
+            .iter()
+            .zip(&residual)
+            .zip(&observed)
+            .filter_map(|((c, r), o)| o.then_some(c + r))
+            .collect();
+        let total = variance(&combined);
+        if total > 0.0 {
+            (1.0 - variance(&observed_residual) / total).clamp(0.0, 1.0)
+        } else {
+            0.0
+        }
+    };
+
+    let noise = variance(&observed_residual).sqrt();
+    let day_of_week = (0..WEEK)
+        .map(|day| {
+            let effect = profile[day];
+            let error = (noise / (samples[day].max(1) as f64).sqrt()).max(1e-12);
+            let score = effect / error;
+            DayOfWeekEffect {
+                day: Weekday::try_from(day as u8).unwrap_or(Weekday::Mon),
+                effect,
+                samples: samples[day],
+                score,
+                significant: samples[day] >= 2 && score.abs() >= SIGNIFICANT_SCORE,
+            }
+        })
+        .collect();
+
+    let deseasonalized: Vec<f64> = trend.iter().zip(&residual).map(|(t, r)| t + r).collect();
+    let change_points = change_points(daily, &deseasonalized, options.change_threshold);
+
+    let points = daily
+        .iter()
+        .enumerate()
+        .map(|(i, (timestamp, value))| DecompositionPoint {
+            timestamp: *timestamp,
+            value: *value,
+            trend: trend[i],
+            weekly: weekly[i],
+            yearly: yearly.as_ref().map(|yearly| yearly[i]),
+            residual: observed[i].then_some(residual[i]),
+        })
+        .collect();
+
+    Ok(Decomposition {
+        timezone: options.timezone.name().to_string(),
+        days: daily.len(),
+        days_with_data,
+        trend_strength: strength(&trend),
+        weekly_strength: strength(&weekly),
+        yearly_strength: yearly.as_deref().map(strength),
+        day_of_week,
+        change_points,
+        points,
+    })
+}
+
+/// Change points with the means of the segments around them
+fn change_points(daily: &[(i64, Option<f64>)], values: &[f64], threshold: f64) -> Vec<ChangePoint> {
+    let splits = changepoints::detect(values, threshold, MIN_SEGMENT_DAYS);
+    let mut bounds = vec![0];
+    bounds.extend(splits.iter().map(|split| split.index));
+    bounds.push(values.len());
+    let means: Vec<f64> = bounds.windows(2).map(|w| mean(&values[w[0]..w[1]])).collect();
+
+    splits
+        .iter()
+        .enumerate()
+        .map(|(i, split)| ChangePoint {
+            timestamp: daily[split.index].0,
+            before: means[i],
+            after: means[i + 1],
+            score: split.score,
+        })
+        .collect()
+}
+
+/// Calendar date a day starts on
+fn local_date(timestamp: i64, tz: Tz) -> NaiveDate {
+    DateTime::from_timestamp_millis(timestamp)
+        .map(|dt| dt.with_timezone(&tz).date_naive())
+        .unwrap_or_default()
+}
+
+/// Fill gaps linearly between the nearest known values, and flat at the ends
+fn interpolate(values: &[Option<f64>]) -> Vec<f64> {
+    let known: Vec<(usize, f64)> = values
+        .iter()
+        .enumerate()
+        .filter_map(|(i, value)| Some((i, (*value)?)))
+        .collect();
+
+    let mut next = 0;
+    (0..values.len())
+        .map(|i| {
+            while next < known.len() && known[next].0 < i {
+                next += 1;
+            }
+            match (next.checked_sub(1).map(|p| known[p]), known.get(next)) {
+                (_, Some(&(j, value))) if j == i => value,
+                (Some((a, from)), Some(&(b, to))) => {
+                    from + (to - from) * (i - a) as f64 / (b - a) as f64
+                }
+                (Some((_, value)), None) | (None, Some(&(_, value))) => value,
+                (None, None) => 0.0,
+            }
+        })
+        .collect()
+}
+
+/// Centered moving average, held flat where the window doesn't fit
+fn moving_average(values: &[f64], window: usize) -> Vec<f64> {
+    if values.len() < window {
+        return vec![mean(values); values.len()];
+    }
+
+    let half = window / 2;
+    let mut sums = Vec::with_capacity(values.len() + 1);
+    sums.push(0.0);
+    for value in values {
+        sums.push(sums[sums.len() - 1] + value);
+    }
+
+    let average = |center: usize| (sums[center + half + 1] - sums[center - half]) / window as f64;
+    let (first, last) = (half, values.len() - 1 - half);
+    (0..values.len())
+        .map(|i| average(i.clamp(first, last)))
+        .collect()
+}
+
+/// Mean value per bin over the included values, and how many there were
+fn seasonal_profile(
+    values: &[f64],
+    bins: &[usize],
+    include: &[bool],
+    period: usize,
+) -> (Vec<f64>, Vec<usize>) {
+    let mut sums = vec![0.0; period];
+    let mut counts = vec![0; period];
+    for ((value, bin), include) in values.iter().zip(bins).zip(include) {
+        if *include {
+            sums[*bin] += value;
+            counts[*bin] += 1;
+        }
+    }
+
+    let means = sums
+        .iter()
+        .zip(&counts)
+        .map(|(sum, count)| if *count > 0 { sum / *count as f64 } else { 0.0 })
+        .collect();
+    (means, counts)
+}
+
+/// Moving average that wraps around the end of a cycle
+fn circular_average(profile: &[f64], window: usize) -> Vec<f64> {
+    let len = profile.len();
+    let half = window / 2;
+    (0..len)
+        .map(|i| {
+            let sum: f64 = (0..window).map(|j| profile[(i + len + j - half) % len]).sum();
+            sum / window as f64
+        })
+        .collect()
+}
+
+/// Shift a seasonal profile to average zero
+fn centered(profile: Vec<f64>) -> Vec<f64> {
+    let offset = mean(&profile);
+    profile.into_iter().map(|value| value - offset).collect()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Sample variance
+fn variance(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let mean = mean(values);
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY_MS: i64 = 24 * 3600 * 1000;
+    /// 2024-01-01, a Monday
+    const MONDAY: i64 = 1_704_067_200_000;
+
+    /// Deterministic noise in [-0.25, 0.25]
+    fn noise(i: usize) -> f64 {
+        (((i * 37 + 11) % 23) as f64 / 22.0 - 0.5) / 2.0
+    }
+
+    fn days(values: impl IntoIterator<Item = Option<f64>>) -> Vec<(i64, Option<f64>)> {
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| (MONDAY + i as i64 * DAY_MS, value))
+            .collect()
+    }
+
+    #[test]
+    fn test_weekly_decomposition() {
+        // Rising slowly, low on Mondays and high on Saturdays, with a gap
+        let daily = days((0..84).map(|i| {
+            let weekly = match i % 7 {
+                0 => -1.5,
+                5 => 1.0,
+                _ => 0.0,
+            };
+            (i != 40).then(|| 5.0 + 0.02 * i as f64 + weekly + noise(i))
+        }));
+        let decomposition = decompose(&daily, &DecomposeOptions::default()).unwrap();
+
+        assert_eq!((decomposition.days, decomposition.days_with_data), (84, 83));
+        assert!((decomposition.trend_change() - 0.02 * 77.0).abs() < 0.2);
+        assert!(decomposition.weekly_strength > 0.8);
+        assert!(decomposition.yearly_strength.is_none());
+        assert!(decomposition.change_points.is_empty());
+
+        let monday = decomposition.strongest_weekday().unwrap();
+        assert_eq!(monday.day, Weekday::Mon);
+        assert!((monday.effect - (-1.5 + 0.5 / 7.0)).abs() < 0.1);
+        assert!(decomposition.day_of_week[5].significant);
+        assert!(!decomposition.day_of_week[2].significant);
+
+        let gap = &decomposition.points[40];
+        assert_eq!((gap.value, gap.residual), (None, None));
+        // A Saturday, so its components still add up to a high day
+        assert!((gap.trend + gap.weekly - (5.0 + 0.02 * 40.0 + 1.0)).abs() < 0.3);
+    }
+
+    #[test]
+    fn test_yearly_and_change_points() {
+        // Two years of a yearly cycle, and a step up halfway through
+        let daily = days((0..2 * YEAR).map(|i| {
+            let yearly = 3.0 * (i as f64 / YEAR as f64 * std::f64::consts::TAU).sin();
+            let step = if i >= 400 { 2.0 } else { 0.0 };
+            Some(10.0 + yearly + step + noise(i))
+        }));
+        let decomposition = decompose(&daily, &DecomposeOptions::default()).unwrap();
+        assert!(decomposition.yearly_strength.unwrap() > 0.8);
+        assert!(decomposition.points.iter().all(|point| point.yearly.is_some()));
+
+        let step = decomposition
+            .change_points
+            .iter()
+            .find(|point| point.timestamp == MONDAY + 400 * DAY_MS)
+            .unwrap();
+        assert!(step.after > step.before);
+        assert_eq!(decomposition.change_points.len(), 1);
+
+        // Too few days with data
+        let sparse = days((0..30).map(|i| (i % 3 == 0).then_some(1.0)));
+        assert!(matches!(
+            decompose(&sparse, &DecomposeOptions::default()),
+            Err(AnalysisError::InsufficientData { needed: 14, found: 10 })
+        ));
+        assert!(DecomposeOptions::default().days(7).validate().is_err());
+    }
+}
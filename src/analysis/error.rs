@@ -0,0 +1,22 @@
+//! Analysis error types
+
+use thiserror::Error;
+
+/// Errors that can occur analyzing a metric
+#[derive(Error, Debug)]
+pub enum AnalysisError {
+    /// Analysis settings are out of range
+    #[error("Invalid analysis request: {0}")]
+    InvalidRequest(String),
+
+    /// Too few days with data to separate trend and seasonality
+    #[error("Not enough data: {found} days with data, at least {needed} needed")]
+    InsufficientData { needed: usize, found: usize },
+
+    /// Reading the daily aggregates failed
+    #[error("Query error: {0}")]
+    Query(#[from] crate::query::QueryError),
+}
+
+/// Result type for analysis operations
+pub type AnalysisResult<T> = Result<T, AnalysisError>;
@@ -0,0 +1,20 @@
+//! Time-Series Analysis
+//!
+//! Statistics over a metric's daily aggregates, read through the
+//! `QueryExecutor`.
+//!
+//! ## Architecture
+//!
+//! - **Decomposition**: Splits a metric into trend, weekly and yearly
+//!   seasonality and residual, with day-of-week effects and change points
+//! - **Change points**: Shifts in the mean of a deseasonalized series
+
+mod changepoints;
+mod decompose;
+mod error;
+
+pub use decompose::{
+    decompose, decompose_metric, ChangePoint, DayOfWeekEffect, DecomposeOptions, Decomposition,
+    DecompositionPoint,
+};
+pub use error::{AnalysisError, AnalysisResult};
@@ -3,8 +3,13 @@
 //! Request and response types for the API endpoints.
 //! These types are serialized/deserialized to/from JSON.
 
+use crate::alerts::{Alert, AlertRule, Condition};
+use crate::analysis::Decomposition;
+use crate::goals::{Goal, GoalProgress};
+use crate::query::{AggregationFunc, CacheStats, Comparison, QueryStats};
+use crate::storage::{CompactionStats, DuplicatePolicy, RetentionPolicy, Tombstone};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 // ============================================
 // INGEST DTOs
@@ -71,27 +76,59 @@ pub struct BatchError {
 // ============================================
 
 /// Query request
+///
+/// Either lists `select` and the other structured fields, or gives CQL
+/// statements in `query`.
 #[derive(Debug, Deserialize)]
 pub struct QueryRequest {
+    /// CQL statements separated by `;`, each returning its own result set
+    #[serde(default)]
+    pub query: Option<String>,
     /// Metrics to select
+    #[serde(default)]
     pub select: Vec<String>,
-    /// Time range to query
+    /// Time range to query (default: the last 7 days)
+    #[serde(default)]
     pub time_range: TimeRangeDto,
-    /// Optional GROUP BY interval
+    /// Optional GROUP BY interval (hour, day, week, month or a duration like 15m)
     #[serde(default)]
     pub group_by: Option<String>,
+    /// Time zone GROUP BY buckets are aligned to (IANA name, default UTC)
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Shift applied to GROUP BY bucket boundaries (duration like 4h)
+    #[serde(default)]
+    pub group_offset: Option<String>,
+    /// Tag keys to split GROUP BY results into series by
+    #[serde(default)]
+    pub group_by_tags: Vec<String>,
+    /// Fill for empty GROUP BY buckets: none, null, previous, linear or a number
+    #[serde(default)]
+    pub fill: Option<String>,
     /// Optional aggregation function
     #[serde(default)]
     pub aggregation: Option<String>,
     /// Optional filters
     #[serde(default)]
     pub filters: Vec<FilterDto>,
+    /// Row order: time, value or a column, then asc or desc (default "time asc")
+    #[serde(default)]
+    pub order: Option<String>,
     /// Optional limit on results
     #[serde(default)]
     pub limit: Option<usize>,
+    /// Optional number of rows to skip
+    #[serde(default)]
+    pub offset: Option<usize>,
+    /// Cursor from a previous response's `meta.next_cursor`, to fetch the next page
+    #[serde(default)]
+    pub cursor: Option<String>,
     /// Output format: json, csv, chart
     #[serde(default = "default_format")]
     pub format: String,
+    /// Include the query plan and execution statistics in `meta` (JSON only)
+    #[serde(default)]
+    pub debug: bool,
 }
 
 fn default_format() -> String {
@@ -107,6 +144,15 @@ pub struct TimeRangeDto {
     pub end: String,
 }
 
+impl Default for TimeRangeDto {
+    fn default() -> Self {
+        Self {
+            start: "now-7d".to_string(),
+            end: "now".to_string(),
+        }
+    }
+}
+
 /// Filter specification
 #[derive(Debug, Deserialize)]
 pub struct FilterDto {
@@ -119,16 +165,37 @@ pub struct FilterDto {
 }
 
 /// Query response (JSON format)
+///
+/// Queries grouped by tags return their rows in `series`, leaving `rows` empty.
 #[derive(Debug, Serialize)]
 pub struct QueryResponse {
     /// Column names
     pub columns: Vec<String>,
     /// Data rows
     pub rows: Vec<QueryRow>,
+    /// One entry per GROUP BY tag value combination
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub series: Vec<QuerySeries>,
     /// Query metadata
     pub meta: QueryMeta,
 }
 
+/// Result sets of CQL statements, in statement order
+#[derive(Debug, Serialize)]
+pub struct QueryResultsResponse {
+    /// One response per statement
+    pub results: Vec<QueryResponse>,
+}
+
+/// Rows of one GROUP BY tag value combination
+#[derive(Debug, Serialize)]
+pub struct QuerySeries {
+    /// Value of each grouped tag
+    pub tags: BTreeMap<String, String>,
+    /// Data rows of this series
+    pub rows: Vec<QueryRow>,
+}
+
 /// Single row in query response
 #[derive(Debug, Serialize, Clone)]
 pub struct QueryRow {
@@ -146,6 +213,12 @@ pub struct QueryMeta {
     pub execution_time_ms: u64,
     /// Number of rows returned
     pub row_count: usize,
+    /// Cursor for the next page, when the page was full
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    /// Query plan and execution statistics, when `debug` was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<QueryStats>,
 }
 
 /// Chart-formatted query response
@@ -186,6 +259,15 @@ pub struct CreateMetricRequest {
     /// Optional description
     #[serde(default)]
     pub description: Option<String>,
+    /// Optional retention policy (overrides the category's policy)
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    /// Duplicate policy: keep_all (default), last_write_wins or reject
+    #[serde(default)]
+    pub duplicates: Option<DuplicatePolicy>,
+    /// CQL expression for a computed metric, e.g. "SUM(calories_in) - SUM(calories_out)"
+    #[serde(default)]
+    pub expression: Option<String>,
 }
 
 /// Update metric request
@@ -197,6 +279,9 @@ pub struct UpdateMetricRequest {
     /// New description (optional)
     #[serde(default)]
     pub description: Option<String>,
+    /// New duplicate policy (optional)
+    #[serde(default)]
+    pub duplicates: Option<DuplicatePolicy>,
 }
 
 /// Metric response
@@ -214,6 +299,20 @@ pub struct MetricResponse {
     pub aggregation: String,
     /// Description
     pub description: Option<String>,
+    /// Retention policy, if the metric has its own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+    /// Source metric ID, if this metric holds rollups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollup_of: Option<u32>,
+    /// Which copies of a re-written point are kept
+    pub duplicates: DuplicatePolicy,
+    /// Expression the metric is computed from, if it is computed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expression: Option<String>,
+    /// Continuous query whose results the metric stores, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continuous_query: Option<String>,
 }
 
 /// List metrics response
@@ -225,6 +324,225 @@ pub struct MetricListResponse {
     pub total: usize,
 }
 
+/// Delete metric query parameters
+#[derive(Debug, Deserialize)]
+pub struct DeleteMetricParams {
+    /// Also delete the metric's data and free its name (default: soft delete)
+    #[serde(default)]
+    pub hard: bool,
+}
+
+// ============================================
+// CONTINUOUS QUERY DTOs
+// ============================================
+
+/// Continuous query creation request
+#[derive(Debug, Deserialize)]
+pub struct CreateContinuousQueryRequest {
+    /// `CREATE CONTINUOUS QUERY name AS SELECT ...`
+    pub statement: String,
+}
+
+/// Continuous query response
+#[derive(Debug, Serialize)]
+pub struct ContinuousQueryResponse {
+    /// Name of the metric holding the results
+    pub name: String,
+    /// The SELECT statement
+    pub query: String,
+    /// Points written by the backfill (only on creation)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backfilled: Option<usize>,
+}
+
+/// List continuous queries response
+#[derive(Debug, Serialize)]
+pub struct ContinuousQueryListResponse {
+    /// Registered continuous queries
+    pub queries: Vec<ContinuousQueryResponse>,
+    /// Total count
+    pub total: usize,
+}
+
+// ============================================
+// ALERT DTOs
+// ============================================
+
+/// Alert rule creation or update request
+#[derive(Debug, Deserialize)]
+pub struct AlertRuleRequest {
+    /// Human-readable name
+    pub name: String,
+    /// Metric the rule watches
+    pub metric: String,
+    /// Threshold, anomaly or missing-data condition
+    pub condition: Condition,
+    /// URL alerts are POSTed to
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// Whether the rule fires (default: true)
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// List alert rules response
+#[derive(Debug, Serialize)]
+pub struct AlertRuleListResponse {
+    /// All rules
+    pub rules: Vec<AlertRule>,
+    /// Total count
+    pub total: usize,
+}
+
+/// Alert history query parameters
+#[derive(Debug, Deserialize)]
+pub struct AlertHistoryParams {
+    /// Only alerts of this rule
+    #[serde(default)]
+    pub rule_id: Option<u64>,
+    /// Maximum alerts returned (default: 100)
+    #[serde(default = "default_alert_limit")]
+    pub limit: usize,
+}
+
+fn default_alert_limit() -> usize {
+    100
+}
+
+/// Alert history response
+#[derive(Debug, Serialize)]
+pub struct AlertHistoryResponse {
+    /// Alerts, most recent first
+    pub alerts: Vec<Alert>,
+    /// Number of alerts returned
+    pub total: usize,
+}
+
+// ============================================
+// GOAL DTOs
+// ============================================
+
+/// Goal creation or update request
+#[derive(Debug, Deserialize)]
+pub struct GoalRequest {
+    /// Human-readable name
+    pub name: String,
+    /// Metric the goal tracks
+    pub metric: String,
+    /// How a day's points are combined (default: sum)
+    #[serde(default = "default_goal_aggregation")]
+    pub aggregation: AggregationFunc,
+    /// Comparison of a day's aggregate against the target
+    pub operator: Comparison,
+    /// Target value
+    pub target: f64,
+    /// Days per period that must meet the target (default: 1)
+    #[serde(default = "default_goal_days")]
+    pub days: u32,
+    /// Days per period (default: 1)
+    #[serde(default = "default_goal_days")]
+    pub window_days: u32,
+    /// Time zone days start in (default: UTC)
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+fn default_goal_aggregation() -> AggregationFunc {
+    AggregationFunc::Sum
+}
+
+fn default_goal_days() -> u32 {
+    1
+}
+
+/// A goal with its current progress
+#[derive(Debug, Serialize)]
+pub struct GoalResponse {
+    /// The goal
+    #[serde(flatten)]
+    pub goal: Goal,
+    /// Streaks and completion rate as of now
+    pub progress: GoalProgress,
+}
+
+/// List goals response
+#[derive(Debug, Serialize)]
+pub struct GoalListResponse {
+    /// All goals with their progress
+    pub goals: Vec<GoalResponse>,
+    /// Total count
+    pub total: usize,
+}
+
+// ============================================
+// DELETE DTOs
+// ============================================
+
+/// Point delete request
+#[derive(Debug, Deserialize)]
+pub struct DeletePointsRequest {
+    /// Metric name (omit to delete from all metrics)
+    #[serde(default)]
+    pub metric: Option<String>,
+    /// Start of the range (ms since epoch, inclusive)
+    pub start: i64,
+    /// End of the range (ms since epoch, exclusive)
+    pub end: i64,
+    /// Only delete points carrying all of these tags
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// List tombstones response
+#[derive(Debug, Serialize)]
+pub struct TombstoneListResponse {
+    /// Deletes not yet purged
+    pub tombstones: Vec<Tombstone>,
+    /// Total count
+    pub total: usize,
+}
+
+/// Purge response
+#[derive(Debug, Serialize)]
+pub struct PurgeResponse {
+    /// Status: "ok" or "nothing_to_purge"
+    pub status: String,
+    /// Rewrite statistics, if segments were rewritten
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<CompactionStats>,
+}
+
+// ============================================
+// BACKUP DTOs
+// ============================================
+
+/// Snapshot request
+#[derive(Debug, Deserialize)]
+pub struct SnapshotRequest {
+    /// Directory to write the snapshot to, relative to the server's backup
+    /// directory (must not exist or be empty)
+    pub path: String,
+}
+
+/// Snapshot response
+#[derive(Debug, Serialize)]
+pub struct SnapshotResponse {
+    /// Directory the snapshot was written to
+    pub path: String,
+    /// When the snapshot was taken (ms since epoch)
+    pub created_at: i64,
+    /// Number of segments included
+    pub segments: usize,
+    /// Number of files written
+    pub files: usize,
+    /// Total size in bytes
+    pub bytes: u64,
+}
+
 // ============================================
 // HEALTH DTOs
 // ============================================
@@ -242,6 +560,9 @@ pub struct HealthResponse {
     pub uptime_seconds: u64,
     /// Application version
     pub version: String,
+    /// Query cache counters (absent when the cache is disabled)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_cache: Option<CacheStats>,
 }
 
 // ============================================
@@ -267,6 +588,39 @@ fn default_export_format() -> String {
     "ndjson".to_string()
 }
 
+// ============================================
+// ANALYSIS DTOs
+// ============================================
+
+/// Decomposition query parameters
+#[derive(Debug, Deserialize)]
+pub struct DecomposeParams {
+    /// Metric to decompose
+    pub metric: String,
+    /// Days of history, ending today (default: 90, min: 14, max: 1825)
+    #[serde(default)]
+    pub days: Option<u32>,
+    /// How a day's points are combined (default: avg)
+    #[serde(default)]
+    pub aggregation: Option<AggregationFunc>,
+    /// Time zone days start in (default: UTC)
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Score a mean shift needs to be a change point (default: 4.0)
+    #[serde(default)]
+    pub change_threshold: Option<f64>,
+}
+
+/// Decomposition response
+#[derive(Debug, Serialize)]
+pub struct DecomposeResponse {
+    /// Metric name
+    pub metric: String,
+    /// Components, day-of-week effects and change points
+    #[serde(flatten)]
+    pub decomposition: Decomposition,
+}
+
 // ============================================
 // INSIGHT DTOs (MemMachine Integration)
 // ============================================
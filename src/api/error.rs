@@ -100,5 +100,41 @@ impl IntoResponse for ApiError {
     }
 }
 
+impl From<crate::alerts::AlertError> for ApiError {
+    fn from(e: crate::alerts::AlertError) -> Self {
+        use crate::alerts::AlertError;
+
+        match e {
+            AlertError::InvalidRule(_) => ApiError::Validation(e.to_string()),
+            AlertError::RuleNotFound(_) => ApiError::NotFound(e.to_string()),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<crate::goals::GoalError> for ApiError {
+    fn from(e: crate::goals::GoalError) -> Self {
+        use crate::goals::GoalError;
+
+        match e {
+            GoalError::InvalidGoal(_) => ApiError::Validation(e.to_string()),
+            GoalError::GoalNotFound(_) => ApiError::NotFound(e.to_string()),
+            GoalError::Query(e) => ApiError::Query(e),
+            e => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<crate::analysis::AnalysisError> for ApiError {
+    fn from(e: crate::analysis::AnalysisError) -> Self {
+        use crate::analysis::AnalysisError;
+
+        match e {
+            AnalysisError::Query(e) => ApiError::Query(e),
+            e => ApiError::Validation(e.to_string()),
+        }
+    }
+}
+
 /// Result type for API operations
 pub type ApiResult<T> = Result<T, ApiError>;
@@ -16,11 +16,43 @@
 //! - `POST /api/v1/metrics` - Create a metric
 //! - `GET /api/v1/metrics/:id` - Get a metric
 //! - `PUT /api/v1/metrics/:id` - Update a metric
-//! - `DELETE /api/v1/metrics/:id` - Delete a metric
+//! - `DELETE /api/v1/metrics/:id` - Delete a metric (`?hard=true` also deletes its data)
+//!
+//! ## Continuous Queries
+//! - `GET /api/v1/continuous-queries` - List continuous queries
+//! - `POST /api/v1/continuous-queries` - Create and backfill a continuous query
+//!   (delete its metric to drop it)
+//!
+//! ## Alerts
+//! - `GET /api/v1/alerts` - Alert history
+//! - `GET /api/v1/alerts/rules` - List alert rules
+//! - `POST /api/v1/alerts/rules` - Create an alert rule
+//! - `GET /api/v1/alerts/rules/:id` - Get an alert rule
+//! - `PUT /api/v1/alerts/rules/:id` - Replace an alert rule
+//! - `DELETE /api/v1/alerts/rules/:id` - Delete an alert rule
+//!
+//! ## Goals
+//! - `GET /api/v1/goals` - List goals with their streaks and completion rates
+//! - `POST /api/v1/goals` - Create a goal
+//! - `GET /api/v1/goals/:id` - Get a goal with its progress
+//! - `PUT /api/v1/goals/:id` - Replace a goal
+//! - `DELETE /api/v1/goals/:id` - Delete a goal
+//!
+//! ## Delete
+//! - `POST /api/v1/delete` - Delete points by metric, time range and tags
+//! - `GET /api/v1/tombstones` - List deletes not yet purged
+//! - `POST /api/v1/purge` - Physically remove deleted points
 //!
 //! ## Export
 //! - `GET /api/v1/export` - Export data
 //!
+//! ## Backup
+//! - `POST /api/v1/snapshot` - Write a consistent snapshot of the data directory
+//!
+//! ## Analysis
+//! - `GET /api/v1/analysis/decompose` - Split a metric into trend, weekly and
+//!   yearly seasonality and residual, with day-of-week effects and change points
+//!
 //! ## Insights (MemMachine Integration)
 //! - `POST /api/v1/insights` - Ask questions about your data
 //! - `GET /api/v1/correlations` - Get metric correlations
@@ -30,7 +62,7 @@
 //! ## Health
 //! - `GET /health/live` - Liveness probe
 //! - `GET /health/ready` - Readiness probe
-//! - `GET /health` - Full health status
+//! - `GET /health` - Full health status, with query cache counters
 //!
 //! ## WebSocket
 //! - `GET /ws` - Real-time streaming connection
@@ -88,8 +120,32 @@ pub fn build_router(state: AppState) -> Router {
         .route("/metrics/:id", get(routes::metrics::get_metric))
         .route("/metrics/:id", put(routes::metrics::update_metric))
         .route("/metrics/:id", delete(routes::metrics::delete_metric))
+        // Continuous query routes
+        .route("/continuous-queries", get(routes::continuous::list_continuous_queries))
+        .route("/continuous-queries", post(routes::continuous::create_continuous_query))
+        // Alert routes
+        .route("/alerts", get(routes::alerts::alert_history))
+        .route("/alerts/rules", get(routes::alerts::list_rules))
+        .route("/alerts/rules", post(routes::alerts::create_rule))
+        .route("/alerts/rules/:id", get(routes::alerts::get_rule))
+        .route("/alerts/rules/:id", put(routes::alerts::update_rule))
+        .route("/alerts/rules/:id", delete(routes::alerts::delete_rule))
+        // Goal routes
+        .route("/goals", get(routes::goals::list_goals))
+        .route("/goals", post(routes::goals::create_goal))
+        .route("/goals/:id", get(routes::goals::get_goal))
+        .route("/goals/:id", put(routes::goals::update_goal))
+        .route("/goals/:id", delete(routes::goals::delete_goal))
+        // Delete routes
+        .route("/delete", post(routes::delete::delete_points))
+        .route("/tombstones", get(routes::delete::list_tombstones))
+        .route("/purge", post(routes::delete::purge))
         // Export routes
         .route("/export", get(routes::export::export_data))
+        // Backup routes
+        .route("/snapshot", post(routes::backup::create_snapshot))
+        // Analysis routes
+        .route("/analysis/decompose", get(routes::analysis::decompose))
         // Insight routes (MemMachine integration)
         .route("/insights", post(routes::insights::generate_insight))
         .route("/correlations", get(routes::correlations::get_correlations))
@@ -166,12 +222,15 @@ async fn shutdown_signal() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::query::QueryExecutor;
+    use crate::alerts::{AlertConfig, AlertEngine};
+    use crate::goals::{GoalConfig, GoalEngine};
+    use crate::query::{GroupByInterval, QueryExecutor};
     use crate::storage::{StorageConfig, StorageEngine};
     use axum::{
         body::Body,
         http::{Request, StatusCode},
     };
+    use chrono::Datelike;
     use tempfile::tempdir;
     use tower::util::ServiceExt;
 
@@ -180,9 +239,17 @@ mod tests {
         let config = StorageConfig::new(dir.path());
         let storage = Arc::new(StorageEngine::new(config).await.unwrap());
         let executor = Arc::new(QueryExecutor::new(Arc::clone(&storage)));
-        let api_config = ApiConfig::default();
-
-        let state = AppState::new(storage, executor, api_config);
+        let api_config = ApiConfig {
+            backup_dir: Some(dir.path().join("backups")),
+            ..Default::default()
+        };
+
+        let alerts = AlertEngine::open(Arc::clone(&storage), AlertConfig::default()).unwrap();
+        let goals =
+            GoalEngine::open(&storage, Arc::clone(&executor), GoalConfig::default()).unwrap();
+        let state = AppState::new(storage, executor, api_config)
+            .with_alerts(Arc::new(alerts))
+            .with_goals(Arc::new(goals));
         let router = build_router(state);
 
         (router, dir)
@@ -299,6 +366,7 @@ mod tests {
         let (app, _dir) = create_test_app().await;
 
         let response = app
+            .clone()
             .oneshot(
                 Request::builder()
                     .method("POST")
@@ -313,5 +381,353 @@ mod tests {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::CREATED);
+
+        // A rollup interval of 0 is rejected rather than stored
+        let metric = r#"{"name": "hr", "unit": "bpm", "category": "health",
+                         "aggregation": "average",
+                         "retention": {"raw_retention_days": 30, "rollup": {"interval_ms": 0}}}"#;
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/v1/metrics")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(metric))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_create_continuous_query() {
+        let (app, _dir) = create_test_app().await;
+
+        let create = |statement: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/api/v1/continuous-queries")
+                .header("Content-Type", "application/json")
+                .body(Body::from(format!(r#"{{"statement": "{}"}}"#, statement)))
+                .unwrap()
+        };
+
+        // The source metric must exist
+        let statement = "CREATE CONTINUOUS QUERY daily_mood AS SELECT AVG(mood) GROUP BY day";
+        let response = app.clone().oneshot(create(statement)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/v1/ingest")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(r#"{"metric": "mood", "value": 7.5}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app.clone().oneshot(create(statement)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .oneshot(create("CREATE CONTINUOUS QUERY x AS SELECT mood"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_query_cursor_pages() {
+        let (app, _dir) = create_test_app().await;
+
+        // Hourly mood on 2024-01-01, with the hours between 00:00 and 03:00 empty
+        let hour_ms = 3600 * 1000;
+        let start = 1_704_067_200_000_i64;
+        let points = serde_json::json!({"points": [
+            {"metric": "mood", "value": 1.0, "timestamp": start + hour_ms / 2},
+            {"metric": "mood", "value": 4.0, "timestamp": start + 3 * hour_ms + hour_ms / 2},
+        ]});
+        let post = |uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(post("/api/v1/ingest/batch", points)).await.unwrap();
+        assert!(response.status().is_success());
+
+        let page = |cursor: Option<&str>| {
+            let mut query = serde_json::json!({
+                "select": ["mood"], "aggregation": "avg", "group_by": "hour", "fill": "linear",
+                "time_range": {"start": "2024-01-01T00:00:00Z", "end": "2024-01-01T04:00:00Z"},
+                "limit": 2,
+            });
+            if let Some(cursor) = cursor {
+                query["cursor"] = cursor.into();
+            }
+            post("/api/v1/query", query)
+        };
+        let read = |response: axum::response::Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let values = |result: &serde_json::Value| -> Vec<f64> {
+            let rows = result["rows"].as_array().unwrap();
+            rows.iter().map(|row| row["mood"].as_f64().unwrap()).collect()
+        };
+
+        // Filled values continue across the page boundary
+        let first = read(app.clone().oneshot(page(None)).await.unwrap()).await;
+        assert_eq!(values(&first), [1.0, 2.0]);
+        let cursor = first["meta"]["next_cursor"].as_str().unwrap().to_string();
+        let second = read(app.clone().oneshot(page(Some(&cursor))).await.unwrap()).await;
+        assert_eq!(values(&second), [3.0, 4.0]);
+
+        let mut request = serde_json::json!({"select": ["mood"], "limit": 2, "offset": 2});
+        request["cursor"] = cursor.into();
+        let response = app.oneshot(post("/api/v1/query", request)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_query_cursor_splits_equal_timestamps() {
+        let (app, _dir) = create_test_app().await;
+
+        // Three readings share the middle timestamp, one per location
+        let start = 1_704_067_200_000_i64;
+        let points = serde_json::json!({"points": [
+            {"metric": "mood", "value": 1.0, "timestamp": start},
+            {"metric": "mood", "value": 2.0, "timestamp": start + 1000, "tags": {"location": "a"}},
+            {"metric": "mood", "value": 3.0, "timestamp": start + 1000, "tags": {"location": "b"}},
+            {"metric": "mood", "value": 4.0, "timestamp": start + 1000, "tags": {"location": "c"}},
+            {"metric": "mood", "value": 5.0, "timestamp": start + 2000},
+        ]});
+        let post = |uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(post("/api/v1/ingest/batch", points)).await.unwrap();
+        assert!(response.status().is_success());
+
+        // Pages of two split the shared timestamp, in either order
+        let orders = [
+            ("time", [1.0, 2.0, 3.0, 4.0, 5.0]),
+            ("time desc", [5.0, 2.0, 3.0, 4.0, 1.0]),
+        ];
+        for (order, expected) in orders {
+            let mut values = Vec::new();
+            let mut cursor: Option<String> = None;
+            loop {
+                let mut query = serde_json::json!({
+                    "select": ["mood"], "order": order, "limit": 2,
+                    "time_range": {"start": "2024-01-01T00:00:00Z", "end": "2024-01-02T00:00:00Z"},
+                });
+                if let Some(cursor) = cursor {
+                    query["cursor"] = cursor.into();
+                }
+                let response = app.clone().oneshot(post("/api/v1/query", query)).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                let rows = page["rows"].as_array().unwrap();
+                values.extend(rows.iter().map(|row| row["mood"].as_f64().unwrap()));
+                cursor = page["meta"]["next_cursor"].as_str().map(str::to_string);
+                if cursor.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(values, expected, "{}", order);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_stays_in_backup_dir() {
+        let (app, dir) = create_test_app().await;
+        let snapshot = |path: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/api/v1/snapshot")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::json!({"path": path}).to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(snapshot("nightly/1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(dir.path().join("backups/nightly/1").is_dir());
+
+        let outside = dir.path().join("outside");
+        for path in ["../outside", "nightly/../../outside", outside.to_str().unwrap()] {
+            let response = app.clone().oneshot(snapshot(path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        assert!(!outside.exists());
+    }
+
+    #[tokio::test]
+    async fn test_alert_rules() {
+        let (app, _dir) = create_test_app().await;
+
+        let request = |method: &str, uri: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let rule = r#"{"name": "No mood", "metric": "mood",
+                       "condition": {"type": "missing_data", "for_ms": 172800000}}"#;
+        let response = app
+            .clone()
+            .oneshot(request("POST", "/api/v1/alerts/rules", rule))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let invalid = r#"{"name": "No mood", "metric": "mood",
+                          "condition": {"type": "missing_data", "for_ms": 0}}"#;
+        let response = app
+            .clone()
+            .oneshot(request("PUT", "/api/v1/alerts/rules/1", invalid))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/api/v1/alerts/rules/1", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", "/api/v1/alerts/rules/1", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/api/v1/alerts/rules/1", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(request("GET", "/api/v1/alerts?limit=10", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_goals() {
+        let (app, _dir) = create_test_app().await;
+
+        let request = |method: &str, uri: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let goal = r#"{"name": "Walk", "metric": "steps", "operator": ">=", "target": 8000,
+                       "days": 5, "window_days": 7}"#;
+        let response = app.clone().oneshot(request("POST", "/api/v1/goals", goal)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let invalid = r#"{"name": "Walk", "metric": "steps", "operator": ">=", "target": 8000,
+                          "days": 8, "window_days": 7}"#;
+        let response =
+            app.clone().oneshot(request("PUT", "/api/v1/goals/1", invalid)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app.clone().oneshot(request("GET", "/api/v1/goals", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(list["total"], 1);
+        assert_eq!(list["goals"][0]["progress"]["current_streak"], 0);
+
+        let response = app.clone().oneshot(request("DELETE", "/api/v1/goals/1", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app.oneshot(request("GET", "/api/v1/goals/1", "")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_decompose() {
+        let (app, _dir) = create_test_app().await;
+
+        // Four weeks of mood, lower on Mondays
+        let day_ms = 24 * 3600 * 1000;
+        let today = GroupByInterval::Day.truncate(chrono::Utc::now().timestamp_millis());
+        let points: Vec<serde_json::Value> = (0..28)
+            .map(|days_ago| {
+                let timestamp = today - days_ago * day_ms + 3600 * 1000;
+                let weekday = chrono::DateTime::from_timestamp_millis(timestamp).unwrap().weekday();
+                let mood = if weekday == chrono::Weekday::Mon { 4.0 } else { 7.0 };
+                serde_json::json!({"metric": "mood", "value": mood + (days_ago % 3) as f64 * 0.1,
+                                   "timestamp": timestamp})
+            })
+            .collect();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/v1/ingest/batch")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::json!({ "points": points }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = app
+            .clone()
+            .oneshot(get("/api/v1/analysis/decompose?metric=mood&days=28"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result["metric"], "mood");
+        assert_eq!(result["days_with_data"], 28);
+        assert_eq!(result["day_of_week"][0]["day"], "Mon");
+        assert_eq!(result["day_of_week"][0]["significant"], true);
+
+        let response = app
+            .clone()
+            .oneshot(get("/api/v1/analysis/decompose?metric=mood&days=7"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .oneshot(get("/api/v1/analysis/decompose?metric=weight"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 }
@@ -0,0 +1,117 @@
+//! Alert Routes
+//!
+//! Alert rules evaluated as data arrives, and the alerts they raised.
+//!
+//! - GET /api/v1/alerts - Alert history (`?rule_id=` and `?limit=`)
+//! - GET /api/v1/alerts/rules - List alert rules
+//! - POST /api/v1/alerts/rules - Create an alert rule
+//! - GET /api/v1/alerts/rules/:id - Get an alert rule
+//! - PUT /api/v1/alerts/rules/:id - Replace an alert rule
+//! - DELETE /api/v1/alerts/rules/:id - Delete an alert rule
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use std::sync::Arc;
+
+use crate::alerts::{AlertEngine, AlertRule};
+use crate::api::dto::{
+    AlertHistoryParams, AlertHistoryResponse, AlertRuleListResponse, AlertRuleRequest,
+};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::state::AppState;
+
+/// GET /api/v1/alerts
+///
+/// Most recent alerts first.
+pub async fn alert_history(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AlertHistoryParams>,
+) -> ApiResult<Json<AlertHistoryResponse>> {
+    let alerts = engine(&state)?.history(params.rule_id, params.limit).await;
+
+    Ok(Json(AlertHistoryResponse {
+        total: alerts.len(),
+        alerts,
+    }))
+}
+
+/// GET /api/v1/alerts/rules
+pub async fn list_rules(
+    State(state): State<Arc<AppState>>,
+) -> ApiResult<Json<AlertRuleListResponse>> {
+    let rules = engine(&state)?.rules().await;
+
+    Ok(Json(AlertRuleListResponse {
+        total: rules.len(),
+        rules,
+    }))
+}
+
+/// POST /api/v1/alerts/rules
+pub async fn create_rule(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<AlertRuleRequest>,
+) -> ApiResult<(StatusCode, Json<AlertRule>)> {
+    let rule = engine(&state)?.create_rule(request_to_rule(req)).await?;
+
+    tracing::info!(rule_id = rule.id, name = %rule.name, "Created alert rule");
+
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+/// GET /api/v1/alerts/rules/:id
+pub async fn get_rule(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<Json<AlertRule>> {
+    engine(&state)?
+        .rule(id)
+        .await
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("Alert rule with id {} not found", id)))
+}
+
+/// PUT /api/v1/alerts/rules/:id
+///
+/// Replace a rule's definition; it re-arms as if it had never fired.
+pub async fn update_rule(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Json(req): Json<AlertRuleRequest>,
+) -> ApiResult<Json<AlertRule>> {
+    let rule = engine(&state)?.update_rule(id, request_to_rule(req)).await?;
+    Ok(Json(rule))
+}
+
+/// DELETE /api/v1/alerts/rules/:id
+///
+/// The rule's alerts stay in the history.
+pub async fn delete_rule(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<StatusCode> {
+    engine(&state)?.delete_rule(id).await?;
+
+    tracing::info!(rule_id = id, "Deleted alert rule");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The alert engine, if the server runs one
+fn engine(state: &AppState) -> ApiResult<&Arc<AlertEngine>> {
+    state
+        .alerts
+        .as_ref()
+        .ok_or_else(|| ApiError::ServiceUnavailable("Alerting not enabled".to_string()))
+}
+
+fn request_to_rule(req: AlertRuleRequest) -> AlertRule {
+    let rule = AlertRule::new(req.name, req.metric, req.condition).enabled(req.enabled);
+    match req.webhook {
+        Some(url) => rule.webhook(url),
+        None => rule,
+    }
+}
@@ -0,0 +1,52 @@
+//! Analysis Routes
+//!
+//! Statistics over a metric's daily aggregates.
+//!
+//! - GET /api/v1/analysis/decompose - Trend, seasonality and change points
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::Utc;
+use chrono_tz::Tz;
+use std::sync::Arc;
+
+use crate::analysis::{decompose_metric, DecomposeOptions};
+use crate::api::dto::{DecomposeParams, DecomposeResponse};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::state::AppState;
+
+/// GET /api/v1/analysis/decompose
+///
+/// Decompose a metric's daily aggregates into trend, weekly and yearly
+/// seasonality and residual. Yearly seasonality needs two years of days.
+pub async fn decompose(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DecomposeParams>,
+) -> ApiResult<Json<DecomposeResponse>> {
+    let mut options = DecomposeOptions::default();
+    if let Some(days) = params.days {
+        options = options.days(days);
+    }
+    if let Some(aggregation) = params.aggregation {
+        options = options.aggregation(aggregation);
+    }
+    if let Some(timezone) = &params.timezone {
+        let tz: Tz = timezone
+            .parse()
+            .map_err(|_| ApiError::Validation(format!("unknown time zone '{}'", timezone)))?;
+        options = options.timezone(tz);
+    }
+    if let Some(threshold) = params.change_threshold {
+        options = options.change_threshold(threshold);
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let decomposition = decompose_metric(&state.executor, &params.metric, &options, now).await?;
+
+    Ok(Json(DecomposeResponse {
+        metric: params.metric,
+        decomposition,
+    }))
+}
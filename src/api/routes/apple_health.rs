@@ -12,7 +12,7 @@ use std::sync::Arc;
 
 use crate::api::error::{ApiError, ApiResult};
 use crate::api::state::AppState;
-use crate::storage::{AggregationType, Category, DataPoint, Metric};
+use crate::storage::{AggregationType, Category, DataPoint, DuplicatePolicy, Metric};
 use crate::websocket::WsEvent;
 
 /// Request body for Apple Health import
@@ -310,7 +310,9 @@ async fn resolve_or_create_metric(state: &AppState, name: &str, unit: &str) -> A
         Category::Custom
     };
 
-    let metric = Metric::new(name, unit, category, AggregationType::Average);
+    // Re-importing an export rewrites the same samples, so keep only the latest copy
+    let metric = Metric::new(name, unit, category, AggregationType::Average)
+        .duplicates(DuplicatePolicy::LastWriteWins);
     let id = state.storage.register_metric(metric).await?;
     tracing::info!(metric_name = %name, metric_id = id, "Created metric from Apple Health import");
     Ok(id)
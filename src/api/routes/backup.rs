@@ -0,0 +1,69 @@
+//! Backup Routes
+//!
+//! Consistent snapshots of the data directory while the server runs.
+//! Snapshots are restored offline with `chronicle-cli restore`. They are
+//! only written under the configured backup directory, so a request can't
+//! overwrite files elsewhere on the server.
+//!
+//! - POST /api/v1/snapshot - Write a snapshot to a directory under the backup directory
+
+use axum::{extract::State, Json};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::api::dto::{SnapshotRequest, SnapshotResponse};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::state::AppState;
+
+/// POST /api/v1/snapshot
+///
+/// Flush and write a snapshot of segments, indexes and metadata.
+pub async fn create_snapshot(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<SnapshotRequest>,
+) -> ApiResult<Json<SnapshotResponse>> {
+    let root = state.config.backup_dir.as_ref().ok_or_else(|| {
+        ApiError::ServiceUnavailable("Snapshots not enabled (set CHRONICLE_BACKUP_DIR)".to_string())
+    })?;
+    let dest = snapshot_path(root, &req.path)?;
+    if dest.exists() && std::fs::read_dir(&dest).map(|mut d| d.next().is_some()).unwrap_or(true) {
+        return Err(ApiError::Validation(format!(
+            "Snapshot path '{}' is not an empty directory",
+            req.path
+        )));
+    }
+
+    let manifest = state.storage.snapshot(&dest).await?;
+
+    tracing::info!(path = %dest.display(), segments = manifest.segments.len(), "Wrote snapshot");
+
+    Ok(Json(SnapshotResponse {
+        path: dest.display().to_string(),
+        created_at: manifest.created_at,
+        segments: manifest.segments.len(),
+        files: manifest.files.len(),
+        bytes: manifest.total_bytes(),
+    }))
+}
+
+/// Resolve a requested snapshot directory under the backup directory
+///
+/// The path must be relative and may not step out with `..`.
+fn snapshot_path(root: &Path, path: &str) -> ApiResult<PathBuf> {
+    if path.trim().is_empty() {
+        return Err(ApiError::Validation("path is required".to_string()));
+    }
+
+    let relative = Path::new(path);
+    let escapes = relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Err(ApiError::Validation(format!(
+            "Snapshot path '{}' must be relative to the backup directory, without '..'",
+            path
+        )));
+    }
+
+    Ok(root.join(relative))
+}
@@ -0,0 +1,63 @@
+//! Continuous Query Routes
+//!
+//! Queries whose results are kept up to date in a derived metric.
+//!
+//! - GET /api/v1/continuous-queries - List continuous queries
+//! - POST /api/v1/continuous-queries - Create and backfill a continuous query
+//!
+//! A continuous query is dropped by deleting its metric.
+
+use axum::{extract::State, http::StatusCode, Json};
+use std::sync::Arc;
+
+use crate::api::dto::{
+    ContinuousQueryListResponse, ContinuousQueryResponse, CreateContinuousQueryRequest,
+};
+use crate::api::error::ApiResult;
+use crate::api::state::AppState;
+use crate::query::parse_continuous_query;
+
+/// GET /api/v1/continuous-queries
+///
+/// List all registered continuous queries.
+pub async fn list_continuous_queries(
+    State(state): State<Arc<AppState>>,
+) -> ApiResult<Json<ContinuousQueryListResponse>> {
+    let queries: Vec<ContinuousQueryResponse> = state
+        .executor
+        .continuous_queries()
+        .await?
+        .into_iter()
+        .map(|cq| ContinuousQueryResponse {
+            name: cq.name,
+            query: cq.text,
+            backfilled: None,
+        })
+        .collect();
+
+    Ok(Json(ContinuousQueryListResponse {
+        total: queries.len(),
+        queries,
+    }))
+}
+
+/// POST /api/v1/continuous-queries
+///
+/// Register a `CREATE CONTINUOUS QUERY` statement and backfill its metric
+/// over all existing data.
+pub async fn create_continuous_query(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateContinuousQueryRequest>,
+) -> ApiResult<(StatusCode, Json<ContinuousQueryResponse>)> {
+    let cq = parse_continuous_query(&req.statement)?;
+    let backfilled = state.executor.create_continuous_query(&cq).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ContinuousQueryResponse {
+            name: cq.name,
+            query: cq.text,
+            backfilled: Some(backfilled),
+        }),
+    ))
+}
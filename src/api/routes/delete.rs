@@ -0,0 +1,86 @@
+//! Delete Routes
+//!
+//! Point-level deletes, e.g. to clean up a bad import.
+//!
+//! - POST /api/v1/delete - Delete points by metric, time range and tags
+//! - GET /api/v1/tombstones - List deletes not yet purged
+//! - POST /api/v1/purge - Physically remove deleted points
+
+use axum::{extract::State, Json};
+use std::sync::Arc;
+
+use crate::api::dto::{DeletePointsRequest, PurgeResponse, TombstoneListResponse};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::state::AppState;
+use crate::storage::{TimeRange, Tombstone};
+
+/// POST /api/v1/delete
+///
+/// Delete matching points. They are hidden from queries immediately and
+/// removed from disk by the next purge or compaction.
+pub async fn delete_points(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<DeletePointsRequest>,
+) -> ApiResult<Json<Tombstone>> {
+    let range = TimeRange::try_new(req.start, req.end)
+        .ok_or_else(|| ApiError::Validation("start must be before end".to_string()))?;
+
+    let metric_id = match &req.metric {
+        Some(name) => Some(
+            state
+                .storage
+                .get_metric(name)
+                .await
+                .ok_or_else(|| ApiError::NotFound(format!("Metric '{}' not found", name)))?
+                .id,
+        ),
+        None => None,
+    };
+
+    if metric_id.is_none() && req.tags.is_empty() {
+        tracing::warn!(
+            start = req.start,
+            end = req.end,
+            "Deleting points of all metrics in range"
+        );
+    }
+
+    let tombstone = state
+        .storage
+        .delete_points(metric_id, range, req.tags)
+        .await?;
+
+    Ok(Json(tombstone))
+}
+
+/// GET /api/v1/tombstones
+///
+/// List deletes whose points have not been purged yet.
+pub async fn list_tombstones(
+    State(state): State<Arc<AppState>>,
+) -> ApiResult<Json<TombstoneListResponse>> {
+    let tombstones = state.storage.tombstones().await;
+
+    Ok(Json(TombstoneListResponse {
+        total: tombstones.len(),
+        tombstones,
+    }))
+}
+
+/// POST /api/v1/purge
+///
+/// Rewrite segments holding deleted points without them.
+pub async fn purge(State(state): State<Arc<AppState>>) -> ApiResult<Json<PurgeResponse>> {
+    let stats = state.storage.purge().await?;
+
+    let status = if stats.is_some() {
+        "ok"
+    } else {
+        "nothing_to_purge"
+    };
+
+    Ok(Json(PurgeResponse {
+        status: status.to_string(),
+        stats,
+    }))
+}
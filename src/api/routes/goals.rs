@@ -0,0 +1,111 @@
+//! Goal Routes
+//!
+//! Daily targets for habit metrics, with their streaks and completion rates.
+//!
+//! - GET /api/v1/goals - List goals with their progress
+//! - POST /api/v1/goals - Create a goal
+//! - GET /api/v1/goals/:id - Get a goal with its progress
+//! - PUT /api/v1/goals/:id - Replace a goal
+//! - DELETE /api/v1/goals/:id - Delete a goal
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use std::sync::Arc;
+
+use crate::api::dto::{GoalListResponse, GoalRequest, GoalResponse};
+use crate::api::error::{ApiError, ApiResult};
+use crate::api::state::AppState;
+use crate::goals::{Goal, GoalEngine};
+
+/// GET /api/v1/goals
+pub async fn list_goals(State(state): State<Arc<AppState>>) -> ApiResult<Json<GoalListResponse>> {
+    let engine = engine(&state)?;
+
+    let mut goals = Vec::new();
+    for goal in engine.goals().await {
+        goals.push(goal_to_response(engine, goal).await?);
+    }
+
+    Ok(Json(GoalListResponse {
+        total: goals.len(),
+        goals,
+    }))
+}
+
+/// POST /api/v1/goals
+pub async fn create_goal(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<GoalRequest>,
+) -> ApiResult<(StatusCode, Json<GoalResponse>)> {
+    let engine = engine(&state)?;
+    let goal = engine.create_goal(request_to_goal(req)).await?;
+
+    tracing::info!(goal_id = goal.id, name = %goal.name, "Created goal");
+
+    Ok((StatusCode::CREATED, Json(goal_to_response(engine, goal).await?)))
+}
+
+/// GET /api/v1/goals/:id
+pub async fn get_goal(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<Json<GoalResponse>> {
+    let engine = engine(&state)?;
+    let goal = engine
+        .goal(id)
+        .await
+        .ok_or_else(|| ApiError::NotFound(format!("Goal with id {} not found", id)))?;
+
+    Ok(Json(goal_to_response(engine, goal).await?))
+}
+
+/// PUT /api/v1/goals/:id
+pub async fn update_goal(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Json(req): Json<GoalRequest>,
+) -> ApiResult<Json<GoalResponse>> {
+    let engine = engine(&state)?;
+    let goal = engine.update_goal(id, request_to_goal(req)).await?;
+
+    Ok(Json(goal_to_response(engine, goal).await?))
+}
+
+/// DELETE /api/v1/goals/:id
+pub async fn delete_goal(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> ApiResult<StatusCode> {
+    engine(&state)?.delete_goal(id).await?;
+
+    tracing::info!(goal_id = id, "Deleted goal");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The goal engine, or 503 when goals are disabled
+fn engine(state: &AppState) -> ApiResult<&Arc<GoalEngine>> {
+    state
+        .goals
+        .as_ref()
+        .ok_or_else(|| ApiError::ServiceUnavailable("Goals not enabled".to_string()))
+}
+
+async fn goal_to_response(engine: &GoalEngine, goal: Goal) -> ApiResult<GoalResponse> {
+    let progress = engine.progress(&goal, Utc::now().timestamp_millis()).await?;
+    Ok(GoalResponse { goal, progress })
+}
+
+fn request_to_goal(req: GoalRequest) -> Goal {
+    let goal = Goal::new(req.name, req.metric, req.operator, req.target)
+        .aggregation(req.aggregation)
+        .days_of(req.days, req.window_days);
+    match req.timezone {
+        Some(timezone) => goal.timezone(timezone),
+        None => goal,
+    }
+}
@@ -57,6 +57,7 @@ pub async fn full_health(State(state): State<Arc<AppState>>) -> Json<HealthRespo
         index: index_status.to_string(),
         uptime_seconds: state.uptime_seconds(),
         version: env!("CARGO_PKG_VERSION").to_string(),
+        query_cache: state.executor.cache().map(|cache| cache.stats()),
     })
 }
 
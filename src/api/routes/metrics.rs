@@ -6,21 +6,22 @@
 //! - POST /api/v1/metrics - Create a new metric
 //! - GET /api/v1/metrics/:id - Get a specific metric
 //! - PUT /api/v1/metrics/:id - Update a metric
-//! - DELETE /api/v1/metrics/:id - Delete a metric (soft delete)
+//! - DELETE /api/v1/metrics/:id - Delete a metric (soft by default, `?hard=true` deletes data)
 
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     Json,
 };
 use std::sync::Arc;
 
 use crate::api::dto::{
-    CreateMetricRequest, MetricListResponse, MetricResponse, UpdateMetricRequest,
+    CreateMetricRequest, DeleteMetricParams, MetricListResponse, MetricResponse,
+    UpdateMetricRequest,
 };
 use crate::api::error::{ApiError, ApiResult};
 use crate::api::state::AppState;
-use crate::storage::{AggregationType, Category, Metric};
+use crate::storage::{AggregationType, Category, DeleteMode, Metric, StorageError};
 
 /// GET /api/v1/metrics
 ///
@@ -84,6 +85,17 @@ pub async fn create_metric(
     if let Some(desc) = &req.description {
         metric = metric.description(desc);
     }
+    if let Some(policy) = &req.retention {
+        metric = metric.retention(policy.clone());
+    }
+    if let Some(policy) = req.duplicates {
+        metric = metric.duplicates(policy);
+    }
+    if let Some(expression) = &req.expression {
+        crate::query::parse_expression(expression)
+            .map_err(|e| ApiError::Validation(format!("Invalid expression: {}", e)))?;
+        metric = metric.computed(expression);
+    }
 
     // Register metric
     let id = state.storage.register_metric(metric.clone()).await?;
@@ -110,59 +122,63 @@ pub async fn create_metric(
 pub async fn update_metric(
     State(state): State<Arc<AppState>>,
     Path(id): Path<u32>,
-    Json(_req): Json<UpdateMetricRequest>,
+    Json(req): Json<UpdateMetricRequest>,
 ) -> ApiResult<Json<MetricResponse>> {
     let metrics = state.storage.get_metrics().await;
 
-    let metric = metrics
-        .iter()
+    let mut metric = metrics
+        .into_iter()
         .find(|m| m.id == id)
         .ok_or_else(|| ApiError::NotFound(format!("Metric with id {} not found", id)))?;
 
-    // NOTE: For now, we can't actually update metrics in storage since we only have register_metric
-    // This would require adding an update method to StorageEngine
-    // For now, we return the metric as-is with a warning
+    if let Some(policy) = req.duplicates {
+        state.storage.set_metric_duplicates(&metric.name, policy).await?;
+        metric.duplicates = policy;
+        tracing::info!(metric_id = id, ?policy, "Updated duplicate policy");
+    }
 
-    tracing::warn!(
-        metric_id = id,
-        "Metric update requested but not implemented - returning existing metric"
-    );
+    // NOTE: Unit and description can't be updated yet; this would require
+    // adding an update method to StorageEngine
 
-    // In a real implementation, we would:
-    // 1. Update unit if provided: req.unit
-    // 2. Update description if provided: req.description
-    // 3. Save the changes
+    if req.unit.is_some() || req.description.is_some() {
+        tracing::warn!(
+            metric_id = id,
+            "Metric unit/description update requested but not implemented"
+        );
+    }
 
-    Ok(Json(metric_to_response(metric)))
+    Ok(Json(metric_to_response(&metric)))
 }
 
 /// DELETE /api/v1/metrics/:id
 ///
-/// Soft delete a metric (data is retained, metric is hidden).
+/// Soft delete a metric (data is retained, metric is hidden; creating it
+/// again restores it). With `?hard=true` its data is deleted too and the
+/// name can be reused.
 pub async fn delete_metric(
     State(state): State<Arc<AppState>>,
     Path(id): Path<u32>,
+    Query(params): Query<DeleteMetricParams>,
 ) -> ApiResult<StatusCode> {
-    let metrics = state.storage.get_metrics().await;
-
-    let _metric = metrics
-        .iter()
-        .find(|m| m.id == id)
-        .ok_or_else(|| ApiError::NotFound(format!("Metric with id {} not found", id)))?;
-
-    // NOTE: For now, we can't actually delete metrics
-    // This would require adding a delete method to StorageEngine
-    // For now, we just acknowledge the request
-
-    tracing::warn!(
-        metric_id = id,
-        "Metric delete requested but not implemented"
-    );
+    let mode = if params.hard {
+        DeleteMode::Hard
+    } else {
+        DeleteMode::Soft
+    };
+
+    // Soft-deleted metrics are hidden from listings but can still be hard deleted
+    state
+        .storage
+        .delete_metric(id, mode)
+        .await
+        .map_err(|e| match e {
+            StorageError::MetricNotFound(_) => {
+                ApiError::NotFound(format!("Metric with id {} not found", id))
+            }
+            e => e.into(),
+        })?;
 
-    // In a real implementation, we would soft-delete by:
-    // 1. Marking the metric as deleted
-    // 2. Not returning it in list queries
-    // 3. Keeping the data for historical queries
+    tracing::info!(metric_id = id, ?mode, "Deleted metric");
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -196,6 +212,12 @@ fn validate_create_request(req: &CreateMetricRequest) -> ApiResult<()> {
         ));
     }
 
+    if let Some(policy) = &req.retention {
+        policy
+            .validate()
+            .map_err(|e| ApiError::Validation(format!("Invalid retention policy: {}", e)))?;
+    }
+
     Ok(())
 }
 
@@ -239,6 +261,11 @@ fn metric_to_response(metric: &Metric) -> MetricResponse {
         category: format!("{}", metric.category),
         aggregation: format!("{:?}", metric.aggregation).to_lowercase(),
         description: metric.description.clone(),
+        retention: metric.retention.clone(),
+        rollup_of: metric.rollup_of,
+        duplicates: metric.duplicates,
+        expression: metric.expression.clone(),
+        continuous_query: metric.continuous_query.clone(),
     }
 }
 
@@ -274,6 +301,9 @@ mod tests {
             category: "mood".to_string(),
             aggregation: "average".to_string(),
             description: None,
+            retention: None,
+            duplicates: None,
+            expression: None,
         };
         assert!(validate_create_request(&valid).is_ok());
 
@@ -2,9 +2,15 @@
 //!
 //! Route handlers organized by functionality.
 
+pub mod alerts;
+pub mod analysis;
 pub mod apple_health;
+pub mod backup;
+pub mod continuous;
 pub mod correlations;
+pub mod delete;
 pub mod export;
+pub mod goals;
 pub mod health;
 pub mod ingest;
 pub mod insights;
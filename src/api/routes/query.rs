@@ -3,6 +3,16 @@
 //! Endpoint for executing Chronicle queries.
 //!
 //! - POST /api/v1/query - Execute a query
+//!
+//! A request either describes one query with `select` and related fields,
+//! or carries CQL text in `query`; `;`-separated statements there return
+//! one result set each under `results`.
+//!
+//! Results ordered by time can be paged with `limit`: a full page returns
+//! `meta.next_cursor`, which is passed back as `cursor` for the next page.
+//! The cursor is the last row's timestamp and the number of rows with that
+//! timestamp returned so far, so rows sharing it can straddle pages.
+//! `offset` can't be combined with `cursor`.
 
 use axum::{
     extract::State,
@@ -11,15 +21,19 @@ use axum::{
     Json,
 };
 use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
 use std::sync::Arc;
 
 use crate::api::dto::{
-    ChartDataset, ChartResponse, FilterDto, QueryMeta, QueryRequest, QueryResponse, QueryRow,
-    TimeRangeDto,
+    ChartDataset, ChartResponse, FilterDto, QueryMeta, QueryRequest, QueryResponse,
+    QueryResultsResponse, QueryRow, QuerySeries, TimeRangeDto,
 };
 use crate::api::error::{ApiError, ApiResult};
 use crate::api::state::AppState;
-use crate::query::{AggregationFunc, Filter, FilterField, FilterValue, GroupByInterval, Operator, Query};
+use crate::query::{
+    AggregationFunc, FillMode, Filter, FilterField, FilterValue, GroupByClause, GroupByInterval,
+    parse_statements, Operator, OrderBy, OrderKey, Query, ResultRow,
+};
 use crate::storage::TimeRange;
 
 /// POST /api/v1/query
@@ -29,6 +43,11 @@ pub async fn execute_query(
     State(state): State<Arc<AppState>>,
     Json(req): Json<QueryRequest>,
 ) -> ApiResult<Response> {
+    // CQL statements run as written
+    if let Some(ref text) = req.query {
+        return execute_statements(&state, &req, text).await;
+    }
+
     // Validate request
     if req.select.is_empty() {
         return Err(ApiError::Validation("select cannot be empty".to_string()));
@@ -37,14 +56,86 @@ pub async fn execute_query(
     // Parse time range
     let time_range = parse_time_range(&req.time_range)?;
 
+    // Parse GROUP BY
+    let group_by = if let Some(ref group_by) = req.group_by {
+        let mut clause = GroupByClause::new(parse_group_by(group_by)?);
+        if let Some(ref timezone) = req.timezone {
+            clause = clause.timezone(parse_timezone(timezone)?);
+        }
+        if let Some(ref offset) = req.group_offset {
+            clause = clause.offset(parse_group_offset(offset)?);
+        }
+        for key in &req.group_by_tags {
+            clause = clause.tag(key.clone());
+        }
+        if let Some(ref fill) = req.fill {
+            clause = clause.fill(fill.parse::<FillMode>().map_err(ApiError::Validation)?);
+        }
+        Some(clause)
+    } else if !req.group_by_tags.is_empty() || req.fill.is_some() {
+        return Err(ApiError::Validation(
+            "group_by_tags and fill require group_by".to_string(),
+        ));
+    } else {
+        None
+    };
+
+    // Parse order
+    let order = match req.order {
+        Some(ref order) => order.parse::<OrderBy>().map_err(ApiError::Validation)?,
+        None => OrderBy::default(),
+    };
+    let pageable = order.key == OrderKey::Time && req.group_by_tags.is_empty();
+
+    // Filling from neighbouring buckets needs the rows before the cursor, so
+    // those pages run over the whole range and are cut after filling
+    let fills_across = group_by
+        .as_ref()
+        .is_some_and(|clause| matches!(clause.fill, FillMode::Previous | FillMode::Linear));
+
+    // Continue after the cursor
+    let cursor = match req.cursor {
+        Some(ref cursor) => {
+            if !pageable || req.limit.is_none() {
+                return Err(ApiError::Validation(
+                    "cursor requires limit and results ordered by time without group_by_tags"
+                        .to_string(),
+                ));
+            }
+            if req.offset.is_some() {
+                return Err(ApiError::Validation(
+                    "offset cannot be combined with cursor".to_string(),
+                ));
+            }
+            Some(parse_cursor(cursor)?)
+        }
+        None => None,
+    };
+    let time_range = match cursor {
+        Some(last) if !fills_across => {
+            match resume_after(time_range, &last, &order, group_by.as_ref()) {
+                Some(range) => range,
+                None => {
+                    let result = empty_result(&req.select);
+                    return Ok(format_response(&req, &result, None));
+                }
+            }
+        }
+        _ => time_range,
+    };
+    let cut_after = cursor.filter(|_| fills_across).map(|cursor| cursor.timestamp);
+    // Ungrouped pages resume at the cursor's timestamp and skip its rows
+    // already returned
+    let skip = cursor.filter(|_| group_by.is_none()).map(|cursor| cursor.seen);
+    let descending = order.descending;
+
     // Build query
     let metrics: Vec<&str> = req.select.iter().map(|s| s.as_str()).collect();
-    let mut builder = Query::select(&metrics).time_range(time_range);
-
-    // Add GROUP BY
-    if let Some(ref group_by) = req.group_by {
-        let interval = parse_group_by(group_by)?;
-        builder = builder.group_by(interval);
+    let mut builder = Query::select(&metrics)
+        .time_range(time_range)
+        .order_by(order);
+    if let Some(clause) = group_by {
+        builder = builder.group_by_clause(clause);
     }
 
     // Add aggregation
@@ -59,53 +150,229 @@ pub async fn execute_query(
         builder = builder.filter(filter);
     }
 
-    // Add limit
-    if let Some(limit) = req.limit {
+    // Add limit and offset
+    if let Some(limit) = req.limit.filter(|_| cut_after.is_none()) {
         builder = builder.limit(limit);
     }
+    if let Some(offset) = req.offset.or(skip) {
+        builder = builder.offset(offset);
+    }
 
     let query = builder.build();
 
     // Execute query
-    let result = state.executor.execute(query).await?;
+    let mut result = state.executor.execute(query).await?;
+    if let (Some(last), Some(limit)) = (cut_after, req.limit) {
+        // Rows are bucket starts, so the cursor bucket is the cursor row
+        result.rows.retain(|row| {
+            if descending {
+                row.timestamp < last
+            } else {
+                row.timestamp > last
+            }
+        });
+        result.rows.truncate(limit);
+    }
+
+    // A full page may have more rows after it
+    let next_cursor = match (req.limit, result.rows.last()) {
+        (Some(limit), Some(last)) if pageable && result.rows.len() == limit => {
+            let mut seen = result
+                .rows
+                .iter()
+                .rev()
+                .take_while(|row| row.timestamp == last.timestamp)
+                .count();
+            if let Some(cursor) = cursor.filter(|cursor| cursor.timestamp == last.timestamp) {
+                seen += cursor.seen;
+            }
+            Some(Cursor { timestamp: last.timestamp, seen }.to_string())
+        }
+        _ => None,
+    };
+
+    Ok(format_response(&req, &result, next_cursor))
+}
+
+/// Run the CQL statements of a request
+///
+/// JSON responses hold one result set per statement; CSV and chart output
+/// need a single statement.
+async fn execute_statements(
+    state: &AppState,
+    req: &QueryRequest,
+    text: &str,
+) -> ApiResult<Response> {
+    if !req.select.is_empty() || req.cursor.is_some() {
+        return Err(ApiError::Validation(
+            "query cannot be combined with select or cursor".to_string(),
+        ));
+    }
+
+    let statements = parse_statements(text)?;
+    let results = state.executor.execute_all(statements).await?;
 
-    // Format response based on requested format
     match req.format.to_lowercase().as_str() {
-        "csv" => Ok(format_csv_response(&result)),
-        "chart" => Ok(format_chart_response(&result)),
-        _ => Ok(format_json_response(&result)),
+        "csv" | "chart" => match results.as_slice() {
+            [result] => Ok(format_response(req, result, None)),
+            _ => Err(ApiError::Validation(format!(
+                "{} output needs a single statement, got {}",
+                req.format,
+                results.len()
+            ))),
+        },
+        _ => {
+            let response = QueryResultsResponse {
+                results: results
+                    .iter()
+                    .map(|result| query_response(result, None, req.debug))
+                    .collect(),
+            };
+            Ok((StatusCode::OK, Json(response)).into_response())
+        }
+    }
+}
+
+/// Format response based on requested format
+fn format_response(
+    req: &QueryRequest,
+    result: &crate::query::QueryResultData,
+    next_cursor: Option<String>,
+) -> Response {
+    match req.format.to_lowercase().as_str() {
+        "csv" => format_csv_response(result),
+        "chart" => format_chart_response(result),
+        _ => format_json_response(result, next_cursor, req.debug),
+    }
+}
+
+/// Result of a page past the end of the time range
+fn empty_result(select: &[String]) -> crate::query::QueryResultData {
+    crate::query::QueryResultData {
+        columns: select.to_vec(),
+        rows: Vec::new(),
+        execution_time_ms: 0,
+        points_scanned: 0,
+        stats: Default::default(),
+    }
+}
+
+/// Position after the last row of a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cursor {
+    /// Timestamp of the last row returned
+    timestamp: i64,
+    /// Rows with that timestamp returned so far
+    seen: usize,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.timestamp, self.seen)
+    }
+}
+
+/// Parse a pagination cursor (`timestamp:seen`)
+fn parse_cursor(s: &str) -> ApiResult<Cursor> {
+    let invalid = || ApiError::Validation(format!("Invalid cursor: {}", s));
+    let (timestamp, seen) = s.split_once(':').ok_or_else(invalid)?;
+    Ok(Cursor {
+        timestamp: timestamp.parse().map_err(|_| invalid())?,
+        seen: seen.parse().map_err(|_| invalid())?,
+    })
+}
+
+/// Narrow the time range to the rows from the cursor on, in result order
+///
+/// Grouped queries have one row per bucket, so they resume after the
+/// cursor's bucket; others resume at its timestamp and skip the rows
+/// already seen. Returns None when no rows can follow it.
+fn resume_after(
+    range: TimeRange,
+    cursor: &Cursor,
+    order: &OrderBy,
+    group_by: Option<&GroupByClause>,
+) -> Option<TimeRange> {
+    let last = cursor.timestamp;
+    match group_by {
+        Some(_) if order.descending => TimeRange::try_new(range.start, range.end.min(last)),
+        Some(clause) => {
+            let next = clause.next_bucket_start(clause.bucket_start(last));
+            TimeRange::try_new(range.start.max(next), range.end)
+        }
+        None if order.descending => {
+            TimeRange::try_new(range.start, range.end.min(last.saturating_add(1)))
+        }
+        None => TimeRange::try_new(range.start.max(last), range.end),
     }
 }
 
 /// Format response as JSON
-fn format_json_response(result: &crate::query::QueryResultData) -> Response {
-    let rows: Vec<QueryRow> = result
-        .rows
-        .iter()
-        .map(|r| QueryRow {
-            timestamp: r.timestamp,
-            values: r.values.clone(),
-        })
-        .collect();
+fn format_json_response(
+    result: &crate::query::QueryResultData,
+    next_cursor: Option<String>,
+    debug: bool,
+) -> Response {
+    let response = query_response(result, next_cursor, debug);
+    (StatusCode::OK, Json(response)).into_response()
+}
 
-    let response = QueryResponse {
+/// Build the JSON body for one result set
+fn query_response(
+    result: &crate::query::QueryResultData,
+    next_cursor: Option<String>,
+    debug: bool,
+) -> QueryResponse {
+    let to_row = |r: &ResultRow| QueryRow {
+        timestamp: r.timestamp,
+        values: r.values.clone(),
+    };
+
+    let grouped_by_tags = result.rows.iter().any(|r| !r.tags.is_empty());
+    let (rows, series) = if grouped_by_tags {
+        let series = result
+            .series()
+            .into_iter()
+            .map(|s| QuerySeries {
+                tags: s.tags.clone(),
+                rows: s.rows.into_iter().map(to_row).collect(),
+            })
+            .collect();
+        (Vec::new(), series)
+    } else {
+        (result.rows.iter().map(to_row).collect(), Vec::new())
+    };
+
+    QueryResponse {
         columns: result.columns.clone(),
-        rows: rows.clone(),
+        rows,
+        series,
         meta: QueryMeta {
             execution_time_ms: result.execution_time_ms,
-            row_count: rows.len(),
+            row_count: result.rows.len(),
+            next_cursor,
+            stats: debug.then(|| result.stats.clone()),
         },
-    };
-
-    (StatusCode::OK, Json(response)).into_response()
+    }
 }
 
 /// Format response as CSV
 fn format_csv_response(result: &crate::query::QueryResultData) -> Response {
     let mut csv = String::new();
 
+    // Grouped tag keys become leading columns
+    let tag_keys: Vec<&String> = result
+        .rows
+        .first()
+        .map(|r| r.tags.keys().collect())
+        .unwrap_or_default();
+
     // Header
     csv.push_str("timestamp");
+    for key in &tag_keys {
+        csv.push(',');
+        csv.push_str(key);
+    }
     for col in &result.columns {
         csv.push(',');
         csv.push_str(col);
@@ -115,6 +382,12 @@ fn format_csv_response(result: &crate::query::QueryResultData) -> Response {
     // Rows
     for row in &result.rows {
         csv.push_str(&row.timestamp.to_string());
+        for key in &tag_keys {
+            csv.push(',');
+            if let Some(value) = row.tags.get(*key) {
+                csv.push_str(value);
+            }
+        }
         for col in &result.columns {
             csv.push(',');
             if let Some(val) = row.values.get(col) {
@@ -133,13 +406,18 @@ fn format_csv_response(result: &crate::query::QueryResultData) -> Response {
 }
 
 /// Format response for charts
+///
+/// Each column of each series becomes one dataset over the shared timestamps.
 fn format_chart_response(result: &crate::query::QueryResultData) -> Response {
+    let mut timestamps: Vec<i64> = result.rows.iter().map(|r| r.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps.dedup();
+
     // Generate labels from timestamps
-    let labels: Vec<String> = result
-        .rows
+    let labels: Vec<String> = timestamps
         .iter()
-        .map(|r| {
-            Utc.timestamp_millis_opt(r.timestamp)
+        .map(|ts| {
+            Utc.timestamp_millis_opt(*ts)
                 .single()
                 .map(|dt| dt.format("%b %d").to_string())
                 .unwrap_or_default()
@@ -151,18 +429,33 @@ fn format_chart_response(result: &crate::query::QueryResultData) -> Response {
 
     // Build datasets
     let datasets: Vec<ChartDataset> = result
-        .columns
+        .series()
         .iter()
+        .flat_map(|series| result.columns.iter().map(move |col| (series, col)))
         .enumerate()
-        .map(|(i, col)| {
-            let data: Vec<f64> = result
-                .rows
+        .map(|(i, (series, col))| {
+            let data: Vec<f64> = timestamps
                 .iter()
-                .map(|r| r.values.get(col).copied().unwrap_or(0.0))
+                .map(|ts| {
+                    series
+                        .rows
+                        .iter()
+                        .find(|r| r.timestamp == *ts)
+                        .and_then(|r| r.values.get(col).copied())
+                        .unwrap_or(0.0)
+                })
                 .collect();
 
+            let label = if series.tags.is_empty() {
+                col.clone()
+            } else {
+                let tags: Vec<String> =
+                    series.tags.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                format!("{} ({})", col, tags.join(", "))
+            };
+
             ChartDataset {
-                label: col.clone(),
+                label,
                 data,
                 color: colors[i % colors.len()].to_string(),
             }
@@ -267,13 +560,28 @@ fn parse_relative_time(s: &str) -> ApiResult<i64> {
 
 /// Parse GROUP BY interval
 fn parse_group_by(s: &str) -> ApiResult<GroupByInterval> {
-    match s.to_lowercase().as_str() {
-        "hour" | "h" => Ok(GroupByInterval::Hour),
-        "day" | "d" => Ok(GroupByInterval::Day),
-        "week" | "w" => Ok(GroupByInterval::Week),
-        "month" | "m" => Ok(GroupByInterval::Month),
+    GroupByInterval::from_str(s).ok_or_else(|| {
+        ApiError::Validation(format!(
+            "Invalid group_by interval: {}. Use hour, day, week, month, or a duration like 15m",
+            s
+        ))
+    })
+}
+
+/// Parse IANA time zone name
+fn parse_timezone(s: &str) -> ApiResult<Tz> {
+    s.parse::<Tz>()
+        .map_err(|_| ApiError::Validation(format!("Unknown time zone: {}", s)))
+}
+
+/// Parse GROUP BY offset like "4h"
+fn parse_group_offset(s: &str) -> ApiResult<i64> {
+    match GroupByInterval::from_str(s) {
+        Some(interval) if s.starts_with(|c: char| c.is_ascii_digit()) => {
+            Ok(interval.approx_duration_ms())
+        }
         _ => Err(ApiError::Validation(format!(
-            "Invalid group_by interval: {}. Use hour, day, week, or month",
+            "Invalid group_offset: {}. Use a duration like 4h or 30m",
             s
         ))),
     }
@@ -281,19 +589,13 @@ fn parse_group_by(s: &str) -> ApiResult<GroupByInterval> {
 
 /// Parse aggregation function
 fn parse_aggregation(s: &str) -> ApiResult<AggregationFunc> {
-    match s.to_lowercase().as_str() {
-        "avg" | "average" => Ok(AggregationFunc::Avg),
-        "sum" => Ok(AggregationFunc::Sum),
-        "min" => Ok(AggregationFunc::Min),
-        "max" => Ok(AggregationFunc::Max),
-        "count" => Ok(AggregationFunc::Count),
-        "last" => Ok(AggregationFunc::Last),
-        "first" => Ok(AggregationFunc::First),
-        _ => Err(ApiError::Validation(format!(
-            "Invalid aggregation: {}. Use avg, sum, min, max, count, last, or first",
+    AggregationFunc::from_str(s).ok_or_else(|| {
+        ApiError::Validation(format!(
+            "Invalid aggregation: {}. Use avg, sum, min, max, count, last, first, median, \
+             stddev, variance, spread, count_distinct, or a percentile like p95",
             s
-        ))),
-    }
+        ))
+    })
 }
 
 /// Parse operator string
@@ -356,6 +658,12 @@ mod tests {
     fn test_parse_group_by() {
         assert!(matches!(parse_group_by("day"), Ok(GroupByInterval::Day)));
         assert!(matches!(parse_group_by("HOUR"), Ok(GroupByInterval::Hour)));
+        assert!(matches!(parse_group_by("15m"), Ok(GroupByInterval::Every(900_000))));
+        assert_eq!(parse_group_offset("4h").unwrap(), 4 * 3600 * 1000);
+        assert!(parse_group_offset("month").is_err());
+        assert!(parse_group_offset("9999999999999999w").is_err());
+        assert!(parse_timezone("Europe/Berlin").is_ok());
+        assert!(parse_timezone("Mars/Olympus").is_err());
         assert!(parse_group_by("invalid").is_err());
     }
 
@@ -363,6 +671,35 @@ mod tests {
     fn test_parse_aggregation() {
         assert!(matches!(parse_aggregation("avg"), Ok(AggregationFunc::Avg)));
         assert!(matches!(parse_aggregation("SUM"), Ok(AggregationFunc::Sum)));
+        assert!(matches!(parse_aggregation("p99"), Ok(AggregationFunc::Percentile(q)) if q == 0.99));
         assert!(parse_aggregation("invalid").is_err());
     }
+
+    #[test]
+    fn test_resume_after_cursor() {
+        let hour = 3600 * 1000;
+        let range = TimeRange::new(0, 10 * hour);
+        let at = |timestamp| Cursor { timestamp, seen: 1 };
+
+        // Ungrouped pages continue at the row's timestamp, in either order
+        let page = resume_after(range, &at(4 * hour), &OrderBy::time_desc(), None).unwrap();
+        assert_eq!((page.start, page.end), (0, 4 * hour + 1));
+        let page = resume_after(range, &at(4 * hour), &OrderBy::default(), None).unwrap();
+        assert_eq!((page.start, page.end), (4 * hour, 10 * hour));
+
+        // Grouped pages continue after the row's bucket
+        let clause = GroupByClause::new(GroupByInterval::Hour);
+        let page = resume_after(range, &at(4 * hour), &OrderBy::default(), Some(&clause));
+        assert_eq!(page.unwrap().start, 5 * hour);
+        let page = resume_after(range, &at(4 * hour), &OrderBy::time_desc(), Some(&clause));
+        assert_eq!(page.unwrap().end, 4 * hour);
+
+        assert!(resume_after(range, &at(0), &OrderBy::time_desc(), Some(&clause)).is_none());
+        assert!(resume_after(range, &at(9 * hour), &OrderBy::default(), Some(&clause)).is_none());
+
+        let cursor = Cursor { timestamp: 1_700_000_000_000, seen: 2 };
+        assert_eq!(parse_cursor(&cursor.to_string()).unwrap(), cursor);
+        assert!(parse_cursor("1700000000000").is_err());
+        assert!(parse_cursor("next:1").is_err());
+    }
 }
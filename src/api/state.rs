@@ -3,10 +3,13 @@
 //! Shared state accessible by all API handlers.
 //! Wrapped in Arc for thread-safe sharing across async tasks.
 
+use crate::alerts::AlertEngine;
+use crate::goals::GoalEngine;
 use crate::memmachine::{CorrelationEngine, InsightEngine, SyncManager};
 use crate::query::QueryExecutor;
 use crate::storage::StorageEngine;
 use crate::websocket::{ConnectionHub, HubConfig};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -29,6 +32,10 @@ pub struct AppState {
     pub correlation_engine: Option<Arc<CorrelationEngine>>,
     /// Sync manager for MemMachine integration (optional)
     pub sync_manager: Option<Arc<SyncManager>>,
+    /// Alert engine (optional)
+    pub alerts: Option<Arc<AlertEngine>>,
+    /// Goal engine (optional)
+    pub goals: Option<Arc<GoalEngine>>,
 }
 
 impl AppState {
@@ -47,6 +54,8 @@ impl AppState {
             insight_engine: None,
             correlation_engine: None,
             sync_manager: None,
+            alerts: None,
+            goals: None,
         }
     }
 
@@ -68,6 +77,8 @@ impl AppState {
             insight_engine: Some(insight_engine),
             correlation_engine: Some(correlation_engine),
             sync_manager: Some(sync_manager),
+            alerts: None,
+            goals: None,
         }
     }
 
@@ -87,9 +98,23 @@ impl AppState {
             insight_engine: None,
             correlation_engine: None,
             sync_manager: None,
+            alerts: None,
+            goals: None,
         }
     }
 
+    /// Builder: serve alert rules and history from an alert engine
+    pub fn with_alerts(mut self, alerts: Arc<AlertEngine>) -> Self {
+        self.alerts = Some(alerts);
+        self
+    }
+
+    /// Builder: serve goals and their progress from a goal engine
+    pub fn with_goals(mut self, goals: Arc<GoalEngine>) -> Self {
+        self.goals = Some(goals);
+        self
+    }
+
     /// Get server uptime in seconds
     pub fn uptime_seconds(&self) -> u64 {
         self.start_time.elapsed().as_secs()
@@ -121,6 +146,8 @@ pub struct ApiConfig {
     pub auto_create_metrics: bool,
     /// Enable data export endpoint
     pub enable_export: bool,
+    /// Directory snapshots are written under (snapshots are disabled without one)
+    pub backup_dir: Option<PathBuf>,
 }
 
 impl Default for ApiConfig {
@@ -132,6 +159,7 @@ impl Default for ApiConfig {
             max_body_size: 10 * 1024 * 1024, // 10MB
             auto_create_metrics: true,
             enable_export: true,
+            backup_dir: None,
         }
     }
 }
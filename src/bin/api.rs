@@ -9,17 +9,23 @@
 //! - `CHRONICLE_PORT`: Port to listen on (default: 8082)
 //! - `CHRONICLE_DATA_DIR`: Data directory (default: chronicle_data)
 //! - `CHRONICLE_AUTO_CREATE_METRICS`: Auto-create metrics (default: true)
+//! - `CHRONICLE_COMPACTION_INTERVAL_MS`: Background compaction interval (default: 600000)
+//! - `CHRONICLE_COMPRESSION`: Block codec for new segments, lz4 or gorilla (default: lz4)
+//! - `CHRONICLE_QUERY_CACHE_ROWS`: Rows kept by the query cache, 0 disables it (default: 100000)
 //! - `MEMMACHINE_URL`: MemMachine API URL (optional, enables AI insights)
 //! - `MEMMACHINE_USER_ID`: User ID for MemMachine (default: default-user)
 //! - `MEMMACHINE_SYNC_ENABLED`: Enable background sync (default: true if MEMMACHINE_URL set)
 //! - `RUST_LOG`: Log level (default: info)
 
+use chronicle::alerts::{AlertConfig, AlertEngine};
 use chronicle::api::{serve, ApiConfig, AppState};
+use chronicle::goals::{GoalConfig, GoalEngine};
 use chronicle::memmachine::{
     CorrelationEngine, InsightEngine, MemMachineClient, MemMachineConfig, SyncConfig, SyncManager,
 };
-use chronicle::query::QueryExecutor;
+use chronicle::query::{QueryCacheConfig, QueryExecutor};
 use chronicle::storage::{StorageConfig, StorageEngine};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -49,8 +55,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let storage = Arc::new(StorageEngine::new(storage_config).await?);
     tracing::info!("Storage engine initialized");
 
+    // Merge small segments in the background
+    let compaction_handle = storage.start_background_compaction();
+
     // Initialize query executor
-    let executor = Arc::new(QueryExecutor::new(Arc::clone(&storage)));
+    let mut executor = QueryExecutor::new(Arc::clone(&storage));
+    if let Some(cache_config) = load_query_cache_config() {
+        tracing::info!("Query cache: {} rows", cache_config.max_rows);
+        executor = executor.with_cache(cache_config);
+    }
+    let executor = Arc::new(executor);
+
+    // Refresh continuous queries as blocks are flushed
+    let continuous_task = executor.start_continuous_queries();
 
     // Create app state (with or without MemMachine)
     let state = if let Some(mm_config) = memmachine_config {
@@ -104,13 +121,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         AppState::new(Arc::clone(&storage), executor, api_config.clone())
     };
 
+    // Evaluate alert rules against ingested points
+    let alerts = Arc::new(AlertEngine::open(Arc::clone(&storage), AlertConfig::default())?);
+    let state = state.with_alerts(Arc::clone(&alerts));
+    let alerts_handle = alerts.start(Arc::clone(&state.ws_hub));
+
+    // Push goal progress as points of their metrics arrive
+    let goals = Arc::new(GoalEngine::open(
+        &storage,
+        Arc::clone(&state.executor),
+        GoalConfig::default(),
+    )?);
+    let state = state.with_goals(Arc::clone(&goals));
+    let goals_handle = goals.start(Arc::clone(&state.ws_hub));
+
     // Run server
     tracing::info!("Starting server on {}:{}", api_config.host, api_config.port);
     serve(state, &api_config).await?;
 
     // Graceful shutdown
     tracing::info!("Shutting down storage engine...");
+    // Let continuous queries catch up on the last points first
+    storage.flush().await?;
+    continuous_task.stop().await;
     storage.shutdown().await?;
+    compaction_handle.abort();
+    alerts_handle.abort();
+    alerts.flush().await?;
+    goals_handle.abort();
     tracing::info!("Chronicle API server stopped");
 
     Ok(())
@@ -129,10 +167,13 @@ fn load_api_config() -> ApiConfig {
         .map(|s| s.to_lowercase() != "false" && s != "0")
         .unwrap_or(true);
 
+    let backup_dir = std::env::var("CHRONICLE_BACKUP_DIR").ok().map(PathBuf::from);
+
     ApiConfig {
         host,
         port,
         auto_create_metrics,
+        backup_dir,
         ..Default::default()
     }
 }
@@ -142,7 +183,34 @@ fn load_storage_config() -> StorageConfig {
     let data_dir = std::env::var("CHRONICLE_DATA_DIR")
         .unwrap_or_else(|_| "chronicle_data".to_string());
 
-    StorageConfig::new(data_dir)
+    let mut config = StorageConfig::new(data_dir);
+
+    if let Some(ms) = std::env::var("CHRONICLE_COMPACTION_INTERVAL_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+    {
+        config.compaction_interval_ms = ms;
+    }
+
+    if let Ok(name) = std::env::var("CHRONICLE_COMPRESSION") {
+        match name.parse() {
+            Ok(compression) => config.compression = compression,
+            Err(e) => tracing::warn!("Ignoring CHRONICLE_COMPRESSION: {}", e),
+        }
+    }
+
+    config
+}
+
+/// Load query cache configuration from environment
+/// Returns None if the cache is disabled
+fn load_query_cache_config() -> Option<QueryCacheConfig> {
+    let max_rows = std::env::var("CHRONICLE_QUERY_CACHE_ROWS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(QueryCacheConfig::default().max_rows);
+
+    (max_rows > 0).then(|| QueryCacheConfig::new(max_rows))
 }
 
 /// Load MemMachine configuration from environment
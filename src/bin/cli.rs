@@ -5,6 +5,9 @@
 //! - Query data
 //! - Check status
 //! - Import/Export data
+//! - Back up and restore the data directory
+//! - Check a data directory's integrity and rebuild its indexes
+//! - Track goals and their streaks
 
 use chrono::{Duration, Utc};
 use clap::{Parser, Subcommand};
@@ -94,6 +97,44 @@ pub enum Commands {
         output: Option<PathBuf>,
     },
 
+    /// Write a consistent snapshot of the server's data directory
+    Backup {
+        /// Snapshot directory (must not exist or be empty; written by the server)
+        dest: PathBuf,
+    },
+
+    /// Restore a snapshot into a data directory (stop chronicle-api first)
+    Restore {
+        /// Snapshot directory written by `backup`
+        snapshot: PathBuf,
+        /// Data directory to restore into (default: $CHRONICLE_DATA_DIR or chronicle_data)
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Move existing data aside instead of refusing to restore
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Check a data directory for corruption (stop chronicle-api first)
+    Fsck {
+        /// Data directory to check (default: $CHRONICLE_DATA_DIR or chronicle_data)
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Rebuild the indexes from the segments if they don't match (refused
+        /// while the data directory is in use)
+        #[arg(long)]
+        rebuild_indexes: bool,
+        /// Skip the tag index (for servers running without one)
+        #[arg(long)]
+        no_tags: bool,
+    },
+
+    /// List, add or remove goals (default: list them with their streaks)
+    Goals {
+        #[command(subcommand)]
+        command: Option<GoalCommand>,
+    },
+
     /// Sync an integration
     Sync {
         /// Integration name (fitbit, github, etc.)
@@ -108,6 +149,44 @@ pub enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+pub enum GoalCommand {
+    /// List goals with their streaks and completion rates
+    List,
+
+    /// Add a goal, e.g. `goals add Walk steps --target 8000 --days 5 --of 7`
+    Add {
+        /// Goal name
+        name: String,
+        /// Metric the goal tracks
+        metric: String,
+        /// Target value
+        #[arg(long)]
+        target: f64,
+        /// Comparison of a day's aggregate against the target (>, >=, <, <=)
+        #[arg(long, default_value = ">=")]
+        op: String,
+        /// How a day's points are combined (avg, sum, min, max, count, p95, ...)
+        #[arg(short, long, default_value = "sum")]
+        aggregation: String,
+        /// Days per period that must meet the target
+        #[arg(long, default_value = "1")]
+        days: u32,
+        /// Days per period
+        #[arg(long, default_value = "1")]
+        of: u32,
+        /// Time zone days start in (default: UTC)
+        #[arg(long)]
+        timezone: Option<String>,
+    },
+
+    /// Remove a goal
+    Remove {
+        /// Goal ID
+        id: u64,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
@@ -434,6 +513,296 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
 
+        Commands::Backup { dest } => {
+            // The server resolves the path, so don't leave it relative
+            let dest = if dest.is_absolute() {
+                dest
+            } else {
+                std::env::current_dir()?.join(dest)
+            };
+
+            let response = client
+                .post(format!("{}/api/v1/snapshot", cli.api_url))
+                .json(&serde_json::json!({ "path": dest }))
+                .send()
+                .await?;
+
+            if response.status().is_success() {
+                let result: serde_json::Value = response.json().await?;
+                println!(
+                    "Snapshot of {} segments ({} bytes) written to {}",
+                    result["segments"].as_u64().unwrap_or(0),
+                    result["bytes"].as_u64().unwrap_or(0),
+                    dest.display()
+                );
+            } else {
+                let status = response.status();
+                let text = response.text().await.unwrap_or_default();
+                eprintln!("Backup failed ({}): {}", status, text);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Restore {
+            snapshot,
+            data_dir,
+            force,
+        } => {
+            use chronicle::storage::lock::LOCK_FILE;
+            use chronicle::storage::snapshot::{self as snapshots, SnapshotManifest};
+            use chronicle::storage::{DirLock, StorageError};
+
+            let data_dir = data_dir.unwrap_or_else(default_data_dir);
+
+            // Hold the lock from moving data aside until the restore is done
+            std::fs::create_dir_all(&data_dir)?;
+            let lock = match DirLock::acquire(&data_dir) {
+                Err(StorageError::Locked(_)) => {
+                    eprintln!(
+                        "{} is in use; stop chronicle-api before restoring",
+                        data_dir.display()
+                    );
+                    std::process::exit(1);
+                }
+                result => result?,
+            };
+
+            if snapshots::has_data(&data_dir)? {
+                if !force {
+                    eprintln!("{} is not empty; use --force to move it aside", data_dir.display());
+                    std::process::exit(1);
+                }
+
+                // Don't touch existing data for a snapshot that won't restore
+                let checked = SnapshotManifest::load(&snapshot)
+                    .and_then(|manifest| manifest.verify(&snapshot));
+                if let Err(e) = checked {
+                    eprintln!("Restore failed: {}", e);
+                    std::process::exit(1);
+                }
+
+                let mut aside = data_dir.clone().into_os_string();
+                aside.push(format!(".pre-restore-{}", Utc::now().format("%Y%m%d%H%M%S")));
+                let aside = PathBuf::from(aside);
+                std::fs::create_dir(&aside)?;
+                for entry in std::fs::read_dir(&data_dir)? {
+                    let entry = entry?;
+                    if entry.file_name() != LOCK_FILE {
+                        std::fs::rename(entry.path(), aside.join(entry.file_name()))?;
+                    }
+                }
+                println!("Moved existing data to {}", aside.display());
+            }
+
+            match snapshots::restore_locked(&snapshot, &data_dir, &lock) {
+                Ok(manifest) => {
+                    let taken = chrono::DateTime::from_timestamp_millis(manifest.created_at)
+                        .map(|dt| dt.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+                        .unwrap_or_else(|| "unknown".to_string());
+                    println!(
+                        "Restored {} segments ({} bytes) from snapshot taken at {} into {}",
+                        manifest.segments.len(),
+                        manifest.total_bytes(),
+                        taken,
+                        data_dir.display()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Restore failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Commands::Fsck {
+            data_dir,
+            rebuild_indexes,
+            no_tags,
+        } => {
+            use chronicle::storage::fsck::{self, FsckOptions};
+            use chronicle::storage::StorageError;
+
+            let data_dir = data_dir.unwrap_or_else(default_data_dir);
+            if !data_dir.exists() {
+                eprintln!("{} does not exist", data_dir.display());
+                std::process::exit(1);
+            }
+            let options = FsckOptions {
+                check_tags: !no_tags,
+            };
+
+            let mut report = fsck::check(&data_dir, &options)?;
+            println!(
+                "Checked {} segments, {} blocks, {} points and {} WAL entries",
+                report.segments, report.blocks, report.points, report.wal_entries
+            );
+            for issue in &report.issues {
+                println!("  {}", issue);
+            }
+
+            if rebuild_indexes && !report.indexes_consistent() {
+                let stats = match fsck::rebuild_indexes(&data_dir, &options) {
+                    Err(StorageError::Locked(_)) => {
+                        eprintln!(
+                            "{} is in use; stop chronicle-api before rebuilding indexes",
+                            data_dir.display()
+                        );
+                        std::process::exit(1);
+                    }
+                    result => result?,
+                };
+                println!(
+                    "Rebuilt indexes from {} segments ({} blocks, {} unreadable blocks indexed by time only)",
+                    stats.segments, stats.blocks, stats.blocks_skipped
+                );
+
+                report = fsck::check(&data_dir, &options)?;
+                if !report.is_clean() {
+                    println!("After the rebuild:");
+                    for issue in &report.issues {
+                        println!("  {}", issue);
+                    }
+                }
+            }
+
+            if report.is_clean() {
+                println!("No problems found");
+            } else {
+                println!("{} problems found", report.issues.len());
+                if !report.indexes_consistent() && !rebuild_indexes {
+                    println!("Run with --rebuild-indexes to rebuild the indexes from the segments");
+                }
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Goals { command } => match command.unwrap_or(GoalCommand::List) {
+            GoalCommand::List => {
+                let response = client
+                    .get(format!("{}/api/v1/goals", cli.api_url))
+                    .send()
+                    .await?;
+
+                if !response.status().is_success() {
+                    eprintln!("Failed to fetch goals: {}", response.status());
+                    std::process::exit(1);
+                }
+
+                let data: serde_json::Value = response.json().await?;
+                let goals = data["goals"].as_array().cloned().unwrap_or_default();
+
+                if cli.format == "json" {
+                    println!("{}", serde_json::to_string_pretty(&data)?);
+                } else if goals.is_empty() {
+                    println!("No goals defined yet.");
+                    println!();
+                    println!("Add your first goal with:");
+                    println!("  chronicle goals add Meditate meditation --target 10");
+                } else {
+                    println!(
+                        "{:<4} {:<20} {:<24} {:>8} {:>6} {:>6}",
+                        "ID", "Name", "Target", "Streak", "Best", "Rate"
+                    );
+                    println!("{}", "-".repeat(73));
+
+                    for goal in goals {
+                        let mut target = format!(
+                            "{} {} {}",
+                            goal["metric"].as_str().unwrap_or("-"),
+                            goal["operator"].as_str().unwrap_or("-"),
+                            goal["target"].as_f64().unwrap_or(0.0)
+                        );
+                        if goal["window_days"].as_u64().unwrap_or(1) > 1 {
+                            target.push_str(&format!(
+                                " ({}/{}d)",
+                                goal["days"].as_u64().unwrap_or(1),
+                                goal["window_days"].as_u64().unwrap_or(1)
+                            ));
+                        }
+                        let progress = &goal["progress"];
+                        let rate = progress["completion_rate"]
+                            .as_f64()
+                            .map(|rate| format!("{:.0}%", rate * 100.0))
+                            .unwrap_or_else(|| "-".to_string());
+
+                        println!(
+                            "{:<4} {:<20} {:<24} {:>8} {:>6} {:>6}",
+                            goal["id"].as_u64().unwrap_or(0),
+                            goal["name"].as_str().unwrap_or("-"),
+                            target,
+                            progress["current_streak"].as_u64().unwrap_or(0),
+                            progress["best_streak"].as_u64().unwrap_or(0),
+                            rate
+                        );
+                    }
+                }
+            }
+
+            GoalCommand::Add {
+                name,
+                metric,
+                target,
+                op,
+                aggregation,
+                days,
+                of,
+                timezone,
+            } => {
+                use chronicle::query::AggregationFunc;
+
+                let Some(aggregation) = AggregationFunc::from_str(&aggregation) else {
+                    eprintln!("Invalid aggregation: {}", aggregation);
+                    std::process::exit(1);
+                };
+                let goal = serde_json::json!({
+                    "name": name,
+                    "metric": metric,
+                    "operator": op,
+                    "target": target,
+                    "aggregation": aggregation,
+                    "days": days,
+                    "window_days": of,
+                    "timezone": timezone,
+                });
+
+                let response = client
+                    .post(format!("{}/api/v1/goals", cli.api_url))
+                    .json(&goal)
+                    .send()
+                    .await?;
+
+                if response.status().is_success() {
+                    let result: serde_json::Value = response.json().await?;
+                    println!(
+                        "Added goal {} ({})",
+                        result["id"].as_u64().unwrap_or(0),
+                        result["name"].as_str().unwrap_or("-")
+                    );
+                } else {
+                    let status = response.status();
+                    let text = response.text().await.unwrap_or_default();
+                    eprintln!("Failed to add goal ({}): {}", status, text);
+                    std::process::exit(1);
+                }
+            }
+
+            GoalCommand::Remove { id } => {
+                let response = client
+                    .delete(format!("{}/api/v1/goals/{}", cli.api_url, id))
+                    .send()
+                    .await?;
+
+                if response.status().is_success() {
+                    println!("Removed goal {}", id);
+                } else {
+                    let status = response.status();
+                    let text = response.text().await.unwrap_or_default();
+                    eprintln!("Failed to remove goal ({}): {}", status, text);
+                    std::process::exit(1);
+                }
+            }
+        },
+
         Commands::Sync { integration } => {
             let response = client
                 .post(format!(
@@ -479,6 +848,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Data directory used when none is given
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(std::env::var("CHRONICLE_DATA_DIR").unwrap_or_else(|_| "chronicle_data".to_string()))
+}
+
 fn parse_duration(s: &str) -> Result<Duration, Box<dyn std::error::Error>> {
     let s = s.trim().to_lowercase();
 
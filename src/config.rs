@@ -39,6 +39,12 @@ pub struct StorageConfig {
 
     #[serde(default = "default_wal_enabled")]
     pub wal_enabled: bool,
+
+    #[serde(default = "default_compaction_interval")]
+    pub compaction_interval_ms: u64,
+
+    #[serde(default = "default_compression")]
+    pub compression: String,
 }
 
 fn default_data_dir() -> String {
@@ -59,6 +65,14 @@ fn default_wal_enabled() -> bool {
     true
 }
 
+fn default_compaction_interval() -> u64 {
+    600_000 // 10 minutes
+}
+
+fn default_compression() -> String {
+    "lz4".to_string()
+}
+
 impl Default for StorageConfig {
     fn default() -> Self {
         Self {
@@ -66,6 +80,8 @@ impl Default for StorageConfig {
             block_size: default_block_size(),
             flush_interval_ms: default_flush_interval(),
             wal_enabled: default_wal_enabled(),
+            compaction_interval_ms: default_compaction_interval(),
+            compression: default_compression(),
         }
     }
 }
@@ -399,6 +415,12 @@ flush_interval_ms = 5000
 # Enable write-ahead log for durability
 wal_enabled = true
 
+# How often to merge small segments in the background (ms)
+compaction_interval_ms = 600000
+
+# Block codec for new segments: lz4 or gorilla (existing segments stay readable)
+compression = "lz4"
+
 [api]
 # API server host
 host = "0.0.0.0"
@@ -0,0 +1,597 @@
+//! Embedded Chronicle
+//!
+//! `Chronicle` bundles what the API server wires together by hand, for
+//! services that link Chronicle as a library instead of talking HTTP:
+//!
+//! - a `StorageEngine` and a `QueryExecutor` over it
+//! - optional background flush and compaction tasks, and a task keeping
+//!   continuous queries up to date
+//! - ingest by metric name, auto-creating metrics like `POST /api/v1/ingest`
+//! - a `Stream` of written points, the in-process counterpart of the
+//!   `metrics.*` WebSocket topics
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use chronicle::{Chronicle, Sample};
+//! use futures_util::StreamExt;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let chronicle = Chronicle::open("./data").await?;
+//!
+//!     let mut written = chronicle.subscribe_metric("mood");
+//!     chronicle.ingest(Sample::new("mood", 7.5).tag("source", "manual")).await?;
+//!     let point = written.next().await;
+//!
+//!     let result = chronicle.query("SELECT AVG(mood) WHERE time >= now() - 7d").await?;
+//!
+//!     chronicle.shutdown().await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Call `shutdown` before dropping the handle. Dropping it without one
+//! stops the background tasks but leaves buffered points in the WAL, where
+//! they are recovered on the next open.
+
+use crate::query::{
+    ContinuousQueryTask, Query, QueryCacheConfig, QueryExecutor, QueryResult, QueryResultData,
+};
+use crate::storage::{
+    AggregationType, Category, DataPoint, DeleteMode, Metric, StorageConfig, StorageEngine,
+    StorageError, StorageResult, TimeRange, Tombstone,
+};
+use chrono::Utc;
+use futures_util::stream::BoxStream;
+use futures_util::{future, StreamExt};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+/// Configuration for an embedded Chronicle
+#[derive(Debug, Clone)]
+pub struct ChronicleConfig {
+    /// Storage engine configuration
+    pub storage: StorageConfig,
+    /// Flush the write buffer every `storage.flush_interval_ms` (default: true)
+    pub background_flush: bool,
+    /// Apply retention and compact every `storage.compaction_interval_ms` (default: true)
+    pub background_compaction: bool,
+    /// Create unknown metrics on ingest instead of failing (default: true)
+    pub auto_create_metrics: bool,
+    /// Written points buffered per subscriber before it starts missing some (default: 1024)
+    pub stream_capacity: usize,
+    /// Query result cache, None to disable it (default: 100,000 rows)
+    pub query_cache: Option<QueryCacheConfig>,
+}
+
+impl Default for ChronicleConfig {
+    fn default() -> Self {
+        Self {
+            storage: StorageConfig::default(),
+            background_flush: true,
+            background_compaction: true,
+            auto_create_metrics: true,
+            stream_capacity: 1024,
+            query_cache: Some(QueryCacheConfig::default()),
+        }
+    }
+}
+
+impl ChronicleConfig {
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            storage: StorageConfig::new(data_dir),
+            ..Default::default()
+        }
+    }
+}
+
+/// A point to ingest, addressed by metric name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Metric name
+    pub metric: String,
+    /// Measured value
+    pub value: f64,
+    /// Timestamp in milliseconds (default: now)
+    pub timestamp: Option<i64>,
+    /// Tags
+    pub tags: HashMap<String, String>,
+}
+
+impl Sample {
+    /// Create a sample taken now
+    pub fn new(metric: impl Into<String>, value: f64) -> Self {
+        Self {
+            metric: metric.into(),
+            value,
+            timestamp: None,
+            tags: HashMap::new(),
+        }
+    }
+
+    /// Builder method: set timestamp
+    pub fn at(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Builder method: add a tag
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+
+    fn validate(&self) -> StorageResult<()> {
+        if self.metric.is_empty() {
+            return Err(StorageError::InvalidPoint(
+                "Metric name cannot be empty".to_string(),
+            ));
+        }
+        if !self.value.is_finite() {
+            return Err(StorageError::InvalidPoint(format!(
+                "Value of {} must be a finite number",
+                self.metric
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Stream of points written through a `Chronicle`
+pub type PointStream = BoxStream<'static, WrittenPoint>;
+
+/// A point that was written, with its metric's name
+#[derive(Debug, Clone, PartialEq)]
+pub struct WrittenPoint {
+    /// Metric name
+    pub metric: String,
+    /// The stored point
+    pub point: DataPoint,
+}
+
+/// An embedded Chronicle instance
+pub struct Chronicle {
+    storage: Arc<StorageEngine>,
+    executor: Arc<QueryExecutor>,
+    auto_create_metrics: bool,
+    /// Sender for `subscribe` streams (None once shut down, ending them)
+    written: Mutex<Option<broadcast::Sender<WrittenPoint>>>,
+    /// Background flush and compaction tasks
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    continuous: Mutex<Option<ContinuousQueryTask>>,
+    closed: AtomicBool,
+}
+
+impl Chronicle {
+    /// Open a data directory with the default configuration
+    pub async fn open(data_dir: impl Into<PathBuf>) -> StorageResult<Self> {
+        Self::with_config(ChronicleConfig::new(data_dir)).await
+    }
+
+    /// Open with a custom configuration
+    pub async fn with_config(config: ChronicleConfig) -> StorageResult<Self> {
+        let storage = Arc::new(StorageEngine::new(config.storage).await?);
+        let mut executor = QueryExecutor::new(Arc::clone(&storage));
+        if let Some(cache_config) = config.query_cache {
+            executor = executor.with_cache(cache_config);
+        }
+        let executor = Arc::new(executor);
+
+        let mut tasks = Vec::new();
+        if config.background_flush {
+            tasks.push(storage.start_background_flush());
+        }
+        if config.background_compaction {
+            tasks.push(storage.start_background_compaction());
+        }
+        let continuous = executor.start_continuous_queries();
+
+        let (written, _) = broadcast::channel(config.stream_capacity.max(1));
+
+        Ok(Self {
+            storage,
+            executor,
+            auto_create_metrics: config.auto_create_metrics,
+            written: Mutex::new(Some(written)),
+            tasks: Mutex::new(tasks),
+            continuous: Mutex::new(Some(continuous)),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Get the underlying storage engine
+    pub fn storage(&self) -> &Arc<StorageEngine> {
+        &self.storage
+    }
+
+    /// Get the query executor
+    pub fn executor(&self) -> &Arc<QueryExecutor> {
+        &self.executor
+    }
+
+    // ========================================================================
+    // Ingest
+    // ========================================================================
+
+    /// Write a single point
+    pub async fn ingest(&self, sample: Sample) -> StorageResult<WrittenPoint> {
+        self.check_open()?;
+        sample.validate()?;
+
+        let written = self.resolve(sample).await?;
+        self.storage.write(written.point.clone()).await?;
+        self.publish(&written);
+
+        Ok(written)
+    }
+
+    /// Write a batch of points
+    ///
+    /// Nothing is written unless every sample is valid and its metric
+    /// exists or can be created.
+    pub async fn ingest_batch(&self, samples: Vec<Sample>) -> StorageResult<Vec<WrittenPoint>> {
+        self.check_open()?;
+        for sample in &samples {
+            sample.validate()?;
+        }
+
+        let mut written = Vec::with_capacity(samples.len());
+        for sample in samples {
+            written.push(self.resolve(sample).await?);
+        }
+
+        let points = written.iter().map(|w| w.point.clone()).collect();
+        self.storage.write_batch(points).await?;
+        for point in &written {
+            self.publish(point);
+        }
+
+        Ok(written)
+    }
+
+    /// Flush buffered points to a segment
+    pub async fn flush(&self) -> StorageResult<()> {
+        self.check_open()?;
+        self.storage.flush().await
+    }
+
+    /// Turn a sample into a point, creating its metric if allowed
+    async fn resolve(&self, sample: Sample) -> StorageResult<WrittenPoint> {
+        let metric_id = match self.storage.get_metric(&sample.metric).await {
+            Some(metric) => metric.id,
+            None if self.auto_create_metrics => {
+                let metric = Metric::new(
+                    &sample.metric,
+                    "",
+                    Category::Custom,
+                    AggregationType::Average,
+                );
+                let id = self.storage.register_metric(metric).await?;
+                tracing::info!(metric_name = %sample.metric, metric_id = id, "Auto-created metric");
+                id
+            }
+            None => return Err(StorageError::MetricNotFound(sample.metric)),
+        };
+
+        let timestamp = sample
+            .timestamp
+            .unwrap_or_else(|| Utc::now().timestamp_millis());
+
+        Ok(WrittenPoint {
+            point: DataPoint::with_timestamp(metric_id, sample.value, timestamp).tags(sample.tags),
+            metric: sample.metric,
+        })
+    }
+
+    // ========================================================================
+    // Queries
+    // ========================================================================
+
+    /// Run a query string, e.g. `SELECT AVG(mood) WHERE time >= now() - 7d`
+    pub async fn query(&self, query: &str) -> QueryResult<QueryResultData> {
+        self.check_open()?;
+        self.executor.execute_str(query).await
+    }
+
+    /// Run a query built with `Query::select`
+    pub async fn execute(&self, query: Query) -> QueryResult<QueryResultData> {
+        self.check_open()?;
+        self.executor.execute(query).await
+    }
+
+    /// Get the raw points of a metric in a time range
+    pub async fn points(&self, metric: &str, range: TimeRange) -> StorageResult<Vec<DataPoint>> {
+        self.check_open()?;
+        self.storage.query_metric(metric, range).await
+    }
+
+    // ========================================================================
+    // Metrics
+    // ========================================================================
+
+    /// Register a metric, returning its id
+    pub async fn register_metric(&self, metric: Metric) -> StorageResult<u32> {
+        self.check_open()?;
+        self.storage.register_metric(metric).await
+    }
+
+    /// Get all metrics that have not been deleted
+    pub async fn metrics(&self) -> Vec<Metric> {
+        self.storage.get_metrics().await
+    }
+
+    /// Get a metric by name
+    pub async fn metric(&self, name: &str) -> Option<Metric> {
+        self.storage.get_metric(name).await
+    }
+
+    /// Delete a metric by name (see `StorageEngine::delete_metric`)
+    pub async fn delete_metric(&self, name: &str, mode: DeleteMode) -> StorageResult<()> {
+        self.check_open()?;
+        let metric = self
+            .storage
+            .get_metric(name)
+            .await
+            .ok_or_else(|| StorageError::MetricNotFound(name.to_string()))?;
+        self.storage.delete_metric(metric.id, mode).await
+    }
+
+    /// Delete a metric's points in a time range that carry all given tags
+    pub async fn delete_points(
+        &self,
+        metric: &str,
+        range: TimeRange,
+        tags: HashMap<String, String>,
+    ) -> StorageResult<Tombstone> {
+        self.check_open()?;
+        let metric = self
+            .storage
+            .get_metric(metric)
+            .await
+            .ok_or_else(|| StorageError::MetricNotFound(metric.to_string()))?;
+        self.storage
+            .delete_points(Some(metric.id), range, tags)
+            .await
+    }
+
+    // ========================================================================
+    // Streaming
+    // ========================================================================
+
+    /// Stream every point written through this handle from now on
+    ///
+    /// A subscriber that falls more than `stream_capacity` points behind
+    /// skips the oldest ones. The stream ends on `shutdown`.
+    pub fn subscribe(&self) -> PointStream {
+        let receiver = self
+            .written
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|sender| sender.subscribe());
+
+        futures_util::stream::unfold(receiver, |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(point) => return Some((point, Some(receiver))),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Point subscriber lagged, skipped {} points", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// Stream points written for a single metric
+    pub fn subscribe_metric(&self, metric: impl Into<String>) -> PointStream {
+        let metric = metric.into();
+        self.subscribe()
+            .filter(move |written| future::ready(written.metric == metric))
+            .boxed()
+    }
+
+    fn publish(&self, point: &WrittenPoint) {
+        if let Some(sender) = self.written.lock().unwrap().as_ref() {
+            // No subscribers is not an error
+            let _ = sender.send(point.clone());
+        }
+    }
+
+    // ========================================================================
+    // Lifecycle
+    // ========================================================================
+
+    /// Flush, sync and persist everything, then stop the background tasks
+    ///
+    /// Ends all `subscribe` streams. Every later call on the handle fails
+    /// with `StorageError::Closed`; calling `shutdown` again does nothing.
+    pub async fn shutdown(&self) -> StorageResult<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.written.lock().unwrap().take();
+
+        // Let continuous queries catch up on the last points first
+        let flushed = self.storage.flush().await;
+        let continuous = self.continuous.lock().unwrap().take();
+        if let Some(task) = continuous {
+            task.stop().await;
+        }
+
+        let result = self.storage.shutdown().await;
+        self.stop_tasks();
+        flushed.and(result)
+    }
+
+    /// Check if `shutdown` has been called
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn check_open(&self) -> StorageResult<()> {
+        if self.is_closed() {
+            return Err(StorageError::Closed);
+        }
+        Ok(())
+    }
+
+    fn stop_tasks(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        if let Some(task) = self.continuous.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Chronicle {
+    fn drop(&mut self) {
+        // The tasks hold the engine; without this it would never be dropped
+        self.stop_tasks();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::QueryError;
+    use tempfile::tempdir;
+
+    fn test_config(dir: &std::path::Path) -> ChronicleConfig {
+        ChronicleConfig {
+            background_flush: false,
+            background_compaction: false,
+            ..ChronicleConfig::new(dir)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ingest_query_and_reopen() {
+        let dir = tempdir().unwrap();
+        let now = Utc::now().timestamp_millis();
+
+        let chronicle = Chronicle::with_config(test_config(dir.path()))
+            .await
+            .unwrap();
+        chronicle
+            .ingest(
+                Sample::new("mood", 7.0)
+                    .at(now - 1000)
+                    .tag("source", "manual"),
+            )
+            .await
+            .unwrap();
+        chronicle
+            .ingest_batch(vec![
+                Sample::new("mood", 9.0).at(now - 500),
+                Sample::new("energy", 3.0).at(now - 500),
+            ])
+            .await
+            .unwrap();
+
+        let result = chronicle
+            .query("SELECT mood WHERE time >= now() - 1d")
+            .await
+            .unwrap();
+        assert_eq!(
+            result.to_time_series(),
+            vec![(now - 1000, 7.0), (now - 500, 9.0)]
+        );
+        assert_eq!(chronicle.metrics().await.len(), 2);
+        chronicle.shutdown().await.unwrap();
+
+        assert!(matches!(
+            chronicle.ingest(Sample::new("mood", 1.0)).await,
+            Err(StorageError::Closed)
+        ));
+        assert!(matches!(
+            chronicle.query("SELECT mood").await,
+            Err(QueryError::Storage(StorageError::Closed))
+        ));
+        drop(chronicle);
+
+        let chronicle = Chronicle::with_config(test_config(dir.path()))
+            .await
+            .unwrap();
+        let points = chronicle
+            .points("mood", TimeRange::new(now - 2000, now))
+            .await
+            .unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            points[0].tags.get("source").map(String::as_str),
+            Some("manual")
+        );
+        chronicle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch_rejected_as_a_whole() {
+        let dir = tempdir().unwrap();
+        let config = ChronicleConfig {
+            auto_create_metrics: false,
+            ..test_config(dir.path())
+        };
+        let chronicle = Chronicle::with_config(config).await.unwrap();
+        chronicle
+            .register_metric(Metric::new(
+                "mood",
+                "1-10",
+                Category::Mood,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+
+        let err = chronicle
+            .ingest_batch(vec![
+                Sample::new("mood", 5.0),
+                Sample::new("mood", f64::NAN),
+            ])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StorageError::InvalidPoint(_)));
+
+        let err = chronicle
+            .ingest_batch(vec![Sample::new("mood", 5.0), Sample::new("sleep", 8.0)])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StorageError::MetricNotFound(_)));
+
+        assert_eq!(chronicle.storage().stats().await.buffer_points, 0);
+        chronicle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_streams_writes_until_shutdown() {
+        let dir = tempdir().unwrap();
+        let chronicle = Chronicle::with_config(test_config(dir.path()))
+            .await
+            .unwrap();
+
+        let all = chronicle.subscribe();
+        let moods = chronicle.subscribe_metric("mood");
+
+        chronicle.ingest(Sample::new("energy", 4.0)).await.unwrap();
+        chronicle.ingest(Sample::new("mood", 6.0)).await.unwrap();
+        chronicle.shutdown().await.unwrap();
+
+        let all: Vec<String> = all.map(|w| w.metric).collect().await;
+        assert_eq!(all, vec!["energy".to_string(), "mood".to_string()]);
+        let moods: Vec<f64> = moods.map(|w| w.point.value).collect().await;
+        assert_eq!(moods, vec![6.0]);
+
+        // Streams opened after shutdown end immediately
+        assert_eq!(chronicle.subscribe().count().await, 0);
+    }
+}
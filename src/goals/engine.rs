@@ -0,0 +1,306 @@
+//! Goal Engine
+//!
+//! Stores goals in `goals.json` in the data directory and measures their
+//! progress from daily aggregates read through the `QueryExecutor`. While
+//! running, it collects the metrics of the points published to the
+//! WebSocket hub, recomputes their goals once per `refresh_interval_ms`, and
+//! sends the progress that changed to the `goals` topic.
+
+use crate::goals::error::{GoalError, GoalResult};
+use crate::goals::goal::{Goal, GoalProgress};
+use crate::query::{GroupByClause, GroupByInterval, Query, QueryError, QueryExecutor};
+use crate::storage::{JsonStore, Record, StorageEngine, StoreState, TimeRange};
+use crate::websocket::{ConnectionHub, ServerMessage, WsEvent};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+/// Name of the goal file in the data directory
+const GOALS_FILE: &str = "goals.json";
+
+/// Goal engine configuration
+#[derive(Debug, Clone)]
+pub struct GoalConfig {
+    /// Days of history streaks and completion rates cover (default: 365)
+    pub history_days: u32,
+    /// How often goals of metrics with new points are recomputed
+    /// (default: 1 second)
+    pub refresh_interval_ms: u64,
+}
+
+impl Default for GoalConfig {
+    fn default() -> Self {
+        Self {
+            history_days: 365,
+            refresh_interval_ms: 1000,
+        }
+    }
+}
+
+/// Everything persisted in the goal file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GoalState {
+    next_goal_id: u64,
+    goals: Vec<Goal>,
+}
+
+impl StoreState for GoalState {
+    type Record = Goal;
+
+    fn records(&self) -> &[Goal] {
+        &self.goals
+    }
+
+    fn records_mut(&mut self) -> &mut Vec<Goal> {
+        &mut self.goals
+    }
+
+    fn last_id(&mut self) -> &mut u64 {
+        &mut self.next_goal_id
+    }
+}
+
+impl Record for Goal {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn created_at(&self) -> i64 {
+        self.created_at
+    }
+
+    fn identify(&mut self, id: u64, created_at: i64) {
+        self.id = id;
+        self.created_at = created_at;
+    }
+}
+
+/// Stores goals and measures their progress
+pub struct GoalEngine {
+    executor: Arc<QueryExecutor>,
+    config: GoalConfig,
+    store: JsonStore<GoalState>,
+}
+
+impl GoalEngine {
+    /// Open the goal file of the storage engine's data directory
+    pub fn open(
+        storage: &StorageEngine,
+        executor: Arc<QueryExecutor>,
+        config: GoalConfig,
+    ) -> GoalResult<Self> {
+        Ok(Self {
+            executor,
+            config,
+            store: JsonStore::open(storage.data_dir(), GOALS_FILE)?,
+        })
+    }
+
+    /// All goals, in creation order
+    pub async fn goals(&self) -> Vec<Goal> {
+        self.store.records().await
+    }
+
+    /// A goal by ID
+    pub async fn goal(&self, id: u64) -> Option<Goal> {
+        self.store.get(id).await
+    }
+
+    /// Add a goal
+    pub async fn create_goal(&self, goal: Goal) -> GoalResult<Goal> {
+        goal.validate()?;
+        Ok(self.store.insert(goal, Utc::now().timestamp_millis()).await?)
+    }
+
+    /// Change a goal's target, period or metric
+    ///
+    /// Its history is recomputed from the data, so nothing carries over.
+    pub async fn update_goal(&self, id: u64, goal: Goal) -> GoalResult<Goal> {
+        goal.validate()?;
+        self.store.replace(id, goal).await?.ok_or(GoalError::GoalNotFound(id))
+    }
+
+    /// Delete a goal
+    pub async fn delete_goal(&self, id: u64) -> GoalResult<()> {
+        if !self.store.remove(id).await? {
+            return Err(GoalError::GoalNotFound(id));
+        }
+        Ok(())
+    }
+
+    /// Progress of a goal as of `now`
+    ///
+    /// History starts at the first day with data within `history_days`; a
+    /// metric that doesn't exist yet has none.
+    pub async fn progress(&self, goal: &Goal, now: i64) -> GoalResult<GoalProgress> {
+        let days = GroupByClause::new(GroupByInterval::Day).timezone(goal.tz()?);
+        let today = days.bucket_start(now);
+        let tomorrow = days.next_bucket_start(today);
+        let mut first = today;
+        for _ in 0..self.config.history_days.saturating_sub(1) {
+            first = days.bucket_start(first - 1);
+        }
+
+        let query = Query::select(&[goal.metric.as_str()])
+            .time_range(TimeRange::new(first, tomorrow))
+            .group_by_clause(days.clone())
+            .with_aggregation(goal.aggregation)
+            .build();
+        let aggregates: HashMap<i64, f64> = match self.executor.execute(query).await {
+            Ok(result) => result
+                .rows
+                .iter()
+                .filter_map(|row| Some((row.timestamp, row.get(&goal.metric)?)))
+                .collect(),
+            Err(QueryError::MetricNotFound(_)) => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Every day from the first with data through today
+        let mut daily = Vec::new();
+        let mut day = aggregates.keys().copied().min().unwrap_or(today).min(today);
+        while day < tomorrow {
+            daily.push(aggregates.get(&day).copied());
+            day = days.next_bucket_start(day);
+        }
+
+        Ok(goal.evaluate(&daily))
+    }
+
+    /// Recompute the goals of the metrics whose points are published to
+    /// `hub`, at most once per `refresh_interval_ms`, and publish the
+    /// progress that changed to the `goals` topic
+    pub fn start(self: &Arc<Self>, hub: Arc<ConnectionHub>) -> JoinHandle<()> {
+        let engine = Arc::clone(self);
+        let mut events = hub.subscribe_broadcast();
+
+        tokio::spawn(async move {
+            let interval = Duration::from_millis(engine.config.refresh_interval_ms.max(1));
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut pending = HashSet::new();
+            let mut published = HashMap::new();
+
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(WsEvent {
+                            message: ServerMessage::DataPoint { metric, .. },
+                            ..
+                        }) => {
+                            pending.insert(metric);
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(skipped, "Goal engine fell behind, refreshing all");
+                            let goals = engine.goals().await;
+                            pending.extend(goals.into_iter().map(|goal| goal.metric));
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = ticker.tick(), if !pending.is_empty() => {
+                        let metrics = std::mem::take(&mut pending);
+                        let now = Utc::now().timestamp_millis();
+                        for (goal, progress) in
+                            engine.changed_progress(&metrics, &mut published, now).await
+                        {
+                            hub.publish(WsEvent::goal_progress(&goal, &progress));
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Recompute the goals of `metrics`, returning those whose progress
+    /// differs from the last one `published` (which is updated)
+    async fn changed_progress(
+        &self,
+        metrics: &HashSet<String>,
+        published: &mut HashMap<u64, GoalProgress>,
+        now: i64,
+    ) -> Vec<(Goal, GoalProgress)> {
+        let goals = self.goals().await;
+        published.retain(|id, _| goals.iter().any(|goal| goal.id == *id));
+
+        let mut changed = Vec::new();
+        for goal in goals.into_iter().filter(|goal| metrics.contains(&goal.metric)) {
+            match self.progress(&goal, now).await {
+                Ok(progress) => {
+                    if published.get(&goal.id) != Some(&progress) {
+                        published.insert(goal.id, progress.clone());
+                        changed.push((goal, progress));
+                    }
+                }
+                Err(e) => tracing::warn!(goal = %goal.name, error = %e, "Goal progress failed"),
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::Comparison;
+    use crate::storage::{AggregationType, Category, DataPoint, Metric, StorageConfig};
+    use tempfile::tempdir;
+
+    const DAY_MS: i64 = 24 * 3600 * 1000;
+
+    #[tokio::test]
+    async fn test_goal_progress() {
+        let dir = tempdir().unwrap();
+        let storage = Arc::new(StorageEngine::new(StorageConfig::new(dir.path())).await.unwrap());
+        let executor = Arc::new(QueryExecutor::new(Arc::clone(&storage)));
+        let engine = GoalEngine::open(&storage, executor, GoalConfig::default()).unwrap();
+
+        let goal = Goal::new("Meditate", "meditation", Comparison::AtLeast, 10.0);
+        let goal = engine.create_goal(goal).await.unwrap();
+
+        // Nothing logged yet
+        let now = Utc::now().timestamp_millis();
+        let progress = engine.progress(&goal, now).await.unwrap();
+        assert_eq!((progress.current_streak, progress.periods_total), (0, 0));
+
+        // Two sessions making up today's 10 minutes, after 3 met days and a miss
+        let metric = Metric::new("meditation", "min", Category::Habit, AggregationType::Sum);
+        let metric_id = storage.register_metric(metric).await.unwrap();
+        let today = GroupByInterval::Day.truncate(now);
+        let sessions = [(5, 15.0), (4, 10.0), (3, 12.0), (2, 20.0), (1, 3.0), (0, 4.0), (0, 6.0)];
+        for (days_ago, minutes) in sessions {
+            let timestamp = today - days_ago * DAY_MS + 60_000 * minutes as i64;
+            let point = DataPoint::with_timestamp(metric_id, minutes, timestamp);
+            storage.write(point).await.unwrap();
+        }
+
+        let progress = engine.progress(&goal, now).await.unwrap();
+        assert_eq!(progress.today, Some(10.0));
+        assert!(progress.period_met);
+        assert_eq!(progress.current_streak, 1);
+        assert_eq!(progress.best_streak, 4);
+        assert_eq!((progress.periods_met, progress.periods_total), (4, 5));
+
+        // Goals survive a restart
+        let executor = Arc::new(QueryExecutor::new(Arc::clone(&storage)));
+        let reopened = GoalEngine::open(&storage, executor, GoalConfig::default()).unwrap();
+        assert_eq!(reopened.goal(goal.id).await, Some(goal));
+        assert!(matches!(reopened.delete_goal(42).await, Err(GoalError::GoalNotFound(42))));
+
+        // Only progress that changed is published again
+        let metrics = HashSet::from(["meditation".to_string()]);
+        let mut published = HashMap::new();
+        let changed = engine.changed_progress(&metrics, &mut published, now).await;
+        assert_eq!(changed.len(), 1);
+        assert!(engine.changed_progress(&metrics, &mut published, now).await.is_empty());
+        let point = DataPoint::with_timestamp(metric_id, 5.0, today + 3_600_000);
+        storage.write(point).await.unwrap();
+        let changed = engine.changed_progress(&metrics, &mut published, now).await;
+        assert_eq!(changed[0].1.today, Some(15.0));
+    }
+}
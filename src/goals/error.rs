@@ -0,0 +1,26 @@
+//! Goal error types
+
+use thiserror::Error;
+
+/// Errors that can occur managing goals
+#[derive(Error, Debug)]
+pub enum GoalError {
+    /// Goal definition is incomplete or out of range
+    #[error("Invalid goal: {0}")]
+    InvalidGoal(String),
+
+    /// No goal with this ID
+    #[error("Goal not found: {0}")]
+    GoalNotFound(u64),
+
+    /// Reading the daily aggregates failed
+    #[error("Query error: {0}")]
+    Query(#[from] crate::query::QueryError),
+
+    /// Reading or writing the goal file failed
+    #[error("Storage error: {0}")]
+    Storage(#[from] crate::storage::StorageError),
+}
+
+/// Result type for goal operations
+pub type GoalResult<T> = Result<T, GoalError>;
@@ -0,0 +1,278 @@
+//! Goals
+//!
+//! A `Goal` sets a daily target for a metric's aggregate, such as
+//! "meditation >= 10 min daily" (1 of 1 days) or "steps >= 8000 on 5 of 7
+//! days". Progress is measured in periods of `window_days` days counted back
+//! from today, so the current period is always the last `window_days` days:
+//!
+//! - **Streak**: consecutive periods that met the goal, up to the current
+//!   one. A current period that isn't met yet doesn't break the streak.
+//! - **Best streak**: the longest such run in the history.
+//! - **Completion rate**: share of the finished periods that met the goal.
+
+use crate::goals::error::{GoalError, GoalResult};
+use crate::query::{AggregationFunc, Comparison};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+/// Longest period a goal may span
+const MAX_WINDOW_DAYS: u32 = 366;
+
+/// A daily target for a metric
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Goal {
+    /// Unique goal ID (assigned on creation)
+    #[serde(default)]
+    pub id: u64,
+    /// Human-readable name
+    pub name: String,
+    /// Metric the goal tracks
+    pub metric: String,
+    /// How a day's points are combined (default: sum)
+    #[serde(default = "default_aggregation")]
+    pub aggregation: AggregationFunc,
+    /// Comparison of a day's aggregate against the target
+    pub operator: Comparison,
+    /// Target value
+    pub target: f64,
+    /// Days per period that must meet the target (default: 1)
+    #[serde(default = "default_days")]
+    pub days: u32,
+    /// Days per period (default: 1, i.e. every day)
+    #[serde(default = "default_days")]
+    pub window_days: u32,
+    /// Time zone days start in (default: UTC)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Creation time in milliseconds (assigned on creation)
+    #[serde(default)]
+    pub created_at: i64,
+}
+
+fn default_aggregation() -> AggregationFunc {
+    AggregationFunc::Sum
+}
+
+fn default_days() -> u32 {
+    1
+}
+
+/// How a goal is going
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GoalProgress {
+    /// Goal ID
+    pub goal_id: u64,
+    /// Consecutive periods that met the goal, up to the current one
+    pub current_streak: u32,
+    /// Longest run of periods that met the goal
+    pub best_streak: u32,
+    /// Share of the finished periods that met the goal (None before the first)
+    pub completion_rate: Option<f64>,
+    /// Finished periods that met the goal
+    pub periods_met: u32,
+    /// Finished periods
+    pub periods_total: u32,
+    /// Days of the current period that met the target
+    pub days_met: u32,
+    /// Whether the current period meets the goal already
+    pub period_met: bool,
+    /// Today's aggregate, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub today: Option<f64>,
+}
+
+impl Goal {
+    /// Create a goal met on days whose sum compares true against `target`
+    pub fn new(
+        name: impl Into<String>,
+        metric: impl Into<String>,
+        operator: Comparison,
+        target: f64,
+    ) -> Self {
+        Self {
+            id: 0,
+            name: name.into(),
+            metric: metric.into(),
+            aggregation: default_aggregation(),
+            operator,
+            target,
+            days: 1,
+            window_days: 1,
+            timezone: None,
+            created_at: 0,
+        }
+    }
+
+    /// Builder: combine a day's points with another aggregation
+    pub fn aggregation(mut self, aggregation: AggregationFunc) -> Self {
+        self.aggregation = aggregation;
+        self
+    }
+
+    /// Builder: require `days` of every `window_days` days
+    pub fn days_of(mut self, days: u32, window_days: u32) -> Self {
+        self.days = days;
+        self.window_days = window_days;
+        self
+    }
+
+    /// Builder: start days in a time zone
+    pub fn timezone(mut self, timezone: impl Into<String>) -> Self {
+        self.timezone = Some(timezone.into());
+        self
+    }
+
+    /// Reject a goal without a name or metric, with a non-finite target or
+    /// percentile out of range, or with more days than its period holds
+    pub fn validate(&self) -> GoalResult<()> {
+        let invalid = |message: &str| Err(GoalError::InvalidGoal(message.to_string()));
+
+        if self.name.trim().is_empty() {
+            return invalid("name cannot be empty");
+        }
+        if self.metric.trim().is_empty() {
+            return invalid("metric cannot be empty");
+        }
+        if !self.target.is_finite() {
+            return invalid("target must be finite");
+        }
+        if let AggregationFunc::Percentile(q) = self.aggregation {
+            if !(0.0..=1.0).contains(&q) {
+                return invalid("percentile must be between 0 and 1");
+            }
+        }
+        if self.window_days == 0 || self.window_days > MAX_WINDOW_DAYS {
+            return invalid("window_days must be between 1 and 366");
+        }
+        if self.days == 0 || self.days > self.window_days {
+            return invalid("days must be between 1 and window_days");
+        }
+        self.tz()?;
+        Ok(())
+    }
+
+    /// Time zone days start in
+    pub fn tz(&self) -> GoalResult<Tz> {
+        match &self.timezone {
+            Some(name) => name
+                .parse()
+                .map_err(|_| GoalError::InvalidGoal(format!("unknown time zone '{}'", name))),
+            None => Ok(Tz::UTC),
+        }
+    }
+
+    /// Whether a day with this aggregate meets the target
+    ///
+    /// Days without points count as 0 for sums and counts, and as not met
+    /// otherwise.
+    pub fn day_met(&self, value: Option<f64>) -> bool {
+        let value = value.or(match self.aggregation {
+            AggregationFunc::Sum | AggregationFunc::Count => Some(0.0),
+            _ => None,
+        });
+        value.is_some_and(|value| self.operator.holds(value, self.target))
+    }
+
+    /// Progress over consecutive days, oldest first and ending today
+    pub fn evaluate(&self, daily: &[Option<f64>]) -> GoalProgress {
+        let window = self.window_days.max(1) as usize;
+        let met: Vec<bool> = daily.iter().map(|value| self.day_met(*value)).collect();
+        let count = |days: &[bool]| days.iter().filter(|met| **met).count() as u32;
+
+        // The current period, then the full periods before it, newest first
+        let current = &met[met.len().saturating_sub(window)..];
+        let finished: Vec<bool> = met[..met.len() - current.len()]
+            .rchunks_exact(window)
+            .map(|period| count(period) >= self.days)
+            .collect();
+
+        let days_met = count(current);
+        let period_met = days_met >= self.days;
+        let periods_met = count(&finished);
+        let periods_total = finished.len() as u32;
+
+        let current_streak = u32::from(period_met)
+            + finished.iter().take_while(|met| **met).count() as u32;
+        let mut best_streak = current_streak;
+        let mut run = 0;
+        for met in finished.iter().rev() {
+            run = if *met { run + 1 } else { 0 };
+            best_streak = best_streak.max(run);
+        }
+
+        GoalProgress {
+            goal_id: self.id,
+            current_streak,
+            best_streak,
+            completion_rate: (periods_total > 0)
+                .then(|| periods_met as f64 / periods_total as f64),
+            periods_met,
+            periods_total,
+            days_met,
+            period_met,
+            today: daily.last().copied().flatten(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_daily_streaks() {
+        let goal = Goal::new("Meditate", "meditation", Comparison::AtLeast, 10.0);
+
+        // Met, met, missed, met x3, and nothing logged yet today
+        let daily = [Some(12.0), Some(10.0), Some(5.0), Some(15.0), Some(10.0), Some(20.0), None];
+        let progress = goal.evaluate(&daily);
+        assert_eq!(progress.current_streak, 3);
+        assert_eq!(progress.best_streak, 3);
+        assert_eq!((progress.periods_met, progress.periods_total), (5, 6));
+        assert!(!progress.period_met);
+        assert_eq!(progress.today, None);
+
+        let progress = goal.evaluate(&[Some(12.0), Some(10.0), None, Some(11.0)]);
+        assert_eq!(progress.current_streak, 1);
+        assert_eq!(progress.best_streak, 2);
+        assert_eq!(progress.completion_rate, Some(2.0 / 3.0));
+    }
+
+    #[test]
+    fn test_days_of_window() {
+        let goal = Goal::new("Walk", "steps", Comparison::AtLeast, 8000.0).days_of(5, 7);
+
+        // Two full weeks (5 and 4 days met) before a current week with 5 met
+        let week = |met: usize| (0..7).map(move |day| Some(if day < met { 9000.0 } else { 100.0 }));
+        let daily: Vec<Option<f64>> = week(5).chain(week(4)).chain(week(5)).collect();
+        let progress = goal.evaluate(&daily);
+        assert!(progress.period_met);
+        assert_eq!(progress.days_met, 5);
+        assert_eq!(progress.current_streak, 1);
+        assert_eq!(progress.best_streak, 1);
+        assert_eq!(progress.completion_rate, Some(0.5));
+
+        // Days before the first full period don't count
+        let progress = goal.evaluate(&daily[3..]);
+        assert_eq!(progress.periods_total, 1);
+    }
+
+    #[test]
+    fn test_validate() {
+        let goal = Goal::new("Walk", "steps", Comparison::AtLeast, 8000.0);
+        assert!(goal.clone().days_of(5, 7).timezone("Europe/Berlin").validate().is_ok());
+        assert!(goal.clone().days_of(8, 7).validate().is_err());
+        assert!(goal.clone().timezone("Mars/Olympus").validate().is_err());
+        let p95 = AggregationFunc::from_str("p95").unwrap();
+        assert!(goal.clone().aggregation(p95).validate().is_ok());
+        for q in [1.5, -0.1, f64::NAN] {
+            let percentile = AggregationFunc::Percentile(q);
+            assert!(goal.clone().aggregation(percentile).validate().is_err());
+        }
+
+        // Days without points are 0 for sums, but not met for averages
+        let under = Goal::new("Caffeine", "coffee", Comparison::AtMost, 2.0);
+        assert!(under.day_met(None));
+        assert!(!under.aggregation(AggregationFunc::Avg).day_met(None));
+    }
+}
@@ -0,0 +1,18 @@
+//! Goals and Streaks
+//!
+//! Daily targets for habit metrics, like "meditation >= 10 min daily" or
+//! "steps >= 8000 on 5 of 7 days".
+//!
+//! ## Architecture
+//!
+//! - **Goal**: Target, and streaks and completion rates over daily aggregates
+//! - **GoalEngine**: Stores goals, reads their daily aggregates through the
+//!   `QueryExecutor` and pushes progress to the WebSocket `goals` topic
+
+mod engine;
+mod error;
+mod goal;
+
+pub use engine::{GoalConfig, GoalEngine};
+pub use error::{GoalError, GoalResult};
+pub use goal::{Goal, GoalProgress};
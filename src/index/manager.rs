@@ -24,7 +24,7 @@
 //! 4. Read only these 2 blocks instead of scanning everything!
 //! ```
 
-use crate::index::{DataLocation, IndexStats, MetricIndex, TagIndex, TimeIndex};
+use crate::index::{DataLocation, IndexStats, MetricIndex, TagIndex, TimeEntry, TimeIndex};
 use crate::storage::{StorageError, TimeRange};
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
@@ -122,11 +122,12 @@ impl IndexManager {
             return Vec::new();
         }
 
-        // Get time range locations, filtered by metric segments
+        // Get time range locations, filtered by metric segments/blocks
         self.time_index
             .find_range(range.start, range.end)
             .into_iter()
             .filter(|loc| metric_segments.contains(&loc.segment_id))
+            .filter(|loc| self.metric_index.may_contain(metric_id, *loc))
             .collect()
     }
 
@@ -136,6 +137,18 @@ impl IndexManager {
         range: &TimeRange,
         metric_id: Option<u32>,
         tags: &HashMap<String, String>,
+    ) -> Vec<DataLocation> {
+        self.find_by_time_metric_and_tag_values(range, metric_id, tags, &HashMap::new())
+    }
+
+    /// Like `find_by_time_metric_and_tags`, also requiring for each key in
+    /// `tag_values` a block holding any of its values
+    pub fn find_by_time_metric_and_tag_values(
+        &self,
+        range: &TimeRange,
+        metric_id: Option<u32>,
+        tags: &HashMap<String, String>,
+        tag_values: &HashMap<String, Vec<String>>,
     ) -> Vec<DataLocation> {
         // Start with time range
         let mut locations: HashSet<DataLocation> = self
@@ -144,28 +157,19 @@ impl IndexManager {
             .into_iter()
             .collect();
 
-        // Filter by metric if specified
+        // Filter by metric if specified (block-level where known)
         if let Some(mid) = metric_id {
-            let metric_segments: HashSet<u32> = self
-                .metric_index
-                .get_segments(mid)
-                .into_iter()
-                .collect();
-
-            locations.retain(|loc| metric_segments.contains(&loc.segment_id));
+            locations.retain(|loc| self.metric_index.may_contain(mid, *loc));
         }
 
-        // Filter by tags if any
-        if !tags.is_empty() && self.tag_index.is_enabled() {
-            let tag_pairs: Vec<(&str, &str)> = tags
-                .iter()
-                .map(|(k, v)| (k.as_str(), v.as_str()))
-                .collect();
-
-            let tag_locations: HashSet<DataLocation> =
-                self.tag_index.find_all(&tag_pairs).into_iter().collect();
+        // Filter by tags if any (blocks indexed with all their tags only)
+        for (key, value) in tags {
+            locations.retain(|loc| self.tag_index.may_contain(key, &[value.as_str()], *loc));
+        }
 
-            locations.retain(|loc| tag_locations.contains(loc));
+        for (key, values) in tag_values {
+            let values: Vec<&str> = values.iter().map(String::as_str).collect();
+            locations.retain(|loc| self.tag_index.may_contain(key, &values, *loc));
         }
 
         locations.into_iter().collect()
@@ -176,6 +180,19 @@ impl IndexManager {
         self.time_index.find_floor(timestamp)
     }
 
+    /// Check whether a block may hold points of a metric
+    pub fn may_contain_metric(&self, metric_id: u32, location: DataLocation) -> bool {
+        self.metric_index.may_contain(metric_id, location)
+    }
+
+    /// Check whether any block of a segment has been indexed
+    ///
+    /// Segments the index has never seen cannot be pruned safely and
+    /// must be scanned in full.
+    pub fn has_segment(&self, segment_id: u32) -> bool {
+        !self.time_index.find_by_segment(segment_id).is_empty()
+    }
+
     // ==================== Index Update Methods ====================
 
     /// Index a new segment
@@ -186,7 +203,8 @@ impl IndexManager {
     /// * `segment_id` - The segment being indexed
     /// * `block_boundaries` - Vec of (block_idx, min_timestamp) pairs
     /// * `metrics` - Metric IDs present in this segment
-    /// * `tags` - Optional: tags and their locations in this segment
+    /// * `tags` - Optional: every tag and its locations in this segment; the
+    ///   segment's blocks are then pruned by tag filters
     pub fn index_segment(
         &mut self,
         segment_id: u32,
@@ -211,6 +229,10 @@ impl IndexManager {
                         self.tag_index.add(key, value, location);
                     }
                 }
+                for (block_idx, _) in block_boundaries {
+                    let location = DataLocation::new(segment_id, *block_idx);
+                    self.tag_index.mark_indexed(location);
+                }
             }
         }
 
@@ -218,6 +240,9 @@ impl IndexManager {
     }
 
     /// Index a single block (simpler API for incremental indexing)
+    ///
+    /// `tags` must hold every tag of the block; use `index_block_tags` for
+    /// blocks with several values per key.
     pub fn index_block(
         &mut self,
         segment_id: u32,
@@ -230,20 +255,54 @@ impl IndexManager {
         self.time_index
             .insert(min_timestamp, segment_id, block_idx)?;
 
-        // Metric index
+        // Metric index (block-level)
+        let location = DataLocation::new(segment_id, block_idx);
         for &metric_id in metrics {
-            self.metric_index.add_segment(metric_id, segment_id);
+            self.metric_index.add_block(metric_id, location);
         }
 
         // Tag index
-        if self.tag_index.is_enabled() && !tags.is_empty() {
-            let location = DataLocation::new(segment_id, block_idx);
+        if self.tag_index.is_enabled() {
             self.tag_index.add_tags(tags, location);
+            self.tag_index.mark_indexed(location);
         }
 
         Ok(())
     }
 
+    /// Index every distinct tag pair present in a block
+    ///
+    /// A block usually holds several values for the same tag key (e.g.
+    /// `location=home` and `location=office`), which a single
+    /// `HashMap<String, String>` cannot express.
+    pub fn index_block_tags(
+        &mut self,
+        segment_id: u32,
+        block_idx: u32,
+        tags: &HashSet<(String, String)>,
+    ) {
+        if !self.tag_index.is_enabled() {
+            return;
+        }
+
+        let location = DataLocation::new(segment_id, block_idx);
+        for (key, value) in tags {
+            self.tag_index.add(key, value, location);
+        }
+        self.tag_index.mark_indexed(location);
+    }
+
+    /// Record which metrics a block holds
+    ///
+    /// `index_segment` only knows metrics per segment; call this afterwards
+    /// so queries can prune individual blocks of the segment.
+    pub fn index_block_metrics(&mut self, segment_id: u32, block_idx: u32, metrics: &[u32]) {
+        let location = DataLocation::new(segment_id, block_idx);
+        for &metric_id in metrics {
+            self.metric_index.add_block(metric_id, location);
+        }
+    }
+
     /// Remove a segment from all indexes (used during compaction)
     pub fn remove_segment(&mut self, segment_id: u32) -> Result<(), StorageError> {
         self.time_index.remove_segment(segment_id)?;
@@ -270,6 +329,28 @@ impl IndexManager {
         Ok(())
     }
 
+    // ==================== Inspection Methods ====================
+
+    /// Every time index entry (used by integrity checks)
+    pub fn time_entries(&self) -> Vec<TimeEntry> {
+        self.time_index.entries()
+    }
+
+    /// Every (metric_id, segment_id) pair in the metric index
+    pub fn metric_segment_entries(&self) -> Vec<(u32, u32)> {
+        self.metric_index.segment_entries()
+    }
+
+    /// Every (metric_id, block location) pair in the metric index
+    pub fn metric_block_entries(&self) -> Vec<(u32, DataLocation)> {
+        self.metric_index.block_entries()
+    }
+
+    /// Every (key, value, location) entry in the tag index
+    pub fn tag_entries(&self) -> Vec<(String, String, DataLocation)> {
+        self.tag_index.entries()
+    }
+
     // ==================== Stats Methods ====================
 
     /// Get statistics about all indexes
@@ -450,6 +531,73 @@ mod tests {
         assert!(locations.iter().all(|loc| loc.segment_id == 1));
     }
 
+    #[test]
+    fn test_block_level_metric_pruning() {
+        let (mut manager, _dir) = create_test_manager();
+
+        // One segment, blocks alternate between metrics 10 and 20
+        manager.index_block(1, 0, 1000, &[10], &HashMap::new()).unwrap();
+        manager.index_block(1, 1, 2000, &[20], &HashMap::new()).unwrap();
+        manager.index_block(1, 2, 3000, &[10, 20], &HashMap::new()).unwrap();
+
+        let range = TimeRange::new(0, 4000);
+        let mut locations = manager.find_by_time_metric_and_tags(&range, Some(20), &HashMap::new());
+        locations.sort_by_key(|loc| loc.block_idx);
+
+        assert_eq!(
+            locations,
+            vec![DataLocation::new(1, 1), DataLocation::new(1, 2)]
+        );
+        assert_eq!(manager.find_by_time_and_metric(&range, 10).len(), 2);
+    }
+
+    #[test]
+    fn test_index_block_tags_multiple_values() {
+        let (mut manager, _dir) = create_test_manager();
+
+        let tags: HashSet<(String, String)> = [
+            ("location".to_string(), "home".to_string()),
+            ("location".to_string(), "office".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        manager.index_block(1, 0, 1000, &[10], &HashMap::new()).unwrap();
+        manager.index_block_tags(1, 0, &tags);
+
+        assert_eq!(manager.find_by_tag("location", "home").len(), 1);
+        assert_eq!(manager.find_by_tag("location", "office").len(), 1);
+        assert!(manager.has_segment(1));
+        assert!(!manager.has_segment(2));
+    }
+
+    #[test]
+    fn test_legacy_tag_blocks_are_not_pruned() {
+        // Older versions indexed one value per key and block, even when the
+        // block held points from home and the office
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("index")).unwrap();
+        let legacy = serde_json::json!({
+            "version": 1,
+            "keys": ["location"],
+            "index": {"location:home": [{"segment_id": 1, "block_idx": 0}]},
+        });
+        std::fs::write(dir.path().join("index/tag_index.json"), legacy.to_string()).unwrap();
+
+        let mut manager = IndexManager::new(dir.path()).unwrap();
+        let range = TimeRange::new(0, 3000);
+        let home = HashMap::from([("location".to_string(), "home".to_string())]);
+        let office = HashMap::from([("location".to_string(), "office".to_string())]);
+        manager.index_segment(1, &[(0, 1000)], &[10], None).unwrap();
+
+        // Blocks indexed with all their tags can be ruled out
+        manager.index_block(2, 0, 2000, &[10], &home).unwrap();
+
+        let locations = manager.find_by_time_metric_and_tags(&range, Some(10), &office);
+        assert_eq!(locations, vec![DataLocation::new(1, 0)]);
+        assert_eq!(manager.find_by_time_metric_and_tags(&range, Some(10), &home).len(), 2);
+    }
+
     #[test]
     fn test_remove_segment() {
         let (mut manager, _dir) = create_test_manager();
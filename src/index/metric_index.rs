@@ -1,7 +1,9 @@
 //! Metric Index - In-memory HashMap with JSON persistence
 //!
 //! Maps metric_id → Set<segment_id> for O(1) lookup of which
-//! segments contain data for a given metric.
+//! segments contain data for a given metric. Blocks indexed one at a
+//! time (the normal flush path) are also tracked at block granularity,
+//! so single-metric queries can skip blocks that never saw the metric.
 //!
 //! # Usage
 //! ```ignore
@@ -10,6 +12,7 @@
 //! // segments = [1, 3, 5] - only scan these segments
 //! ```
 
+use crate::index::DataLocation;
 use crate::storage::StorageError;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
@@ -24,6 +27,10 @@ use std::path::{Path, PathBuf};
 pub struct MetricIndex {
     /// metric_id → set of segment_ids containing that metric
     index: HashMap<u32, HashSet<u32>>,
+    /// metric_id → set of block locations containing that metric
+    blocks: HashMap<u32, HashSet<DataLocation>>,
+    /// Segments whose blocks have been indexed individually
+    block_indexed_segments: HashSet<u32>,
     /// Path to persistence file
     path: PathBuf,
     /// Track if modified since last save
@@ -37,28 +44,45 @@ struct MetricIndexData {
     version: u32,
     /// The actual index data: metric_id → [segment_ids]
     index: HashMap<u32, Vec<u32>>,
+    /// Block-level data: metric_id → [locations] (added in version 2)
+    #[serde(default)]
+    blocks: HashMap<u32, Vec<DataLocation>>,
 }
 
+/// Segment-level and block-level maps as loaded from disk
+type LoadedIndex = (
+    HashMap<u32, HashSet<u32>>,
+    HashMap<u32, HashSet<DataLocation>>,
+);
+
 impl MetricIndex {
     /// Create or load a metric index
     pub fn new(data_dir: &Path) -> Result<Self, StorageError> {
         let path = data_dir.join("metric_index.json");
 
-        let index = if path.exists() {
+        let (index, blocks) = if path.exists() {
             Self::load_from_file(&path)?
         } else {
-            HashMap::new()
+            (HashMap::new(), HashMap::new())
         };
 
+        let block_indexed_segments = blocks
+            .values()
+            .flatten()
+            .map(|loc: &DataLocation| loc.segment_id)
+            .collect();
+
         Ok(Self {
             index,
+            blocks,
+            block_indexed_segments,
             path,
             dirty: false,
         })
     }
 
     /// Load index from JSON file
-    fn load_from_file(path: &Path) -> Result<HashMap<u32, HashSet<u32>>, StorageError> {
+    fn load_from_file(path: &Path) -> Result<LoadedIndex, StorageError> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
 
@@ -73,7 +97,13 @@ impl MetricIndex {
             .map(|(k, v)| (k, v.into_iter().collect()))
             .collect();
 
-        Ok(index)
+        let blocks = data
+            .blocks
+            .into_iter()
+            .map(|(k, v)| (k, v.into_iter().collect()))
+            .collect();
+
+        Ok((index, blocks))
     }
 
     /// Add a segment to a metric's segment set
@@ -100,6 +130,37 @@ impl MetricIndex {
         }
     }
 
+    /// Record that a specific block contains a metric
+    ///
+    /// Also registers the block's segment, so segment-level lookups
+    /// stay consistent with block-level ones.
+    pub fn add_block(&mut self, metric_id: u32, location: DataLocation) {
+        self.add_segment(metric_id, location.segment_id);
+
+        self.block_indexed_segments.insert(location.segment_id);
+        let inserted = self.blocks.entry(metric_id).or_default().insert(location);
+
+        if inserted {
+            self.dirty = true;
+        }
+    }
+
+    /// Get every (metric_id, segment_id) pair
+    pub fn segment_entries(&self) -> Vec<(u32, u32)> {
+        self.index
+            .iter()
+            .flat_map(|(&metric_id, segments)| segments.iter().map(move |&s| (metric_id, s)))
+            .collect()
+    }
+
+    /// Get every (metric_id, block location) pair
+    pub fn block_entries(&self) -> Vec<(u32, DataLocation)> {
+        self.blocks
+            .iter()
+            .flat_map(|(&metric_id, locations)| locations.iter().map(move |&l| (metric_id, l)))
+            .collect()
+    }
+
     /// Remove a segment from all metrics (used during compaction)
     pub fn remove_segment(&mut self, segment_id: u32) {
         for segments in self.index.values_mut() {
@@ -108,8 +169,37 @@ impl MetricIndex {
             }
         }
 
+        for locations in self.blocks.values_mut() {
+            let before = locations.len();
+            locations.retain(|loc| loc.segment_id != segment_id);
+            if locations.len() != before {
+                self.dirty = true;
+            }
+        }
+        self.block_indexed_segments.remove(&segment_id);
+
         // Clean up empty entries
         self.index.retain(|_, segments| !segments.is_empty());
+        self.blocks.retain(|_, locations| !locations.is_empty());
+    }
+
+    /// Check whether a block may contain data for a metric
+    ///
+    /// Uses block-level information when the block's segment was indexed
+    /// block by block, and falls back to segment membership otherwise
+    /// (e.g. segments indexed via `index_segment` or by older versions).
+    pub fn may_contain(&self, metric_id: u32, location: DataLocation) -> bool {
+        if self.block_indexed_segments.contains(&location.segment_id) {
+            self.blocks
+                .get(&metric_id)
+                .map(|set| set.contains(&location))
+                .unwrap_or(false)
+        } else {
+            self.index
+                .get(&metric_id)
+                .map(|set| set.contains(&location.segment_id))
+                .unwrap_or(false)
+        }
     }
 
     /// Get all segment IDs containing a metric
@@ -162,12 +252,17 @@ impl MetricIndex {
 
         // Convert HashSet to Vec for JSON serialization
         let data = MetricIndexData {
-            version: 1,
+            version: 2,
             index: self
                 .index
                 .iter()
                 .map(|(&k, v)| (k, v.iter().copied().collect()))
                 .collect(),
+            blocks: self
+                .blocks
+                .iter()
+                .map(|(&k, v)| (k, v.iter().copied().collect()))
+                .collect(),
         };
 
         let file = File::create(&self.path)?;
@@ -388,6 +483,53 @@ mod tests {
         assert!(index.is_dirty());
     }
 
+    #[test]
+    fn test_block_level_lookup() {
+        let dir = tempdir().unwrap();
+        let mut index = MetricIndex::new(dir.path()).unwrap();
+
+        // Segment 1 indexed block by block
+        index.add_block(1, DataLocation::new(1, 0));
+        index.add_block(2, DataLocation::new(1, 1));
+
+        // Segment 2 only known at segment level
+        index.add_segment(1, 2);
+
+        assert!(index.may_contain(1, DataLocation::new(1, 0)));
+        assert!(!index.may_contain(1, DataLocation::new(1, 1)));
+        assert!(index.may_contain(2, DataLocation::new(1, 1)));
+
+        // Segment-level fallback keeps every block of segment 2
+        assert!(index.may_contain(1, DataLocation::new(2, 0)));
+        assert!(index.may_contain(1, DataLocation::new(2, 7)));
+        assert!(!index.may_contain(2, DataLocation::new(2, 0)));
+
+        // Block entries also register the segment
+        assert_eq!(index.get_segments(2), vec![1]);
+
+        index.remove_segment(1);
+        assert!(!index.may_contain(1, DataLocation::new(1, 0)));
+        assert!(!index.has_metric(2));
+    }
+
+    #[test]
+    fn test_block_level_persistence() {
+        let dir = tempdir().unwrap();
+
+        {
+            let mut index = MetricIndex::new(dir.path()).unwrap();
+            index.add_block(1, DataLocation::new(3, 0));
+            index.add_block(2, DataLocation::new(3, 1));
+            index.persist().unwrap();
+        }
+
+        {
+            let index = MetricIndex::new(dir.path()).unwrap();
+            assert!(index.may_contain(1, DataLocation::new(3, 0)));
+            assert!(!index.may_contain(1, DataLocation::new(3, 1)));
+        }
+    }
+
     #[test]
     fn test_total_segments() {
         let dir = tempdir().unwrap();
@@ -13,6 +13,8 @@
 //! - Optional index (not all deployments need tag queries)
 //! - In-memory with JSON persistence
 //! - Deduplicates locations automatically
+//! - Older versions kept one value per key and block, so only blocks
+//!   recorded as fully indexed can be ruled out by a tag filter
 
 use crate::index::DataLocation;
 use crate::storage::StorageError;
@@ -31,6 +33,8 @@ pub struct TagIndex {
     index: HashMap<String, HashSet<DataLocation>>,
     /// All known tag keys (for enumeration)
     keys: HashSet<String>,
+    /// Blocks whose every tag pair has been added
+    indexed: HashSet<DataLocation>,
     /// Path to persistence file
     path: PathBuf,
     /// Track if modified since last save
@@ -45,22 +49,33 @@ struct TagIndexData {
     version: u32,
     keys: Vec<String>,
     index: HashMap<String, Vec<DataLocation>>,
+    /// Fully indexed blocks (added in version 2)
+    #[serde(default)]
+    indexed: Vec<DataLocation>,
 }
 
+/// Tag map, keys and fully indexed blocks as loaded from disk
+type LoadedIndex = (
+    HashMap<String, HashSet<DataLocation>>,
+    HashSet<String>,
+    HashSet<DataLocation>,
+);
+
 impl TagIndex {
     /// Create a new tag index
     pub fn new(data_dir: &Path) -> Result<Self, StorageError> {
         let path = data_dir.join("tag_index.json");
 
-        let (index, keys) = if path.exists() {
+        let (index, keys, indexed) = if path.exists() {
             Self::load_from_file(&path)?
         } else {
-            (HashMap::new(), HashSet::new())
+            (HashMap::new(), HashSet::new(), HashSet::new())
         };
 
         Ok(Self {
             index,
             keys,
+            indexed,
             path,
             dirty: false,
             enabled: true,
@@ -72,6 +87,7 @@ impl TagIndex {
         Self {
             index: HashMap::new(),
             keys: HashSet::new(),
+            indexed: HashSet::new(),
             path: PathBuf::new(),
             dirty: false,
             enabled: false,
@@ -79,9 +95,7 @@ impl TagIndex {
     }
 
     /// Load index from JSON file
-    fn load_from_file(
-        path: &Path,
-    ) -> Result<(HashMap<String, HashSet<DataLocation>>, HashSet<String>), StorageError> {
+    fn load_from_file(path: &Path) -> Result<LoadedIndex, StorageError> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
 
@@ -95,7 +109,7 @@ impl TagIndex {
             .map(|(k, v)| (k, v.into_iter().collect()))
             .collect();
 
-        Ok((index, keys))
+        Ok((index, keys, data.indexed.into_iter().collect()))
     }
 
     /// Check if the index is enabled
@@ -135,6 +149,29 @@ impl TagIndex {
         }
     }
 
+    /// Record that every tag pair of a block has been added
+    pub fn mark_indexed(&mut self, location: DataLocation) {
+        if self.enabled && self.indexed.insert(location) {
+            self.dirty = true;
+        }
+    }
+
+    /// Check whether a block may hold points with any of `values` for `key`
+    ///
+    /// Blocks that weren't fully indexed, e.g. by older versions, always may.
+    pub fn may_contain(&self, key: &str, values: &[&str], location: DataLocation) -> bool {
+        if !self.enabled || !self.indexed.contains(&location) {
+            return true;
+        }
+
+        values.iter().any(|value| {
+            self.index
+                .get(&format!("{}:{}", key, value))
+                .map(|set| set.contains(&location))
+                .unwrap_or(false)
+        })
+    }
+
     /// Find all locations with a specific tag value
     pub fn find(&self, key: &str, value: &str) -> Vec<DataLocation> {
         if !self.enabled {
@@ -220,6 +257,18 @@ impl TagIndex {
         self.keys.contains(key)
     }
 
+    /// Get every (key, value, location) entry
+    pub fn entries(&self) -> Vec<(String, String, DataLocation)> {
+        self.index
+            .iter()
+            .filter_map(|(tag_key, locations)| {
+                let (key, value) = tag_key.split_once(':')?;
+                Some(locations.iter().map(move |&l| (key.to_string(), value.to_string(), l)))
+            })
+            .flatten()
+            .collect()
+    }
+
     /// Remove all entries for a segment
     pub fn remove_segment(&mut self, segment_id: u32) {
         if !self.enabled {
@@ -234,6 +283,12 @@ impl TagIndex {
             }
         }
 
+        let before = self.indexed.len();
+        self.indexed.retain(|loc| loc.segment_id != segment_id);
+        if self.indexed.len() != before {
+            self.dirty = true;
+        }
+
         // Clean up empty entries
         self.index.retain(|_, locations| !locations.is_empty());
     }
@@ -249,6 +304,9 @@ impl TagIndex {
                 self.dirty = true;
             }
         }
+        if self.indexed.remove(&location) {
+            self.dirty = true;
+        }
 
         // Clean up empty entries
         self.index.retain(|_, locations| !locations.is_empty());
@@ -281,13 +339,14 @@ impl TagIndex {
         }
 
         let data = TagIndexData {
-            version: 1,
+            version: 2,
             keys: self.keys.iter().cloned().collect(),
             index: self
                 .index
                 .iter()
                 .map(|(k, v)| (k.clone(), v.iter().copied().collect()))
                 .collect(),
+            indexed: self.indexed.iter().copied().collect(),
         };
 
         let file = File::create(&self.path)?;
@@ -314,6 +373,7 @@ impl TagIndex {
 
         self.index.clear();
         self.keys.clear();
+        self.indexed.clear();
         self.dirty = true;
     }
 }
@@ -496,6 +556,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_may_contain() {
+        let dir = tempdir().unwrap();
+        let mut index = TagIndex::new(dir.path()).unwrap();
+        let legacy = DataLocation::new(1, 0);
+        let indexed = DataLocation::new(2, 0);
+
+        // An older version kept only one of the block's values
+        index.add("location", "home", legacy);
+        index.add("location", "home", indexed);
+        index.mark_indexed(indexed);
+
+        assert!(index.may_contain("location", &["office"], legacy));
+        assert!(!index.may_contain("location", &["office"], indexed));
+        assert!(index.may_contain("location", &["office", "home"], indexed));
+
+        // Fully indexed blocks survive a restart
+        index.persist().unwrap();
+        drop(index);
+        let index = TagIndex::new(dir.path()).unwrap();
+        assert!(!index.may_contain("location", &["office"], indexed));
+    }
+
     #[test]
     fn test_disabled_index() {
         let index = TagIndex::disabled();
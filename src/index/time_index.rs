@@ -8,7 +8,7 @@
 //! - Range query: O(log n + k) where k = results
 //! - Expected: 1M entries in < 10 seconds, range query < 10ms
 
-use crate::index::DataLocation;
+use crate::index::{DataLocation, TimeEntry};
 use crate::storage::StorageError;
 use rusqlite::{params, Connection, OpenFlags};
 use std::path::{Path, PathBuf};
@@ -235,6 +235,32 @@ impl TimeIndex {
         rows.filter_map(Result::ok).collect()
     }
 
+    /// Get every entry, ordered by segment and block
+    pub fn entries(&self) -> Vec<TimeEntry> {
+        let mut stmt = match self.conn.prepare_cached(
+            "SELECT timestamp, segment_id, block_idx FROM time_index
+             ORDER BY segment_id, block_idx, timestamp",
+        ) {
+            Ok(stmt) => stmt,
+            Err(_) => return Vec::new(),
+        };
+
+        let rows = match stmt.query_map([], |row| {
+            Ok(TimeEntry {
+                timestamp: row.get(0)?,
+                location: DataLocation {
+                    segment_id: row.get(1)?,
+                    block_idx: row.get(2)?,
+                },
+            })
+        }) {
+            Ok(rows) => rows,
+            Err(_) => return Vec::new(),
+        };
+
+        rows.filter_map(Result::ok).collect()
+    }
+
     /// Remove all entries for a segment (used during compaction)
     pub fn remove_segment(&mut self, segment_id: u32) -> Result<(), StorageError> {
         self.conn
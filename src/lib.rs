@@ -17,6 +17,10 @@
 //! - [`index`]: Index structures for efficient queries
 //! - [`query`]: Query language parser and executor
 //! - [`api`]: REST API server with Axum
+//! - [`alerts`]: Alert rules evaluated as data arrives
+//! - [`goals`]: Goals and streaks for habit metrics
+//! - [`analysis`]: Trend and seasonality decomposition of metrics
+//! - [`embedded`]: [`Chronicle`] handle for using Chronicle in-process
 //!
 //! ## Quick Start
 //!
@@ -51,8 +55,12 @@
 //! }
 //! ```
 
+pub mod alerts;
+pub mod analysis;
 pub mod api;
 pub mod config;
+pub mod embedded;
+pub mod goals;
 pub mod index;
 pub mod integrations;
 pub mod memmachine;
@@ -62,16 +70,24 @@ pub mod websocket;
 
 // Re-export top-level types for convenience
 pub use storage::{
-    AggregationType, Category, DataPoint, Metric, QueryFilter, StorageConfig, StorageEngine,
-    StorageError, StorageResult, StorageStats, TimeRange,
+    AggregationType, Category, DataPoint, Metric, QueryFilter, ScanStats, StorageConfig,
+    StorageEngine, StorageError, StorageResult, StorageStats, TimeRange,
 };
 
+pub use embedded::{Chronicle, ChronicleConfig, PointStream, Sample, WrittenPoint};
+
 pub use index::{DataLocation, IndexManager, IndexStats};
 
 pub use query::{
     AggregationFunc, GroupByInterval, Query, QueryError, QueryExecutor, QueryResultData, ResultRow,
 };
 
+pub use alerts::{Alert, AlertConfig, AlertEngine, AlertError, AlertRule, Condition};
+
+pub use goals::{Goal, GoalConfig, GoalEngine, GoalError, GoalProgress};
+
+pub use analysis::{AnalysisError, DecomposeOptions, Decomposition};
+
 pub use api::{build_router, serve, ApiConfig, ApiError, AppState};
 
 pub use memmachine::{
@@ -24,8 +24,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let engine = Arc::new(StorageEngine::new(config).await?);
 
-    // Start background flush task
+    // Start background flush and compaction tasks
     let flush_handle = engine.start_background_flush();
+    let compaction_handle = engine.start_background_compaction();
 
     // Register some default metrics
     register_default_metrics(&engine).await?;
@@ -44,6 +45,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing::info!("Shutting down...");
     engine.shutdown().await?;
     flush_handle.abort();
+    compaction_handle.abort();
 
     tracing::info!("Chronicle shutdown complete");
     Ok(())
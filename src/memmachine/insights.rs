@@ -1,11 +1,14 @@
 //! Insight Engine
 //!
 //! Generates insights by combining Chronicle data with MemMachine context.
-//! Uses rule-based analysis (with potential for LLM integration).
+//! Uses rule-based analysis (with potential for LLM integration), with trends
+//! and day-of-week effects taken from a seasonal decomposition.
 
+use crate::analysis::{decompose_metric, DayOfWeekEffect, DecomposeOptions};
 use crate::memmachine::client::{MemMachineClient, MemMachineError, MemoryResult};
 use crate::query::{AggregationFunc, GroupByInterval, Query, QueryError, QueryExecutor};
 use crate::storage::{StorageEngine, TimeRange};
+use chrono::{Utc, Weekday};
 use std::collections::HashMap;
 use std::sync::Arc;
 use thiserror::Error;
@@ -64,6 +67,9 @@ impl InsightEngine {
 
         let mut metric_data = HashMap::new();
         let mut metric_trends = HashMap::new();
+        let mut metric_weekdays = HashMap::new();
+        let now = Utc::now().timestamp_millis();
+        let options = DecomposeOptions::default().days(u32::try_from(context_days).unwrap_or(0));
 
         for metric in &metrics {
             // Get daily averages
@@ -84,8 +90,16 @@ impl InsightEngine {
                     let avg = values.iter().sum::<f64>() / values.len() as f64;
                     metric_data.insert(metric.name.clone(), avg);
 
-                    // Calculate trend (comparing first half to second half)
-                    if values.len() >= 4 {
+                    // Trend and day-of-week effects from a decomposition, or
+                    // comparing first half to second half with too few days
+                    let decomposition =
+                        decompose_metric(&self.executor, &metric.name, &options, now).await;
+                    if let Ok(decomposition) = decomposition {
+                        metric_trends.insert(metric.name.clone(), decomposition.trend_change());
+                        if let Some(effect) = decomposition.strongest_weekday() {
+                            metric_weekdays.insert(metric.name.clone(), effect.clone());
+                        }
+                    } else if values.len() >= 4 {
                         let mid = values.len() / 2;
                         let first_half_avg =
                             values[..mid].iter().sum::<f64>() / mid as f64;
@@ -99,7 +113,13 @@ impl InsightEngine {
         }
 
         // 3. Generate insight (rule-based)
-        let insight = self.generate_rule_based_insight(question, &memories, &metric_data, &metric_trends);
+        let insight = self.generate_rule_based_insight(
+            question,
+            &memories,
+            &metric_data,
+            &metric_trends,
+            &metric_weekdays,
+        );
 
         // 4. Store this interaction in MemMachine
         let interaction_content = format!(
@@ -121,6 +141,7 @@ impl InsightEngine {
         memories: &[MemoryResult],
         data: &HashMap<String, f64>,
         trends: &HashMap<String, f64>,
+        weekdays: &HashMap<String, DayOfWeekEffect>,
     ) -> InsightResponse {
         let question_lower = question.to_lowercase();
         let mut insight_parts = Vec::new();
@@ -153,6 +174,8 @@ impl InsightEngine {
             self.analyze_patterns(&mut insight_parts, &mut supporting_data, &mut recommendations, data, trends);
         }
 
+        self.analyze_weekdays(&question_lower, &mut insight_parts, &mut supporting_data, weekdays);
+
         // Extract related patterns from memories
         let related_patterns: Vec<String> = memories
             .iter()
@@ -432,6 +455,58 @@ impl InsightEngine {
             supporting_data.insert(format!("{}_avg", key), *value);
         }
     }
+
+    /// Report day-of-week effects of the metrics asked about, or of all
+    /// metrics for questions about patterns
+    fn analyze_weekdays(
+        &self,
+        question_lower: &str,
+        insight_parts: &mut Vec<String>,
+        supporting_data: &mut HashMap<String, f64>,
+        weekdays: &HashMap<String, DayOfWeekEffect>,
+    ) {
+        let all = ["pattern", "trend", "week", "day"]
+            .iter()
+            .any(|word| question_lower.contains(word));
+
+        let mut metrics: Vec<&String> = weekdays
+            .keys()
+            .filter(|metric| {
+                all || question_lower.contains(metric.as_str())
+                    || question_lower.contains(&metric.replace('_', " "))
+            })
+            .collect();
+        metrics.sort();
+
+        for metric in metrics {
+            let effect = &weekdays[metric];
+            let day = weekday_name(effect.day);
+            supporting_data.insert(
+                format!("{}_{}_effect", metric, day.to_lowercase()),
+                effect.effect,
+            );
+            insight_parts.push(format!(
+                "Your {} {} on {}s ({:.1} {} your weekly average).",
+                metric.replace('_', " "),
+                if effect.effect < 0.0 { "dips" } else { "peaks" },
+                day,
+                effect.effect.abs(),
+                if effect.effect < 0.0 { "below" } else { "above" }
+            ));
+        }
+    }
+}
+
+fn weekday_name(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "Monday",
+        Weekday::Tue => "Tuesday",
+        Weekday::Wed => "Wednesday",
+        Weekday::Thu => "Thursday",
+        Weekday::Fri => "Friday",
+        Weekday::Sat => "Saturday",
+        Weekday::Sun => "Sunday",
+    }
 }
 
 /// Response from insight generation
@@ -474,4 +549,37 @@ mod tests {
         assert!(json.contains("Test insight"));
         assert!(json.contains("7.5"));
     }
+
+    #[tokio::test]
+    async fn test_weekday_insight() {
+        use crate::memmachine::client::MemMachineConfig;
+        use crate::storage::StorageConfig;
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(StorageEngine::new(StorageConfig::new(dir.path())).await.unwrap());
+        let executor = Arc::new(QueryExecutor::new(Arc::clone(&storage)));
+        let client = Arc::new(MemMachineClient::new(MemMachineConfig::default()));
+        let engine = InsightEngine::new(client, storage, executor);
+
+        let monday = DayOfWeekEffect {
+            day: Weekday::Mon,
+            effect: -1.2,
+            samples: 8,
+            score: -4.0,
+            significant: true,
+        };
+        let weekdays = HashMap::from([("mood".to_string(), monday)]);
+        let data = HashMap::from([("mood".to_string(), 6.0), ("sleep".to_string(), 7.5)]);
+
+        let trends = HashMap::new();
+        let ask = |question| {
+            engine.generate_rule_based_insight(question, &[], &data, &trends, &weekdays)
+        };
+
+        let response = ask("How is my mood?");
+        let expected = "Your mood dips on Mondays (1.2 below your weekly average).";
+        assert!(response.insight.contains(expected));
+        assert_eq!(response.supporting_data.get("mood_monday_effect"), Some(&-1.2));
+        assert!(!ask("How did I sleep?").insight.contains("Monday"));
+    }
 }
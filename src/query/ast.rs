@@ -8,26 +8,59 @@
 //! ```text
 //! SELECT mood WHERE time >= now() - 7d
 //! SELECT AVG(mood) GROUP BY day
+//! SELECT PERCENTILE(heart_rate, 0.95), STDDEV(heart_rate) GROUP BY day
 //! SELECT mood, energy WHERE tags.location = 'office'
+//! SELECT AVG(mood) GROUP BY week, tags.location
+//! SELECT AVG(mood) GROUP BY day FILL(linear)
+//! SELECT SUM(calories_in) - SUM(calories_out) AS balance GROUP BY day
+//! SELECT MOVING_AVG(AVG(mood), 7) GROUP BY day
+//! SELECT mood ORDER BY time DESC LIMIT 20 OFFSET 20
+//! SELECT TOP(steps, 3) WHERE time >= now() - 30d
+//! EXPLAIN ANALYZE SELECT AVG(mood) WHERE tags.location = 'office' GROUP BY day
+//! SELECT AVG(peak) FROM (SELECT MAX(heart_rate) AS peak GROUP BY day) GROUP BY week
+//! SELECT mood WHERE tags.location IN ('office', 'cafe') OR NOT value < 5
 //! ```
 
-use crate::storage::TimeRange;
-use chrono::{Datelike, TimeZone, Timelike, Utc};
+use super::error::{QueryError, QueryResult};
+use super::sketch::Aggregator;
+use crate::storage::{DataPoint, TimeRange};
+use chrono::{
+    Datelike, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Timelike,
+};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// A parsed query ready for execution
 #[derive(Debug, Clone)]
 pub struct Query {
     /// Metrics/columns to select
     pub select: Vec<SelectItem>,
+    /// Subquery whose result columns are selected from instead of metrics
+    pub from: Option<Box<Query>>,
     /// Time range to query
     pub time_range: TimeRange,
-    /// Filters to apply
-    pub filters: Vec<Filter>,
+    /// Filter expressions, all of which must match
+    pub filters: Vec<FilterExpr>,
     /// Optional grouping clause
     pub group_by: Option<GroupByClause>,
+    /// Row order (ascending by time by default)
+    pub order_by: OrderBy,
     /// Optional limit on results
     pub limit: Option<usize>,
+    /// Optional number of leading rows to skip
+    pub offset: Option<usize>,
+    /// Return the execution plan instead of rows
+    pub explain: Option<ExplainMode>,
+}
+
+/// What `EXPLAIN` reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExplainMode {
+    /// The blocks that would be read, without reading them (`EXPLAIN`)
+    Plan,
+    /// Run the query and report its statistics (`EXPLAIN ANALYZE`)
+    Analyze,
 }
 
 impl Query {
@@ -45,12 +78,18 @@ impl Query {
 /// An item in the SELECT clause
 #[derive(Debug, Clone, PartialEq)]
 pub struct SelectItem {
-    /// Metric name to select
+    /// Metric name to select (the expression text for computed items)
     pub metric: String,
     /// Optional aggregation function
     pub aggregation: Option<AggregationFunc>,
     /// Optional alias for the result column
     pub alias: Option<String>,
+    /// Arithmetic expression, for items that are more than one metric
+    pub expr: Option<SelectExpr>,
+    /// Window function applied across the item's rows
+    pub window: Option<WindowFunc>,
+    /// Keep only the rows with the n largest values of this item
+    pub top: Option<usize>,
 }
 
 impl SelectItem {
@@ -60,10 +99,49 @@ impl SelectItem {
             metric: metric.into(),
             aggregation: None,
             alias: None,
+            expr: None,
+            window: None,
+            top: None,
         }
     }
 
+    /// Create a select item computed from an expression
+    pub fn expression(expr: SelectExpr) -> Self {
+        Self {
+            metric: expr.to_string(),
+            aggregation: None,
+            alias: None,
+            expr: Some(expr),
+            window: None,
+            top: None,
+        }
+    }
+
+    /// Apply a window function to the item's values
+    pub fn with_window(mut self, window: WindowFunc) -> Self {
+        let expr = self.to_expr();
+        self.metric = window.label(&expr);
+        self.expr = Some(expr);
+        self.window = Some(window);
+        self
+    }
+
+    /// Select the rows with the n largest values of the item
+    pub fn with_top(mut self, n: usize) -> Self {
+        let expr = self.to_expr();
+        let inner = match self.window {
+            Some(window) => window.label(&expr),
+            None => expr.to_string(),
+        };
+        self.metric = format!("TOP({}, {})", inner, n);
+        self.expr = Some(expr);
+        self.top = Some(n);
+        self
+    }
+
     /// Add an aggregation function
+    ///
+    /// For expressions this applies to metrics not wrapped in an aggregation.
     pub fn with_aggregation(mut self, agg: AggregationFunc) -> Self {
         self.aggregation = Some(agg);
         self
@@ -79,11 +157,306 @@ impl SelectItem {
     pub fn display_name(&self) -> &str {
         self.alias.as_deref().unwrap_or(&self.metric)
     }
+
+    /// Whether the item aggregates any metric
+    pub fn is_aggregated(&self) -> bool {
+        self.aggregation.is_some() || self.expr.as_ref().is_some_and(SelectExpr::has_aggregation)
+    }
+
+    /// The item as an expression (a plain metric is a one-node expression)
+    pub fn to_expr(&self) -> SelectExpr {
+        match (&self.expr, self.aggregation) {
+            (Some(expr), _) => expr.clone(),
+            (None, Some(agg)) => SelectExpr::Aggregate(agg, self.metric.clone()),
+            (None, None) => SelectExpr::Metric(self.metric.clone()),
+        }
+    }
+
+    /// Names of the metrics this item reads
+    pub fn metrics(&self) -> Vec<&str> {
+        match &self.expr {
+            Some(expr) => expr.metrics(),
+            None => vec![self.metric.as_str()],
+        }
+    }
+}
+
+/// Arithmetic expression in the SELECT clause
+///
+/// In grouped queries every metric reference is reduced to one value per
+/// bucket (bare metrics use the item's aggregation, or LAST), so metrics are
+/// aligned on the bucket. Without GROUP BY, a query with an aggregation is
+/// reduced over its whole time range as one bucket; otherwise metrics are
+/// aligned on identical timestamps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectExpr {
+    /// Value of a metric
+    Metric(String),
+    /// Aggregation of a metric
+    Aggregate(AggregationFunc, String),
+    /// Numeric constant
+    Number(f64),
+    /// Negation
+    Neg(Box<SelectExpr>),
+    /// Arithmetic on two expressions
+    Binary(Box<SelectExpr>, ArithOp, Box<SelectExpr>),
+}
+
+impl SelectExpr {
+    /// Combine two expressions with an operator
+    pub fn binary(left: SelectExpr, op: ArithOp, right: SelectExpr) -> Self {
+        Self::Binary(Box::new(left), op, Box::new(right))
+    }
+
+    /// Names of the metrics the expression reads, without duplicates
+    pub fn metrics(&self) -> Vec<&str> {
+        let mut metrics = Vec::new();
+        self.visit_metrics(&mut |metric| {
+            if !metrics.contains(&metric) {
+                metrics.push(metric);
+            }
+        });
+        metrics
+    }
+
+    fn visit_metrics<'a>(&'a self, visit: &mut impl FnMut(&'a str)) {
+        match self {
+            Self::Metric(metric) | Self::Aggregate(_, metric) => visit(metric),
+            Self::Number(_) => {}
+            Self::Neg(expr) => expr.visit_metrics(visit),
+            Self::Binary(left, _, right) => {
+                left.visit_metrics(visit);
+                right.visit_metrics(visit);
+            }
+        }
+    }
+
+    /// Whether any metric is wrapped in an aggregation
+    pub fn has_aggregation(&self) -> bool {
+        match self {
+            Self::Aggregate(..) => true,
+            Self::Metric(_) | Self::Number(_) => false,
+            Self::Neg(expr) => expr.has_aggregation(),
+            Self::Binary(left, _, right) => left.has_aggregation() || right.has_aggregation(),
+        }
+    }
+
+    /// Rewrite every metric reference (with its aggregation, if any)
+    pub fn try_map_metrics<E>(
+        self,
+        map: &mut impl FnMut(Option<AggregationFunc>, String) -> Result<SelectExpr, E>,
+    ) -> Result<SelectExpr, E> {
+        Ok(match self {
+            Self::Metric(metric) => map(None, metric)?,
+            Self::Aggregate(agg, metric) => map(Some(agg), metric)?,
+            Self::Number(n) => Self::Number(n),
+            Self::Neg(expr) => Self::Neg(Box::new(expr.try_map_metrics(map)?)),
+            Self::Binary(left, op, right) => Self::binary(
+                left.try_map_metrics(map)?,
+                op,
+                right.try_map_metrics(map)?,
+            ),
+        })
+    }
+
+    /// Evaluate with `value` supplying each metric reference
+    ///
+    /// Returns None if a metric has no value or the result is not finite
+    /// (e.g. division by zero).
+    pub fn evaluate(
+        &self,
+        value: &mut impl FnMut(Option<AggregationFunc>, &str) -> Option<f64>,
+    ) -> Option<f64> {
+        let result = match self {
+            Self::Metric(metric) => value(None, metric)?,
+            Self::Aggregate(agg, metric) => value(Some(*agg), metric)?,
+            Self::Number(n) => *n,
+            Self::Neg(expr) => -expr.evaluate(value)?,
+            Self::Binary(left, op, right) => {
+                op.apply(left.evaluate(value)?, right.evaluate(value)?)
+            }
+        };
+        result.is_finite().then_some(result)
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Binary(_, op, _) => op.precedence(),
+            _ => u8::MAX,
+        }
+    }
+}
+
+impl std::fmt::Display for SelectExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Metric(metric) => write!(f, "{}", metric),
+            Self::Aggregate(AggregationFunc::Percentile(q), metric) => {
+                write!(f, "PERCENTILE({}, {})", metric, q)
+            }
+            Self::Aggregate(agg, metric) => write!(f, "{}({})", agg, metric),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Neg(expr) if expr.precedence() == u8::MAX => write!(f, "-{}", expr),
+            Self::Neg(expr) => write!(f, "-({})", expr),
+            Self::Binary(left, op, right) => {
+                // Parenthesise looser operands, and equal ones on the right
+                // since - and / are not associative
+                if left.precedence() < op.precedence() {
+                    write!(f, "({})", left)?;
+                } else {
+                    write!(f, "{}", left)?;
+                }
+                write!(f, " {} ", op)?;
+                if right.precedence() <= op.precedence() {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
+            }
+        }
+    }
+}
+
+/// Window functions applied across the rows of each series, after
+/// aggregation and FILL
+///
+/// Rows where the item has no value are skipped; rows before the window has
+/// enough values get none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowFunc {
+    /// Average of the last n values
+    MovingAvg(usize),
+    /// Change per unit of time (in milliseconds) since the previous value
+    Derivative(i64),
+    /// Change since the previous value
+    Difference,
+    /// Running total
+    CumulativeSum,
+    /// Exponentially weighted moving average with smoothing factor alpha
+    Ewma(f64),
+}
+
+impl WindowFunc {
+    /// Column label for the window applied to an expression
+    pub fn label(&self, expr: &SelectExpr) -> String {
+        match self {
+            Self::MovingAvg(n) => format!("MOVING_AVG({}, {})", expr, n),
+            Self::Derivative(unit_ms) => {
+                format!("DERIVATIVE({}, {})", expr, format_duration_ms(*unit_ms))
+            }
+            Self::Difference => format!("DIFFERENCE({})", expr),
+            Self::CumulativeSum => format!("CUMULATIVE_SUM({})", expr),
+            Self::Ewma(alpha) => format!("EWMA({}, {})", expr, alpha),
+        }
+    }
+
+    /// Apply to a series of (timestamp, value) pairs in time order
+    pub fn apply(&self, values: &[(i64, f64)]) -> Vec<Option<f64>> {
+        match *self {
+            Self::MovingAvg(n) => {
+                let n = n.max(1);
+                let mut sum = 0.0;
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &(_, value))| {
+                        sum += value;
+                        if i >= n {
+                            sum -= values[i - n].1;
+                        }
+                        (i + 1 >= n).then(|| sum / n as f64)
+                    })
+                    .collect()
+            }
+            Self::Derivative(unit_ms) => std::iter::once(None)
+                .chain(values.windows(2).map(|pair| {
+                    let ((t0, v0), (t1, v1)) = (pair[0], pair[1]);
+                    (t1 > t0).then(|| (v1 - v0) / ((t1 - t0) as f64 / unit_ms as f64))
+                }))
+                .take(values.len())
+                .collect(),
+            Self::Difference => std::iter::once(None)
+                .chain(values.windows(2).map(|pair| Some(pair[1].1 - pair[0].1)))
+                .take(values.len())
+                .collect(),
+            Self::CumulativeSum => {
+                let mut sum = 0.0;
+                values
+                    .iter()
+                    .map(|&(_, value)| {
+                        sum += value;
+                        Some(sum)
+                    })
+                    .collect()
+            }
+            Self::Ewma(alpha) => {
+                let mut smoothed: Option<f64> = None;
+                values
+                    .iter()
+                    .map(|&(_, value)| {
+                        let next = match smoothed {
+                            Some(previous) => alpha * value + (1.0 - alpha) * previous,
+                            None => value,
+                        };
+                        smoothed = Some(next);
+                        smoothed
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Arithmetic operators in SELECT expressions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    /// Addition
+    Add,
+    /// Subtraction
+    Sub,
+    /// Multiplication
+    Mul,
+    /// Division
+    Div,
+}
+
+impl ArithOp {
+    /// Apply the operator
+    pub fn apply(&self, left: f64, right: f64) -> f64 {
+        match self {
+            Self::Add => left + right,
+            Self::Sub => left - right,
+            Self::Mul => left * right,
+            Self::Div => left / right,
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Self::Add | Self::Sub => 1,
+            Self::Mul | Self::Div => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for ArithOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Add => write!(f, "+"),
+            Self::Sub => write!(f, "-"),
+            Self::Mul => write!(f, "*"),
+            Self::Div => write!(f, "/"),
+        }
+    }
 }
 
 /// Aggregation functions available in queries
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
+///
+/// Not `Eq`, since `Percentile` holds an `f64`. Serialized as the same
+/// lowercase names as before (`countdistinct`, from the former naming, is
+/// accepted too), with percentiles written as `{"percentile": 0.95}`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AggregationFunc {
     /// Average of values
     Avg,
@@ -99,29 +472,35 @@ pub enum AggregationFunc {
     Last,
     /// First value in the group
     First,
+    /// Median value
+    Median,
+    /// Value at a quantile between 0.0 and 1.0
+    Percentile(f64),
+    /// Sample standard deviation
+    Stddev,
+    /// Sample variance
+    Variance,
+    /// Difference between the maximum and minimum value
+    Spread,
+    /// Number of distinct values
+    #[serde(alias = "countdistinct")]
+    CountDistinct,
 }
 
 impl AggregationFunc {
     /// Apply aggregation to a slice of values
     pub fn apply(&self, values: &[f64]) -> Option<f64> {
-        if values.is_empty() {
-            return None;
-        }
-
-        Some(match self {
-            Self::Avg => values.iter().sum::<f64>() / values.len() as f64,
-            Self::Sum => values.iter().sum(),
-            Self::Min => values.iter().cloned().fold(f64::INFINITY, f64::min),
-            Self::Max => values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
-            Self::Count => values.len() as f64,
-            Self::Last => *values.last()?,
-            Self::First => *values.first()?,
-        })
+        let mut aggregator = Aggregator::new(*self);
+        aggregator.extend(values.iter().copied());
+        aggregator.finish()
     }
 
     /// Parse from string
+    ///
+    /// Percentiles are written as `p95` or `p99.9`.
     pub fn from_str(s: &str) -> Option<Self> {
-        match s.to_lowercase().as_str() {
+        let s = s.to_lowercase();
+        match s.as_str() {
             "avg" | "average" => Some(Self::Avg),
             "sum" => Some(Self::Sum),
             "min" => Some(Self::Min),
@@ -129,7 +508,17 @@ impl AggregationFunc {
             "count" => Some(Self::Count),
             "last" => Some(Self::Last),
             "first" => Some(Self::First),
-            _ => None,
+            "median" => Some(Self::Median),
+            "stddev" => Some(Self::Stddev),
+            "variance" => Some(Self::Variance),
+            "spread" => Some(Self::Spread),
+            "count_distinct" => Some(Self::CountDistinct),
+            _ => {
+                let percent: f64 = s.strip_prefix('p')?.parse().ok()?;
+                (0.0..=100.0)
+                    .contains(&percent)
+                    .then(|| Self::Percentile(percent / 100.0))
+            }
         }
     }
 }
@@ -144,6 +533,12 @@ impl std::fmt::Display for AggregationFunc {
             Self::Count => write!(f, "COUNT"),
             Self::Last => write!(f, "LAST"),
             Self::First => write!(f, "FIRST"),
+            Self::Median => write!(f, "MEDIAN"),
+            Self::Percentile(q) => write!(f, "PERCENTILE({})", q),
+            Self::Stddev => write!(f, "STDDEV"),
+            Self::Variance => write!(f, "VARIANCE"),
+            Self::Spread => write!(f, "SPREAD"),
+            Self::CountDistinct => write!(f, "COUNT_DISTINCT"),
         }
     }
 }
@@ -182,6 +577,162 @@ impl Filter {
             value: FilterValue::Number(value),
         }
     }
+
+    /// Check if a data point satisfies this comparison
+    ///
+    /// A point without the tag only matches `!=`.
+    pub fn matches(&self, point: &DataPoint) -> bool {
+        match &self.field {
+            // Metrics are resolved before points are fetched
+            FilterField::Metric => true,
+            FilterField::Tag(key) => match (point.tags.get(key), &self.value) {
+                (Some(tag_value), FilterValue::String(s)) => self.op.compare_str(tag_value, s),
+                (Some(_), FilterValue::Number(_)) => false,
+                (None, _) => self.op == Operator::Ne,
+            },
+            FilterField::Value => match &self.value {
+                FilterValue::Number(n) => self.op.compare_f64(point.value, *n),
+                FilterValue::String(_) => false,
+            },
+        }
+    }
+}
+
+/// A boolean expression over filters in the WHERE clause
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterExpr {
+    /// A single comparison
+    Compare(Filter),
+    /// Field equals one of the values (`tags.location IN ('home', 'office')`)
+    In {
+        field: FilterField,
+        values: Vec<FilterValue>,
+    },
+    /// All sub-expressions match
+    And(Vec<FilterExpr>),
+    /// At least one sub-expression matches
+    Or(Vec<FilterExpr>),
+    /// The sub-expression does not match
+    Not(Box<FilterExpr>),
+}
+
+impl FilterExpr {
+    /// Create an IN list over tag values
+    pub fn tag_in<I, S>(key: impl Into<String>, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::In {
+            field: FilterField::Tag(key.into()),
+            values: values
+                .into_iter()
+                .map(|v| FilterValue::String(v.into()))
+                .collect(),
+        }
+    }
+
+    /// Negate this expression
+    pub fn negate(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+
+    /// Check if a data point satisfies this expression
+    pub fn matches(&self, point: &DataPoint) -> bool {
+        match self {
+            Self::Compare(filter) => filter.matches(point),
+            Self::In { field, values } => values
+                .iter()
+                .any(|value| Filter::new(field.clone(), Operator::Eq, value.clone()).matches(point)),
+            Self::And(exprs) => exprs.iter().all(|e| e.matches(point)),
+            Self::Or(exprs) => exprs.iter().any(|e| e.matches(point)),
+            Self::Not(expr) => !expr.matches(point),
+        }
+    }
+
+    /// Tag values a matching point must have, if this expression implies any
+    ///
+    /// `tags.k = 'a'`, `tags.k IN ('a', 'b')` and ORs of those over the same
+    /// key imply the point's `k` tag is one of the listed values. Used to
+    /// narrow the blocks read through the tag index.
+    pub fn required_tag_values(&self) -> Option<(&str, Vec<&str>)> {
+        match self {
+            Self::Compare(Filter {
+                field: FilterField::Tag(key),
+                op: Operator::Eq,
+                value: FilterValue::String(value),
+            }) => Some((key, vec![value.as_str()])),
+            Self::In {
+                field: FilterField::Tag(key),
+                values,
+            } => {
+                let strings: Option<Vec<&str>> = values
+                    .iter()
+                    .map(|v| match v {
+                        FilterValue::String(s) => Some(s.as_str()),
+                        FilterValue::Number(_) => None,
+                    })
+                    .collect();
+                Some((key, strings?))
+            }
+            Self::Or(exprs) => {
+                let mut required: Option<(&str, Vec<&str>)> = None;
+                for expr in exprs {
+                    let (key, values) = expr.required_tag_values()?;
+                    match &mut required {
+                        Some((k, all)) if *k == key => all.extend(values),
+                        Some(_) => return None,
+                        None => required = Some((key, values)),
+                    }
+                }
+                required
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.field, self.op, self.value)
+    }
+}
+
+impl std::fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |f: &mut std::fmt::Formatter<'_>, exprs: &[FilterExpr], sep: &str| {
+            for (i, expr) in exprs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", sep)?;
+                }
+                match expr {
+                    // AND binds tighter than OR
+                    Self::Or(_) if sep == "AND" => write!(f, "({})", expr)?,
+                    _ => write!(f, "{}", expr)?,
+                }
+            }
+            Ok(())
+        };
+        match self {
+            Self::Compare(filter) => write!(f, "{}", filter),
+            Self::In { field, values } => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "{} IN ({})", field, values.join(", "))
+            }
+            Self::And(exprs) => join(f, exprs, "AND"),
+            Self::Or(exprs) => join(f, exprs, "OR"),
+            Self::Not(expr) => match **expr {
+                Self::Compare(_) | Self::In { .. } | Self::Not(_) => write!(f, "NOT {}", expr),
+                _ => write!(f, "NOT ({})", expr),
+            },
+        }
+    }
+}
+
+impl From<Filter> for FilterExpr {
+    fn from(filter: Filter) -> Self {
+        Self::Compare(filter)
+    }
 }
 
 /// Fields that can be filtered
@@ -195,6 +746,16 @@ pub enum FilterField {
     Value,
 }
 
+impl std::fmt::Display for FilterField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Metric => write!(f, "metric"),
+            Self::Tag(key) => write!(f, "tags.{}", key),
+            Self::Value => write!(f, "value"),
+        }
+    }
+}
+
 /// Comparison operators
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operator {
@@ -265,6 +826,41 @@ impl std::fmt::Display for Operator {
     }
 }
 
+/// Comparison of a value against a threshold, for alert rules and goals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = ">=")]
+    AtLeast,
+    #[serde(rename = "<")]
+    Below,
+    #[serde(rename = "<=")]
+    AtMost,
+}
+
+impl Comparison {
+    /// Whether `value` compares true against `threshold`
+    pub fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Self::Above => value > threshold,
+            Self::AtLeast => value >= threshold,
+            Self::Below => value < threshold,
+            Self::AtMost => value <= threshold,
+        }
+    }
+
+    /// Operator symbol
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Above => ">",
+            Self::AtLeast => ">=",
+            Self::Below => "<",
+            Self::AtMost => "<=",
+        }
+    }
+}
+
 /// Values used in filter comparisons
 #[derive(Debug, Clone, PartialEq)]
 pub enum FilterValue {
@@ -274,17 +870,276 @@ pub enum FilterValue {
     Number(f64),
 }
 
+impl std::fmt::Display for FilterValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "'{}'", s),
+            Self::Number(n) => write!(f, "{}", n),
+        }
+    }
+}
+
 /// GROUP BY clause specification
+///
+/// Buckets are aligned on the wall clock of `timezone`: days start at local
+/// midnight, weeks on local Monday midnight, and fixed intervals count from
+/// the local epoch (so `6h` buckets start at 00:00, 06:00, ...). A non-zero
+/// `offset_ms` shifts every boundary forward, e.g. days starting at 04:00.
+/// Grouping by tags splits the result into one series per tag value
+/// combination; points without a grouped tag fall into the "" series.
+/// `fill` decides what happens to buckets of a series without points.
 #[derive(Debug, Clone, PartialEq)]
 pub struct GroupByClause {
     /// Time interval to group by
     pub interval: GroupByInterval,
+    /// Time zone bucket boundaries are aligned to
+    pub timezone: Tz,
+    /// Shift applied to every bucket boundary, in milliseconds
+    pub offset_ms: i64,
+    /// Tag keys to split series by
+    pub tags: Vec<String>,
+    /// How buckets without points are filled
+    pub fill: FillMode,
 }
 
 impl GroupByClause {
     /// Create a new GROUP BY clause
     pub fn new(interval: GroupByInterval) -> Self {
-        Self { interval }
+        Self {
+            interval,
+            timezone: Tz::UTC,
+            offset_ms: 0,
+            tags: Vec::new(),
+            fill: FillMode::None,
+        }
+    }
+
+    /// Builder: fill buckets without points
+    pub fn fill(mut self, fill: FillMode) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Builder: split series by a tag key
+    pub fn tag(mut self, key: impl Into<String>) -> Self {
+        self.tags.push(key.into());
+        self
+    }
+
+    /// Series key of a point: its value for each grouped tag
+    pub fn series_key(&self, point: &DataPoint) -> BTreeMap<String, String> {
+        self.tags
+            .iter()
+            .map(|key| (key.clone(), point.tags.get(key).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    /// Builder: align buckets to a time zone
+    pub fn timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    /// Builder: shift bucket boundaries by `offset_ms`
+    pub fn offset(mut self, offset_ms: i64) -> Self {
+        self.offset_ms = offset_ms;
+        self
+    }
+
+    /// Start of the bucket containing `timestamp` (milliseconds, UTC)
+    pub fn bucket_start(&self, timestamp: i64) -> i64 {
+        let shifted = timestamp.saturating_sub(self.offset_ms);
+        let local = match self.timezone.timestamp_millis_opt(shifted) {
+            chrono::LocalResult::Single(dt) => dt,
+            _ => return timestamp,
+        };
+
+        let naive = local.naive_local();
+        let midnight = |date: NaiveDate| date.and_time(NaiveTime::MIN);
+        let floored = match self.interval {
+            GroupByInterval::Hour => naive
+                .with_minute(0)
+                .and_then(|d| d.with_second(0))
+                .and_then(|d| d.with_nanosecond(0))
+                .unwrap_or(naive),
+            GroupByInterval::Day => midnight(naive.date()),
+            GroupByInterval::Week => {
+                let days_since_monday = naive.weekday().num_days_from_monday() as i64;
+                midnight(naive.date() - chrono::Duration::days(days_since_monday))
+            }
+            GroupByInterval::Month => midnight(naive.date().with_day(1).unwrap_or(naive.date())),
+            GroupByInterval::Every(interval_ms) => {
+                let local_ms = naive.and_utc().timestamp_millis();
+                let floored = local_ms - local_ms.rem_euclid(interval_ms.max(1));
+                chrono::DateTime::from_timestamp_millis(floored)
+                    .map(|dt| dt.naive_utc())
+                    .unwrap_or(naive)
+            }
+        };
+
+        self.resolve_local(floored, shifted).saturating_add(self.offset_ms)
+    }
+
+    /// Start of the bucket following the one that starts at `start`
+    pub fn next_bucket_start(&self, start: i64) -> i64 {
+        // Step by less than the shortest possible bucket (DST days are 23h,
+        // months 28 days) so no bucket is skipped
+        let nominal = self.interval.approx_duration_ms();
+        let shortest = match self.interval {
+            GroupByInterval::Month => 28 * 24 * 3600 * 1000,
+            _ => nominal,
+        };
+        let step = (shortest - 2 * 3600 * 1000).max(shortest / 2).max(1);
+
+        let mut probe = start.saturating_add(step);
+        loop {
+            let next = self.bucket_start(probe);
+            if next > start || probe == i64::MAX {
+                return next.max(start.saturating_add(1));
+            }
+            probe = probe.saturating_add(step);
+        }
+    }
+
+    /// Map a local wall-clock bucket start back to UTC
+    ///
+    /// Ambiguous times (clocks going back) resolve to the latest candidate not
+    /// after `instant`; times skipped by a DST gap resolve to the end of the gap.
+    fn resolve_local(&self, naive: NaiveDateTime, instant: i64) -> i64 {
+        match self.timezone.from_local_datetime(&naive) {
+            chrono::LocalResult::Single(dt) => dt.timestamp_millis(),
+            chrono::LocalResult::Ambiguous(earliest, latest) => {
+                if latest.timestamp_millis() <= instant {
+                    latest.timestamp_millis()
+                } else {
+                    earliest.timestamp_millis()
+                }
+            }
+            chrono::LocalResult::None => {
+                let before_gap = naive.and_utc() - chrono::Duration::days(1);
+                let offset = self
+                    .timezone
+                    .offset_from_utc_datetime(&before_gap.naive_utc())
+                    .fix()
+                    .local_minus_utc() as i64;
+                naive.and_utc().timestamp_millis() - offset * 1000
+            }
+        }
+    }
+}
+
+/// What ORDER BY sorts rows by
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum OrderKey {
+    /// Row timestamp
+    #[default]
+    Time,
+    /// Value of the first column
+    Value,
+    /// Value of a column, by its name or alias
+    Column(String),
+}
+
+/// ORDER BY clause
+///
+/// Ordering applies within each GROUP BY tag series; rows without a value
+/// to sort by come last.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OrderBy {
+    /// Sort key
+    pub key: OrderKey,
+    /// Largest (or latest) first
+    pub descending: bool,
+}
+
+impl OrderBy {
+    /// Ascending order by the given key
+    pub fn new(key: OrderKey) -> Self {
+        Self {
+            key,
+            descending: false,
+        }
+    }
+
+    /// Latest rows first
+    pub fn time_desc() -> Self {
+        Self::new(OrderKey::Time).desc()
+    }
+
+    /// Reverse the order
+    pub fn desc(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+}
+
+impl std::str::FromStr for OrderBy {
+    type Err = String;
+
+    /// Parse `time`, `value` or a column name, optionally followed by `asc` or `desc`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let key = match parts.next() {
+            Some(key) if key.eq_ignore_ascii_case("time") => OrderKey::Time,
+            Some(key) if key.eq_ignore_ascii_case("value") => OrderKey::Value,
+            Some(column) => OrderKey::Column(column.to_string()),
+            None => return Err("Order cannot be empty".to_string()),
+        };
+        let descending = match parts.next().map(|d| d.to_lowercase()) {
+            None => false,
+            Some(d) if d == "asc" => false,
+            Some(d) if d == "desc" => true,
+            Some(_) => {
+                return Err(format!(
+                    "Invalid order: {}. Use time, value or a column, then asc or desc",
+                    s
+                ))
+            }
+        };
+        if parts.next().is_some() {
+            return Err(format!("Invalid order: {}", s));
+        }
+        Ok(Self { key, descending })
+    }
+}
+
+/// How GROUP BY buckets without points are filled
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FillMode {
+    /// Omit empty buckets
+    #[default]
+    None,
+    /// Emit empty buckets without values
+    Null,
+    /// Repeat the value of the previous bucket
+    Previous,
+    /// Interpolate between the surrounding buckets (edges stay empty)
+    Linear,
+    /// Use a constant value
+    Value(f64),
+}
+
+impl std::str::FromStr for FillMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "null" => Ok(Self::Null),
+            "previous" => Ok(Self::Previous),
+            "linear" => Ok(Self::Linear),
+            other => other
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .map(Self::Value)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid fill: {}. Use none, null, previous, linear, or a number",
+                        s
+                    )
+                }),
+        }
     }
 }
 
@@ -300,78 +1155,87 @@ pub enum GroupByInterval {
     Week,
     /// Group by calendar month
     Month,
+    /// Group by a fixed interval in milliseconds (e.g. 15 minutes)
+    Every(i64),
 }
 
 impl GroupByInterval {
+    /// Fixed interval of `interval_ms` milliseconds
+    ///
+    /// One hour, day and week map to their calendar variants.
+    pub fn every(interval_ms: i64) -> Self {
+        assert!(interval_ms > 0, "GroupByInterval: interval must be positive");
+        match interval_ms {
+            HOUR_MS => Self::Hour,
+            DAY_MS => Self::Day,
+            WEEK_MS => Self::Week,
+            _ => Self::Every(interval_ms),
+        }
+    }
+
     /// Truncate a timestamp to the start of this interval
     ///
     /// # Arguments
     /// * `timestamp` - Unix timestamp in milliseconds
     ///
     /// # Returns
-    /// The timestamp truncated to the start of the interval
+    /// The timestamp truncated to the start of the interval (in UTC; use
+    /// `GroupByClause` for other time zones)
     pub fn truncate(&self, timestamp: i64) -> i64 {
-        let dt = match Utc.timestamp_millis_opt(timestamp) {
-            chrono::LocalResult::Single(dt) => dt,
-            _ => return timestamp,
-        };
-
-        let truncated = match self {
-            Self::Hour => dt
-                .with_minute(0)
-                .and_then(|d| d.with_second(0))
-                .and_then(|d| d.with_nanosecond(0))
-                .unwrap_or(dt),
-            Self::Day => dt
-                .with_hour(0)
-                .and_then(|d| d.with_minute(0))
-                .and_then(|d| d.with_second(0))
-                .and_then(|d| d.with_nanosecond(0))
-                .unwrap_or(dt),
-            Self::Week => {
-                let days_since_monday = dt.weekday().num_days_from_monday() as i64;
-                let monday = dt - chrono::Duration::days(days_since_monday);
-                monday
-                    .with_hour(0)
-                    .and_then(|d| d.with_minute(0))
-                    .and_then(|d| d.with_second(0))
-                    .and_then(|d| d.with_nanosecond(0))
-                    .unwrap_or(monday)
-            }
-            Self::Month => dt
-                .with_day(1)
-                .and_then(|d| d.with_hour(0))
-                .and_then(|d| d.with_minute(0))
-                .and_then(|d| d.with_second(0))
-                .and_then(|d| d.with_nanosecond(0))
-                .unwrap_or(dt),
-        };
-
-        truncated.timestamp_millis()
+        GroupByClause::new(*self).bucket_start(timestamp)
     }
 
-    /// Parse from string
+    /// Parse from string (a name like `day` or a duration like `15m`)
     pub fn from_str(s: &str) -> Option<Self> {
         match s.to_lowercase().as_str() {
             "hour" | "h" => Some(Self::Hour),
             "day" | "d" => Some(Self::Day),
             "week" | "w" => Some(Self::Week),
             "month" | "m" => Some(Self::Month),
-            _ => None,
+            other => {
+                let unit_start = other.find(|c: char| !c.is_ascii_digit())?;
+                let (count, unit) = other.split_at(unit_start);
+                let count: i64 = count.parse().ok()?;
+                let unit_ms = match unit {
+                    "s" => 1000,
+                    "m" => 60 * 1000,
+                    "h" => HOUR_MS,
+                    "d" => DAY_MS,
+                    "w" => WEEK_MS,
+                    _ => return None,
+                };
+                (count > 0).then_some(duration_ms(count, unit_ms)?).map(Self::every)
+            }
         }
     }
 
     /// Get the duration in milliseconds (approximate for variable intervals)
     pub fn approx_duration_ms(&self) -> i64 {
         match self {
-            Self::Hour => 3600 * 1000,
-            Self::Day => 24 * 3600 * 1000,
-            Self::Week => 7 * 24 * 3600 * 1000,
-            Self::Month => 30 * 24 * 3600 * 1000,
+            Self::Hour => HOUR_MS,
+            Self::Day => DAY_MS,
+            Self::Week => WEEK_MS,
+            Self::Month => 30 * DAY_MS,
+            Self::Every(interval_ms) => *interval_ms,
         }
     }
 }
 
+const HOUR_MS: i64 = 3600 * 1000;
+const DAY_MS: i64 = 24 * HOUR_MS;
+const WEEK_MS: i64 = 7 * DAY_MS;
+
+/// Longest duration accepted for intervals and offsets (10 years)
+pub(crate) const MAX_DURATION_MS: i64 = 3653 * DAY_MS;
+
+/// `count` units of `unit_ms`, if that neither overflows nor exceeds
+/// `MAX_DURATION_MS`
+pub(crate) fn duration_ms(count: i64, unit_ms: i64) -> Option<i64> {
+    count
+        .checked_mul(unit_ms)
+        .filter(|duration| (0..=MAX_DURATION_MS).contains(duration))
+}
+
 impl std::fmt::Display for GroupByInterval {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -379,18 +1243,134 @@ impl std::fmt::Display for GroupByInterval {
             Self::Day => write!(f, "day"),
             Self::Week => write!(f, "week"),
             Self::Month => write!(f, "month"),
+            Self::Every(interval_ms) => write!(f, "{}", format_duration_ms(*interval_ms)),
         }
     }
 }
 
+/// Format a duration with the largest unit that divides it (e.g. `15m`)
+fn format_duration_ms(duration_ms: i64) -> String {
+    let (count, unit) = [(DAY_MS, "d"), (HOUR_MS, "h"), (60 * 1000, "m"), (1000, "s")]
+        .into_iter()
+        .find(|(unit_ms, _)| duration_ms % unit_ms == 0)
+        .map(|(unit_ms, unit)| (duration_ms / unit_ms, unit))
+        .unwrap_or((duration_ms, "ms"));
+    format!("{}{}", count, unit)
+}
+
+/// A query whose results are stored in a metric
+#[derive(Debug, Clone)]
+pub struct ContinuousQuery {
+    /// Name of the metric holding the results
+    pub name: String,
+    /// The SELECT statement, as written
+    pub text: String,
+    /// The parsed SELECT statement
+    pub query: Query,
+}
+
+impl ContinuousQuery {
+    /// Check a parsed definition
+    ///
+    /// The query must group by time and select exactly one column, without
+    /// window functions, TOP, LIMIT, OFFSET or FILL(previous|linear), which
+    /// depend on rows outside the buckets being refreshed. Time conditions
+    /// are rejected by `parse_continuous_definition`, as the parsed query
+    /// no longer tells them apart from the default range.
+    pub fn new(
+        name: impl Into<String>,
+        text: impl Into<String>,
+        query: Query,
+    ) -> QueryResult<Self> {
+        validate_continuous(&query)?;
+        Ok(Self {
+            name: name.into(),
+            text: text.into(),
+            query,
+        })
+    }
+
+    /// The query and its subqueries, outermost first
+    pub(crate) fn levels(&self) -> impl Iterator<Item = &Query> {
+        std::iter::successors(Some(&self.query), |query| query.from.as_deref())
+    }
+
+    /// Whole buckets, at every level of the query, covering the points
+    /// from `min_timestamp` to `max_timestamp` (inclusive)
+    pub fn refresh_range(&self, min_timestamp: i64, max_timestamp: i64) -> TimeRange {
+        let clauses: Vec<&GroupByClause> =
+            self.levels().filter_map(|query| query.group_by.as_ref()).collect();
+
+        let (mut start, mut end) = (min_timestamp, max_timestamp.saturating_add(1));
+        loop {
+            let previous = (start, end);
+            for group_by in &clauses {
+                start = start.min(group_by.bucket_start(start));
+                end = end.max(group_by.next_bucket_start(group_by.bucket_start(end - 1)));
+            }
+            if (start, end) == previous {
+                return TimeRange::new(start, end);
+            }
+        }
+    }
+
+    /// The query restricted to `range`, subqueries included
+    pub fn covering(&self, range: TimeRange) -> Query {
+        fn with_range(mut query: Query, range: TimeRange) -> Query {
+            query.time_range = range;
+            query.from = query.from.map(|subquery| Box::new(with_range(*subquery, range)));
+            query
+        }
+        with_range(self.query.clone(), range)
+    }
+}
+
+/// Reject queries whose buckets can't be recomputed on their own
+fn validate_continuous(query: &Query) -> QueryResult<()> {
+    let invalid = |reason: &str| Err(QueryError::InvalidContinuousQuery(reason.to_string()));
+
+    if query.explain.is_some() {
+        return invalid("EXPLAIN can't be stored");
+    }
+    if query.group_by.is_none() {
+        return invalid("a continuous query needs a GROUP BY interval");
+    }
+    if query.select.len() != 1 || query.select[0].metric == "*" {
+        return invalid("a continuous query must select exactly one column");
+    }
+
+    let mut level = Some(query);
+    while let Some(query) = level {
+        if query.select.iter().any(|item| item.window.is_some()) {
+            return invalid("window functions are not supported");
+        }
+        if query.select.iter().any(|item| item.top.is_some()) {
+            return invalid("TOP is not supported");
+        }
+        if query.limit.is_some() || query.offset.is_some() {
+            return invalid("LIMIT and OFFSET are not supported");
+        }
+        let fill = query.group_by.as_ref().map(|group_by| &group_by.fill);
+        if matches!(fill, Some(FillMode::Previous | FillMode::Linear)) {
+            return invalid("FILL(previous) and FILL(linear) are not supported");
+        }
+        level = query.from.as_deref();
+    }
+    Ok(())
+}
+
 /// Builder for constructing queries programmatically
 #[derive(Debug, Clone)]
 pub struct QueryBuilder {
     select: Vec<SelectItem>,
+    from: Option<Query>,
     time_range: Option<TimeRange>,
-    filters: Vec<Filter>,
+    filters: Vec<FilterExpr>,
     group_by: Option<GroupByClause>,
+    order_by: OrderBy,
     limit: Option<usize>,
+    offset: Option<usize>,
+    explain: Option<ExplainMode>,
 }
 
 impl QueryBuilder {
@@ -401,13 +1381,26 @@ impl QueryBuilder {
                 .iter()
                 .map(|m| SelectItem::new(*m))
                 .collect(),
+            from: None,
             time_range: None,
             filters: Vec::new(),
             group_by: None,
+            order_by: OrderBy::default(),
             limit: None,
+            offset: None,
+            explain: None,
         }
     }
 
+    /// Select from the result of a subquery rather than from stored metrics
+    ///
+    /// Its columns are selected like metrics, and its time range is used
+    /// unless another one is set.
+    pub fn from(mut self, subquery: Query) -> Self {
+        self.from = Some(subquery);
+        self
+    }
+
     /// Set an explicit time range
     pub fn time_range(mut self, range: TimeRange) -> Self {
         self.time_range = Some(range);
@@ -435,6 +1428,12 @@ impl QueryBuilder {
         self
     }
 
+    /// Add a GROUP BY clause with a time zone or offset
+    pub fn group_by_clause(mut self, clause: GroupByClause) -> Self {
+        self.group_by = Some(clause);
+        self
+    }
+
     /// Apply an aggregation to all select items
     pub fn with_aggregation(mut self, agg: AggregationFunc) -> Self {
         for item in &mut self.select {
@@ -443,9 +1442,9 @@ impl QueryBuilder {
         self
     }
 
-    /// Add a filter
-    pub fn filter(mut self, filter: Filter) -> Self {
-        self.filters.push(filter);
+    /// Add a filter (all filters must match)
+    pub fn filter(mut self, filter: impl Into<FilterExpr>) -> Self {
+        self.filters.push(filter.into());
         self
     }
 
@@ -459,20 +1458,52 @@ impl QueryBuilder {
         self.filter(Filter::value(op, value))
     }
 
+    /// Add a select item, e.g. an expression
+    pub fn item(mut self, item: SelectItem) -> Self {
+        self.select.push(item);
+        self
+    }
+
+    /// Set the row order
+    pub fn order_by(mut self, order: OrderBy) -> Self {
+        self.order_by = order;
+        self
+    }
+
     /// Set a limit on results
     pub fn limit(mut self, n: usize) -> Self {
         self.limit = Some(n);
         self
     }
 
+    /// Skip the first n rows (of each series)
+    pub fn offset(mut self, n: usize) -> Self {
+        self.offset = Some(n);
+        self
+    }
+
+    /// Return the execution plan instead of rows
+    pub fn explain(mut self, mode: ExplainMode) -> Self {
+        self.explain = Some(mode);
+        self
+    }
+
     /// Build the query
     pub fn build(self) -> Query {
+        let default_range = match self.from {
+            Some(ref subquery) => subquery.time_range,
+            None => TimeRange::last_days(7),
+        };
         Query {
             select: self.select,
-            time_range: self.time_range.unwrap_or_else(|| TimeRange::last_days(7)),
+            from: self.from.map(Box::new),
+            time_range: self.time_range.unwrap_or(default_range),
             filters: self.filters,
             group_by: self.group_by,
+            order_by: self.order_by,
             limit: self.limit,
+            offset: self.offset,
+            explain: self.explain,
         }
     }
 }
@@ -480,6 +1511,7 @@ impl QueryBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::Utc;
 
     #[test]
     fn test_query_builder_basic() {
@@ -580,6 +1612,112 @@ mod tests {
         assert_eq!(truncated, expected);
     }
 
+    fn utc_ms(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> i64 {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn test_group_by_fixed_interval_and_offset() {
+        let every_15m = GroupByClause::new(GroupByInterval::from_str("15m").unwrap());
+        assert_eq!(every_15m.interval, GroupByInterval::Every(15 * 60 * 1000));
+        assert_eq!(
+            every_15m.bucket_start(utc_ms(2024, 1, 15, 10, 7)),
+            utc_ms(2024, 1, 15, 10, 0)
+        );
+        assert_eq!(
+            every_15m.next_bucket_start(utc_ms(2024, 1, 15, 10, 0)),
+            utc_ms(2024, 1, 15, 10, 15)
+        );
+
+        // Days starting at 04:00
+        let shifted = GroupByClause::new(GroupByInterval::Day).offset(4 * 3600 * 1000);
+        assert_eq!(
+            shifted.bucket_start(utc_ms(2024, 1, 15, 3, 0)),
+            utc_ms(2024, 1, 14, 4, 0)
+        );
+
+        let monthly = GroupByClause::new(GroupByInterval::Month);
+        assert_eq!(
+            monthly.next_bucket_start(utc_ms(2024, 1, 1, 0, 0)),
+            utc_ms(2024, 2, 1, 0, 0)
+        );
+        assert_eq!(
+            monthly.next_bucket_start(utc_ms(2024, 2, 1, 0, 0)),
+            utc_ms(2024, 3, 1, 0, 0)
+        );
+        assert_eq!(GroupByInterval::Every(6 * 3600 * 1000).to_string(), "6h");
+
+        // Intervals that overflow or exceed 10 years are rejected, and
+        // stepping near the end of time doesn't overflow
+        assert_eq!(GroupByInterval::from_str("9999999999999999w"), None);
+        assert_eq!(GroupByInterval::from_str("3660d"), None);
+        assert!(GroupByInterval::from_str("520w").is_some());
+        let weekly = GroupByClause::new(GroupByInterval::from_str("2w").unwrap());
+        assert!(weekly.next_bucket_start(i64::MAX - 1000) > i64::MAX - 1000);
+    }
+
+    #[test]
+    fn test_order_by_from_str() {
+        assert_eq!("time".parse::<OrderBy>(), Ok(OrderBy::default()));
+        assert_eq!("TIME DESC".parse::<OrderBy>(), Ok(OrderBy::time_desc()));
+        assert_eq!(
+            "value desc".parse::<OrderBy>(),
+            Ok(OrderBy::new(OrderKey::Value).desc())
+        );
+        assert_eq!(
+            "steps asc".parse::<OrderBy>(),
+            Ok(OrderBy::new(OrderKey::Column("steps".to_string())))
+        );
+        assert!("".parse::<OrderBy>().is_err());
+        assert!("time down".parse::<OrderBy>().is_err());
+    }
+
+    #[test]
+    fn test_fill_mode_from_str() {
+        assert_eq!("LINEAR".parse::<FillMode>(), Ok(FillMode::Linear));
+        assert_eq!("none".parse::<FillMode>(), Ok(FillMode::None));
+        assert_eq!("2.5".parse::<FillMode>(), Ok(FillMode::Value(2.5)));
+        assert!("nan".parse::<FillMode>().is_err());
+        assert!("sideways".parse::<FillMode>().is_err());
+    }
+
+    #[test]
+    fn test_group_by_timezone_alignment() {
+        let berlin = GroupByClause::new(GroupByInterval::Day).timezone(chrono_tz::Europe::Berlin);
+
+        // 00:30 local on Jan 16 belongs to Jan 16, which starts 23:00 UTC
+        assert_eq!(
+            berlin.bucket_start(utc_ms(2024, 1, 15, 23, 30)),
+            utc_ms(2024, 1, 15, 23, 0)
+        );
+
+        // DST starts on 2024-03-31 (23h day) and ends on 2024-10-27 (25h day)
+        let spring = berlin.bucket_start(utc_ms(2024, 3, 31, 12, 0));
+        assert_eq!(spring, utc_ms(2024, 3, 30, 23, 0));
+        assert_eq!(berlin.next_bucket_start(spring), utc_ms(2024, 3, 31, 22, 0));
+        let autumn = berlin.bucket_start(utc_ms(2024, 10, 27, 12, 0));
+        assert_eq!(autumn, utc_ms(2024, 10, 26, 22, 0));
+        assert_eq!(berlin.next_bucket_start(autumn), utc_ms(2024, 10, 27, 23, 0));
+
+        // The repeated 02:00 hour is two separate buckets
+        let hourly = GroupByClause::new(GroupByInterval::Hour).timezone(chrono_tz::Europe::Berlin);
+        assert_eq!(
+            hourly.bucket_start(utc_ms(2024, 10, 27, 0, 30)),
+            utc_ms(2024, 10, 27, 0, 0)
+        );
+        assert_eq!(
+            hourly.bucket_start(utc_ms(2024, 10, 27, 1, 30)),
+            utc_ms(2024, 10, 27, 1, 0)
+        );
+
+        // Weeks start at local Monday midnight
+        let weekly = GroupByClause::new(GroupByInterval::Week).timezone(chrono_tz::Europe::Berlin);
+        assert_eq!(
+            weekly.bucket_start(utc_ms(2024, 1, 14, 23, 30)),
+            utc_ms(2024, 1, 14, 23, 0)
+        );
+    }
+
     #[test]
     fn test_aggregation_functions() {
         let values = vec![1.0, 2.0, 3.0, 4.0, 5.0];
@@ -591,12 +1729,112 @@ mod tests {
         assert_eq!(AggregationFunc::Count.apply(&values), Some(5.0));
         assert_eq!(AggregationFunc::First.apply(&values), Some(1.0));
         assert_eq!(AggregationFunc::Last.apply(&values), Some(5.0));
+        assert_eq!(AggregationFunc::Median.apply(&values), Some(3.0));
+        assert_eq!(AggregationFunc::Percentile(0.75).apply(&values), Some(4.0));
+        assert_eq!(AggregationFunc::Variance.apply(&values), Some(2.5));
+        assert_eq!(AggregationFunc::Spread.apply(&values), Some(4.0));
+        assert_eq!(AggregationFunc::CountDistinct.apply(&[1.0, 1.0, 2.0]), Some(2.0));
 
         // Empty slice
         let empty: Vec<f64> = vec![];
         assert_eq!(AggregationFunc::Avg.apply(&empty), None);
     }
 
+    #[test]
+    fn test_aggregation_from_str() {
+        assert_eq!(AggregationFunc::from_str("MEDIAN"), Some(AggregationFunc::Median));
+        assert_eq!(
+            AggregationFunc::from_str("count_distinct"),
+            Some(AggregationFunc::CountDistinct)
+        );
+        assert_eq!(AggregationFunc::from_str("p95"), Some(AggregationFunc::Percentile(0.95)));
+        assert_eq!(AggregationFunc::from_str("p150"), None);
+        assert_eq!(AggregationFunc::from_str("pie"), None);
+    }
+
+    #[test]
+    fn test_aggregation_serde() {
+        let parse = |json: &str| serde_json::from_str::<AggregationFunc>(json).unwrap();
+        assert_eq!(parse(r#""avg""#), AggregationFunc::Avg);
+        assert_eq!(parse(r#""countdistinct""#), AggregationFunc::CountDistinct);
+        assert_eq!(parse(r#""count_distinct""#), AggregationFunc::CountDistinct);
+        assert_eq!(parse(r#"{"percentile":0.95}"#), AggregationFunc::Percentile(0.95));
+
+        let p95 = serde_json::to_string(&AggregationFunc::Percentile(0.95)).unwrap();
+        assert_eq!(p95, r#"{"percentile":0.95}"#);
+        assert_eq!(serde_json::to_string(&AggregationFunc::Max).unwrap(), r#""max""#);
+    }
+
+    #[test]
+    fn test_select_expr_evaluate() {
+        // (a - b) * 2 with a = 5, b = 3
+        let expr = SelectExpr::binary(
+            SelectExpr::binary(
+                SelectExpr::Metric("a".to_string()),
+                ArithOp::Sub,
+                SelectExpr::Metric("b".to_string()),
+            ),
+            ArithOp::Mul,
+            SelectExpr::Number(2.0),
+        );
+        assert_eq!(expr.to_string(), "(a - b) * 2");
+        assert_eq!(expr.metrics(), vec!["a", "b"]);
+
+        let mut lookup = |_: Option<AggregationFunc>, metric: &str| match metric {
+            "a" => Some(5.0),
+            "b" => Some(3.0),
+            _ => None,
+        };
+        assert_eq!(expr.evaluate(&mut lookup), Some(4.0));
+
+        let missing = SelectExpr::binary(
+            SelectExpr::Metric("a".to_string()),
+            ArithOp::Add,
+            SelectExpr::Metric("c".to_string()),
+        );
+        assert_eq!(missing.evaluate(&mut lookup), None);
+
+        let divide_by_zero = SelectExpr::binary(
+            SelectExpr::Metric("a".to_string()),
+            ArithOp::Div,
+            SelectExpr::Number(0.0),
+        );
+        assert_eq!(divide_by_zero.evaluate(&mut lookup), None);
+    }
+
+    #[test]
+    fn test_window_functions() {
+        let day = DAY_MS;
+        let values = [(0, 70.0), (day, 71.0), (3 * day, 70.0), (4 * day, 72.0)];
+
+        assert_eq!(
+            WindowFunc::MovingAvg(2).apply(&values),
+            vec![None, Some(70.5), Some(70.5), Some(71.0)]
+        );
+        assert_eq!(
+            WindowFunc::Derivative(day).apply(&values),
+            vec![None, Some(1.0), Some(-0.5), Some(2.0)]
+        );
+        assert_eq!(
+            WindowFunc::Difference.apply(&values),
+            vec![None, Some(1.0), Some(-1.0), Some(2.0)]
+        );
+        assert_eq!(
+            WindowFunc::CumulativeSum.apply(&values),
+            vec![Some(70.0), Some(141.0), Some(211.0), Some(283.0)]
+        );
+        assert_eq!(
+            WindowFunc::Ewma(0.5).apply(&values),
+            vec![Some(70.0), Some(70.5), Some(70.25), Some(71.125)]
+        );
+        assert!(WindowFunc::Difference.apply(&[]).is_empty());
+
+        let item = SelectItem::new("weight")
+            .with_aggregation(AggregationFunc::Avg)
+            .with_window(WindowFunc::Derivative(day));
+        assert_eq!(item.display_name(), "DERIVATIVE(AVG(weight), 1d)");
+    }
+
     #[test]
     fn test_operator_compare() {
         assert!(Operator::Eq.compare_f64(5.0, 5.0));
@@ -621,4 +1859,55 @@ mod tests {
 
         assert!(Operator::Ne.compare_str("hello", "world"));
     }
+
+    #[test]
+    fn test_filter_expr_matches() {
+        let home = DataPoint::with_timestamp(1, 3.0, 0).tag("location", "home");
+        let cafe = DataPoint::with_timestamp(1, 8.0, 0).tag("location", "cafe");
+        let untagged = DataPoint::with_timestamp(1, 8.0, 0);
+
+        let in_list = FilterExpr::tag_in("location", ["office", "cafe"]);
+        assert!(!in_list.matches(&home));
+        assert!(in_list.matches(&cafe));
+        assert!(!in_list.matches(&untagged));
+        assert!(in_list.clone().negate().matches(&untagged));
+
+        let expr = FilterExpr::Or(vec![
+            Filter::tag("location", Operator::Eq, "home").into(),
+            FilterExpr::And(vec![
+                Filter::value(Operator::Gt, 5.0).into(),
+                in_list.negate(),
+            ]),
+        ]);
+        assert!(expr.matches(&home));
+        assert!(!expr.matches(&cafe));
+        assert!(expr.matches(&untagged));
+        assert_eq!(
+            expr.to_string(),
+            "tags.location = 'home' OR value > 5 AND NOT tags.location IN ('office', 'cafe')"
+        );
+        let and = FilterExpr::And(vec![expr.clone(), Filter::value(Operator::Lt, 9.0).into()]);
+        assert_eq!(and.to_string(), format!("({}) AND value < 9", expr));
+    }
+
+    #[test]
+    fn test_required_tag_values() {
+        let or = FilterExpr::Or(vec![
+            Filter::tag("location", Operator::Eq, "office").into(),
+            FilterExpr::tag_in("location", ["cafe", "home"]),
+        ]);
+        assert_eq!(
+            or.required_tag_values(),
+            Some(("location", vec!["office", "cafe", "home"]))
+        );
+
+        // Different keys, negations and value filters imply nothing
+        let mixed = FilterExpr::Or(vec![
+            Filter::tag("location", Operator::Eq, "office").into(),
+            Filter::tag("source", Operator::Eq, "manual").into(),
+        ]);
+        assert_eq!(mixed.required_tag_values(), None);
+        assert_eq!(FilterExpr::tag_in("location", ["a"]).negate().required_tag_values(), None);
+        assert_eq!(FilterExpr::from(Filter::value(Operator::Gt, 1.0)).required_tag_values(), None);
+    }
 }
@@ -0,0 +1,487 @@
+//! Query Result Cache
+//!
+//! Dashboards issue the same queries over and over, mostly over history
+//! that no longer changes. The cache keeps two kinds of entries:
+//!
+//! - **Results** of queries that don't group by time, keyed by the whole
+//!   normalized query (time range included)
+//! - **Buckets** of GROUP BY queries: the aggregated rows of every bucket
+//!   that lay completely inside a query's range, keyed by the query without
+//!   its time range and FILL mode. A later query over a shifted range (such
+//!   as `now() - 7d`) reads only the buckets that aren't cached, typically
+//!   the two cut by its range.
+//!
+//! Entries are dropped when the storage engine reports a `DataChange`:
+//! writes drop the results and buckets overlapping the written range of the
+//! written metrics, anything else clears the cache. A query that raced with
+//! a change doesn't store what it read. The cache holds at most
+//! `max_rows` rows (an empty bucket counts as one) and evicts the least
+//! recently used entries beyond that.
+//!
+//! EXPLAIN, `SELECT *` and FROM subqueries are never cached.
+
+use crate::query::ast::*;
+use crate::query::executor::{QueryResult2, ResultRow};
+use crate::storage::{DataChange, TimeRange};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Most buckets a single query may cache; longer queries bypass the cache
+const MAX_QUERY_BUCKETS: usize = 10_000;
+
+/// Query cache configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryCacheConfig {
+    /// Rows kept across all entries (default: 100,000)
+    pub max_rows: usize,
+}
+
+impl Default for QueryCacheConfig {
+    fn default() -> Self {
+        Self { max_rows: 100_000 }
+    }
+}
+
+impl QueryCacheConfig {
+    /// Cache up to `max_rows` rows
+    pub fn new(max_rows: usize) -> Self {
+        Self { max_rows }
+    }
+}
+
+/// Cache counters since startup, and its current size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    /// Queries answered from the cache alone
+    pub hits: u64,
+    /// GROUP BY queries that read some of their buckets
+    pub partial_hits: u64,
+    /// Queries that read everything
+    pub misses: u64,
+    /// Entries dropped to stay within `max_rows`
+    pub evictions: u64,
+    /// Entries dropped or trimmed because their data changed
+    pub invalidations: u64,
+    /// Cached entries
+    pub entries: usize,
+    /// Cached rows
+    pub rows: usize,
+}
+
+/// Cache of query results and GROUP BY buckets
+pub struct QueryCache {
+    config: QueryCacheConfig,
+    /// Bumped by every invalidation
+    generation: AtomicU64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// Logical clock for least-recently-used eviction
+    clock: u64,
+    stats: CacheStats,
+}
+
+struct Entry {
+    /// Metrics the query read
+    metric_ids: Vec<u32>,
+    last_used: u64,
+    /// Rows held, empty buckets counting as one
+    rows: usize,
+    data: EntryData,
+}
+
+enum EntryData {
+    Result {
+        range: TimeRange,
+        result: QueryResult2,
+    },
+    Buckets {
+        group_by: GroupByClause,
+        /// Rows of each complete bucket, by bucket start
+        buckets: BTreeMap<i64, Vec<ResultRow>>,
+    },
+}
+
+impl Entry {
+    fn count_rows(&self) -> usize {
+        match &self.data {
+            EntryData::Result { result, .. } => result.rows.len().max(1),
+            EntryData::Buckets { buckets, .. } => buckets.values().map(|r| r.len().max(1)).sum(),
+        }
+    }
+
+    /// Drop what a write to `[min, max]` may have changed; false if nothing is left
+    fn invalidate(&mut self, min: i64, max: i64) -> bool {
+        match &mut self.data {
+            EntryData::Result { range, .. } => !(range.start <= max && min < range.end),
+            EntryData::Buckets { group_by, buckets } => {
+                let first = group_by.bucket_start(min);
+                let stale: Vec<i64> = buckets.range(first..=max).map(|(b, _)| *b).collect();
+                for bucket in stale {
+                    buckets.remove(&bucket);
+                }
+                !buckets.is_empty()
+            }
+        }
+    }
+}
+
+impl QueryCache {
+    /// Create an empty cache
+    pub fn new(config: QueryCacheConfig) -> Self {
+        Self {
+            config,
+            generation: AtomicU64::new(0),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Current counters and size
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Drop the entries a change to stored data may have made stale
+    pub fn invalidate(&self, change: &DataChange) {
+        let mut state = self.state.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        let state = &mut *state;
+        match change {
+            DataChange::Written {
+                metric_ids,
+                min_timestamp,
+                max_timestamp,
+            } => {
+                let mut dropped = Vec::new();
+                for (key, entry) in state.entries.iter_mut() {
+                    if !entry.metric_ids.iter().any(|id| metric_ids.contains(id)) {
+                        continue;
+                    }
+                    let before = entry.rows;
+                    let kept = entry.invalidate(*min_timestamp, *max_timestamp);
+                    entry.rows = entry.count_rows();
+                    if !kept {
+                        dropped.push(key.clone());
+                    } else if entry.rows == before {
+                        continue;
+                    }
+                    state.stats.invalidations += 1;
+                    state.stats.rows -= before - entry.rows;
+                }
+                for key in dropped {
+                    if let Some(entry) = state.entries.remove(&key) {
+                        state.stats.rows -= entry.rows;
+                    }
+                }
+            }
+            DataChange::Reset => {
+                state.stats.invalidations += state.entries.len() as u64;
+                state.entries.clear();
+                state.stats.rows = 0;
+            }
+        }
+        state.stats.entries = state.entries.len();
+    }
+
+    /// Whether a query's results may be cached
+    pub(crate) fn accepts(query: &Query) -> bool {
+        query.explain.is_none()
+            && query.from.is_none()
+            && !query.select.iter().any(|item| item.metric == "*")
+    }
+
+    /// Key of a query's whole result
+    pub(crate) fn result_key(query: &Query) -> String {
+        format!("result {:?}", query)
+    }
+
+    /// Key of a GROUP BY query's buckets: the query without its time range
+    /// and FILL mode, and without the ordering and paging applied afterwards
+    pub(crate) fn bucket_key(query: &Query) -> String {
+        let group_by = query.group_by.clone().map(|group_by| group_by.fill(FillMode::None));
+        format!("buckets {:?} {:?} {:?}", query.select, query.filters, group_by)
+    }
+
+    /// Changes counter; pass it back when inserting what was read after it
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Starts of the buckets overlapping `range` (None if there are too many)
+    pub(crate) fn bucket_starts(group_by: &GroupByClause, range: TimeRange) -> Option<Vec<i64>> {
+        let mut buckets = Vec::new();
+        let mut bucket = group_by.bucket_start(range.start);
+        while bucket < range.end {
+            if buckets.len() == MAX_QUERY_BUCKETS {
+                return None;
+            }
+            buckets.push(bucket);
+            bucket = group_by.next_bucket_start(bucket);
+        }
+        Some(buckets)
+    }
+
+    /// Runs of consecutive buckets as `[start, end)` ranges
+    pub(crate) fn spans(group_by: &GroupByClause, buckets: &[i64]) -> Vec<(i64, i64)> {
+        let mut spans: Vec<(i64, i64)> = Vec::new();
+        for &bucket in buckets {
+            let end = group_by.next_bucket_start(bucket);
+            match spans.last_mut() {
+                Some(span) if span.1 == bucket => span.1 = end,
+                _ => spans.push((bucket, end)),
+            }
+        }
+        spans
+    }
+
+    /// A cached result
+    pub(crate) fn result(&self, key: &str) -> Option<QueryResult2> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let result = match state.entries.get_mut(key) {
+            Some(Entry {
+                last_used,
+                data: EntryData::Result { result, .. },
+                ..
+            }) => {
+                *last_used = clock;
+                Some(result.clone())
+            }
+            _ => None,
+        };
+        match result {
+            Some(_) => state.stats.hits += 1,
+            None => state.stats.misses += 1,
+        }
+        result
+    }
+
+    /// Cache a result read since `generation`
+    pub(crate) fn insert_result(
+        &self,
+        key: String,
+        generation: u64,
+        metric_ids: Vec<u32>,
+        range: TimeRange,
+        result: &QueryResult2,
+    ) {
+        let data = EntryData::Result {
+            range,
+            result: result.clone(),
+        };
+        self.insert(key, generation, metric_ids, data);
+    }
+
+    /// Rows of the cached buckets among `buckets`, and the missing buckets
+    pub(crate) fn buckets(&self, key: &str, buckets: &[i64]) -> (Vec<ResultRow>, Vec<i64>) {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let mut rows = Vec::new();
+        let mut missing = Vec::new();
+        match state.entries.get_mut(key) {
+            Some(Entry {
+                last_used,
+                data: EntryData::Buckets { buckets: cached, .. },
+                ..
+            }) => {
+                *last_used = clock;
+                for bucket in buckets {
+                    match cached.get(bucket) {
+                        Some(bucket_rows) => rows.extend(bucket_rows.iter().cloned()),
+                        None => missing.push(*bucket),
+                    }
+                }
+            }
+            _ => missing = buckets.to_vec(),
+        }
+
+        if missing.is_empty() {
+            state.stats.hits += 1;
+        } else if missing.len() < buckets.len() {
+            state.stats.partial_hits += 1;
+        } else {
+            state.stats.misses += 1;
+        }
+        (rows, missing)
+    }
+
+    /// Cache the `complete` buckets among `rows`, read since `generation`
+    pub(crate) fn insert_buckets(
+        &self,
+        key: &str,
+        generation: u64,
+        group_by: &GroupByClause,
+        metric_ids: Vec<u32>,
+        complete: &[i64],
+        rows: &[ResultRow],
+    ) {
+        if complete.is_empty() {
+            return;
+        }
+        let mut buckets: BTreeMap<i64, Vec<ResultRow>> =
+            complete.iter().map(|bucket| (*bucket, Vec::new())).collect();
+        for row in rows {
+            if let Some(bucket_rows) = buckets.get_mut(&row.timestamp) {
+                bucket_rows.push(row.clone());
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if self.generation() != generation {
+            return;
+        }
+
+        // Merge into the buckets cached already
+        if let Some(entry) = state.entries.get_mut(key) {
+            if let EntryData::Buckets { buckets: cached, .. } = &mut entry.data {
+                cached.extend(buckets);
+                let before = entry.rows;
+                entry.rows = entry.count_rows();
+                state.stats.rows += entry.rows - before;
+                self.evict(&mut state);
+                return;
+            }
+        }
+
+        let data = EntryData::Buckets {
+            group_by: group_by.clone(),
+            buckets,
+        };
+        self.store(&mut state, key.to_string(), metric_ids, data);
+    }
+
+    fn insert(&self, key: String, generation: u64, metric_ids: Vec<u32>, data: EntryData) {
+        let mut state = self.state.lock().unwrap();
+        if self.generation() == generation {
+            self.store(&mut state, key, metric_ids, data);
+        }
+    }
+
+    fn store(&self, state: &mut CacheState, key: String, metric_ids: Vec<u32>, data: EntryData) {
+        let mut entry = Entry {
+            metric_ids,
+            last_used: 0,
+            rows: 0,
+            data,
+        };
+        entry.rows = entry.count_rows();
+        if entry.rows > self.config.max_rows {
+            return;
+        }
+
+        state.clock += 1;
+        entry.last_used = state.clock;
+        state.stats.rows += entry.rows;
+        if let Some(replaced) = state.entries.insert(key, entry) {
+            state.stats.rows -= replaced.rows;
+        }
+        self.evict(state);
+    }
+
+    /// Drop the least recently used entries until within `max_rows`
+    fn evict(&self, state: &mut CacheState) {
+        while state.stats.rows > self.config.max_rows {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            let Some(evicted) = oldest.and_then(|key| state.entries.remove(&key)) else {
+                break;
+            };
+            state.stats.rows -= evicted.rows;
+            state.stats.evictions += 1;
+        }
+        state.stats.entries = state.entries.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: i64 = 3600 * 1000;
+
+    fn hourly() -> GroupByClause {
+        GroupByClause::new(GroupByInterval::Hour)
+    }
+
+    fn row(timestamp: i64) -> ResultRow {
+        ResultRow {
+            timestamp,
+            tags: BTreeMap::new(),
+            values: HashMap::from([("steps".to_string(), 1.0)]),
+        }
+    }
+
+    #[test]
+    fn test_bucket_spans() {
+        let buckets =
+            QueryCache::bucket_starts(&hourly(), TimeRange::new(HOUR_MS / 2, 4 * HOUR_MS)).unwrap();
+        assert_eq!(buckets, vec![0, HOUR_MS, 2 * HOUR_MS, 3 * HOUR_MS]);
+
+        let spans = QueryCache::spans(&hourly(), &[0, HOUR_MS, 3 * HOUR_MS]);
+        assert_eq!(spans, vec![(0, 2 * HOUR_MS), (3 * HOUR_MS, 4 * HOUR_MS)]);
+
+        let years = TimeRange::new(0, 5 * 365 * 24 * HOUR_MS);
+        assert!(QueryCache::bucket_starts(&hourly(), years).is_none());
+    }
+
+    #[test]
+    fn test_invalidate_and_evict() {
+        let cache = QueryCache::new(QueryCacheConfig::new(5));
+        let buckets = [0, HOUR_MS, 2 * HOUR_MS];
+        let rows: Vec<ResultRow> = buckets.iter().map(|b| row(*b)).collect();
+        cache.insert_buckets("a", cache.generation(), &hourly(), vec![1], &buckets, &rows);
+
+        let (cached, missing) = cache.buckets("a", &[0, HOUR_MS, 3 * HOUR_MS]);
+        assert_eq!(cached.len(), 2);
+        assert_eq!(missing, vec![3 * HOUR_MS]);
+        assert_eq!(cache.stats().partial_hits, 1);
+
+        // Only the written buckets of the written metrics are dropped
+        let generation = cache.generation();
+        cache.invalidate(&DataChange::Written {
+            metric_ids: vec![2],
+            min_timestamp: 0,
+            max_timestamp: 10,
+        });
+        cache.invalidate(&DataChange::Written {
+            metric_ids: vec![1],
+            min_timestamp: HOUR_MS + 5,
+            max_timestamp: HOUR_MS + 10,
+        });
+        let (_, missing) = cache.buckets("a", &buckets);
+        assert_eq!(missing, vec![HOUR_MS]);
+        assert_eq!(cache.stats().rows, 2);
+
+        // Reads that raced with a write aren't stored
+        cache.insert_buckets("b", generation, &hourly(), vec![1], &buckets, &rows);
+        assert_eq!(cache.stats().entries, 1);
+
+        // Beyond max_rows the least recently used entries go
+        cache.insert_buckets("b", cache.generation(), &hourly(), vec![1], &buckets, &rows);
+        let (_, missing) = cache.buckets("b", &buckets);
+        assert!(missing.is_empty());
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 0);
+        cache.insert_buckets("c", cache.generation(), &hourly(), vec![1], &[0], &rows);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.rows, stats.evictions), (2, 4, 1));
+        assert_eq!(cache.buckets("a", &[0]).1, vec![0]);
+
+        cache.invalidate(&DataChange::Reset);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().rows, 0);
+    }
+}
@@ -0,0 +1,508 @@
+//! Continuous Queries
+//!
+//! A continuous query keeps the results of a grouped query stored in a
+//! metric of its own:
+//!
+//! ```text
+//! CREATE CONTINUOUS QUERY daily_mood AS SELECT AVG(mood) GROUP BY day
+//! ```
+//!
+//! stores one point per day in `daily_mood`, which is then queried like any
+//! other metric. Creating the query backfills it over all existing data.
+//! After that, every block written by a flush recomputes only the buckets
+//! the block touches; the derived metric keeps the last written copy of a
+//! point (`DuplicatePolicy::LastWriteWins`), so a recomputed bucket
+//! replaces its previous value.
+//!
+//! Flushes made while no refresh task runs, such as the WAL recovery on
+//! startup or the last flush before a crash, are caught up on by rebuilding
+//! every query when the task starts. Every run skips the buckets whose
+//! stored point already has the recomputed value, so a rebuild only writes
+//! the buckets that changed.
+//!
+//! The definition is stored on the derived metric
+//! (`Metric::continuous_query`), so deleting the metric drops the query.
+//! Time conditions and FILL(previous) or FILL(linear) are rejected, as each
+//! run covers only the buckets being refreshed. Deleting source points does not recompute the
+//! buckets they were in.
+
+use crate::query::ast::*;
+use crate::query::error::{QueryError, QueryResult};
+use crate::query::executor::QueryExecutor;
+use crate::query::parser::parse_continuous_definition;
+use crate::storage::{
+    AggregationType, DataPoint, DeleteMode, DuplicatePolicy, FlushedBlock, Metric, QueryFilter,
+    TimeRange,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Background task refreshing continuous queries as blocks are flushed
+pub struct ContinuousQueryTask {
+    handle: JoinHandle<()>,
+    stop: Arc<Notify>,
+}
+
+impl ContinuousQueryTask {
+    /// Refresh the flushes announced so far, then stop
+    ///
+    /// Flush the storage engine first so its buffered points are included.
+    pub async fn stop(self) {
+        self.stop.notify_one();
+        if let Err(e) = self.handle.await {
+            if !e.is_cancelled() {
+                tracing::error!("Continuous query task failed: {}", e);
+            }
+        }
+    }
+
+    /// Stop at once, dropping pending refreshes
+    pub fn abort(&self) {
+        self.handle.abort();
+    }
+}
+
+/// The continuous query whose results a metric stores, if any
+fn of_metric(metric: &Metric) -> Option<QueryResult<ContinuousQuery>> {
+    metric
+        .continuous_query
+        .as_ref()
+        .map(|text| parse_continuous_definition(metric.name.clone(), text))
+}
+
+/// How the derived metric combines its points
+fn aggregation_type(func: Option<AggregationFunc>) -> AggregationType {
+    match func {
+        Some(AggregationFunc::Sum) | Some(AggregationFunc::Count) => AggregationType::Sum,
+        Some(AggregationFunc::Max) => AggregationType::Max,
+        Some(AggregationFunc::Min) => AggregationType::Min,
+        Some(AggregationFunc::Last) => AggregationType::Last,
+        _ => AggregationType::Average,
+    }
+}
+
+impl QueryExecutor {
+    /// Register a continuous query and backfill it over all existing data
+    ///
+    /// Returns the number of points written.
+    pub async fn create_continuous_query(&self, cq: &ContinuousQuery) -> QueryResult<usize> {
+        let storage = self.storage();
+        if storage.get_metric(&cq.name).await.is_some() {
+            return Err(QueryError::InvalidContinuousQuery(format!(
+                "a metric named '{}' already exists",
+                cq.name
+            )));
+        }
+
+        // The derived metric takes its unit and category from the first source
+        let mut sources = Vec::new();
+        for name in self.source_metrics(cq).await? {
+            let metric = storage
+                .get_metric(&name)
+                .await
+                .ok_or(QueryError::MetricNotFound(name))?;
+            sources.push(metric);
+        }
+        let Some(source) = sources.first() else {
+            return Err(QueryError::InvalidContinuousQuery(
+                "a continuous query must read at least one metric".to_string(),
+            ));
+        };
+
+        let metric = Metric::new(
+            &cq.name,
+            &source.unit,
+            source.category,
+            aggregation_type(cq.query.select[0].aggregation),
+        )
+        .description(format!("Continuous query: {}", cq.text))
+        .duplicates(DuplicatePolicy::LastWriteWins)
+        .materialized(&cq.text);
+        let id = storage.register_metric(metric).await?;
+
+        // Registering the name of a soft-deleted metric restores that metric
+        let registered = storage.get_metric(&cq.name).await;
+        if registered.and_then(|m| m.continuous_query).as_deref() != Some(cq.text.as_str()) {
+            storage.delete_metric(id, DeleteMode::Soft).await?;
+            return Err(QueryError::InvalidContinuousQuery(format!(
+                "'{}' is the name of a deleted metric",
+                cq.name
+            )));
+        }
+
+        let backfill = match storage.data_bounds().await {
+            Some((min, max)) => self.run_continuous_query(cq, id, cq.refresh_range(min, max)).await,
+            None => Ok(0),
+        };
+        match backfill {
+            Ok(written) => {
+                tracing::info!(continuous_query = %cq.name, written, "Created continuous query");
+                Ok(written)
+            }
+            Err(e) => {
+                storage.delete_metric(id, DeleteMode::Hard).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// All registered continuous queries
+    pub async fn continuous_queries(&self) -> QueryResult<Vec<ContinuousQuery>> {
+        self.storage()
+            .get_metrics()
+            .await
+            .iter()
+            .filter_map(of_metric)
+            .collect()
+    }
+
+    /// Recompute the buckets of a flushed block in every continuous query
+    /// reading one of its metrics
+    ///
+    /// A query that fails is logged and skipped. Returns the number of
+    /// points written.
+    pub async fn refresh_continuous_queries(&self, block: &FlushedBlock) -> QueryResult<usize> {
+        let metrics = self.storage().get_metrics().await;
+        let flushed: HashSet<&str> = metrics
+            .iter()
+            .filter(|m| block.metric_ids.contains(&m.id))
+            .map(|m| m.name.as_str())
+            .collect();
+
+        let mut written = 0;
+        for metric in &metrics {
+            let Some(cq) = of_metric(metric) else {
+                continue;
+            };
+            let refreshed = match cq {
+                Ok(cq) => self.refresh_continuous_query(&cq, metric.id, &flushed, block).await,
+                Err(e) => Err(e),
+            };
+            match refreshed {
+                Ok(points) => written += points,
+                Err(e) => tracing::warn!(
+                    continuous_query = %metric.name,
+                    "Continuous query refresh failed: {}",
+                    e
+                ),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Recompute every continuous query over all data
+    pub async fn rebuild_continuous_queries(&self) -> QueryResult<usize> {
+        let Some((min_timestamp, max_timestamp)) = self.storage().data_bounds().await else {
+            return Ok(0);
+        };
+        let block = FlushedBlock {
+            metric_ids: self.storage().get_metrics().await.iter().map(|m| m.id).collect(),
+            min_timestamp,
+            max_timestamp,
+        };
+        self.refresh_continuous_queries(&block).await
+    }
+
+    /// Start refreshing continuous queries as blocks are flushed
+    ///
+    /// The task first rebuilds every query, catching up on flushes made
+    /// before it subscribed. If it falls behind the flushes, it rebuilds
+    /// every query again.
+    pub fn start_continuous_queries(self: &Arc<Self>) -> ContinuousQueryTask {
+        let executor = Arc::clone(self);
+        let mut flushes = self.storage().subscribe_flushes();
+        let stop = Arc::new(Notify::new());
+        let stopped = Arc::clone(&stop);
+
+        let handle = tokio::spawn(async move {
+            if let Err(e) = executor.rebuild_continuous_queries().await {
+                tracing::error!("Continuous query rebuild failed: {}", e);
+            }
+
+            loop {
+                let received = tokio::select! {
+                    biased;
+                    received = flushes.recv() => received,
+                    _ = stopped.notified() => break,
+                };
+                if received == Err(RecvError::Closed) {
+                    return;
+                }
+                executor.refresh_received(received).await;
+            }
+
+            // Stopping: refresh what was announced before the stop
+            loop {
+                let received = match flushes.try_recv() {
+                    Ok(block) => Ok(block),
+                    Err(TryRecvError::Lagged(missed)) => Err(RecvError::Lagged(missed)),
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                };
+                executor.refresh_received(received).await;
+            }
+        });
+
+        ContinuousQueryTask { handle, stop }
+    }
+
+    /// Refresh a received flush, or rebuild everything after missing some
+    async fn refresh_received(&self, received: Result<FlushedBlock, RecvError>) {
+        let result = match received {
+            Ok(block) => self.refresh_continuous_queries(&block).await,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Missed {} flushes; rebuilding continuous queries", missed);
+                self.rebuild_continuous_queries().await
+            }
+            Err(RecvError::Closed) => Ok(0),
+        };
+        if let Err(e) = result {
+            tracing::error!("Continuous query refresh failed: {}", e);
+        }
+    }
+
+    /// Refresh one query if it reads a flushed metric
+    async fn refresh_continuous_query(
+        &self,
+        cq: &ContinuousQuery,
+        metric_id: u32,
+        flushed: &HashSet<&str>,
+        block: &FlushedBlock,
+    ) -> QueryResult<usize> {
+        let sources = self.source_metrics(cq).await?;
+        if !sources.iter().any(|name| flushed.contains(name.as_str())) {
+            return Ok(0);
+        }
+        let range = cq.refresh_range(block.min_timestamp, block.max_timestamp);
+        self.run_continuous_query(cq, metric_id, range).await
+    }
+
+    /// Metrics read from storage by the innermost query, computed metrics
+    /// expanded
+    async fn source_metrics(&self, cq: &ContinuousQuery) -> QueryResult<Vec<String>> {
+        let innermost = cq.levels().last().unwrap_or(&cq.query);
+        let select = self.expand_computed(&innermost.select).await?;
+
+        let mut sources: Vec<String> = Vec::new();
+        for name in select.iter().flat_map(|item| item.metrics()) {
+            if !sources.iter().any(|source| source == name) {
+                sources.push(name.to_string());
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Run a query over `range` and store its rows as points of `metric_id`
+    ///
+    /// Rows whose stored point already has their value are not written again.
+    async fn run_continuous_query(
+        &self,
+        cq: &ContinuousQuery,
+        metric_id: u32,
+        range: TimeRange,
+    ) -> QueryResult<usize> {
+        let result = self.execute(cq.covering(range)).await?;
+        let Some(column) = result.columns.first() else {
+            return Ok(0);
+        };
+
+        // Later copies of a point replace earlier ones, as for LastWriteWins
+        let stored: HashMap<(i64, BTreeMap<String, String>), f64> = self
+            .storage()
+            .query(range, Some(QueryFilter::new().metric_id(metric_id)))
+            .await?
+            .into_iter()
+            .map(|p| ((p.timestamp, p.tags.into_iter().collect()), p.value))
+            .collect();
+
+        let points: Vec<DataPoint> = result
+            .rows
+            .iter()
+            .filter_map(|row| {
+                let value = row.get(column).filter(|v| v.is_finite())?;
+                if stored.get(&(row.timestamp, row.tags.clone())) == Some(&value) {
+                    return None;
+                }
+                let mut point = DataPoint::with_timestamp(metric_id, value, row.timestamp);
+                point.tags.extend(row.tags.clone());
+                Some(point)
+            })
+            .collect();
+
+        let written = points.len();
+        self.storage().write_batch(points).await?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Category, StorageConfig, StorageEngine};
+    use tempfile::TempDir;
+
+    const DAY: i64 = 24 * 3600 * 1000;
+
+    async fn create_test_executor() -> (Arc<QueryExecutor>, Arc<StorageEngine>, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let engine = Arc::new(StorageEngine::new(StorageConfig::new(dir.path())).await.unwrap());
+        let executor = Arc::new(QueryExecutor::new(Arc::clone(&engine)));
+        (executor, engine, dir)
+    }
+
+    #[test]
+    fn test_continuous_query_definition() {
+        let cq =
+            parse_continuous_definition("daily_mood", " SELECT AVG(mood) GROUP BY day; ").unwrap();
+        assert_eq!(cq.text, "SELECT AVG(mood) GROUP BY day");
+
+        // Refreshes cover whole days
+        let range = cq.refresh_range(DAY + 5, 2 * DAY + 5);
+        assert_eq!((range.start, range.end), (DAY, 3 * DAY));
+        assert_eq!(cq.refresh_range(DAY, DAY).end, 2 * DAY);
+
+        // ...and whole weeks when a subquery groups by week
+        let cq = parse_continuous_definition(
+            "peak",
+            "SELECT MAX(x) FROM (SELECT SUM(steps) AS x GROUP BY week) GROUP BY day",
+        )
+        .unwrap();
+        let range = cq.refresh_range(10 * DAY, 10 * DAY);
+        let week = GroupByInterval::Week;
+        assert_eq!(range.start, week.truncate(10 * DAY));
+        assert_eq!(range.end - range.start, 7 * DAY);
+        assert_eq!(cq.covering(range).from.unwrap().time_range, range);
+
+        for text in [
+            "SELECT AVG(mood)",
+            "SELECT AVG(mood), MAX(mood) GROUP BY day",
+            "SELECT * GROUP BY day",
+            "SELECT MOVING_AVG(AVG(mood), 7) GROUP BY day",
+            "SELECT AVG(mood) GROUP BY day LIMIT 5",
+            "EXPLAIN SELECT AVG(mood) GROUP BY day",
+            "SELECT AVG(mood) GROUP BY day FILL(previous)",
+            "SELECT AVG(mood) GROUP BY day FILL(linear)",
+            "SELECT AVG(mood) WHERE time >= now() - 7d GROUP BY day",
+            "SELECT MAX(x) FROM (SELECT AVG(mood) AS x WHERE time > 1000 GROUP BY day) \
+             GROUP BY week",
+        ] {
+            assert!(
+                matches!(
+                    parse_continuous_definition("x", text),
+                    Err(QueryError::InvalidContinuousQuery(_))
+                ),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_continuous_query_backfill_and_refresh() {
+        let (executor, engine, _dir) = create_test_executor().await;
+        let mood_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        let today = GroupByInterval::Day.truncate(chrono::Utc::now().timestamp_millis());
+        let yesterday = today - DAY;
+        for (timestamp, value) in [(yesterday + 1000, 4.0), (yesterday + 2000, 6.0)] {
+            engine
+                .write(DataPoint::with_timestamp(mood_id, value, timestamp))
+                .await
+                .unwrap();
+        }
+        engine.flush().await.unwrap();
+
+        let cq =
+            parse_continuous_definition("daily_mood", "SELECT AVG(mood) GROUP BY day").unwrap();
+        assert_eq!(executor.create_continuous_query(&cq).await.unwrap(), 1);
+        assert!(executor.create_continuous_query(&cq).await.is_err());
+
+        let metric = engine.get_metric("daily_mood").await.unwrap();
+        assert!(metric.is_materialized());
+        assert_eq!(metric.unit, "1-10");
+        assert_eq!(executor.continuous_queries().await.unwrap().len(), 1);
+
+        // New points update their bucket once flushed
+        engine.flush().await.unwrap();
+        let mut flushes = engine.subscribe_flushes();
+        engine
+            .write(DataPoint::with_timestamp(mood_id, 8.0, yesterday + 3000))
+            .await
+            .unwrap();
+        engine
+            .write(DataPoint::with_timestamp(mood_id, 9.0, today + 1000))
+            .await
+            .unwrap();
+        engine.flush().await.unwrap();
+        let block = flushes.recv().await.unwrap();
+        assert_eq!(block.metric_ids, vec![mood_id]);
+        assert_eq!((block.min_timestamp, block.max_timestamp), (yesterday + 3000, today + 1000));
+
+        assert_eq!(executor.refresh_continuous_queries(&block).await.unwrap(), 2);
+        let result = executor
+            .execute_str("SELECT daily_mood WHERE time >= now() - 3d")
+            .await
+            .unwrap();
+        assert_eq!(result.to_time_series(), vec![(yesterday, 6.0), (today, 9.0)]);
+
+        // Blocks of other metrics leave it alone
+        let other = FlushedBlock {
+            metric_ids: vec![metric.id],
+            ..block
+        };
+        assert_eq!(executor.refresh_continuous_queries(&other).await.unwrap(), 0);
+
+        // Rebuilds only rewrite the buckets that changed
+        assert_eq!(executor.rebuild_continuous_queries().await.unwrap(), 0);
+        engine
+            .write(DataPoint::with_timestamp(mood_id, 3.0, today + 2000))
+            .await
+            .unwrap();
+        engine.flush().await.unwrap();
+        assert_eq!(executor.rebuild_continuous_queries().await.unwrap(), 1);
+        assert_eq!(executor.rebuild_continuous_queries().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_continuous_query_background_refresh() {
+        let (executor, engine, _dir) = create_test_executor().await;
+        let steps_id = engine
+            .register_metric(Metric::new("steps", "count", Category::Health, AggregationType::Sum))
+            .await
+            .unwrap();
+
+        let cq = parse_continuous_definition(
+            "daily_steps",
+            "SELECT SUM(steps) GROUP BY day, tags.device",
+        )
+        .unwrap();
+        assert_eq!(executor.create_continuous_query(&cq).await.unwrap(), 0);
+
+        // Flushed before the task subscribes, as WAL recovery does on startup
+        let now = chrono::Utc::now().timestamp_millis();
+        let write = |value: f64, device: &str| {
+            DataPoint::with_timestamp(steps_id, value, now).tag("device", device)
+        };
+        engine.write(write(1000.0, "watch")).await.unwrap();
+        engine.flush().await.unwrap();
+
+        let task = executor.start_continuous_queries();
+        for (value, device) in [(500.0, "watch"), (200.0, "phone")] {
+            engine.write(write(value, device)).await.unwrap();
+        }
+        engine.flush().await.unwrap();
+
+        // Stopping refreshes the flushes already announced
+        task.stop().await;
+
+        let query = "SELECT SUM(daily_steps) AS steps WHERE tags.device = 'watch' \
+                     AND time >= now() - 2d GROUP BY day";
+        let result = executor.execute_str(query).await.unwrap();
+        assert_eq!(result.rows.first().and_then(|row| row.get("steps")), Some(1500.0));
+    }
+}
@@ -38,6 +38,10 @@ pub enum QueryError {
     /// Invalid filter operation
     #[error("Invalid filter: {0}")]
     InvalidFilter(String),
+
+    /// Continuous query that can't be stored or refreshed
+    #[error("Invalid continuous query: {0}")]
+    InvalidContinuousQuery(String),
 }
 
 /// Result type for query operations
@@ -5,20 +5,35 @@
 //! 2. Data fetching from segments
 //! 3. Filtering by conditions
 //! 4. Aggregation with GROUP BY
+//! 5. Gap filling with FILL
+//! 6. Window functions over each series
+//! 7. TOP selection, ordering and pagination within each series
+//!
+//! A query can read the rows of a `FROM (subquery)` result instead of
+//! storage; each of its columns then stands in for a metric.
+//!
+//! Every result carries `QueryStats`; `EXPLAIN` returns them without reading
+//! any blocks, and `EXPLAIN ANALYZE` runs the query but returns no rows.
 //!
 //! # Execution Pipeline
 //!
 //! ```text
-//! Query → Plan → Index Lookup → Fetch → Filter → Aggregate → Result
+//! Query → Plan → Index Lookup → Fetch → Filter → Aggregate → Fill → Window
+//!       → Top → Order → Offset/Limit → Result
 //! ```
 
 use crate::query::ast::*;
+use crate::query::cache::{QueryCache, QueryCacheConfig};
 use crate::query::error::{QueryError, QueryResult};
+use crate::query::explain::{MetricScan, QueryStats};
 use crate::storage::{DataPoint, StorageEngine, TimeRange};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use std::time::Instant;
 
+/// Upper bound on the buckets FILL may emit for one series
+const MAX_FILL_BUCKETS: i64 = 100_000;
+
 /// Result of a query execution
 #[derive(Debug, Clone)]
 pub struct QueryResult2 {
@@ -30,6 +45,8 @@ pub struct QueryResult2 {
     pub execution_time_ms: u64,
     /// Number of points scanned
     pub points_scanned: usize,
+    /// How the query was planned and executed
+    pub stats: QueryStats,
 }
 
 impl QueryResult2 {
@@ -44,18 +61,52 @@ impl QueryResult2 {
     }
 
     /// Convert to a simple vector of (timestamp, value) pairs for single-metric queries
+    ///
+    /// Only the first series is used when the query grouped by tags.
     pub fn to_time_series(&self) -> Vec<(i64, f64)> {
         if self.columns.is_empty() {
             return Vec::new();
         }
         let first_col = &self.columns[0];
-        self.rows
-            .iter()
-            .filter_map(|row| row.values.get(first_col).map(|v| (row.timestamp, *v)))
-            .collect()
+        self.series()
+            .first()
+            .map(|series| {
+                series
+                    .rows
+                    .iter()
+                    .filter_map(|row| row.values.get(first_col).map(|v| (row.timestamp, *v)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Rows split into one series per GROUP BY tag value combination
+    ///
+    /// Queries without tag grouping have a single series with no tags.
+    pub fn series(&self) -> Vec<ResultSeries<'_>> {
+        let mut series: Vec<ResultSeries<'_>> = Vec::new();
+        for row in &self.rows {
+            match series.last_mut() {
+                Some(current) if current.tags == &row.tags => current.rows.push(row),
+                _ => series.push(ResultSeries {
+                    tags: &row.tags,
+                    rows: vec![row],
+                }),
+            }
+        }
+        series
     }
 }
 
+/// Rows of a query result sharing the same GROUP BY tag values
+#[derive(Debug, Clone)]
+pub struct ResultSeries<'a> {
+    /// Value of each grouped tag (empty without tag grouping)
+    pub tags: &'a BTreeMap<String, String>,
+    /// Rows of this series, in result order
+    pub rows: Vec<&'a ResultRow>,
+}
+
 /// A single result row
 #[derive(Debug, Clone)]
 pub struct ResultRow {
@@ -63,6 +114,8 @@ pub struct ResultRow {
     pub timestamp: i64,
     /// Values keyed by column name
     pub values: HashMap<String, f64>,
+    /// Series this row belongs to (empty without tag grouping)
+    pub tags: BTreeMap<String, String>,
 }
 
 impl ResultRow {
@@ -76,12 +129,36 @@ impl ResultRow {
 pub struct QueryExecutor {
     /// Reference to storage engine
     storage: Arc<StorageEngine>,
+    /// Cache of results and GROUP BY buckets (None = disabled)
+    cache: Option<Arc<QueryCache>>,
 }
 
 impl QueryExecutor {
     /// Create a new query executor
     pub fn new(storage: Arc<StorageEngine>) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            cache: None,
+        }
+    }
+
+    /// Builder: cache query results, invalidated by every write to storage
+    pub fn with_cache(mut self, config: QueryCacheConfig) -> Self {
+        let cache = Arc::new(QueryCache::new(config));
+        let invalidated = Arc::clone(&cache);
+        self.storage.on_change(move |change| invalidated.invalidate(change));
+        self.cache = Some(cache);
+        self
+    }
+
+    /// The result cache, if enabled
+    pub fn cache(&self) -> Option<&Arc<QueryCache>> {
+        self.cache.as_ref()
+    }
+
+    /// Storage engine the queries read
+    pub(super) fn storage(&self) -> &Arc<StorageEngine> {
+        &self.storage
     }
 
     /// Execute a query string (parses and executes)
@@ -90,108 +167,457 @@ impl QueryExecutor {
         self.execute(query).await
     }
 
+    /// Execute several queries, e.g. from `parse_statements`, in order
+    pub async fn execute_all(&self, queries: Vec<Query>) -> QueryResult<Vec<QueryResult2>> {
+        let mut results = Vec::with_capacity(queries.len());
+        for query in queries {
+            results.push(self.execute(query).await?);
+        }
+        Ok(results)
+    }
+
     /// Execute a parsed query
-    pub async fn execute(&self, query: Query) -> QueryResult<QueryResult2> {
+    ///
+    /// With a cache, whole results and complete GROUP BY buckets are served
+    /// from it; only the buckets it lacks are read.
+    pub async fn execute(&self, mut query: Query) -> QueryResult<QueryResult2> {
         let start = Instant::now();
+        unique_columns(&mut query.select)?;
+
+        let mut stats = QueryStats {
+            analyzed: query.explain != Some(ExplainMode::Plan),
+            filter: (!query.filters.is_empty())
+                .then(|| FilterExpr::And(query.filters.clone()).to_string()),
+            ..QueryStats::default()
+        };
+
+        // Queries that don't group by time are cached as a whole
+        let cache = self.cache.as_deref().filter(|_| QueryCache::accepts(&query));
+        let result_key = match cache {
+            Some(cache) if query.group_by.is_none() => {
+                let key = QueryCache::result_key(&query);
+                if let Some(mut result) = cache.result(&key) {
+                    result.execution_time_ms = start.elapsed().as_millis() as u64;
+                    result.stats.cached = true;
+                    return Ok(result);
+                }
+                Some((key, cache.generation()))
+            }
+            _ => None,
+        };
+
+        // 1-5. Resolve metrics, fetch their points (or the subquery's rows),
+        // filter and aggregate them
+        let (select, metric_ids, rows) = match (cache, &query.group_by) {
+            (Some(cache), Some(group_by)) => {
+                self.read_buckets(cache, &query, group_by, &mut stats).await?
+            }
+            _ => self.read_rows(&query, &mut stats).await?,
+        };
+
+        let columns: Vec<String> = select
+            .iter()
+            .map(|s| s.display_name().to_string())
+            .collect();
+
+        // EXPLAIN stops at the plan
+        if !stats.analyzed {
+            return Ok(QueryResult2 {
+                columns,
+                rows: Vec::new(),
+                execution_time_ms: start.elapsed().as_millis() as u64,
+                points_scanned: 0,
+                stats,
+            });
+        }
+
+        // 5. Fill empty buckets
+        let aggregate_start = Instant::now();
+        let mut rows = match query.group_by {
+            Some(ref group_by) if group_by.fill != FillMode::None => {
+                self.fill(rows, group_by, query.time_range, &columns)?
+            }
+            _ => rows,
+        };
+
+        // 6. Apply window functions
+        for item in &select {
+            if let Some(window) = item.window {
+                apply_window(&mut rows, item.display_name(), window);
+            }
+        }
+        stats.aggregate_us += aggregate_start.elapsed().as_micros() as u64;
+
+        // 7. Keep the rows selected by TOP
+        let mut top_items = select.iter().filter_map(|s| s.top.map(|n| (s.display_name(), n)));
+        if let Some((column, n)) = top_items.next() {
+            if top_items.next().is_some() {
+                return Err(QueryError::Execution(
+                    "Only one TOP selector is allowed per query".to_string(),
+                ));
+            }
+            rows = select_top(rows, column, n);
+        }
+
+        // 8. Order rows (per series)
+        let sort_column = match &query.order_by.key {
+            OrderKey::Time => None,
+            OrderKey::Value => columns.first().map(String::as_str),
+            OrderKey::Column(name) => match columns.iter().find(|c| *c == name) {
+                Some(column) => Some(column.as_str()),
+                None => {
+                    return Err(QueryError::Execution(format!(
+                        "ORDER BY column '{}' is not selected",
+                        name
+                    )))
+                }
+            },
+        };
+        order_rows(&mut rows, sort_column, query.order_by.descending);
+
+        // 9. Apply offset and limit (per series)
+        let rows = if query.limit.is_some() || query.offset.is_some() {
+            let skip = query.offset.unwrap_or(0);
+            let limit = query.limit.unwrap_or(usize::MAX);
+            let mut position = 0;
+            let mut current: Option<BTreeMap<String, String>> = None;
+            rows.into_iter()
+                .filter(|row| {
+                    if current.as_ref() != Some(&row.tags) {
+                        current = Some(row.tags.clone());
+                        position = 0;
+                    }
+                    position += 1;
+                    position > skip && position - skip <= limit
+                })
+                .collect()
+        } else {
+            rows
+        };
+
+        // 10. Build result (EXPLAIN ANALYZE only reports the statistics)
+        stats.rows_returned = rows.len();
+        let rows = match query.explain {
+            Some(ExplainMode::Analyze) => Vec::new(),
+            _ => rows,
+        };
+        let result = QueryResult2 {
+            columns,
+            rows,
+            execution_time_ms: start.elapsed().as_millis() as u64,
+            points_scanned: stats.points_read,
+            stats,
+        };
+
+        if let (Some(cache), Some((key, generation))) = (cache, result_key) {
+            let ids: Vec<u32> = metric_ids.iter().map(|(_, id)| *id).collect();
+            cache.insert_result(key, generation, ids, query.time_range, &result);
+        }
+        Ok(result)
+    }
+
+    /// Steps 1-5 of `execute` (before FILL) over the query's time range
+    ///
+    /// Statistics add up over several calls.
+    async fn read_rows(
+        &self,
+        query: &Query,
+        stats: &mut QueryStats,
+    ) -> QueryResult<(Vec<SelectItem>, Vec<(String, u32)>, Vec<ResultRow>)> {
+        // 1-3. Resolve metrics and fetch their points (or the subquery's rows)
+        let (select, metric_ids, all_points) = match query.from {
+            Some(ref subquery) => self.read_subquery(query, subquery, stats).await?,
+            None => self.read_storage(query, stats).await?,
+        };
+        if !stats.analyzed {
+            return Ok((select, metric_ids, Vec::new()));
+        }
+        stats.points_read += all_points.len();
+
+        // 4. Apply value filters
+        let filtered = self.apply_filters(all_points, &query.filters);
+        stats.points_matched += filtered.len();
+
+        // 5. Aggregate or convert to rows
+        let aggregate_start = Instant::now();
+        let rows = match query.group_by {
+            Some(ref group_by) => self.aggregate(filtered, &select, group_by, &metric_ids),
+            // Without GROUP BY, aggregations reduce the whole range to one row
+            None if query.select.iter().any(SelectItem::is_aggregated) => {
+                let values = self.aggregate_group(&filtered, &select, &metric_ids);
+                (!values.is_empty())
+                    .then(|| ResultRow {
+                        timestamp: query.time_range.start,
+                        values,
+                        tags: BTreeMap::new(),
+                    })
+                    .into_iter()
+                    .collect()
+            }
+            None => self.to_rows(filtered, &select, &metric_ids),
+        };
+        stats.aggregate_us += aggregate_start.elapsed().as_micros() as u64;
+
+        Ok((select, metric_ids, rows))
+    }
+
+    /// `read_rows` for a GROUP BY query, taking complete buckets from the
+    /// cache and reading only the spans of buckets it lacks
+    async fn read_buckets(
+        &self,
+        cache: &QueryCache,
+        query: &Query,
+        group_by: &GroupByClause,
+        stats: &mut QueryStats,
+    ) -> QueryResult<(Vec<SelectItem>, Vec<(String, u32)>, Vec<ResultRow>)> {
+        let range = query.time_range;
+        let Some(buckets) = QueryCache::bucket_starts(group_by, range) else {
+            return self.read_rows(query, stats).await;
+        };
+
+        // Buckets cut by the query range are read, never cached
+        let (complete, cut): (Vec<i64>, Vec<i64>) = buckets.iter().partition(|&&bucket| {
+            bucket >= range.start && group_by.next_bucket_start(bucket) <= range.end
+        });
+
+        let key = QueryCache::bucket_key(query);
+        let generation = cache.generation();
+        let (mut rows, mut missing) = cache.buckets(&key, &complete);
+        stats.buckets_cached = complete.len() - missing.len();
+        missing.extend(cut);
+        missing.sort_unstable();
+
+        let mut select = query.select.clone();
+        let mut metric_ids = Vec::new();
+        for (span_start, span_end) in QueryCache::spans(group_by, &missing) {
+            let span = TimeRange::new(span_start.max(range.start), span_end.min(range.end));
+            let span_query = Query {
+                time_range: span,
+                ..query.clone()
+            };
+            let (span_select, span_ids, span_rows) = self.read_rows(&span_query, stats).await?;
+
+            let read: Vec<i64> = complete
+                .iter()
+                .copied()
+                .filter(|&bucket| bucket >= span_start && bucket < span_end)
+                .collect();
+            let ids: Vec<u32> = span_ids.iter().map(|(_, id)| *id).collect();
+            cache.insert_buckets(&key, generation, group_by, ids, &read, &span_rows);
+
+            rows.extend(span_rows);
+            select = span_select;
+            metric_ids = span_ids;
+        }
 
-        // 1. Resolve metric names to IDs
-        let metric_ids = self.resolve_metrics(&query.select).await?;
+        rows.sort_by(|a, b| a.tags.cmp(&b.tags).then(a.timestamp.cmp(&b.timestamp)));
+        Ok((select, metric_ids, rows))
+    }
 
-        // 2. Build tag filters from query filters
+    /// Resolve the selected metrics and read their points from storage
+    async fn read_storage(
+        &self,
+        query: &Query,
+        stats: &mut QueryStats,
+    ) -> QueryResult<(Vec<SelectItem>, Vec<(String, u32)>, Vec<DataPoint>)> {
+        // 1. Expand computed metrics and resolve metric names to IDs
+        let select = self.expand_computed(&query.select).await?;
+        let metric_ids = self.resolve_metrics(&select).await?;
+
+        // 2. Tag values every matching point must have, to narrow the read
         let tag_filters = self.extract_tag_filters(&query.filters);
 
-        // 3. Fetch data points using storage engine's query method
+        let mut pushed_down: Vec<String> = tag_filters
+            .iter()
+            .map(|(key, values)| match values.as_slice() {
+                [value] => Filter::tag(key.clone(), Operator::Eq, value.clone()).to_string(),
+                _ => FilterExpr::tag_in(key.clone(), values.clone()).to_string(),
+            })
+            .collect();
+        pushed_down.sort();
+
+        // 3. Fetch data points, narrowing each metric's read by its tags
         let mut all_points = Vec::new();
 
         for (metric_name, metric_id) in &metric_ids {
-            let points = if tag_filters.is_empty() {
-                // Simple query by metric
-                self.storage
-                    .query_metric(metric_name, query.time_range)
-                    .await?
+            let mut filter = crate::storage::QueryFilter::new().metric_id(*metric_id);
+            for (key, values) in &tag_filters {
+                filter = match values.as_slice() {
+                    [value] => filter.tag(key.clone(), value.clone()),
+                    _ => filter.tag_in(key.clone(), values.clone()),
+                };
+            }
+
+            let scan = if stats.analyzed {
+                let (points, scan) = self
+                    .storage
+                    .query_with_stats(query.time_range, Some(filter))
+                    .await?;
+                all_points.extend(points);
+                scan
             } else {
-                // Query all and filter (tag filtering is done in memory)
-                let mut filter = crate::storage::QueryFilter::new().metric_id(*metric_id);
-                for (key, value) in &tag_filters {
-                    filter = filter.tag(key.clone(), value.clone());
-                }
                 self.storage
-                    .query(query.time_range, Some(filter))
+                    .plan_query(query.time_range, Some(filter))
                     .await?
             };
-            all_points.extend(points);
+            stats.scans.push(MetricScan {
+                metric: metric_name.clone(),
+                pushed_down: pushed_down.clone(),
+                stats: scan,
+            });
         }
 
-        let points_scanned = all_points.len();
+        Ok((select, metric_ids, all_points))
+    }
 
-        // 4. Apply value filters
-        let filtered = self.apply_filters(all_points, &query.filters);
+    /// Run a FROM subquery and turn its rows into points
+    ///
+    /// Each result column stands in for a metric and yields one point per
+    /// row that has a value, carrying the row's GROUP BY tags. Rows are read
+    /// when their bucket overlaps the outer time range.
+    async fn read_subquery(
+        &self,
+        query: &Query,
+        subquery: &Query,
+        stats: &mut QueryStats,
+    ) -> QueryResult<(Vec<SelectItem>, Vec<(String, u32)>, Vec<DataPoint>)> {
+        // Under EXPLAIN the subquery is only planned; otherwise its rows are needed
+        let mut subquery = subquery.clone();
+        subquery.explain = query.explain.filter(|mode| *mode == ExplainMode::Plan);
+        let result = Box::pin(self.execute(subquery.clone())).await?;
+
+        let metric_ids: Vec<(String, u32)> = result
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| (column.clone(), i as u32))
+            .collect();
+        for item in query.select.iter().filter(|item| item.metric != "*") {
+            for name in item.metrics() {
+                if !metric_ids.iter().any(|(column, _)| column == name) {
+                    return Err(QueryError::MetricNotFound(format!(
+                        "{} (not a column of the subquery)",
+                        name
+                    )));
+                }
+            }
+        }
 
-        // 5. Aggregate or convert to rows
-        let rows = if let Some(ref group_by) = query.group_by {
-            self.aggregate(filtered, &query.select, group_by, &metric_ids)
-        } else {
-            self.to_rows(filtered, &query.select, &metric_ids)
+        let range = query.time_range;
+        let overlaps = |timestamp: i64| match subquery.group_by {
+            Some(ref group_by) => {
+                timestamp < range.end && group_by.next_bucket_start(timestamp) > range.start
+            }
+            None => range.contains(timestamp),
         };
+        let points = result
+            .rows
+            .iter()
+            .filter(|row| overlaps(row.timestamp))
+            .flat_map(|row| {
+                let tags: HashMap<String, String> = row.tags.clone().into_iter().collect();
+                metric_ids.iter().filter_map(move |(column, id)| {
+                    row.get(column).map(|value| {
+                        DataPoint::with_timestamp(*id, value, row.timestamp).tags(tags.clone())
+                    })
+                })
+            })
+            .collect();
 
-        // 6. Apply limit
-        let rows = if let Some(limit) = query.limit {
-            rows.into_iter().take(limit).collect()
-        } else {
-            rows
-        };
+        stats.source = Some(Box::new(result.stats));
+        Ok((query.select.clone(), metric_ids, points))
+    }
 
-        // 7. Build result
-        let columns = query
-            .select
-            .iter()
-            .map(|s| s.display_name().to_string())
+    /// Replace references to computed metrics with their expressions
+    ///
+    /// An aggregation of a computed metric applies to each metric its
+    /// expression reads, so `AVG(weight_lb)` over `weight_kg * 2.2` becomes
+    /// `AVG(weight_kg) * 2.2`; computed metrics that aggregate themselves
+    /// cannot be aggregated again.
+    pub(super) async fn expand_computed(
+        &self,
+        select: &[SelectItem],
+    ) -> QueryResult<Vec<SelectItem>> {
+        let computed: HashMap<String, String> = self
+            .storage
+            .get_metrics()
+            .await
+            .into_iter()
+            .filter_map(|m| m.expression.map(|expression| (m.name, expression)))
             .collect();
 
-        Ok(QueryResult2 {
-            columns,
-            rows,
-            execution_time_ms: start.elapsed().as_millis() as u64,
-            points_scanned,
-        })
+        if computed.is_empty() {
+            return Ok(select.to_vec());
+        }
+
+        select
+            .iter()
+            .map(|item| {
+                if item.metric == "*" || !item.metrics().iter().any(|m| computed.contains_key(*m)) {
+                    return Ok(item.clone());
+                }
+
+                let expr = expand_expr(item.to_expr(), &computed, 0)?;
+                let mut expanded = SelectItem::expression(expr).with_alias(item.display_name());
+                expanded.aggregation = item.aggregation;
+                expanded.window = item.window;
+                expanded.top = item.top;
+                Ok(expanded)
+            })
+            .collect()
     }
 
-    /// Resolve metric names to IDs
+    /// Resolve the metrics read by the select items to IDs, once per name
     async fn resolve_metrics(
         &self,
         select: &[SelectItem],
     ) -> QueryResult<Vec<(String, u32)>> {
-        let mut result = Vec::new();
+        let mut result: Vec<(String, u32)> = Vec::new();
 
         for item in select {
             if item.metric == "*" {
                 // SELECT * - get all metrics
                 let metrics = self.storage.get_metrics().await;
-                for m in metrics {
+                for m in metrics.into_iter().filter(|m| !m.is_computed()) {
                     result.push((m.name.clone(), m.id));
                 }
-            } else {
+                continue;
+            }
+
+            for name in item.metrics() {
+                if result.iter().any(|(existing, _)| existing == name) {
+                    continue;
+                }
                 let metric = self
                     .storage
-                    .get_metric(&item.metric)
+                    .get_metric(name)
                     .await
-                    .ok_or_else(|| QueryError::MetricNotFound(item.metric.clone()))?;
-                result.push((item.metric.clone(), metric.id));
+                    .ok_or_else(|| QueryError::MetricNotFound(name.to_string()))?;
+                result.push((name.to_string(), metric.id));
             }
         }
 
         Ok(result)
     }
 
-    /// Extract tag filters from query filters
-    fn extract_tag_filters(&self, filters: &[Filter]) -> HashMap<String, String> {
-        let mut result = HashMap::new();
+    /// Extract the tag values implied by query filters
+    ///
+    /// Each filter that pins a tag to one or more values (`=`, `IN`, or an
+    /// OR of those) narrows that tag; a key pinned twice keeps only values
+    /// allowed by both.
+    fn extract_tag_filters(&self, filters: &[FilterExpr]) -> HashMap<String, Vec<String>> {
+        let mut result: HashMap<String, Vec<String>> = HashMap::new();
 
         for filter in filters {
-            if let FilterField::Tag(key) = &filter.field {
-                if filter.op == Operator::Eq {
-                    if let FilterValue::String(value) = &filter.value {
-                        result.insert(key.clone(), value.clone());
+            if let Some((key, values)) = filter.required_tag_values() {
+                let mut values: Vec<String> = values.into_iter().map(String::from).collect();
+                values.sort();
+                values.dedup();
+
+                match result.get_mut(key) {
+                    Some(existing) => existing.retain(|v| values.contains(v)),
+                    None => {
+                        result.insert(key.to_string(), values);
                     }
                 }
             }
@@ -201,43 +627,13 @@ impl QueryExecutor {
     }
 
     /// Apply filters to data points
-    fn apply_filters(&self, points: Vec<DataPoint>, filters: &[Filter]) -> Vec<DataPoint> {
+    fn apply_filters(&self, points: Vec<DataPoint>, filters: &[FilterExpr]) -> Vec<DataPoint> {
         points
             .into_iter()
-            .filter(|point| self.matches_all_filters(point, filters))
+            .filter(|point| filters.iter().all(|filter| filter.matches(point)))
             .collect()
     }
 
-    /// Check if a point matches all filters
-    fn matches_all_filters(&self, point: &DataPoint, filters: &[Filter]) -> bool {
-        filters.iter().all(|filter| self.matches_filter(point, filter))
-    }
-
-    /// Check if a point matches a single filter
-    fn matches_filter(&self, point: &DataPoint, filter: &Filter) -> bool {
-        match &filter.field {
-            FilterField::Metric => {
-                // Metric filter is handled at query time
-                true
-            }
-            FilterField::Tag(key) => {
-                if let Some(tag_value) = point.tags.get(key) {
-                    match &filter.value {
-                        FilterValue::String(s) => filter.op.compare_str(tag_value, s),
-                        FilterValue::Number(_) => false,
-                    }
-                } else {
-                    // Tag not present - only match for "not equal"
-                    filter.op == Operator::Ne
-                }
-            }
-            FilterField::Value => match &filter.value {
-                FilterValue::Number(n) => filter.op.compare_f64(point.value, *n),
-                FilterValue::String(_) => false,
-            },
-        }
-    }
-
     /// Aggregate points by time windows
     fn aggregate(
         &self,
@@ -246,28 +642,98 @@ impl QueryExecutor {
         group_by: &GroupByClause,
         metric_ids: &[(String, u32)],
     ) -> Vec<ResultRow> {
-        // Group points by truncated timestamp
-        let mut groups: HashMap<i64, Vec<DataPoint>> = HashMap::new();
+        // Group points by series and truncated timestamp
+        let mut groups: HashMap<(BTreeMap<String, String>, i64), Vec<DataPoint>> =
+            HashMap::new();
 
         for point in points {
-            let bucket = group_by.interval.truncate(point.timestamp);
-            groups.entry(bucket).or_default().push(point);
+            let series = group_by.series_key(&point);
+            let bucket = group_by.bucket_start(point.timestamp);
+            groups.entry((series, bucket)).or_default().push(point);
         }
 
         // Aggregate each group
         let mut rows: Vec<ResultRow> = groups
             .into_iter()
-            .map(|(timestamp, points)| {
+            .map(|((tags, timestamp), points)| {
                 let values = self.aggregate_group(&points, select, metric_ids);
-                ResultRow { timestamp, values }
+                ResultRow {
+                    timestamp,
+                    values,
+                    tags,
+                }
             })
             .collect();
 
-        // Sort by timestamp
-        rows.sort_by_key(|r| r.timestamp);
+        // Sort by series, then timestamp
+        rows.sort_by(|a, b| a.tags.cmp(&b.tags).then(a.timestamp.cmp(&b.timestamp)));
         rows
     }
 
+    /// Emit every bucket of the time range for each series, filling
+    /// missing column values according to the FILL mode
+    fn fill(
+        &self,
+        rows: Vec<ResultRow>,
+        group_by: &GroupByClause,
+        time_range: TimeRange,
+        columns: &[String],
+    ) -> QueryResult<Vec<ResultRow>> {
+        let first = group_by.bucket_start(time_range.start);
+        let estimate = (time_range.end - first) / group_by.interval.approx_duration_ms().max(1);
+        if estimate > MAX_FILL_BUCKETS {
+            return Err(QueryError::Execution(format!(
+                "FILL would emit about {} buckets per series (limit {}); narrow the time range",
+                estimate, MAX_FILL_BUCKETS
+            )));
+        }
+
+        let mut buckets = Vec::new();
+        let mut bucket = first;
+        while bucket < time_range.end {
+            buckets.push(bucket);
+            bucket = group_by.next_bucket_start(bucket);
+        }
+
+        // Rows arrive sorted by series, then timestamp
+        let mut series: Vec<(BTreeMap<String, String>, Vec<ResultRow>)> = Vec::new();
+        for row in rows {
+            match series.last_mut() {
+                Some((tags, current)) if *tags == row.tags => current.push(row),
+                _ => series.push((row.tags.clone(), vec![row])),
+            }
+        }
+
+        // A query without GROUP BY tags has its one series even when no
+        // point matched; tag series are only known from their points
+        if series.is_empty() && group_by.tags.is_empty() {
+            series.push((BTreeMap::new(), Vec::new()));
+        }
+
+        let mut filled = Vec::with_capacity(series.len() * buckets.len());
+        for (tags, rows) in series {
+            let mut by_bucket: HashMap<i64, ResultRow> =
+                rows.into_iter().map(|row| (row.timestamp, row)).collect();
+            let mut series_rows: Vec<ResultRow> = buckets
+                .iter()
+                .map(|&timestamp| {
+                    by_bucket.remove(&timestamp).unwrap_or_else(|| ResultRow {
+                        timestamp,
+                        values: HashMap::new(),
+                        tags: tags.clone(),
+                    })
+                })
+                .collect();
+
+            for column in columns {
+                fill_column(&mut series_rows, column, group_by.fill);
+            }
+            filled.extend(series_rows);
+        }
+
+        Ok(filled)
+    }
+
     /// Aggregate a group of points
     fn aggregate_group(
         &self,
@@ -278,27 +744,25 @@ impl QueryExecutor {
         let mut values = HashMap::new();
 
         for item in select {
-            // Find the metric ID for this select item
-            let metric_id = metric_ids
-                .iter()
-                .find(|(name, _)| name == &item.metric)
-                .map(|(_, id)| *id);
-
-            // Get values for this metric
-            let metric_values: Vec<f64> = points
-                .iter()
-                .filter(|p| metric_id.map(|id| p.metric_id == id).unwrap_or(true))
-                .map(|p| p.value)
-                .collect();
-
-            // Apply aggregation
-            let aggregated = match item.aggregation {
-                Some(agg) => agg.apply(&metric_values),
-                None => {
-                    // No aggregation - use last value
-                    AggregationFunc::Last.apply(&metric_values)
-                }
-            };
+            // Metrics without their own aggregation use the item's, or the last value
+            let default_agg = item.aggregation.unwrap_or(AggregationFunc::Last);
+
+            let aggregated = item.to_expr().evaluate(&mut |agg, metric| {
+                // Find the metric ID for this reference
+                let metric_id = metric_ids
+                    .iter()
+                    .find(|(name, _)| name == metric)
+                    .map(|(_, id)| *id);
+
+                // Get values for this metric
+                let metric_values: Vec<f64> = points
+                    .iter()
+                    .filter(|p| metric_id.map(|id| p.metric_id == id).unwrap_or(true))
+                    .map(|p| p.value)
+                    .collect();
+
+                agg.unwrap_or(default_agg).apply(&metric_values)
+            });
 
             if let Some(val) = aggregated {
                 let name = item.display_name().to_string();
@@ -310,13 +774,16 @@ impl QueryExecutor {
     }
 
     /// Convert raw points to result rows (no aggregation)
+    ///
+    /// Points are aligned on their timestamp. A metric with several points
+    /// at one timestamp (other tags, or duplicates kept by `KeepAll`) gets a
+    /// row for each, its n-th point aligned with the n-th of the others.
     fn to_rows(
         &self,
         points: Vec<DataPoint>,
         select: &[SelectItem],
         metric_ids: &[(String, u32)],
     ) -> Vec<ResultRow> {
-        // For non-aggregated queries, each point becomes a row
         // Group by timestamp for multi-metric queries
         let mut timestamp_groups: HashMap<i64, Vec<&DataPoint>> = HashMap::new();
 
@@ -324,31 +791,47 @@ impl QueryExecutor {
             timestamp_groups.entry(point.timestamp).or_default().push(point);
         }
 
-        let mut rows: Vec<ResultRow> = timestamp_groups
-            .into_iter()
-            .map(|(timestamp, group_points)| {
+        let mut rows = Vec::with_capacity(points.len());
+        for (timestamp, group_points) in timestamp_groups {
+            let depth = metric_ids
+                .iter()
+                .map(|(_, id)| group_points.iter().filter(|p| p.metric_id == *id).count())
+                .max()
+                .unwrap_or(group_points.len());
+
+            for n in 0..depth {
                 let mut values = HashMap::new();
 
                 for item in select {
-                    // Find the metric ID for this select item
-                    let metric_id = metric_ids
-                        .iter()
-                        .find(|(name, _)| name == &item.metric)
-                        .map(|(_, id)| *id);
-
-                    // Find the point for this metric
-                    if let Some(point) = group_points
-                        .iter()
-                        .find(|p| metric_id.map(|id| p.metric_id == id).unwrap_or(true))
-                    {
+                    // Metrics are aligned on the timestamp
+                    let value = item.to_expr().evaluate(&mut |_, metric| {
+                        // Find the metric ID for this reference
+                        let metric_id = metric_ids
+                            .iter()
+                            .find(|(name, _)| name == metric)
+                            .map(|(_, id)| *id);
+
+                        // Find the n-th point for this metric
+                        group_points
+                            .iter()
+                            .filter(|p| metric_id.map(|id| p.metric_id == id).unwrap_or(true))
+                            .nth(n)
+                            .map(|p| p.value)
+                    });
+
+                    if let Some(value) = value {
                         let name = item.display_name().to_string();
-                        values.insert(name, point.value);
+                        values.insert(name, value);
                     }
                 }
 
-                ResultRow { timestamp, values }
-            })
-            .collect();
+                rows.push(ResultRow {
+                    timestamp,
+                    values,
+                    tags: BTreeMap::new(),
+                });
+            }
+        }
 
         // Sort by timestamp
         rows.sort_by_key(|r| r.timestamp);
@@ -393,6 +876,184 @@ impl QueryExecutor {
     }
 }
 
+/// Deepest chain of computed metrics referring to each other
+const MAX_COMPUTED_DEPTH: usize = 8;
+
+/// Expand computed metric references in an expression
+fn expand_expr(
+    expr: SelectExpr,
+    computed: &HashMap<String, String>,
+    depth: usize,
+) -> QueryResult<SelectExpr> {
+    expr.try_map_metrics(&mut |agg, metric| {
+        let Some(expression) = computed.get(&metric) else {
+            return Ok(match agg {
+                Some(agg) => SelectExpr::Aggregate(agg, metric),
+                None => SelectExpr::Metric(metric),
+            });
+        };
+        if depth >= MAX_COMPUTED_DEPTH {
+            return Err(QueryError::Execution(format!(
+                "computed metric '{}' is nested too deeply (does it refer to itself?)",
+                metric
+            )));
+        }
+
+        let mut inner = crate::query::parser::parse_expression(expression)?;
+        if let Some(agg) = agg {
+            if inner.has_aggregation() {
+                return Err(QueryError::InvalidAggregation(format!(
+                    "computed metric '{}' already aggregates and cannot be wrapped in {}",
+                    metric, agg
+                )));
+            }
+            inner = inner.try_map_metrics(&mut |_, name| {
+                Ok::<_, QueryError>(SelectExpr::Aggregate(agg, name))
+            })?;
+        }
+        expand_expr(inner, computed, depth + 1)
+    })
+}
+
+/// Name each result column uniquely
+///
+/// An unaliased aggregation sharing its metric's name with another item is
+/// named after the aggregation instead, so `MAX(mood), MIN(mood)` yields
+/// the columns `MAX(mood)` and `MIN(mood)`. Other duplicates need an alias.
+fn unique_columns(select: &mut [SelectItem]) -> QueryResult<()> {
+    let names: Vec<String> = select.iter().map(|s| s.display_name().to_string()).collect();
+    let shared = |i: usize| names.iter().enumerate().any(|(j, name)| j != i && *name == names[i]);
+
+    for (i, item) in select.iter_mut().enumerate() {
+        if shared(i) && item.alias.is_none() && item.expr.is_none() && item.aggregation.is_some() {
+            item.alias = Some(item.to_expr().to_string());
+        }
+    }
+
+    for (i, item) in select.iter().enumerate() {
+        let name = item.display_name();
+        if select[..i].iter().any(|s| s.display_name() == name) {
+            return Err(QueryError::Execution(format!(
+                "Duplicate column '{}'; name one of them with AS",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Replace one column with a window function of its values, per series
+fn apply_window(rows: &mut [ResultRow], column: &str, window: WindowFunc) {
+    for series in rows.chunk_by_mut(|a, b| a.tags == b.tags) {
+        let present: Vec<usize> = (0..series.len())
+            .filter(|&i| series[i].values.contains_key(column))
+            .collect();
+        let values: Vec<(i64, f64)> = present
+            .iter()
+            .map(|&i| (series[i].timestamp, series[i].values[column]))
+            .collect();
+
+        for (i, result) in present.into_iter().zip(window.apply(&values)) {
+            match result {
+                Some(value) => series[i].values.insert(column.to_string(), value),
+                None => series[i].values.remove(column),
+            };
+        }
+    }
+}
+
+/// Keep the n rows of each series with the largest values of a column
+///
+/// Ties go to the earlier row; the kept rows stay in time order.
+fn select_top(rows: Vec<ResultRow>, column: &str, n: usize) -> Vec<ResultRow> {
+    let mut selected = Vec::with_capacity(rows.len().min(n));
+    let mut rows = rows.into_iter().peekable();
+
+    while let Some(first) = rows.next() {
+        let mut series = vec![first];
+        while let Some(row) = rows.next_if(|row| row.tags == series[0].tags) {
+            series.push(row);
+        }
+
+        let mut ranked: Vec<(usize, f64)> = series
+            .iter()
+            .enumerate()
+            .filter_map(|(i, row)| row.get(column).map(|value| (i, value)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut keep: Vec<usize> = ranked.into_iter().take(n).map(|(i, _)| i).collect();
+        keep.sort_unstable();
+
+        let mut keep = keep.into_iter().peekable();
+        for (i, row) in series.into_iter().enumerate() {
+            if keep.next_if_eq(&i).is_some() {
+                selected.push(row);
+            }
+        }
+    }
+
+    selected
+}
+
+/// Sort the rows of each series by time, or by a column with rows lacking
+/// it last
+fn order_rows(rows: &mut [ResultRow], column: Option<&str>, descending: bool) {
+    for series in rows.chunk_by_mut(|a, b| a.tags == b.tags) {
+        match column {
+            None if descending => series.sort_by_key(|row| std::cmp::Reverse(row.timestamp)),
+            None => series.sort_by_key(|row| row.timestamp),
+            Some(column) => series.sort_by(|a, b| match (a.get(column), b.get(column)) {
+                (Some(x), Some(y)) if descending => y.total_cmp(&x),
+                (Some(x), Some(y)) => x.total_cmp(&y),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            }),
+        }
+    }
+}
+
+/// Fill missing values of one column across the rows of a series
+fn fill_column(rows: &mut [ResultRow], column: &str, fill: FillMode) {
+    match fill {
+        FillMode::None | FillMode::Null => {}
+        FillMode::Value(value) => {
+            for row in rows.iter_mut() {
+                row.values.entry(column.to_string()).or_insert(value);
+            }
+        }
+        FillMode::Previous => {
+            let mut previous = None;
+            for row in rows.iter_mut() {
+                match row.values.get(column) {
+                    Some(&value) => previous = Some(value),
+                    None => {
+                        if let Some(value) = previous {
+                            row.values.insert(column.to_string(), value);
+                        }
+                    }
+                }
+            }
+        }
+        FillMode::Linear => {
+            let known: Vec<(usize, i64, f64)> = rows
+                .iter()
+                .enumerate()
+                .filter_map(|(i, row)| row.values.get(column).map(|&v| (i, row.timestamp, v)))
+                .collect();
+
+            for pair in known.windows(2) {
+                let (start, t0, v0) = pair[0];
+                let (end, t1, v1) = pair[1];
+                for row in &mut rows[start + 1..end] {
+                    let fraction = (row.timestamp - t0) as f64 / (t1 - t0) as f64;
+                    row.values.insert(column.to_string(), v0 + (v1 - v0) * fraction);
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -494,32 +1155,82 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_filter_query() {
+    async fn test_aggregations_of_one_metric() {
         let (executor, engine, _dir) = create_test_executor().await;
 
         let metric_id = engine
-            .register_metric(Metric::new(
-                "mood",
-                "1-10",
-                Category::Mood,
-                AggregationType::Average,
-            ))
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
             .await
             .unwrap();
 
-        // Write data with tags and distinct timestamps
-        let now = chrono::Utc::now().timestamp_millis();
-        for i in 0..10 {
-            let location = if i % 2 == 0 { "home" } else { "office" };
-            let point = DataPoint::with_timestamp(metric_id, 5.0 + i as f64 * 0.5, now - (10 - i) * 1000)
-                .tag("location", location);
-            engine.write(point).await.unwrap();
+        // Yesterday: 2, 4, 9
+        let today = GroupByInterval::Day.truncate(chrono::Utc::now().timestamp_millis());
+        for (i, value) in [2.0, 4.0, 9.0].into_iter().enumerate() {
+            let timestamp = today - 3600 * 1000 + i as i64 * 1000;
+            engine.write(DataPoint::with_timestamp(metric_id, value, timestamp)).await.unwrap();
         }
         engine.flush().await.unwrap();
 
-        // Query with tag filter
-        let query = Query::select(&["mood"])
-            .last_days(1)
+        // Each aggregation gets its own column, with and without GROUP BY
+        for group_by in ["GROUP BY day", ""] {
+            let result = executor
+                .execute_str(&format!(
+                    "SELECT MAX(mood), MIN(mood), PERCENTILE(mood, 0.5) AS p50, mood \
+                     WHERE time >= now() - 2d {}",
+                    group_by
+                ))
+                .await
+                .unwrap();
+            assert_eq!(result.columns, vec!["MAX(mood)", "MIN(mood)", "p50", "mood"]);
+            assert_eq!(result.rows.len(), 1, "{}", group_by);
+            assert_eq!(result.rows[0].get("MAX(mood)"), Some(9.0));
+            assert_eq!(result.rows[0].get("MIN(mood)"), Some(2.0));
+            assert_eq!(result.rows[0].get("p50"), Some(4.0));
+            assert_eq!(result.rows[0].get("mood"), Some(9.0));
+        }
+
+        // An aggregation alone keeps its metric's name
+        let result = executor
+            .execute_str("SELECT AVG(mood) WHERE time >= now() - 2d")
+            .await
+            .unwrap();
+        assert_eq!(result.columns, vec!["mood"]);
+        assert_eq!(result.rows[0].get("mood"), Some(5.0));
+
+        let err = executor
+            .execute_str("SELECT AVG(mood) AS m, MAX(mood) AS m WHERE time >= now() - 2d")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Duplicate column 'm'"));
+    }
+
+    #[tokio::test]
+    async fn test_filter_query() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new(
+                "mood",
+                "1-10",
+                Category::Mood,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+
+        // Write data with tags and distinct timestamps
+        let now = chrono::Utc::now().timestamp_millis();
+        for i in 0..10 {
+            let location = if i % 2 == 0 { "home" } else { "office" };
+            let point = DataPoint::with_timestamp(metric_id, 5.0 + i as f64 * 0.5, now - (10 - i) * 1000)
+                .tag("location", location);
+            engine.write(point).await.unwrap();
+        }
+        engine.flush().await.unwrap();
+
+        // Query with tag filter
+        let query = Query::select(&["mood"])
+            .last_days(1)
             .filter_tag("location", "home")
             .build();
 
@@ -720,4 +1431,634 @@ mod tests {
             assert_eq!(*val, i as f64);
         }
     }
+
+    #[tokio::test]
+    async fn test_boolean_filter_query() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new(
+                "mood",
+                "1-10",
+                Category::Mood,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let locations = ["home", "office", "cafe", "gym"];
+        for (i, location) in locations.iter().enumerate() {
+            // One block per location, so the tag index can prune
+            let point = DataPoint::with_timestamp(metric_id, i as f64, now - 10_000 + i as i64)
+                .tag("location", *location);
+            engine.write(point).await.unwrap();
+            engine.flush().await.unwrap();
+        }
+
+        let values = |result: QueryResult2| -> Vec<f64> {
+            result.to_time_series().into_iter().map(|(_, v)| v).collect()
+        };
+
+        let result = executor
+            .execute_str("SELECT mood WHERE tags.location = 'office' OR tags.location = 'cafe'")
+            .await
+            .unwrap();
+        assert_eq!(values(result), vec![1.0, 2.0]);
+
+        let (_, stats) = engine
+            .query_with_stats(
+                TimeRange::last_days(1),
+                Some(crate::storage::QueryFilter::new().tag_in("location", ["office", "cafe"])),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stats.blocks_read, 2);
+
+        let result = executor
+            .execute_str("SELECT mood WHERE tags.location NOT IN ('home', 'gym') AND NOT value = 1")
+            .await
+            .unwrap();
+        assert_eq!(values(result), vec![2.0]);
+
+        let result = executor
+            .execute_str("SELECT mood WHERE tags.location IN ('home', 'cafe') AND tags.location = 'home'")
+            .await
+            .unwrap();
+        assert_eq!(values(result), vec![0.0]);
+    }
+
+    #[tokio::test]
+    async fn test_group_by_tag_query() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new(
+                "mood",
+                "1-10",
+                Category::Mood,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+
+        let day_ms = 24 * 3600 * 1000;
+        let today = GroupByInterval::Day.truncate(chrono::Utc::now().timestamp_millis());
+        let points = [
+            (today - 2 * day_ms + 1000, 4.0, Some("home")),
+            (today - 2 * day_ms + 2000, 6.0, Some("home")),
+            (today - 2 * day_ms + 3000, 8.0, Some("office")),
+            (today - day_ms + 1000, 7.0, Some("home")),
+            (today - day_ms + 2000, 3.0, None),
+        ];
+        for (timestamp, value, location) in points {
+            let mut point = DataPoint::with_timestamp(metric_id, value, timestamp);
+            if let Some(location) = location {
+                point = point.tag("location", location);
+            }
+            engine.write(point).await.unwrap();
+        }
+
+        let result = executor
+            .execute_str("SELECT AVG(mood) WHERE time >= now() - 7d GROUP BY day, tags.location")
+            .await
+            .unwrap();
+
+        let series = result.series();
+        let summary: Vec<(String, Vec<(i64, f64)>)> = series
+            .iter()
+            .map(|s| {
+                let rows = s.rows.iter().map(|r| (r.timestamp, r.values["mood"])).collect();
+                (s.tags["location"].clone(), rows)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("".to_string(), vec![(today - day_ms, 3.0)]),
+                (
+                    "home".to_string(),
+                    vec![(today - 2 * day_ms, 5.0), (today - day_ms, 7.0)]
+                ),
+                ("office".to_string(), vec![(today - 2 * day_ms, 8.0)]),
+            ]
+        );
+
+        // LIMIT applies to each series
+        let result = executor
+            .execute_str(
+                "SELECT AVG(mood) WHERE time >= now() - 7d GROUP BY day, tags.location LIMIT 1",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.len(), 3);
+        assert_eq!(result.series().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fill_empty_buckets() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new(
+                "mood",
+                "1-10",
+                Category::Mood,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+
+        // Points on days 1 and 4 of a fixed five-day range
+        let day_ms = 24 * 3600 * 1000;
+        let start = 1_704_067_200_000; // 2024-01-01
+        engine
+            .write(DataPoint::with_timestamp(metric_id, 2.0, start + day_ms + 1000))
+            .await
+            .unwrap();
+        engine
+            .write(DataPoint::with_timestamp(metric_id, 8.0, start + 4 * day_ms + 1000))
+            .await
+            .unwrap();
+
+        let run = |fill: FillMode| {
+            let query = Query::select(&["mood"])
+                .time_range(TimeRange::new(start, start + 5 * day_ms))
+                .group_by_clause(GroupByClause::new(GroupByInterval::Day).fill(fill))
+                .with_aggregation(AggregationFunc::Avg)
+                .build();
+            let executor = &executor;
+            async move {
+                let result = executor.execute(query).await.unwrap();
+                result
+                    .rows
+                    .iter()
+                    .map(|row| row.get("mood"))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(run(FillMode::None).await, vec![Some(2.0), Some(8.0)]);
+        assert_eq!(
+            run(FillMode::Null).await,
+            vec![None, Some(2.0), None, None, Some(8.0)]
+        );
+        assert_eq!(
+            run(FillMode::Previous).await,
+            vec![None, Some(2.0), Some(2.0), Some(2.0), Some(8.0)]
+        );
+        assert_eq!(
+            run(FillMode::Linear).await,
+            vec![None, Some(2.0), Some(4.0), Some(6.0), Some(8.0)]
+        );
+        assert_eq!(
+            run(FillMode::Value(0.0)).await,
+            vec![Some(0.0), Some(2.0), Some(0.0), Some(0.0), Some(8.0)]
+        );
+
+        // A range without any points still gets its buckets
+        let query = Query::select(&["mood"])
+            .time_range(TimeRange::new(start + 10 * day_ms, start + 13 * day_ms))
+            .group_by_clause(GroupByClause::new(GroupByInterval::Day).fill(FillMode::Value(0.0)))
+            .with_aggregation(AggregationFunc::Avg)
+            .build();
+        let result = executor.execute(query).await.unwrap();
+        let values: Vec<_> = result.rows.iter().map(|row| row.get("mood")).collect();
+        assert_eq!(values, vec![Some(0.0); 3]);
+        assert_eq!(result.rows[0].timestamp, start + 10 * day_ms);
+
+        // Too many buckets is an error rather than a huge result
+        let query = Query::select(&["mood"])
+            .time_range(TimeRange::new(0, start))
+            .group_by_clause(GroupByClause::new(GroupByInterval::every(1000)).fill(FillMode::Null))
+            .with_aggregation(AggregationFunc::Avg)
+            .build();
+        assert!(matches!(
+            executor.execute(query).await,
+            Err(QueryError::Execution(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expressions_and_computed_metrics() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let register = |name: &'static str| {
+            let engine = engine.clone();
+            async move {
+                engine
+                    .register_metric(Metric::new(
+                        name,
+                        "kcal",
+                        Category::Health,
+                        AggregationType::Sum,
+                    ))
+                    .await
+                    .unwrap()
+            }
+        };
+        let calories_in = register("calories_in").await;
+        let calories_out = register("calories_out").await;
+
+        let day_ms = 24 * 3600 * 1000;
+        let day = GroupByInterval::Day.truncate(chrono::Utc::now().timestamp_millis()) - day_ms;
+        for (metric_id, value, offset) in [
+            (calories_in, 800.0, 1000),
+            (calories_in, 1200.0, 2000),
+            (calories_out, 1500.0, 1000),
+        ] {
+            engine
+                .write(DataPoint::with_timestamp(metric_id, value, day + offset))
+                .await
+                .unwrap();
+        }
+
+        let result = executor
+            .execute_str(
+                "SELECT SUM(calories_in) - SUM(calories_out) AS balance, \
+                 AVG(calories_in) / 1000 WHERE time >= now() - 3d GROUP BY day",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.columns, vec!["balance", "AVG(calories_in) / 1000"]);
+        assert_eq!(result.rows.len(), 1);
+        assert_eq!(result.rows[0].get("balance"), Some(500.0));
+        assert_eq!(result.rows[0].get("AVG(calories_in) / 1000"), Some(1.0));
+
+        // Without GROUP BY, metrics are aligned on identical timestamps
+        let result = executor
+            .execute_str("SELECT calories_in - calories_out AS net WHERE time >= now() - 3d")
+            .await
+            .unwrap();
+        assert_eq!(result.to_time_series(), vec![(day + 1000, -700.0)]);
+
+        // Computed metrics are queried like stored ones
+        engine
+            .register_metric(
+                Metric::new("balance", "kcal", Category::Health, AggregationType::Sum)
+                    .computed("SUM(calories_in) - SUM(calories_out)"),
+            )
+            .await
+            .unwrap();
+        let intake_mj = engine
+            .register_metric(
+                Metric::new("intake_mj", "MJ", Category::Health, AggregationType::Sum)
+                    .computed("calories_in * 0.004184"),
+            )
+            .await
+            .unwrap();
+
+        let result = executor
+            .execute_str(
+                "SELECT balance, SUM(intake_mj) AS mj WHERE time >= now() - 3d GROUP BY day",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.rows[0].get("balance"), Some(500.0));
+        assert!((result.rows[0].get("mj").unwrap() - 2000.0 * 0.004184).abs() < 1e-9);
+
+        let err = executor
+            .execute_str("SELECT AVG(balance) GROUP BY day")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, QueryError::InvalidAggregation(_)));
+
+        // Computed metrics hold no points of their own
+        let err = engine
+            .write(DataPoint::new(intake_mj, 1.0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, crate::storage::StorageError::InvalidPoint(_)));
+    }
+
+    #[tokio::test]
+    async fn test_window_functions() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new(
+                "weight",
+                "kg",
+                Category::Health,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+
+        // Daily weights over four days, with day 3 missing and two readings on day 4
+        let day_ms = 24 * 3600 * 1000;
+        let today = GroupByInterval::Day.truncate(chrono::Utc::now().timestamp_millis());
+        for (days_ago, value) in [(5, 70.0), (4, 71.0), (2, 70.0), (2, 72.0), (1, 73.0)] {
+            engine
+                .write(DataPoint::with_timestamp(metric_id, value, today - days_ago * day_ms + 1000))
+                .await
+                .unwrap();
+        }
+
+        let result = executor
+            .execute_str(
+                "SELECT MOVING_AVG(AVG(weight), 2) AS avg2, DERIVATIVE(AVG(weight), 1d) AS rate, \
+                 DIFFERENCE(AVG(weight)) AS diff, CUMULATIVE_SUM(COUNT(weight)) AS total, \
+                 EWMA(AVG(weight), 0.5) AS smooth WHERE time >= now() - 7d GROUP BY day",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.columns, vec!["avg2", "rate", "diff", "total", "smooth"]);
+
+        let column = |name: &str| -> Vec<Option<f64>> {
+            result.rows.iter().map(|row| row.get(name)).collect()
+        };
+        assert_eq!(result.rows.len(), 4);
+        assert_eq!(column("avg2"), vec![None, Some(70.5), Some(71.0), Some(72.0)]);
+        assert_eq!(column("rate"), vec![None, Some(1.0), Some(0.0), Some(2.0)]);
+        assert_eq!(column("diff"), vec![None, Some(1.0), Some(0.0), Some(2.0)]);
+        assert_eq!(column("total"), vec![Some(1.0), Some(2.0), Some(4.0), Some(5.0)]);
+        assert_eq!(
+            column("smooth"),
+            vec![Some(70.0), Some(70.5), Some(70.75), Some(71.875)]
+        );
+
+        // Windows see filled buckets, and LIMIT applies afterwards
+        let result = executor
+            .execute_str(
+                "SELECT CUMULATIVE_SUM(COUNT(weight)) AS total \
+                 WHERE time >= now() - 7d GROUP BY day FILL(0) LIMIT 3",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.rows.len(), 3);
+        assert!(result.rows.iter().all(|row| row.get("total").is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_order_offset_and_top() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new("steps", "count", Category::Health, AggregationType::Sum))
+            .await
+            .unwrap();
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let values = [4000.0, 9000.0, 6000.0, 12000.0, 3000.0];
+        for (i, value) in values.iter().enumerate() {
+            engine
+                .write(DataPoint::with_timestamp(metric_id, *value, now - 10_000 + i as i64 * 1000))
+                .await
+                .unwrap();
+        }
+
+        let steps = |result: &QueryResult2| -> Vec<f64> {
+            result.rows.iter().filter_map(|row| row.get("steps")).collect()
+        };
+
+        // Latest entries first, paged with OFFSET
+        let result = executor
+            .execute_str("SELECT steps ORDER BY time DESC LIMIT 2")
+            .await
+            .unwrap();
+        assert_eq!(steps(&result), vec![3000.0, 12000.0]);
+        let result = executor
+            .execute_str("SELECT steps ORDER BY time DESC LIMIT 2 OFFSET 2")
+            .await
+            .unwrap();
+        assert_eq!(steps(&result), vec![6000.0, 9000.0]);
+
+        let result = executor
+            .execute_str("SELECT steps ORDER BY value DESC LIMIT 3")
+            .await
+            .unwrap();
+        assert_eq!(steps(&result), vec![12000.0, 9000.0, 6000.0]);
+
+        // TOP keeps the largest values in time order
+        let result = executor.execute_str("SELECT TOP(steps, 2) AS top").await.unwrap();
+        let top: Vec<f64> = result.rows.iter().filter_map(|row| row.get("top")).collect();
+        assert_eq!(top, vec![9000.0, 12000.0]);
+
+        let err = executor
+            .execute_str("SELECT steps ORDER BY calories")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, QueryError::Execution(_)));
+        let err = executor
+            .execute_str("SELECT TOP(steps, 1), TOP(steps, 2)")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, QueryError::Execution(_)));
+    }
+
+    #[tokio::test]
+    async fn test_explain() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        // One flushed block per location
+        let now = chrono::Utc::now().timestamp_millis();
+        for (i, location) in ["office", "home"].iter().enumerate() {
+            for j in 0..5 {
+                let timestamp = now - 10_000 + i as i64 * 10 + j;
+                let point = DataPoint::with_timestamp(metric_id, j as f64, timestamp)
+                    .tag("location", *location);
+                engine.write(point).await.unwrap();
+            }
+            engine.flush().await.unwrap();
+        }
+
+        // EXPLAIN plans the read without running it
+        let result = executor
+            .execute_str(
+                "EXPLAIN SELECT AVG(mood) WHERE tags.location = 'office' AND value > 1 \
+                 GROUP BY day",
+            )
+            .await
+            .unwrap();
+        let stats = &result.stats;
+        assert!(result.rows.is_empty());
+        assert!(!stats.analyzed);
+        assert_eq!(stats.scans.len(), 1);
+        assert_eq!(stats.scans[0].pushed_down, vec!["tags.location = 'office'"]);
+        assert_eq!(stats.scans[0].stats.blocks_pruned_by_index, 1);
+        assert_eq!(stats.scans[0].stats.segments.len(), 1);
+        assert_eq!(stats.scans[0].stats.points_scanned, 0);
+        assert_eq!(stats.filter.as_deref(), Some("tags.location = 'office' AND value > 1"));
+        assert!(stats.to_string().contains("Scan mood [index: tags.location = 'office']"));
+
+        // EXPLAIN ANALYZE runs it and reports what it did
+        let result = executor
+            .execute_str("EXPLAIN ANALYZE SELECT mood WHERE tags.location = 'office' AND value > 1")
+            .await
+            .unwrap();
+        let stats = &result.stats;
+        assert!(result.rows.is_empty());
+        assert!(stats.analyzed);
+        assert_eq!(stats.blocks_read(), 1);
+        assert_eq!(stats.points_scanned(), 5);
+        assert_eq!(stats.points_read, 5);
+        assert_eq!(stats.points_matched, 3);
+        assert_eq!(stats.rows_returned, 3);
+        assert!(stats.to_string().contains("5 scanned, 5 read, 3 matched; 3 rows returned"));
+
+        // Plain queries return their rows along with the same statistics
+        let result = executor
+            .execute_str("SELECT mood WHERE tags.location = 'office' AND value > 1")
+            .await
+            .unwrap();
+        assert_eq!(result.rows.len(), 3);
+        assert_eq!(result.stats.rows_returned, 3);
+    }
+
+    #[tokio::test]
+    async fn test_subquery_and_statements() {
+        let (executor, engine, _dir) = create_test_executor().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new(
+                "heart_rate",
+                "bpm",
+                Category::Health,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+
+        // Two readings on each of four days, at two locations
+        let day_ms = 24 * 3600 * 1000;
+        let today = GroupByInterval::Day.truncate(chrono::Utc::now().timestamp_millis());
+        for day in 1..=4 {
+            let readings = [(1000, 60.0, "home"), (2000, 100.0 + day as f64, "gym")];
+            for (offset, value, location) in readings {
+                let timestamp = today - day * day_ms + offset;
+                let point = DataPoint::with_timestamp(metric_id, value, timestamp)
+                    .tag("location", location);
+                engine.write(point).await.unwrap();
+            }
+        }
+
+        // Average of the daily peaks over the whole range
+        let result = executor
+            .execute_str(
+                "SELECT AVG(peak) AS avg_peak, COUNT(peak) AS days FROM \
+                 (SELECT MAX(heart_rate) AS peak WHERE time >= now() - 7d GROUP BY day) \
+                 GROUP BY time(28d)",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.columns, vec!["avg_peak", "days"]);
+        let total: f64 = result.rows.iter().filter_map(|row| row.get("days")).sum();
+        assert_eq!(total, 4.0);
+        let peaks: Vec<f64> = result.rows.iter().filter_map(|row| row.get("avg_peak")).collect();
+        assert_eq!(peaks, [102.5]);
+
+        // Subquery tags carry over, and outer filters apply to its rows
+        let result = executor
+            .execute_str(
+                "SELECT peak FROM (SELECT MAX(heart_rate) AS peak WHERE time >= now() - 7d \
+                 GROUP BY day, tags.location) WHERE tags.location = 'gym' AND value > 102",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.to_time_series().len(), 2);
+
+        let err = executor
+            .execute_str("SELECT heart_rate FROM (SELECT MAX(heart_rate) AS peak GROUP BY day)")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, QueryError::MetricNotFound(_)));
+
+        // EXPLAIN plans the subquery too
+        let result = executor
+            .execute_str(
+                "EXPLAIN SELECT AVG(peak) FROM (SELECT MAX(heart_rate) AS peak GROUP BY day)",
+            )
+            .await
+            .unwrap();
+        let source = result.stats.source.as_deref().unwrap();
+        assert!(!source.analyzed);
+        assert_eq!(source.scans[0].metric, "heart_rate");
+        assert!(result.stats.to_string().starts_with("Subquery\n  Scan heart_rate"));
+
+        let statements = crate::query::parse_statements(
+            "SELECT COUNT(heart_rate) AS n GROUP BY time(28d); SELECT heart_rate LIMIT 3",
+        )
+        .unwrap();
+        let results = executor.execute_all(statements).await.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].rows.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_query_cache() {
+        let (executor, engine, _dir) = create_test_executor().await;
+        let executor = executor.with_cache(QueryCacheConfig::default());
+
+        let metric_id = engine
+            .register_metric(Metric::new("steps", "count", Category::Health, AggregationType::Sum))
+            .await
+            .unwrap();
+
+        // One point per hour for ten hours
+        let hour_ms = 3600 * 1000;
+        let base = GroupByInterval::Hour.truncate(chrono::Utc::now().timestamp_millis())
+            - 12 * hour_ms;
+        for hour in 0..10 {
+            let timestamp = base + hour * hour_ms + 60;
+            let point = DataPoint::with_timestamp(metric_id, hour as f64, timestamp);
+            engine.write(point).await.unwrap();
+        }
+        engine.flush().await.unwrap();
+
+        // Identical raw queries are served from the cache
+        let range = TimeRange::new(base, base + 10 * hour_ms);
+        let raw = Query::select(&["steps"]).time_range(range).build();
+        let first = executor.execute(raw.clone()).await.unwrap();
+        let second = executor.execute(raw.clone()).await.unwrap();
+        assert!(!first.stats.cached);
+        assert!(second.stats.cached);
+        assert_eq!(second.rows.len(), 10);
+
+        // A shifted GROUP BY range reuses the complete buckets it shares
+        let hourly = |start: i64, end: i64| {
+            Query::select(&["steps"])
+                .time_range(TimeRange::new(start, end))
+                .group_by(GroupByInterval::Hour)
+                .with_aggregation(AggregationFunc::Sum)
+                .build()
+        };
+        executor.execute(hourly(base, base + 6 * hour_ms)).await.unwrap();
+        let shifted = executor
+            .execute(hourly(base + 2 * hour_ms + 30, base + 9 * hour_ms))
+            .await
+            .unwrap();
+        assert_eq!(shifted.stats.buckets_cached, 3);
+        assert!(shifted.stats.to_string().contains("Cache: 3 buckets"));
+
+        let uncached = QueryExecutor::new(Arc::clone(&engine))
+            .execute(hourly(base + 2 * hour_ms + 30, base + 9 * hour_ms))
+            .await
+            .unwrap();
+        let sums = |result: &QueryResult2| -> Vec<(i64, Option<f64>)> {
+            result.rows.iter().map(|row| (row.timestamp, row.get("steps"))).collect()
+        };
+        assert_eq!(shifted.rows.len(), 7);
+        assert_eq!(sums(&shifted), sums(&uncached));
+
+        // Writes invalidate what they overlap
+        let point = DataPoint::with_timestamp(metric_id, 100.0, base + 4 * hour_ms + 120);
+        engine.write(point).await.unwrap();
+        let result = executor.execute(raw).await.unwrap();
+        assert!(!result.stats.cached);
+        assert_eq!(result.rows.len(), 11);
+
+        let result = executor.execute(hourly(base, base + 6 * hour_ms)).await.unwrap();
+        assert_eq!(result.stats.buckets_cached, 5);
+        assert_eq!(result.rows[4].get("steps"), Some(104.0));
+
+        let stats = executor.cache().unwrap().stats();
+        assert_eq!(stats.hits, 1);
+        assert!(stats.invalidations >= 2);
+    }
 }
@@ -0,0 +1,122 @@
+//! Query Statistics
+//!
+//! Describes how a query was planned and executed: the segments and blocks
+//! each metric read, which filters were pushed down to the index, and where
+//! the time went. Returned with every result and rendered by `EXPLAIN`.
+
+use crate::storage::ScanStats;
+use serde::Serialize;
+use std::fmt;
+
+/// How a query was (or would be) executed
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryStats {
+    /// Whether the query ran (false for a plain `EXPLAIN`)
+    pub analyzed: bool,
+    /// One storage read per metric
+    pub scans: Vec<MetricScan>,
+    /// WHERE condition evaluated on the points read
+    pub filter: Option<String>,
+    /// Points returned by the storage reads (or taken from the subquery rows)
+    pub points_read: usize,
+    /// Points left after the WHERE condition
+    pub points_matched: usize,
+    /// Rows in the result
+    pub rows_returned: usize,
+    /// Time spent aggregating, filling and applying window functions, in microseconds
+    pub aggregate_us: u64,
+    /// Whether the whole result came from the query cache
+    pub cached: bool,
+    /// GROUP BY buckets taken from the query cache
+    pub buckets_cached: usize,
+    /// Statistics of the FROM subquery read instead of storage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Box<QueryStats>>,
+}
+
+impl QueryStats {
+    /// Blocks read across all metrics
+    pub fn blocks_read(&self) -> usize {
+        self.scans.iter().map(|s| s.stats.blocks_read).sum()
+    }
+
+    /// Points decompressed across all metrics
+    pub fn points_scanned(&self) -> usize {
+        self.scans.iter().map(|s| s.stats.points_scanned).sum()
+    }
+
+    /// Time spent decompressing blocks across all metrics, in microseconds
+    pub fn decompress_us(&self) -> u64 {
+        self.scans.iter().map(|s| s.stats.decompress_us).sum()
+    }
+}
+
+/// Storage read for one metric
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricScan {
+    /// Metric name
+    pub metric: String,
+    /// Tag conditions used to narrow the read through the index
+    pub pushed_down: Vec<String>,
+    /// Blocks chosen and points read
+    #[serde(flatten)]
+    pub stats: ScanStats,
+}
+
+impl fmt::Display for QueryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(source) = &self.source {
+            writeln!(f, "Subquery")?;
+            for line in source.to_string().lines() {
+                writeln!(f, "  {}", line)?;
+            }
+        }
+        for scan in &self.scans {
+            write!(f, "Scan {}", scan.metric)?;
+            if !scan.pushed_down.is_empty() {
+                write!(f, " [index: {}]", scan.pushed_down.join(" AND "))?;
+            }
+            writeln!(f)?;
+
+            let stats = &scan.stats;
+            writeln!(
+                f,
+                "  blocks: {} of {} chosen ({} pruned by time, {} by index)",
+                stats.blocks_total - stats.blocks_pruned(),
+                stats.blocks_total,
+                stats.blocks_pruned_by_time,
+                stats.blocks_pruned_by_index
+            )?;
+            for segment in &stats.segments {
+                let blocks: Vec<String> = segment.blocks.iter().map(|b| b.to_string()).collect();
+                writeln!(f, "    {}: blocks {}", segment.segment, blocks.join(", "))?;
+            }
+        }
+        if let Some(filter) = &self.filter {
+            writeln!(f, "Filter: {}", filter)?;
+        }
+
+        if self.analyzed {
+            writeln!(
+                f,
+                "Points: {} scanned, {} read, {} matched; {} rows returned",
+                self.points_scanned(),
+                self.points_read,
+                self.points_matched,
+                self.rows_returned
+            )?;
+            writeln!(
+                f,
+                "Time: {:.3} ms decompressing, {:.3} ms aggregating",
+                self.decompress_us() as f64 / 1000.0,
+                self.aggregate_us as f64 / 1000.0
+            )?;
+            if self.cached {
+                writeln!(f, "Cache: whole result")?;
+            } else if self.buckets_cached > 0 {
+                writeln!(f, "Cache: {} buckets", self.buckets_cached)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -5,15 +5,24 @@
 //! - **AST**: Query abstract syntax tree types
 //! - **Parser**: Parse query strings into AST
 //! - **Executor**: Execute queries against storage
+//! - **Explain**: Plans and execution statistics
+//! - **Continuous**: Continuous queries stored as derived metrics
+//! - **Cache**: Results and GROUP BY buckets, invalidated by writes
+//! - **Sketch**: Mergeable aggregation state (moments, quantiles, distinct counts)
 //!
 //! # Query Language
 //!
 //! ```text
-//! SELECT metric [, metric2, ...]
+//! [EXPLAIN [ANALYZE]]
+//! SELECT metric | AGG(metric) | PERCENTILE(metric, 0.95) [, ...]
+//! [FROM (SELECT ...)]
 //! [WHERE time >= now() - 7d]
-//! [AND tags.location = 'office']
-//! [GROUP BY day|hour|week|month]
-//! [LIMIT n]
+//! [AND (tags.location IN ('office', 'cafe') OR NOT value < 5)]
+//! [GROUP BY day|hour|week|month|15m|time(1d, 'Europe/Berlin') [, tags.key] [FILL(linear)]]
+//! [ORDER BY time|value|column [ASC|DESC]]
+//! [LIMIT n] [OFFSET n]
+//!
+//! CREATE CONTINUOUS QUERY name AS SELECT AGG(metric) ... GROUP BY ...
 //! ```
 //!
 //! # Examples
@@ -46,14 +55,26 @@
 //! ```
 
 mod ast;
+mod cache;
+mod continuous;
 mod error;
 mod executor;
+mod explain;
 mod parser;
+mod sketch;
 
 pub use ast::{
-    AggregationFunc, Filter, FilterField, FilterValue, GroupByClause, GroupByInterval, Operator,
-    Query, QueryBuilder, SelectItem,
+    AggregationFunc, ArithOp, Comparison, ContinuousQuery, ExplainMode, FillMode, Filter,
+    FilterExpr, FilterField, FilterValue, GroupByClause, GroupByInterval, Operator, OrderBy,
+    OrderKey, Query, QueryBuilder, SelectExpr, SelectItem, WindowFunc,
 };
+pub use cache::{CacheStats, QueryCache, QueryCacheConfig};
+pub use continuous::ContinuousQueryTask;
 pub use error::{QueryError, QueryResult};
-pub use executor::{QueryExecutor, QueryResult2 as QueryResultData, ResultRow};
-pub use parser::parse_query;
+pub use executor::{QueryExecutor, QueryResult2 as QueryResultData, ResultRow, ResultSeries};
+pub use explain::{MetricScan, QueryStats};
+pub use parser::{
+    parse_continuous_definition, parse_continuous_query, parse_expression, parse_query,
+    parse_statements,
+};
+pub use sketch::{Aggregator, DistinctSketch, Moments, QuantileSketch};
@@ -5,29 +5,70 @@
 //! # Supported Syntax
 //!
 //! ```text
-//! SELECT metric [, metric2, ...]
+//! [EXPLAIN [ANALYZE]]
+//! SELECT metric | AGG(metric) | PERCENTILE(metric, 0.95) | expression [AS alias] [, ...]
+//! [FROM (subquery)]
 //! [WHERE time >= now() - 7d]
 //! [AND tags.location = 'office']
-//! [GROUP BY day|hour|week|month]
-//! [LIMIT n]
+//! [GROUP BY day|hour|week|month|15m|time(1d[, 4h][, 'Europe/Berlin']) [, tags.key ...]
+//!     [FILL(none|null|previous|linear|<value>)]]
+//! [ORDER BY time|value|column [ASC|DESC]]
+//! [LIMIT n] [OFFSET n]
 //! ```
 //!
+//! `parse_continuous_query` reads `CREATE CONTINUOUS QUERY name AS SELECT ...`,
+//! which stores the SELECT's results in the metric `name` (see
+//! `ContinuousQuery`).
+//!
+//! Conditions combine with `AND`, `OR` and `NOT` (binding tightest first:
+//! `NOT`, `AND`, `OR`) and can be grouped with parentheses. Tags and values
+//! can be matched against lists with `IN (...)` and `NOT IN (...)`. Time
+//! conditions must be ANDed with the rest of the WHERE clause; several of
+//! them narrow the range to their intersection.
+//!
+//! SELECT items can combine metrics, aggregations and numbers with `+`, `-`,
+//! `*`, `/` and parentheses. Names of computed metrics are expanded into
+//! their stored expression by the executor. An item can be wrapped in one
+//! window function: `MOVING_AVG(x, n)`, `DERIVATIVE(x[, unit])`,
+//! `DIFFERENCE(x)`, `CUMULATIVE_SUM(x)` or `EWMA(x, alpha)`, and selected
+//! with `TOP(x, n)` to keep the n rows where it is largest.
+//!
+//! `FROM (subquery)` selects from the columns of another query's result,
+//! which keep its GROUP BY tags; without a time condition of its own the
+//! outer query covers the subquery's range. `parse_statements` accepts
+//! several queries separated by `;`.
+//!
 //! # Examples
 //!
 //! ```text
 //! SELECT mood
 //! SELECT mood WHERE time >= now() - 7d
 //! SELECT AVG(mood) GROUP BY day
+//! SELECT MEDIAN(sleep), PERCENTILE(heart_rate, 0.95) AS p95 GROUP BY week
+//! SELECT AVG(heart_rate) GROUP BY 15m
+//! SELECT AVG(mood) GROUP BY time(1d, 'Europe/Berlin')
+//! SELECT AVG(mood) GROUP BY week, tags.location
+//! SELECT AVG(mood) WHERE time >= now() - 30d GROUP BY day FILL(previous)
 //! SELECT mood, energy WHERE tags.location = 'office'
+//! SELECT AVG(sleep_hours) * 60 AS sleep_min GROUP BY day
+//! SELECT MOVING_AVG(AVG(mood), 7) AS weekly_mood GROUP BY day
+//! SELECT mood ORDER BY time DESC LIMIT 20 OFFSET 20
+//! SELECT TOP(SUM(steps), 3) WHERE time >= now() - 30d GROUP BY day
+//! EXPLAIN ANALYZE SELECT mood WHERE tags.location = 'office'
+//! SELECT AVG(peak) FROM (SELECT MAX(heart_rate) AS peak GROUP BY day) GROUP BY week
+//! SELECT mood WHERE tags.location = 'office' OR tags.location = 'cafe'
+//! SELECT mood WHERE time >= now() - 7d AND tags.location NOT IN ('home', 'gym')
+//! SELECT mood WHERE NOT (tags.source = 'import' AND value < 2)
 //! ```
 
 use nom::{
     branch::alt,
     bytes::complete::{tag, tag_no_case, take_while, take_while1},
-    character::complete::{char, digit1, multispace0, multispace1},
-    combinator::{map, map_res, opt, recognize, value},
-    multi::separated_list1,
-    sequence::{delimited, pair, tuple},
+    character::complete::{char, digit1, multispace0, multispace1, satisfy},
+    combinator::{map, map_res, not, opt, recognize, value, verify},
+    error::{Error, ErrorKind},
+    multi::{many0, separated_list1},
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
@@ -35,15 +76,22 @@ use crate::query::ast::*;
 use crate::query::error::{QueryError, QueryResult};
 use crate::storage::TimeRange;
 use chrono::Utc;
+use std::cell::Cell;
+
+/// Deepest nesting of parentheses, subqueries and NOT or `-` prefixes
+const MAX_NESTING: usize = 32;
+
+/// Most `;`-separated statements in one input
+const MAX_STATEMENTS: usize = 100;
 
 /// Parse a query string into a Query AST
 pub fn parse_query(input: &str) -> QueryResult<Query> {
     let input = input.trim();
 
     match parse_full_query(input) {
-        Ok((remaining, query)) => {
+        Ok((remaining, parsed)) => {
             if remaining.trim().is_empty() {
-                Ok(query)
+                parsed.resolve()
             } else {
                 Err(QueryError::Parse(format!(
                     "Unexpected input after query: '{}'",
@@ -51,40 +99,247 @@ pub fn parse_query(input: &str) -> QueryResult<Query> {
                 )))
             }
         }
-        Err(e) => Err(QueryError::Parse(format!("Parse error: {:?}", e))),
+        Err(e) => Err(parse_error(e)),
+    }
+}
+
+/// Parse `;`-separated queries (a trailing `;` is allowed)
+pub fn parse_statements(input: &str) -> QueryResult<Vec<Query>> {
+    let input = input.trim();
+    let separator = |input| delimited(multispace0, char(';'), multispace0)(input);
+
+    match terminated(separated_list1(separator, parse_full_query), opt(separator))(input) {
+        Ok((_, statements)) if statements.len() > MAX_STATEMENTS => Err(QueryError::Parse(
+            format!("Too many statements: at most {} are allowed", MAX_STATEMENTS),
+        )),
+        Ok((remaining, statements)) => {
+            if remaining.trim().is_empty() {
+                statements.into_iter().map(ParsedQuery::resolve).collect()
+            } else {
+                Err(QueryError::Parse(format!(
+                    "Unexpected input after statement {}: '{}'",
+                    statements.len(),
+                    remaining.trim()
+                )))
+            }
+        }
+        Err(e) => Err(parse_error(e)),
+    }
+}
+
+/// Parse `CREATE CONTINUOUS QUERY name AS SELECT ...`
+pub fn parse_continuous_query(input: &str) -> QueryResult<ContinuousQuery> {
+    let mut prefix = tuple((
+        delimited(multispace0, keyword("CREATE"), multispace1),
+        terminated(keyword("CONTINUOUS"), multispace1),
+        terminated(keyword("QUERY"), multispace1),
+        terminated(parse_identifier, multispace1),
+        terminated(keyword("AS"), multispace1),
+    ));
+
+    match prefix(input) {
+        Ok((select, (_, _, _, name, _))) => parse_continuous_definition(name, select),
+        Err(e) => Err(parse_error(e)),
+    }
+}
+
+/// Parse and check the SELECT statement of a continuous query
+pub fn parse_continuous_definition(
+    name: impl Into<String>,
+    text: &str,
+) -> QueryResult<ContinuousQuery> {
+    let text = text.trim().trim_end_matches(';').trim_end();
+    let parsed = match parse_full_query(text) {
+        Ok((remaining, parsed)) if remaining.trim().is_empty() => parsed,
+        Ok((remaining, _)) => {
+            return Err(QueryError::Parse(format!(
+                "Unexpected input after query: '{}'",
+                remaining.trim()
+            )))
+        }
+        Err(e) => return Err(parse_error(e)),
+    };
+    if parsed.has_time_condition()? {
+        return Err(QueryError::InvalidContinuousQuery(
+            "time conditions are not supported".to_string(),
+        ));
     }
+    ContinuousQuery::new(name, text, parsed.resolve()?)
 }
 
-/// Parse the full query
-fn parse_full_query(input: &str) -> IResult<&str, Query> {
+/// Convert a nom error, explaining nesting, percentile and time zone failures
+fn parse_error(e: nom::Err<Error<&str>>) -> QueryError {
+    match e {
+        nom::Err::Failure(Error {
+            code: ErrorKind::TooLarge,
+            ..
+        }) => QueryError::Parse(format!(
+            "Query is nested too deeply: at most {} levels are allowed",
+            MAX_NESTING
+        )),
+        nom::Err::Failure(Error {
+            input,
+            code: ErrorKind::Verify,
+        }) => QueryError::Parse(format!(
+            "Percentile must be in [0, 1], got {}",
+            parse_number(input).map_or(0.0, |(_, q)| q)
+        )),
+        nom::Err::Failure(Error {
+            input,
+            code: ErrorKind::MapRes,
+        }) => QueryError::Parse(format!(
+            "Unknown time zone '{}'",
+            parse_quoted_string(input).map_or_else(|_| String::new(), |(_, name)| name)
+        )),
+        e => QueryError::Parse(format!("Parse error: {:?}", e)),
+    }
+}
+
+thread_local! {
+    /// Nesting depth of the parse running on this thread
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Leaves a nesting level when dropped
+struct NestingGuard;
+
+impl Drop for NestingGuard {
+    fn drop(&mut self) {
+        NESTING.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Run `parser` one nesting level deeper, failing past `MAX_NESTING`
+///
+/// The failure is not backtracked over, so it ends the parse.
+fn nested<'a, O>(
+    mut parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    move |input| {
+        let depth = NESTING.with(|depth| {
+            depth.set(depth.get() + 1);
+            depth.get()
+        });
+        let _guard = NestingGuard;
+        if depth > MAX_NESTING {
+            return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+        }
+        parser(input)
+    }
+}
+
+/// A parsed query whose WHERE clauses have not been split yet
+struct ParsedQuery {
+    query: Query,
+    where_clause: Option<Condition>,
+    from: Option<Box<ParsedQuery>>,
+}
+
+impl ParsedQuery {
+    /// Split the WHERE clauses into time ranges and filters, innermost first
+    ///
+    /// A query over a subquery without its own time condition uses the
+    /// subquery's range.
+    fn resolve(self) -> QueryResult<Query> {
+        let mut query = self.query;
+        if let Some(from) = self.from {
+            let subquery = from.resolve()?;
+            query.time_range = subquery.time_range;
+            query.from = Some(Box::new(subquery));
+        }
+        if let Some(condition) = self.where_clause {
+            let (time_range, filters) = split_where_clause(condition)?;
+            if let Some(time_range) = time_range {
+                query.time_range = time_range;
+            }
+            query.filters = filters;
+        }
+        Ok(query)
+    }
+
+    /// Check if the query or one of its subqueries has a time condition
+    fn has_time_condition(&self) -> QueryResult<bool> {
+        let mut level = Some(self);
+        while let Some(parsed) = level {
+            if let Some(condition) = &parsed.where_clause {
+                if split_where_clause(condition.clone())?.0.is_some() {
+                    return Ok(true);
+                }
+            }
+            level = parsed.from.as_deref();
+        }
+        Ok(false)
+    }
+}
+
+/// Parse the full query, with an optional EXPLAIN prefix
+fn parse_full_query(input: &str) -> IResult<&str, ParsedQuery> {
     let (input, _) = multispace0(input)?;
+    let (input, explain) = opt(terminated(parse_explain, multispace1))(input)?;
+    let (input, mut parsed) = parse_select_query(input)?;
+    parsed.query.explain = explain;
+    Ok((input, parsed))
+}
+
+/// Parse a SELECT query
+///
+/// The WHERE clauses are kept for `ParsedQuery::resolve`.
+fn parse_select_query(input: &str) -> IResult<&str, ParsedQuery> {
     let (input, select) = parse_select_clause(input)?;
     let (input, _) = multispace0(input)?;
+    let (input, from) = opt(parse_from_clause)(input)?;
+    let (input, _) = multispace0(input)?;
     let (input, where_clause) = opt(parse_where_clause)(input)?;
     let (input, _) = multispace0(input)?;
     let (input, group_by) = opt(parse_group_by_clause)(input)?;
     let (input, _) = multispace0(input)?;
+    let (input, order_by) = opt(parse_order_by_clause)(input)?;
+    let (input, _) = multispace0(input)?;
     let (input, limit) = opt(parse_limit_clause)(input)?;
     let (input, _) = multispace0(input)?;
-
-    // Extract time range and filters from WHERE clause
-    let (time_range, filters) = match where_clause {
-        Some((tr, f)) => (tr, f),
-        None => (None, Vec::new()),
-    };
+    let (input, offset) = opt(parse_offset_clause)(input)?;
+    let (input, _) = multispace0(input)?;
 
     Ok((
         input,
-        Query {
-            select,
-            time_range: time_range.unwrap_or_else(|| TimeRange::last_days(7)),
-            filters,
-            group_by,
-            limit,
+        ParsedQuery {
+            query: Query {
+                select,
+                from: None,
+                time_range: TimeRange::last_days(7),
+                filters: Vec::new(),
+                group_by,
+                order_by: order_by.unwrap_or_default(),
+                limit,
+                offset,
+                explain: None,
+            },
+            where_clause,
+            from: from.map(Box::new),
         },
     ))
 }
 
+/// Parse a FROM (subquery) source
+fn parse_from_clause(input: &str) -> IResult<&str, ParsedQuery> {
+    let (input, _) = keyword("FROM")(input)?;
+    let (input, _) = delimited(multispace0, char('('), multispace0)(input)?;
+    let (input, subquery) = nested(parse_select_query)(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, subquery))
+}
+
+/// Parse an EXPLAIN or EXPLAIN ANALYZE prefix
+fn parse_explain(input: &str) -> IResult<&str, ExplainMode> {
+    let (input, _) = keyword("EXPLAIN")(input)?;
+    let (input, analyze) = opt(preceded(multispace1, keyword("ANALYZE")))(input)?;
+    let mode = match analyze {
+        Some(_) => ExplainMode::Analyze,
+        None => ExplainMode::Plan,
+    };
+    Ok((input, mode))
+}
+
 /// Parse SELECT clause
 fn parse_select_clause(input: &str) -> IResult<&str, Vec<SelectItem>> {
     let (input, _) = tag_no_case("SELECT")(input)?;
@@ -101,13 +356,156 @@ fn parse_select_clause(input: &str) -> IResult<&str, Vec<SelectItem>> {
     )(input)
 }
 
-/// Parse a single SELECT item (metric or aggregated metric)
+/// Parse a single SELECT item: a metric, an aggregation or an expression,
+/// optionally wrapped in a window function and a TOP selector
 fn parse_select_item(input: &str) -> IResult<&str, SelectItem> {
-    alt((parse_aggregated_item, parse_simple_item))(input)
+    let (input, (top, (window, expr))) = alt((
+        map(parse_top_call, |(n, inner)| (Some(n), inner)),
+        map(parse_windowed_expr, |inner| (None, inner)),
+    ))(input)?;
+    let (input, alias) = opt(parse_alias)(input)?;
+
+    let item = match expr {
+        SelectExpr::Metric(metric) => SelectItem::new(metric),
+        SelectExpr::Aggregate(agg, metric) => SelectItem::new(metric).with_aggregation(agg),
+        expr => SelectItem::expression(expr),
+    };
+    let item = match window {
+        Some(window) => item.with_window(window),
+        None => item,
+    };
+    let item = match top {
+        Some(n) => item.with_top(n),
+        None => item,
+    };
+    Ok((
+        input,
+        match alias {
+            Some(alias) => item.with_alias(alias),
+            None => item,
+        },
+    ))
+}
+
+/// Parse a TOP(expr, n) selector
+fn parse_top_call(input: &str) -> IResult<&str, (usize, (Option<WindowFunc>, SelectExpr))> {
+    let (input, _) = keyword("TOP")(input)?;
+    let (input, _) = delimited(multispace0, char('('), multispace0)(input)?;
+    let (input, inner) = parse_windowed_expr(input)?;
+    let (input, n) = argument(parse_positive_count)(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, (n, inner)))
 }
 
-/// Parse an aggregated item like AVG(mood)
-fn parse_aggregated_item(input: &str) -> IResult<&str, SelectItem> {
+/// Parse an expression, optionally wrapped in a window function
+fn parse_windowed_expr(input: &str) -> IResult<&str, (Option<WindowFunc>, SelectExpr)> {
+    alt((
+        map(parse_window_call, |(window, expr)| (Some(window), expr)),
+        map(parse_select_expr, |expr| (None, expr)),
+    ))(input)
+}
+
+/// Parse a window function call like MOVING_AVG(AVG(mood), 7)
+fn parse_window_call(input: &str) -> IResult<&str, (WindowFunc, SelectExpr)> {
+    let (input, name) = alt((
+        keyword("MOVING_AVERAGE"),
+        keyword("MOVING_AVG"),
+        keyword("DERIVATIVE"),
+        keyword("DIFFERENCE"),
+        keyword("CUMULATIVE_SUM"),
+        keyword("EWMA"),
+    ))(input)?;
+    let (input, _) = delimited(multispace0, char('('), multispace0)(input)?;
+    let (input, expr) = parse_select_expr(input)?;
+    let (input, window) = match name.to_uppercase().as_str() {
+        "MOVING_AVERAGE" | "MOVING_AVG" => {
+            map(argument(parse_positive_count), WindowFunc::MovingAvg)(input)?
+        }
+        "DERIVATIVE" => map(opt(argument(parse_duration)), |unit| {
+            WindowFunc::Derivative(unit.unwrap_or(1000))
+        })(input)?,
+        "DIFFERENCE" => (input, WindowFunc::Difference),
+        "CUMULATIVE_SUM" => (input, WindowFunc::CumulativeSum),
+        _ => map(
+            argument(verify(parse_number, |alpha: &f64| *alpha > 0.0 && *alpha <= 1.0)),
+            WindowFunc::Ewma,
+        )(input)?,
+    };
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, (window, expr)))
+}
+
+/// Parse a standalone SELECT expression, as stored for computed metrics
+pub fn parse_expression(input: &str) -> QueryResult<SelectExpr> {
+    match parse_select_expr(input.trim()) {
+        Ok(("", expr)) => Ok(expr),
+        Ok((remaining, _)) => Err(QueryError::Parse(format!(
+            "Unexpected input after expression: '{}'",
+            remaining.trim()
+        ))),
+        Err(e) => Err(parse_error(e)),
+    }
+}
+
+/// Parse a sum or difference of terms
+fn parse_select_expr(input: &str) -> IResult<&str, SelectExpr> {
+    let (input, first) = parse_select_term(input)?;
+    let (input, rest) = many0(pair(
+        delimited(
+            multispace0,
+            alt((value(ArithOp::Add, char('+')), value(ArithOp::Sub, char('-')))),
+            multispace0,
+        ),
+        parse_select_term,
+    ))(input)?;
+
+    let expr = rest
+        .into_iter()
+        .fold(first, |left, (op, right)| SelectExpr::binary(left, op, right));
+    Ok((input, expr))
+}
+
+/// Parse a product or quotient of factors
+fn parse_select_term(input: &str) -> IResult<&str, SelectExpr> {
+    let (input, first) = parse_select_factor(input)?;
+    let (input, rest) = many0(pair(
+        delimited(
+            multispace0,
+            alt((value(ArithOp::Mul, char('*')), value(ArithOp::Div, char('/')))),
+            multispace0,
+        ),
+        parse_select_factor,
+    ))(input)?;
+
+    let expr = rest
+        .into_iter()
+        .fold(first, |left, (op, right)| SelectExpr::binary(left, op, right));
+    Ok((input, expr))
+}
+
+/// Parse a negation, parenthesised expression, number, aggregation or metric
+fn parse_select_factor(input: &str) -> IResult<&str, SelectExpr> {
+    alt((
+        map(preceded(pair(char('-'), multispace0), nested(parse_select_factor)), |expr| {
+            SelectExpr::Neg(Box::new(expr))
+        }),
+        delimited(
+            pair(char('('), multispace0),
+            nested(parse_select_expr),
+            pair(multispace0, char(')')),
+        ),
+        map(parse_number, SelectExpr::Number),
+        map(alt((parse_percentile_call, parse_aggregation_call)), |(agg, metric)| {
+            SelectExpr::Aggregate(agg, metric.to_string())
+        }),
+        map(parse_identifier, |metric| SelectExpr::Metric(metric.to_string())),
+    ))(input)
+}
+
+/// Parse a single-argument aggregation call like AVG(mood)
+fn parse_aggregation_call(input: &str) -> IResult<&str, (AggregationFunc, &str)> {
     let (input, agg) = parse_aggregation_func(input)?;
     let (input, _) = multispace0(input)?;
     let (input, _) = char('(')(input)?;
@@ -115,31 +513,25 @@ fn parse_aggregated_item(input: &str) -> IResult<&str, SelectItem> {
     let (input, metric) = parse_identifier(input)?;
     let (input, _) = multispace0(input)?;
     let (input, _) = char(')')(input)?;
-    let (input, alias) = opt(parse_alias)(input)?;
-
-    Ok((
-        input,
-        SelectItem {
-            metric: metric.to_string(),
-            aggregation: Some(agg),
-            alias,
-        },
-    ))
+    Ok((input, (agg, metric)))
 }
 
-/// Parse a simple metric item
-fn parse_simple_item(input: &str) -> IResult<&str, SelectItem> {
+/// Parse PERCENTILE(metric, q), failing the parse unless q is between 0 and 1
+fn parse_percentile_call(input: &str) -> IResult<&str, (AggregationFunc, &str)> {
+    let (input, _) = tag_no_case("PERCENTILE")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char('(')(input)?;
+    let (input, _) = multispace0(input)?;
     let (input, metric) = parse_identifier(input)?;
-    let (input, alias) = opt(parse_alias)(input)?;
-
-    Ok((
-        input,
-        SelectItem {
-            metric: metric.to_string(),
-            aggregation: None,
-            alias,
-        },
-    ))
+    let (input, _) = delimited(multispace0, char(','), multispace0)(input)?;
+    let (rest, q) = parse_number(input)?;
+    if !(0.0..=1.0).contains(&q) {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Verify)));
+    }
+    let input = rest;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, (AggregationFunc::Percentile(q), metric)))
 }
 
 /// Parse AS alias clause
@@ -158,55 +550,154 @@ fn parse_aggregation_func(input: &str) -> IResult<&str, AggregationFunc> {
         value(AggregationFunc::Sum, tag_no_case("SUM")),
         value(AggregationFunc::Min, tag_no_case("MIN")),
         value(AggregationFunc::Max, tag_no_case("MAX")),
+        value(AggregationFunc::CountDistinct, tag_no_case("COUNT_DISTINCT")),
         value(AggregationFunc::Count, tag_no_case("COUNT")),
         value(AggregationFunc::Last, tag_no_case("LAST")),
         value(AggregationFunc::First, tag_no_case("FIRST")),
+        value(AggregationFunc::Median, tag_no_case("MEDIAN")),
+        value(AggregationFunc::Stddev, tag_no_case("STDDEV")),
+        value(AggregationFunc::Variance, tag_no_case("VARIANCE")),
+        value(AggregationFunc::Spread, tag_no_case("SPREAD")),
     ))(input)
 }
 
 /// Parse WHERE clause
-fn parse_where_clause(input: &str) -> IResult<&str, (Option<TimeRange>, Vec<Filter>)> {
+fn parse_where_clause(input: &str) -> IResult<&str, Condition> {
     let (input, _) = tag_no_case("WHERE")(input)?;
     let (input, _) = multispace1(input)?;
+    parse_or_condition(input)
+}
 
-    let (input, conditions) = separated_list1(
-        delimited(
-            multispace0,
-            tag_no_case("AND"),
-            multispace1,
-        ),
-        parse_condition,
-    )(input)?;
+/// Condition tree of a WHERE clause, before time conditions are split off
+#[derive(Clone)]
+enum Condition {
+    TimeRange(TimeRange),
+    Filter(FilterExpr),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    /// Convert to a filter expression, or None if it contains a time condition
+    fn into_filter(self) -> Option<FilterExpr> {
+        let all = |conditions: Vec<Condition>| {
+            conditions
+                .into_iter()
+                .map(Condition::into_filter)
+                .collect::<Option<Vec<_>>>()
+        };
+
+        match self {
+            Condition::TimeRange(_) => None,
+            Condition::Filter(expr) => Some(expr),
+            Condition::And(conditions) => all(conditions).map(FilterExpr::And),
+            Condition::Or(conditions) => all(conditions).map(FilterExpr::Or),
+            Condition::Not(condition) => condition.into_filter().map(FilterExpr::negate),
+        }
+    }
+
+    /// Flatten nested ANDs into a list of conditions that must all hold
+    fn into_conjuncts(self, conjuncts: &mut Vec<Condition>) {
+        match self {
+            Condition::And(conditions) => {
+                for condition in conditions {
+                    condition.into_conjuncts(conjuncts);
+                }
+            }
+            other => conjuncts.push(other),
+        }
+    }
+}
+
+/// Separate the time range from the filters of a WHERE clause
+fn split_where_clause(condition: Condition) -> QueryResult<(Option<TimeRange>, Vec<FilterExpr>)> {
+    let mut conjuncts = Vec::new();
+    condition.into_conjuncts(&mut conjuncts);
 
-    // Separate time conditions from filter conditions
     let mut time_range: Option<TimeRange> = None;
     let mut filters = Vec::new();
 
-    for condition in conditions {
+    for condition in conjuncts {
         match condition {
             Condition::TimeRange(tr) => {
-                time_range = Some(tr);
+                time_range = match time_range {
+                    Some(current) => Some(current.intersection(&tr).ok_or_else(|| {
+                        QueryError::InvalidTimeRange("time conditions do not overlap".to_string())
+                    })?),
+                    None => Some(tr),
+                };
             }
-            Condition::Filter(f) => {
-                filters.push(f);
+            other => {
+                let filter = other.into_filter().ok_or_else(|| {
+                    QueryError::Parse(
+                        "time conditions can only be combined with AND".to_string(),
+                    )
+                })?;
+                filters.push(filter);
             }
         }
     }
 
-    Ok((input, (time_range, filters)))
+    Ok((time_range, filters))
 }
 
-/// Condition in WHERE clause (either time range or filter)
-enum Condition {
-    TimeRange(TimeRange),
-    Filter(Filter),
+/// Parse conditions joined by OR
+fn parse_or_condition(input: &str) -> IResult<&str, Condition> {
+    let (input, first) = parse_and_condition(input)?;
+    let (input, rest) = many0(preceded(
+        delimited(multispace0, keyword("OR"), multispace0),
+        parse_and_condition,
+    ))(input)?;
+
+    if rest.is_empty() {
+        return Ok((input, first));
+    }
+    let mut conditions = vec![first];
+    conditions.extend(rest);
+    Ok((input, Condition::Or(conditions)))
+}
+
+/// Parse conditions joined by AND
+fn parse_and_condition(input: &str) -> IResult<&str, Condition> {
+    let (input, first) = parse_not_condition(input)?;
+    let (input, rest) = many0(preceded(
+        delimited(multispace0, keyword("AND"), multispace0),
+        parse_not_condition,
+    ))(input)?;
+
+    if rest.is_empty() {
+        return Ok((input, first));
+    }
+    let mut conditions = vec![first];
+    conditions.extend(rest);
+    Ok((input, Condition::And(conditions)))
+}
+
+/// Parse an optionally negated condition
+fn parse_not_condition(input: &str) -> IResult<&str, Condition> {
+    alt((
+        map(
+            preceded(pair(keyword("NOT"), multispace0), nested(parse_not_condition)),
+            |condition| Condition::Not(Box::new(condition)),
+        ),
+        parse_condition,
+    ))(input)
 }
 
-/// Parse a single condition
+/// Parse a single condition or a parenthesized group
 fn parse_condition(input: &str) -> IResult<&str, Condition> {
     alt((
+        delimited(
+            pair(char('('), multispace0),
+            nested(parse_or_condition),
+            pair(multispace0, char(')')),
+        ),
         map(parse_time_condition, Condition::TimeRange),
-        map(parse_filter_condition, Condition::Filter),
+        map(parse_in_condition, Condition::Filter),
+        map(parse_filter_condition, |filter| {
+            Condition::Filter(FilterExpr::Compare(filter))
+        }),
     ))(input)
 }
 
@@ -276,15 +767,18 @@ fn parse_absolute_time(input: &str) -> IResult<&str, i64> {
 
 /// Parse duration like "7d", "24h", "30m"
 fn parse_duration(input: &str) -> IResult<&str, i64> {
-    let (input, num) = map_res(digit1, |s: &str| s.parse::<i64>())(input)?;
-    let (input, unit) = alt((
-        value(24 * 60 * 60 * 1000i64, alt((tag("d"), tag("D")))),
-        value(60 * 60 * 1000i64, alt((tag("h"), tag("H")))),
-        value(60 * 1000i64, alt((tag("m"), tag("M")))),
-        value(1000i64, alt((tag("s"), tag("S")))),
-    ))(input)?;
-
-    Ok((input, num * unit))
+    map_res(
+        pair(
+            map_res(digit1, |s: &str| s.parse::<i64>()),
+            alt((
+                value(24 * 60 * 60 * 1000i64, alt((tag("d"), tag("D")))),
+                value(60 * 60 * 1000i64, alt((tag("h"), tag("H")))),
+                value(60 * 1000i64, alt((tag("m"), tag("M")))),
+                value(1000i64, alt((tag("s"), tag("S")))),
+            )),
+        ),
+        |(num, unit)| duration_ms(num, unit).ok_or("duration too long"),
+    )(input)
 }
 
 /// Parse filter condition like "tags.location = 'office'"
@@ -295,6 +789,34 @@ fn parse_filter_condition(input: &str) -> IResult<&str, Filter> {
     ))(input)
 }
 
+/// Parse IN list like "tags.location IN ('office', 'home')" or "value NOT IN (1, 2)"
+fn parse_in_condition(input: &str) -> IResult<&str, FilterExpr> {
+    let (input, field) = alt((
+        map(preceded(tag_no_case("tags."), parse_identifier), |key| {
+            FilterField::Tag(key.to_string())
+        }),
+        value(FilterField::Value, keyword("value")),
+    ))(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, negated) = opt(terminated(keyword("NOT"), multispace1))(input)?;
+    let (input, _) = keyword("IN")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, values) = delimited(
+        pair(char('('), multispace0),
+        separated_list1(
+            delimited(multispace0, char(','), multispace0),
+            alt((
+                map(parse_quoted_string, FilterValue::String),
+                map(parse_number, FilterValue::Number),
+            )),
+        ),
+        pair(multispace0, char(')')),
+    )(input)?;
+
+    let expr = FilterExpr::In { field, values };
+    Ok((input, if negated.is_some() { expr.negate() } else { expr }))
+}
+
 /// Parse tag filter like "tags.location = 'office'"
 fn parse_tag_filter(input: &str) -> IResult<&str, Filter> {
     let (input, _) = tag_no_case("tags.")(input)?;
@@ -338,21 +860,112 @@ fn parse_group_by_clause(input: &str) -> IResult<&str, GroupByClause> {
     let (input, _) = multispace1(input)?;
     let (input, _) = tag_no_case("BY")(input)?;
     let (input, _) = multispace1(input)?;
+    let (input, clause) = alt((
+        parse_group_by_time,
+        map(parse_group_by_interval, GroupByClause::new),
+    ))(input)?;
+    let (input, tags) = many0(preceded(
+        delimited(multispace0, char(','), multispace0),
+        preceded(tag_no_case("tags."), parse_identifier),
+    ))(input)?;
+    let (input, fill) = opt(preceded(multispace0, parse_fill_clause))(input)?;
+
+    let clause = tags.into_iter().fold(clause, GroupByClause::tag);
+    Ok((input, clause.fill(fill.unwrap_or_default())))
+}
+
+/// Parse FILL(none | null | previous | linear | <value>)
+fn parse_fill_clause(input: &str) -> IResult<&str, FillMode> {
+    let (input, _) = keyword("FILL")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char('(')(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, fill) = alt((
+        value(FillMode::None, keyword("none")),
+        value(FillMode::Null, keyword("null")),
+        value(FillMode::Previous, keyword("previous")),
+        value(FillMode::Linear, keyword("linear")),
+        map(parse_number, FillMode::Value),
+    ))(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char(')')(input)?;
+    Ok((input, fill))
+}
+
+/// Parse time(interval [, offset] [, 'Time/Zone'])
+fn parse_group_by_time(input: &str) -> IResult<&str, GroupByClause> {
+    let (input, _) = tag_no_case("time")(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char('(')(input)?;
+    let (input, _) = multispace0(input)?;
     let (input, interval) = parse_group_by_interval(input)?;
+    let separator = |input| delimited(multispace0, char(','), multispace0)(input);
+    let (input, offset) = opt(preceded(separator, parse_duration))(input)?;
+    let (input, timezone) = opt(preceded(
+        separator,
+        parse_timezone,
+    ))(input)?;
+    let (input, _) = multispace0(input)?;
+    let (input, _) = char(')')(input)?;
 
-    Ok((input, GroupByClause { interval }))
+    let mut clause = GroupByClause::new(interval).offset(offset.unwrap_or(0));
+    if let Some(timezone) = timezone {
+        clause = clause.timezone(timezone);
+    }
+    Ok((input, clause))
+}
+
+/// Parse a quoted time zone name, failing the parse on unknown ones
+fn parse_timezone(input: &str) -> IResult<&str, chrono_tz::Tz> {
+    let (rest, name) = parse_quoted_string(input)?;
+    match name.parse() {
+        Ok(timezone) => Ok((rest, timezone)),
+        Err(_) => Err(nom::Err::Failure(Error::new(input, ErrorKind::MapRes))),
+    }
 }
 
-/// Parse GROUP BY interval
+/// Parse GROUP BY interval (a name like `day` or a duration like `15m`)
 fn parse_group_by_interval(input: &str) -> IResult<&str, GroupByInterval> {
     alt((
-        value(GroupByInterval::Hour, tag_no_case("hour")),
-        value(GroupByInterval::Day, tag_no_case("day")),
-        value(GroupByInterval::Week, tag_no_case("week")),
-        value(GroupByInterval::Month, tag_no_case("month")),
+        value(GroupByInterval::Hour, keyword("hour")),
+        value(GroupByInterval::Day, keyword("day")),
+        value(GroupByInterval::Week, keyword("week")),
+        value(GroupByInterval::Month, keyword("month")),
+        map_res(
+            terminated(
+                recognize(pair(digit1, satisfy(|c| "wdhmsWDHMS".contains(c)))),
+                not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+            ),
+            |s: &str| GroupByInterval::from_str(s).ok_or("invalid interval"),
+        ),
     ))(input)
 }
 
+/// Parse ORDER BY clause
+fn parse_order_by_clause(input: &str) -> IResult<&str, OrderBy> {
+    let (input, _) = keyword("ORDER")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, _) = keyword("BY")(input)?;
+    let (input, _) = multispace1(input)?;
+    let (input, key) = alt((
+        value(OrderKey::Time, keyword("time")),
+        value(OrderKey::Value, keyword("value")),
+        map(parse_identifier, |column| OrderKey::Column(column.to_string())),
+    ))(input)?;
+    let (input, descending) = opt(preceded(
+        multispace1,
+        alt((value(false, keyword("ASC")), value(true, keyword("DESC")))),
+    ))(input)?;
+
+    Ok((
+        input,
+        OrderBy {
+            key,
+            descending: descending.unwrap_or(false),
+        },
+    ))
+}
+
 /// Parse LIMIT clause
 fn parse_limit_clause(input: &str) -> IResult<&str, usize> {
     let (input, _) = tag_no_case("LIMIT")(input)?;
@@ -360,6 +973,18 @@ fn parse_limit_clause(input: &str) -> IResult<&str, usize> {
     map_res(digit1, |s: &str| s.parse::<usize>())(input)
 }
 
+/// Parse OFFSET clause
+fn parse_offset_clause(input: &str) -> IResult<&str, usize> {
+    let (input, _) = keyword("OFFSET")(input)?;
+    let (input, _) = multispace1(input)?;
+    map_res(digit1, |s: &str| s.parse::<usize>())(input)
+}
+
+/// Parse a count of at least one (window sizes, TOP)
+fn parse_positive_count(input: &str) -> IResult<&str, usize> {
+    verify(map_res(digit1, |s: &str| s.parse::<usize>()), |n: &usize| *n > 0)(input)
+}
+
 /// Parse comparison operator
 fn parse_operator(input: &str) -> IResult<&str, Operator> {
     alt((
@@ -372,6 +997,21 @@ fn parse_operator(input: &str) -> IResult<&str, Operator> {
     ))(input)
 }
 
+/// Parse a comma followed by a further function argument
+fn argument<'a, O>(
+    parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    preceded(delimited(multispace0, char(','), multispace0), parser)
+}
+
+/// Parse a keyword that is not the prefix of a longer word (`OR` in `ORDER`)
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(
+        tag_no_case(word),
+        not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+    )
+}
+
 /// Parse identifier (metric name, tag key, etc.)
 fn parse_identifier(input: &str) -> IResult<&str, &str> {
     recognize(pair(
@@ -429,6 +1069,143 @@ mod tests {
         assert_eq!(query.select[0].aggregation, Some(AggregationFunc::Avg));
     }
 
+    #[test]
+    fn test_parse_statistical_aggregations() {
+        let query = parse_query(
+            "SELECT MEDIAN(sleep), STDDEV(mood), VARIANCE(mood), SPREAD(mood), COUNT_DISTINCT(mood)",
+        )
+        .unwrap();
+        let aggs: Vec<_> = query.select.iter().map(|item| item.aggregation).collect();
+        assert_eq!(
+            aggs,
+            vec![
+                Some(AggregationFunc::Median),
+                Some(AggregationFunc::Stddev),
+                Some(AggregationFunc::Variance),
+                Some(AggregationFunc::Spread),
+                Some(AggregationFunc::CountDistinct),
+            ]
+        );
+
+        let query = parse_query("SELECT percentile( heart_rate , 0.95 ) AS p95").unwrap();
+        assert_eq!(query.select[0].metric, "heart_rate");
+        assert_eq!(query.select[0].aggregation, Some(AggregationFunc::Percentile(0.95)));
+        assert_eq!(query.select[0].alias, Some("p95".to_string()));
+
+        let err = parse_query("SELECT PERCENTILE(heart_rate, 95)").unwrap_err();
+        assert_eq!(err.to_string(), "Parse error: Percentile must be in [0, 1], got 95");
+        assert!(parse_query("SELECT PERCENTILE(heart_rate)").is_err());
+    }
+
+    #[test]
+    fn test_parse_select_expressions() {
+        let query = parse_query("SELECT AVG(sleep_hours) * 60 AS sleep_min GROUP BY day").unwrap();
+        let item = &query.select[0];
+        assert_eq!(item.display_name(), "sleep_min");
+        assert_eq!(
+            item.expr,
+            Some(SelectExpr::binary(
+                SelectExpr::Aggregate(AggregationFunc::Avg, "sleep_hours".to_string()),
+                ArithOp::Mul,
+                SelectExpr::Number(60.0),
+            ))
+        );
+
+        // * binds tighter than -, and unaliased items are named by their text
+        let query =
+            parse_query("SELECT SUM(calories_in) - SUM(calories_out) / 2, (mood + energy) / -2")
+                .unwrap();
+        assert_eq!(query.select[0].display_name(), "SUM(calories_in) - SUM(calories_out) / 2");
+        assert_eq!(query.select[1].display_name(), "(mood + energy) / -2");
+        assert_eq!(query.select[1].metrics(), vec!["mood", "energy"]);
+
+        // Plain items stay plain
+        let query = parse_query("SELECT mood, MAX(energy) AS peak").unwrap();
+        assert_eq!(query.select[0], SelectItem::new("mood"));
+        assert_eq!(
+            query.select[1],
+            SelectItem::new("energy")
+                .with_aggregation(AggregationFunc::Max)
+                .with_alias("peak")
+        );
+
+        assert!(parse_expression("weight_kg * 2.2046").is_ok());
+        assert!(parse_expression("weight_kg *").is_err());
+        assert!(parse_query("SELECT mood +").is_err());
+    }
+
+    #[test]
+    fn test_parse_window_functions() {
+        let query =
+            parse_query("SELECT MOVING_AVG(AVG(mood), 7) AS weekly GROUP BY day").unwrap();
+        assert_eq!(
+            query.select[0],
+            SelectItem::new("mood")
+                .with_aggregation(AggregationFunc::Avg)
+                .with_window(WindowFunc::MovingAvg(7))
+                .with_alias("weekly")
+        );
+
+        let query = parse_query(
+            "SELECT DERIVATIVE(weight, 1d), derivative(weight), CUMULATIVE_SUM(SUM(steps)), \
+             DIFFERENCE(weight * 2), EWMA(mood, 0.3) GROUP BY day",
+        )
+        .unwrap();
+        let windows: Vec<_> = query.select.iter().map(|item| item.window).collect();
+        assert_eq!(
+            windows,
+            vec![
+                Some(WindowFunc::Derivative(24 * 60 * 60 * 1000)),
+                Some(WindowFunc::Derivative(1000)),
+                Some(WindowFunc::CumulativeSum),
+                Some(WindowFunc::Difference),
+                Some(WindowFunc::Ewma(0.3)),
+            ]
+        );
+        assert_eq!(query.select[3].display_name(), "DIFFERENCE(weight * 2)");
+        assert_eq!(query.select[3].metrics(), vec!["weight"]);
+
+        // A metric may share a window function's name
+        assert_eq!(parse_query("SELECT ewma").unwrap().select[0], SelectItem::new("ewma"));
+
+        assert!(parse_query("SELECT MOVING_AVG(mood, 0)").is_err());
+        assert!(parse_query("SELECT MOVING_AVG(mood)").is_err());
+        assert!(parse_query("SELECT EWMA(mood, 1.5)").is_err());
+    }
+
+    #[test]
+    fn test_parse_order_offset_and_top() {
+        let query = parse_query("SELECT mood ORDER BY time DESC LIMIT 20 OFFSET 40").unwrap();
+        assert_eq!(query.order_by, OrderBy::time_desc());
+        assert_eq!(query.limit, Some(20));
+        assert_eq!(query.offset, Some(40));
+
+        let query = parse_query(
+            "SELECT SUM(steps) AS total WHERE time >= now() - 30d GROUP BY day \
+             ORDER BY total ASC OFFSET 5",
+        )
+        .unwrap();
+        assert_eq!(query.order_by, OrderBy::new(OrderKey::Column("total".to_string())));
+        assert_eq!(query.limit, None);
+        assert_eq!(query.offset, Some(5));
+
+        let query = parse_query("SELECT mood ORDER BY value desc").unwrap();
+        assert_eq!(query.order_by, OrderBy::new(OrderKey::Value).desc());
+
+        let query = parse_query("SELECT TOP(SUM(steps), 3), TOP(MOVING_AVG(mood, 7), 2)").unwrap();
+        assert_eq!(query.select[0].top, Some(3));
+        assert_eq!(query.select[0].display_name(), "TOP(SUM(steps), 3)");
+        assert_eq!(query.select[1].window, Some(WindowFunc::MovingAvg(7)));
+        assert_eq!(query.select[1].display_name(), "TOP(MOVING_AVG(mood, 7), 2)");
+
+        // A metric named like the keyword still parses
+        assert_eq!(parse_query("SELECT top").unwrap().select[0], SelectItem::new("top"));
+
+        assert!(parse_query("SELECT TOP(steps, 0)").is_err());
+        assert!(parse_query("SELECT mood ORDER BY").is_err());
+        assert!(parse_query("SELECT mood OFFSET 5 LIMIT 5").is_err());
+    }
+
     #[test]
     fn test_parse_with_alias() {
         let query = parse_query("SELECT AVG(mood) AS daily_mood").unwrap();
@@ -453,6 +1230,55 @@ mod tests {
         assert_eq!(query.group_by.unwrap().interval, GroupByInterval::Day);
     }
 
+    #[test]
+    fn test_parse_group_by_time() {
+        let query = parse_query("SELECT AVG(heart_rate) GROUP BY 15m").unwrap();
+        assert_eq!(query.group_by.unwrap().interval, GroupByInterval::Every(15 * 60 * 1000));
+
+        let query = parse_query("SELECT AVG(mood) GROUP BY time(1d, 'Europe/Berlin')").unwrap();
+        let group_by = query.group_by.unwrap();
+        assert_eq!(group_by.interval, GroupByInterval::Day);
+        assert_eq!(group_by.timezone, chrono_tz::Europe::Berlin);
+        assert_eq!(group_by.offset_ms, 0);
+
+        let query = parse_query("SELECT AVG(mood) GROUP BY TIME(week, 4h) LIMIT 10").unwrap();
+        let group_by = query.group_by.unwrap();
+        assert_eq!(group_by.interval, GroupByInterval::Week);
+        assert_eq!(group_by.timezone, chrono_tz::UTC);
+        assert_eq!(group_by.offset_ms, 4 * 3600 * 1000);
+        assert_eq!(query.limit, Some(10));
+
+        let err = parse_query("SELECT AVG(mood) GROUP BY time(1d, 'Mars/Olympus')").unwrap_err();
+        assert_eq!(err.to_string(), "Parse error: Unknown time zone 'Mars/Olympus'");
+        assert!(parse_query("SELECT AVG(mood) GROUP BY 9999999999999999w").is_err());
+        assert!(parse_query("SELECT AVG(mood) GROUP BY time(1d, 9999999999999999d)").is_err());
+        assert!(parse_query("SELECT mood WHERE time > now() - 9999999999999999d").is_err());
+
+        let query =
+            parse_query("SELECT AVG(mood) GROUP BY time(1w, 'Europe/Berlin'), tags.location, tags.source")
+                .unwrap();
+        let group_by = query.group_by.unwrap();
+        assert_eq!(group_by.interval, GroupByInterval::Week);
+        assert_eq!(group_by.tags, vec!["location".to_string(), "source".to_string()]);
+        assert_eq!(group_by.fill, FillMode::None);
+    }
+
+    #[test]
+    fn test_parse_fill() {
+        let fill = |q: &str| parse_query(q).unwrap().group_by.unwrap().fill;
+        assert_eq!(fill("SELECT AVG(mood) GROUP BY day FILL(linear)"), FillMode::Linear);
+        assert_eq!(fill("SELECT AVG(mood) GROUP BY day fill( null )"), FillMode::Null);
+        assert_eq!(
+            fill("SELECT AVG(mood) GROUP BY day, tags.location FILL(previous) LIMIT 5"),
+            FillMode::Previous
+        );
+        assert_eq!(fill("SELECT AVG(mood) GROUP BY 6h FILL(-1.5)"), FillMode::Value(-1.5));
+
+        assert!(parse_query("SELECT AVG(mood) GROUP BY day FILL(sideways)").is_err());
+        assert!(parse_query("SELECT AVG(mood) FILL(0)").is_err());
+        assert!(parse_query("SELECT AVG(mood) GROUP BY 0m").is_err());
+    }
+
     #[test]
     fn test_parse_limit() {
         let query = parse_query("SELECT mood LIMIT 100").unwrap();
@@ -477,7 +1303,10 @@ mod tests {
     fn test_parse_tag_filter() {
         let query = parse_query("SELECT mood WHERE tags.location = 'office'").unwrap();
         assert_eq!(query.filters.len(), 1);
-        assert_eq!(query.filters[0].field, FilterField::Tag("location".to_string()));
+        assert_eq!(
+            query.filters[0],
+            FilterExpr::Compare(Filter::tag("location", Operator::Eq, "office"))
+        );
     }
 
     #[test]
@@ -493,7 +1322,10 @@ mod tests {
 
         // Tag filter should be captured
         assert_eq!(query.filters.len(), 1);
-        assert_eq!(query.filters[0].field, FilterField::Tag("location".to_string()));
+        assert_eq!(
+            query.filters[0],
+            FilterExpr::Compare(Filter::tag("location", Operator::Eq, "office"))
+        );
     }
 
     #[test]
@@ -507,8 +1339,7 @@ mod tests {
     fn test_parse_value_filter() {
         let query = parse_query("SELECT mood WHERE value >= 5.0").unwrap();
         assert_eq!(query.filters.len(), 1);
-        assert_eq!(query.filters[0].field, FilterField::Value);
-        assert_eq!(query.filters[0].op, Operator::Gte);
+        assert_eq!(query.filters[0], FilterExpr::Compare(Filter::value(Operator::Gte, 5.0)));
     }
 
     #[test]
@@ -523,4 +1354,185 @@ mod tests {
         assert_eq!(query.select.len(), 1);
         assert_eq!(query.select[0].metric, "*");
     }
+
+    #[test]
+    fn test_parse_or_and_precedence() {
+        let query = parse_query(
+            "SELECT mood WHERE tags.location = 'office' OR tags.location = 'cafe' AND value > 5",
+        )
+        .unwrap();
+
+        let office = FilterExpr::Compare(Filter::tag("location", Operator::Eq, "office"));
+        let cafe = FilterExpr::Compare(Filter::tag("location", Operator::Eq, "cafe"));
+        let above = FilterExpr::Compare(Filter::value(Operator::Gt, 5.0));
+        assert_eq!(
+            query.filters,
+            vec![FilterExpr::Or(vec![office, FilterExpr::And(vec![cafe, above])])]
+        );
+    }
+
+    #[test]
+    fn test_parse_parentheses_and_not() {
+        let query = parse_query(
+            "SELECT mood WHERE time >= now() - 7d AND NOT (tags.source = 'import' OR value < 2)",
+        )
+        .unwrap();
+
+        let now = Utc::now().timestamp_millis();
+        assert!(query.time_range.start < now);
+        assert_eq!(
+            query.filters,
+            vec![FilterExpr::Or(vec![
+                FilterExpr::Compare(Filter::tag("source", Operator::Eq, "import")),
+                FilterExpr::Compare(Filter::value(Operator::Lt, 2.0)),
+            ])
+            .negate()]
+        );
+    }
+
+    #[test]
+    fn test_parse_in_lists() {
+        let query = parse_query(
+            "SELECT mood WHERE tags.location IN ('office', 'home') AND value NOT IN (1, 2.5)",
+        )
+        .unwrap();
+
+        assert_eq!(
+            query.filters,
+            vec![
+                FilterExpr::tag_in("location", ["office", "home"]),
+                FilterExpr::In {
+                    field: FilterField::Value,
+                    values: vec![FilterValue::Number(1.0), FilterValue::Number(2.5)],
+                }
+                .negate(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_subquery_and_statements() {
+        let query = parse_query(
+            "SELECT AVG(peak) FROM (SELECT MAX(heart_rate) AS peak \
+             WHERE time >= now() - 28d GROUP BY day) GROUP BY week",
+        )
+        .unwrap();
+        let subquery = query.from.as_deref().unwrap();
+        assert_eq!(subquery.select[0].display_name(), "peak");
+        assert_eq!(subquery.group_by.as_ref().unwrap().interval, GroupByInterval::Day);
+        assert_eq!(query.group_by.as_ref().unwrap().interval, GroupByInterval::Week);
+        // The outer query covers the subquery's range unless it has its own
+        assert_eq!(query.time_range, subquery.time_range);
+
+        let query = parse_query(
+            "SELECT x FROM (SELECT SUM(steps) AS x FROM (SELECT steps WHERE value > 0)) \
+             WHERE time >= now() - 1d AND value > 100",
+        )
+        .unwrap();
+        assert_eq!(query.filters, vec![FilterExpr::from(Filter::value(Operator::Gt, 100.0))]);
+        assert!(query.from.unwrap().from.unwrap().filters.len() == 1);
+
+        let statements =
+            parse_statements("SELECT mood; SELECT AVG(energy) GROUP BY day ;").unwrap();
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[1].select[0].aggregation, Some(AggregationFunc::Avg));
+        assert_eq!(parse_statements("SELECT mood").unwrap().len(), 1);
+
+        assert!(parse_query("SELECT mood; SELECT energy").is_err());
+        assert!(parse_statements("SELECT mood; DROP mood").is_err());
+        assert!(parse_query("SELECT x FROM (EXPLAIN SELECT mood)").is_err());
+        assert!(parse_query("SELECT x FROM (SELECT mood").is_err());
+    }
+
+    #[test]
+    fn test_parse_nesting_limit() {
+        let too_deep = |result: QueryResult<Query>| {
+            matches!(result, Err(QueryError::Parse(message)) if message.contains("nested"))
+        };
+        let parens = |depth: usize| {
+            format!("SELECT mood WHERE {}value > 1{}", "(".repeat(depth), ")".repeat(depth))
+        };
+        assert!(parse_query(&parens(MAX_NESTING)).is_ok());
+        assert!(too_deep(parse_query(&parens(MAX_NESTING + 1))));
+        assert!(too_deep(parse_query(&parens(5000))));
+
+        let nots = format!("SELECT mood WHERE {}value > 1", "NOT ".repeat(5000));
+        assert!(too_deep(parse_query(&nots)));
+        let negations = format!("SELECT {}AVG(mood)", "-".repeat(5000));
+        assert!(too_deep(parse_query(&negations)));
+        assert!(parse_expression(&format!("{}x{}", "(".repeat(5000), ")".repeat(5000))).is_err());
+        let subqueries =
+            format!("{}SELECT mood{}", "SELECT x FROM (".repeat(5000), ")".repeat(5000));
+        assert!(too_deep(parse_query(&subqueries)));
+
+        let statements = "SELECT mood;".repeat(MAX_STATEMENTS);
+        assert_eq!(parse_statements(&statements).unwrap().len(), MAX_STATEMENTS);
+        assert!(parse_statements(&format!("{}SELECT mood", statements)).is_err());
+    }
+
+    #[test]
+    fn test_parse_continuous_query() {
+        let cq = parse_continuous_query(
+            "create continuous query daily_mood as SELECT AVG(mood) GROUP BY day;",
+        )
+        .unwrap();
+        assert_eq!(cq.name, "daily_mood");
+        assert_eq!(cq.text, "SELECT AVG(mood) GROUP BY day");
+        assert_eq!(cq.query.group_by.unwrap().interval, GroupByInterval::Day);
+
+        assert!(parse_continuous_query("CREATE CONTINUOUS QUERY AS SELECT AVG(mood)").is_err());
+        assert!(parse_continuous_query("CREATE QUERY x AS SELECT AVG(mood) GROUP BY day").is_err());
+        assert!(matches!(
+            parse_continuous_query("CREATE CONTINUOUS QUERY x AS SELECT mood"),
+            Err(QueryError::InvalidContinuousQuery(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_explain() {
+        let query = parse_query("SELECT mood").unwrap();
+        assert_eq!(query.explain, None);
+
+        let query = parse_query("EXPLAIN SELECT mood WHERE tags.location = 'office'").unwrap();
+        assert_eq!(query.explain, Some(ExplainMode::Plan));
+        assert_eq!(query.filters.len(), 1);
+
+        let query = parse_query("explain analyze SELECT AVG(mood) GROUP BY day").unwrap();
+        assert_eq!(query.explain, Some(ExplainMode::Analyze));
+
+        // The WHERE clause renders back to an equivalent condition
+        let query = parse_query(
+            "SELECT mood WHERE (tags.a = 'x' OR value > 5) AND NOT tags.b IN ('y', 'z')",
+        )
+        .unwrap();
+        let rendered = FilterExpr::And(query.filters.clone()).to_string();
+        let reparsed = parse_query(&format!("SELECT mood WHERE {}", rendered)).unwrap();
+        assert_eq!(reparsed.filters, query.filters);
+
+        assert!(parse_query("EXPLAINSELECT mood").is_err());
+        assert!(parse_query("EXPLAIN ANALYZE").is_err());
+    }
+
+    #[test]
+    fn test_parse_keywords_need_word_boundary() {
+        // "ORDER" is not OR, "tags.origin" is not OR either
+        let query = parse_query("SELECT mood WHERE tags.origin = 'a' OR tags.order = 'b'").unwrap();
+        assert_eq!(query.filters.len(), 1);
+        assert!(parse_query("SELECT mood WHERE tags.a = 'x' ORDER").is_err());
+    }
+
+    #[test]
+    fn test_parse_time_inside_or_rejected() {
+        let result = parse_query("SELECT mood WHERE time >= now() - 7d OR tags.location = 'office'");
+        assert!(matches!(result, Err(QueryError::Parse(_))));
+    }
+
+    #[test]
+    fn test_parse_time_conditions_intersect() {
+        let query = parse_query("SELECT mood WHERE time >= 1000 AND (time < 5000)").unwrap();
+        assert_eq!(query.time_range, TimeRange::new(1000, 5000));
+
+        let result = parse_query("SELECT mood WHERE time >= 5000 AND time < 1000");
+        assert!(matches!(result, Err(QueryError::InvalidTimeRange(_))));
+    }
 }
@@ -0,0 +1,688 @@
+//! Mergeable aggregation state
+//!
+//! Every aggregation function is backed by a state that can absorb values one
+//! at a time and be merged with the state of another group, so partial
+//! results (per block, per bucket, per rollup) combine without revisiting the
+//! raw points:
+//!
+//! - **Moments**: count, sum, min, max and Welford mean/variance
+//! - **QuantileSketch**: exact up to a limit, then log-bucketed with 1%
+//!   relative error (DDSketch)
+//! - **DistinctSketch**: exact up to a limit, then HyperLogLog
+
+use std::collections::{BTreeMap, HashSet};
+
+use super::ast::AggregationFunc;
+
+/// Values kept verbatim before a quantile sketch switches to buckets
+const QUANTILE_EXACT_LIMIT: usize = 1024;
+
+/// Relative accuracy of bucketed quantiles
+const QUANTILE_RELATIVE_ERROR: f64 = 0.01;
+
+/// Magnitudes below this land in the zero bucket
+const QUANTILE_MIN_MAGNITUDE: f64 = 1e-9;
+
+/// Distinct values kept verbatim before switching to HyperLogLog
+const DISTINCT_EXACT_LIMIT: usize = 1024;
+
+/// HyperLogLog precision (2^12 registers, ~1.6% standard error)
+const HLL_PRECISION: u32 = 12;
+
+/// Running count, sum, extremes and variance of a set of values
+#[derive(Debug, Clone, PartialEq)]
+pub struct Moments {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Default for Moments {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+}
+
+impl Moments {
+    /// Add a value
+    pub fn push(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Combine with the moments of another set of values
+    pub fn merge(&mut self, other: &Moments) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = other.clone();
+            return;
+        }
+
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        let (n_a, n_b, n) = (self.count as f64, other.count as f64, count as f64);
+
+        self.mean += delta * n_b / n;
+        self.m2 += other.m2 + delta * delta * n_a * n_b / n;
+        self.count = count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Number of values
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of values
+    pub fn sum(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.sum)
+    }
+
+    /// Arithmetic mean
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// Smallest value
+    pub fn min(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    /// Largest value
+    pub fn max(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// Difference between the largest and smallest value
+    pub fn spread(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max - self.min)
+    }
+
+    /// Sample variance (None for fewer than two values)
+    pub fn variance(&self) -> Option<f64> {
+        (self.count > 1).then(|| self.m2 / (self.count - 1) as f64)
+    }
+
+    /// Sample standard deviation (None for fewer than two values)
+    pub fn stddev(&self) -> Option<f64> {
+        self.variance().map(f64::sqrt)
+    }
+}
+
+/// Quantile estimator that is exact for small inputs
+///
+/// Above `QUANTILE_EXACT_LIMIT` values it keeps counts in logarithmic buckets,
+/// so any quantile is within 1% of a value actually present in the input.
+/// NaN values are ignored.
+#[derive(Debug, Clone)]
+pub struct QuantileSketch {
+    store: QuantileStore,
+}
+
+#[derive(Debug, Clone)]
+enum QuantileStore {
+    Exact(Vec<f64>),
+    Buckets(LogBuckets),
+}
+
+impl Default for QuantileSketch {
+    fn default() -> Self {
+        Self {
+            store: QuantileStore::Exact(Vec::new()),
+        }
+    }
+}
+
+impl QuantileSketch {
+    /// Add a value
+    pub fn push(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+
+        match &mut self.store {
+            QuantileStore::Exact(values) => {
+                values.push(value);
+                if values.len() > QUANTILE_EXACT_LIMIT {
+                    self.store = QuantileStore::Buckets(LogBuckets::from_values(values));
+                }
+            }
+            QuantileStore::Buckets(buckets) => buckets.push(value),
+        }
+    }
+
+    /// Combine with another sketch
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        match (&mut self.store, &other.store) {
+            (QuantileStore::Exact(values), QuantileStore::Exact(others))
+                if values.len() + others.len() <= QUANTILE_EXACT_LIMIT =>
+            {
+                values.extend_from_slice(others);
+            }
+            (QuantileStore::Buckets(buckets), QuantileStore::Exact(others)) => {
+                for &value in others {
+                    buckets.push(value);
+                }
+            }
+            (QuantileStore::Buckets(buckets), QuantileStore::Buckets(others)) => {
+                buckets.merge(others);
+            }
+            (QuantileStore::Exact(values), _) => {
+                let mut buckets = LogBuckets::from_values(values);
+                match &other.store {
+                    QuantileStore::Exact(others) => {
+                        for &value in others {
+                            buckets.push(value);
+                        }
+                    }
+                    QuantileStore::Buckets(others) => buckets.merge(others),
+                }
+                self.store = QuantileStore::Buckets(buckets);
+            }
+        }
+    }
+
+    /// Number of values seen
+    pub fn count(&self) -> u64 {
+        match &self.store {
+            QuantileStore::Exact(values) => values.len() as u64,
+            QuantileStore::Buckets(buckets) => buckets.count,
+        }
+    }
+
+    /// Whether the sketch still holds every value verbatim
+    pub fn is_exact(&self) -> bool {
+        matches!(self.store, QuantileStore::Exact(_))
+    }
+
+    /// Value at quantile `q` (0.0 to 1.0)
+    ///
+    /// Exact sketches interpolate linearly between the closest ranks.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count() == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+
+        match &self.store {
+            QuantileStore::Exact(values) => {
+                let mut sorted = values.clone();
+                sorted.sort_by(f64::total_cmp);
+
+                let rank = q * (sorted.len() - 1) as f64;
+                let lower = rank.floor() as usize;
+                let upper = rank.ceil() as usize;
+                let fraction = rank - lower as f64;
+                Some(sorted[lower] + (sorted[upper] - sorted[lower]) * fraction)
+            }
+            QuantileStore::Buckets(buckets) => buckets.quantile(q),
+        }
+    }
+}
+
+/// Logarithmically sized buckets for positive and negative magnitudes
+#[derive(Debug, Clone, Default)]
+struct LogBuckets {
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+}
+
+impl LogBuckets {
+    fn from_values(values: &[f64]) -> Self {
+        let mut buckets = Self::default();
+        for &value in values {
+            buckets.push(value);
+        }
+        buckets
+    }
+
+    fn gamma() -> f64 {
+        (1.0 + QUANTILE_RELATIVE_ERROR) / (1.0 - QUANTILE_RELATIVE_ERROR)
+    }
+
+    fn key(magnitude: f64) -> i32 {
+        (magnitude.ln() / Self::gamma().ln()).ceil() as i32
+    }
+
+    /// Representative value of a bucket, within the relative error of every
+    /// magnitude that maps to it
+    fn representative(key: i32) -> f64 {
+        let gamma = Self::gamma();
+        2.0 * gamma.powi(key) / (gamma + 1.0)
+    }
+
+    fn push(&mut self, value: f64) {
+        self.count += 1;
+        if value.abs() < QUANTILE_MIN_MAGNITUDE {
+            self.zero += 1;
+        } else if value > 0.0 {
+            *self.positive.entry(Self::key(value)).or_default() += 1;
+        } else {
+            *self.negative.entry(Self::key(-value)).or_default() += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &LogBuckets) {
+        for (&key, &count) in &other.positive {
+            *self.positive.entry(key).or_default() += count;
+        }
+        for (&key, &count) in &other.negative {
+            *self.negative.entry(key).or_default() += count;
+        }
+        self.zero += other.zero;
+        self.count += other.count;
+    }
+
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let rank = q * (self.count - 1) as f64;
+
+        // Ascending order: largest negative magnitudes first, then zero,
+        // then positive magnitudes
+        let negatives = self
+            .negative
+            .iter()
+            .rev()
+            .map(|(&key, &count)| (-Self::representative(key), count));
+        let zero = std::iter::once((0.0, self.zero));
+        let positives = self
+            .positive
+            .iter()
+            .map(|(&key, &count)| (Self::representative(key), count));
+
+        let mut seen = 0u64;
+        for (value, count) in negatives.chain(zero).chain(positives) {
+            seen += count;
+            if seen as f64 > rank {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+/// Distinct-value counter that is exact for small inputs
+///
+/// Above `DISTINCT_EXACT_LIMIT` distinct values it switches to a HyperLogLog
+/// estimate. Values are compared by bit pattern, with -0.0 equal to 0.0;
+/// NaN values are ignored.
+#[derive(Debug, Clone)]
+pub struct DistinctSketch {
+    store: DistinctStore,
+}
+
+#[derive(Debug, Clone)]
+enum DistinctStore {
+    Exact(HashSet<u64>),
+    HyperLogLog(Vec<u8>),
+}
+
+impl Default for DistinctSketch {
+    fn default() -> Self {
+        Self {
+            store: DistinctStore::Exact(HashSet::new()),
+        }
+    }
+}
+
+impl DistinctSketch {
+    /// Add a value
+    pub fn push(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        // Normalise -0.0 so it counts as 0.0
+        let bits = (value + 0.0).to_bits();
+
+        match &mut self.store {
+            DistinctStore::Exact(set) => {
+                set.insert(bits);
+                if set.len() > DISTINCT_EXACT_LIMIT {
+                    self.store = DistinctStore::HyperLogLog(Self::registers_from(set));
+                }
+            }
+            DistinctStore::HyperLogLog(registers) => Self::observe(registers, bits),
+        }
+    }
+
+    /// Combine with another sketch
+    pub fn merge(&mut self, other: &DistinctSketch) {
+        match (&mut self.store, &other.store) {
+            (DistinctStore::Exact(set), DistinctStore::Exact(others)) => {
+                set.extend(others);
+                if set.len() > DISTINCT_EXACT_LIMIT {
+                    self.store = DistinctStore::HyperLogLog(Self::registers_from(set));
+                }
+            }
+            (DistinctStore::HyperLogLog(registers), DistinctStore::Exact(others)) => {
+                for &bits in others {
+                    Self::observe(registers, bits);
+                }
+            }
+            (DistinctStore::HyperLogLog(registers), DistinctStore::HyperLogLog(others)) => {
+                for (register, &other) in registers.iter_mut().zip(others) {
+                    *register = (*register).max(other);
+                }
+            }
+            (DistinctStore::Exact(set), DistinctStore::HyperLogLog(others)) => {
+                let mut registers = others.clone();
+                for &bits in set.iter() {
+                    Self::observe(&mut registers, bits);
+                }
+                self.store = DistinctStore::HyperLogLog(registers);
+            }
+        }
+    }
+
+    /// Whether the count is still exact
+    pub fn is_exact(&self) -> bool {
+        matches!(self.store, DistinctStore::Exact(_))
+    }
+
+    /// Number of distinct values (estimated once the sketch is no longer exact)
+    pub fn count(&self) -> u64 {
+        match &self.store {
+            DistinctStore::Exact(set) => set.len() as u64,
+            DistinctStore::HyperLogLog(registers) => Self::estimate(registers),
+        }
+    }
+
+    fn registers_from(set: &HashSet<u64>) -> Vec<u8> {
+        let mut registers = vec![0u8; 1 << HLL_PRECISION];
+        for &bits in set {
+            Self::observe(&mut registers, bits);
+        }
+        registers
+    }
+
+    fn observe(registers: &mut [u8], bits: u64) {
+        let hash = mix64(bits);
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rest = hash << HLL_PRECISION;
+        let rank = rest.leading_zeros().min(64 - HLL_PRECISION) as u8 + 1;
+        registers[index] = registers[index].max(rank);
+    }
+
+    fn estimate(registers: &[u8]) -> u64 {
+        let m = registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+
+        // Small-range correction (linear counting)
+        let zeros = registers.iter().filter(|&&r| r == 0).count();
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+}
+
+/// SplitMix64 finaliser, spreading value bits evenly over the hash space
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Incremental, mergeable state of one aggregation function
+#[derive(Debug, Clone)]
+pub struct Aggregator {
+    func: AggregationFunc,
+    state: AggregateState,
+}
+
+#[derive(Debug, Clone)]
+enum AggregateState {
+    Moments(Moments),
+    Edges { first: Option<f64>, last: Option<f64> },
+    Quantiles(QuantileSketch),
+    Distinct(DistinctSketch),
+}
+
+impl Aggregator {
+    /// Create an empty aggregator for a function
+    pub fn new(func: AggregationFunc) -> Self {
+        let state = match func {
+            AggregationFunc::Avg
+            | AggregationFunc::Sum
+            | AggregationFunc::Min
+            | AggregationFunc::Max
+            | AggregationFunc::Count
+            | AggregationFunc::Stddev
+            | AggregationFunc::Variance
+            | AggregationFunc::Spread => AggregateState::Moments(Moments::default()),
+            AggregationFunc::First | AggregationFunc::Last => AggregateState::Edges {
+                first: None,
+                last: None,
+            },
+            AggregationFunc::Median | AggregationFunc::Percentile(_) => {
+                AggregateState::Quantiles(QuantileSketch::default())
+            }
+            AggregationFunc::CountDistinct => AggregateState::Distinct(DistinctSketch::default()),
+        };
+        Self { func, state }
+    }
+
+    /// Aggregation function this state computes
+    pub fn func(&self) -> AggregationFunc {
+        self.func
+    }
+
+    /// Add a value (values are expected in time order)
+    pub fn push(&mut self, value: f64) {
+        match &mut self.state {
+            AggregateState::Moments(moments) => moments.push(value),
+            AggregateState::Edges { first, last } => {
+                first.get_or_insert(value);
+                *last = Some(value);
+            }
+            AggregateState::Quantiles(sketch) => sketch.push(value),
+            AggregateState::Distinct(sketch) => sketch.push(value),
+        }
+    }
+
+    /// Combine with the state of a later group of values
+    ///
+    /// # Panics
+    ///
+    /// Panics if `other` was built for a function with a different kind of
+    /// state (e.g. merging a MEDIAN into a SUM).
+    pub fn merge(&mut self, other: &Aggregator) {
+        match (&mut self.state, &other.state) {
+            (AggregateState::Moments(moments), AggregateState::Moments(others)) => {
+                moments.merge(others)
+            }
+            (
+                AggregateState::Edges { first, last },
+                AggregateState::Edges {
+                    first: other_first,
+                    last: other_last,
+                },
+            ) => {
+                if first.is_none() {
+                    *first = *other_first;
+                }
+                if other_last.is_some() {
+                    *last = *other_last;
+                }
+            }
+            (AggregateState::Quantiles(sketch), AggregateState::Quantiles(others)) => {
+                sketch.merge(others)
+            }
+            (AggregateState::Distinct(sketch), AggregateState::Distinct(others)) => {
+                sketch.merge(others)
+            }
+            _ => panic!("cannot merge {} state into {}", other.func, self.func),
+        }
+    }
+
+    /// Final value of the aggregation (None if there is not enough data)
+    pub fn finish(&self) -> Option<f64> {
+        match (&self.state, self.func) {
+            (AggregateState::Moments(m), AggregationFunc::Avg) => m.mean(),
+            (AggregateState::Moments(m), AggregationFunc::Sum) => m.sum(),
+            (AggregateState::Moments(m), AggregationFunc::Min) => m.min(),
+            (AggregateState::Moments(m), AggregationFunc::Max) => m.max(),
+            (AggregateState::Moments(m), AggregationFunc::Count) => {
+                (m.count() > 0).then(|| m.count() as f64)
+            }
+            (AggregateState::Moments(m), AggregationFunc::Stddev) => m.stddev(),
+            (AggregateState::Moments(m), AggregationFunc::Variance) => m.variance(),
+            (AggregateState::Moments(m), AggregationFunc::Spread) => m.spread(),
+            (AggregateState::Edges { first, .. }, AggregationFunc::First) => *first,
+            (AggregateState::Edges { last, .. }, AggregationFunc::Last) => *last,
+            (AggregateState::Quantiles(sketch), AggregationFunc::Median) => sketch.quantile(0.5),
+            (AggregateState::Quantiles(sketch), AggregationFunc::Percentile(q)) => {
+                sketch.quantile(q)
+            }
+            (AggregateState::Distinct(sketch), AggregationFunc::CountDistinct) => {
+                let count = sketch.count();
+                (count > 0).then_some(count as f64)
+            }
+            _ => unreachable!("aggregator state always matches its function"),
+        }
+    }
+}
+
+impl Extend<f64> for Aggregator {
+    fn extend<I: IntoIterator<Item = f64>>(&mut self, values: I) {
+        for value in values {
+            self.push(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(func: AggregationFunc, values: &[f64]) -> Option<f64> {
+        let mut aggregator = Aggregator::new(func);
+        aggregator.extend(values.iter().copied());
+        aggregator.finish()
+    }
+
+    #[test]
+    fn test_exact_statistics() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+
+        assert_eq!(aggregate(AggregationFunc::Median, &values), Some(4.5));
+        assert_eq!(aggregate(AggregationFunc::Percentile(1.0), &values), Some(9.0));
+        assert_eq!(aggregate(AggregationFunc::Percentile(0.25), &values), Some(4.0));
+        assert_eq!(aggregate(AggregationFunc::Spread, &values), Some(7.0));
+        assert_eq!(aggregate(AggregationFunc::CountDistinct, &values), Some(5.0));
+
+        let variance = aggregate(AggregationFunc::Variance, &values).unwrap();
+        assert!((variance - 32.0 / 7.0).abs() < 1e-12);
+        let stddev = aggregate(AggregationFunc::Stddev, &values).unwrap();
+        assert!((stddev - (32.0f64 / 7.0).sqrt()).abs() < 1e-12);
+
+        assert_eq!(aggregate(AggregationFunc::Stddev, &[3.0]), None);
+        assert_eq!(aggregate(AggregationFunc::Median, &[]), None);
+    }
+
+    #[test]
+    fn test_merge_matches_single_pass() {
+        let values: Vec<f64> = (0..500).map(|i| ((i * 37) % 101) as f64 / 3.0).collect();
+        let (left, right) = values.split_at(180);
+
+        for func in [
+            AggregationFunc::Avg,
+            AggregationFunc::Count,
+            AggregationFunc::First,
+            AggregationFunc::Last,
+            AggregationFunc::Median,
+            AggregationFunc::Percentile(0.9),
+            AggregationFunc::Variance,
+            AggregationFunc::Spread,
+            AggregationFunc::CountDistinct,
+        ] {
+            let mut merged = Aggregator::new(func);
+            merged.extend(left.iter().copied());
+            let mut later = Aggregator::new(func);
+            later.extend(right.iter().copied());
+            merged.merge(&later);
+
+            let whole = aggregate(func, &values).unwrap();
+            let merged = merged.finish().unwrap();
+            assert!((whole - merged).abs() < 1e-9, "{}: {} vs {}", func, whole, merged);
+        }
+    }
+
+    #[test]
+    fn test_quantile_sketch_bounded_error() {
+        let values: Vec<f64> = (1..=20_000).map(|i| i as f64 * 0.5 - 2_000.0).collect();
+
+        let mut sketch = QuantileSketch::default();
+        let mut halves = QuantileSketch::default();
+        for (i, &value) in values.iter().enumerate() {
+            if i % 2 == 0 {
+                sketch.push(value);
+            } else {
+                halves.push(value);
+            }
+        }
+        sketch.merge(&halves);
+        assert!(!sketch.is_exact());
+        assert_eq!(sketch.count(), values.len() as u64);
+
+        for q in [0.01, 0.25, 0.5, 0.95, 0.999] {
+            let expected = values[(q * (values.len() - 1) as f64) as usize];
+            let estimate = sketch.quantile(q).unwrap();
+            let tolerance = expected.abs() * QUANTILE_RELATIVE_ERROR + 0.5;
+            assert!(
+                (estimate - expected).abs() <= tolerance,
+                "q={}: {} vs {}",
+                q,
+                estimate,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_distinct_sketch_estimate() {
+        let mut sketch = DistinctSketch::default();
+        let mut other = DistinctSketch::default();
+        for i in 0..30_000 {
+            sketch.push(i as f64);
+            // Half overlaps with the first sketch
+            other.push((i + 15_000) as f64);
+        }
+        sketch.merge(&other);
+        assert!(!sketch.is_exact());
+
+        let estimate = sketch.count() as f64;
+        assert!((estimate - 45_000.0).abs() / 45_000.0 < 0.05, "{}", estimate);
+
+        let mut zeros = DistinctSketch::default();
+        zeros.push(0.0);
+        zeros.push(-0.0);
+        zeros.push(f64::NAN);
+        assert_eq!(zeros.count(), 1);
+    }
+}
@@ -0,0 +1,488 @@
+//! Segment compaction
+//!
+//! Every flush appends one small block to the active segment, so over time
+//! the segments directory fills up with tiny blocks and segments whose time
+//! ranges overlap. Compaction rewrites a run of adjacent segments into a
+//! single segment of full-size blocks, sorted by metric and then timestamp,
+//! so the block-level metric index can prune most of them.
+//!
+//! # Crash Safety
+//!
+//! Every compaction is recorded in `segments/compaction.json` before any
+//! segment file is touched:
+//!
+//! ```text
+//! 1. Write the manifest (input ids + output id), fsync
+//! 2. Build the output as segment_NNNNNN.dat.tmp, fsync
+//! 3. Rename it to segment_NNNNNN.dat            ← commit point
+//! 4. Swap segments in memory and re-index
+//! 5. Delete the input files, then the manifest
+//! ```
+//!
+//! On startup a leftover manifest is rolled back (temp file deleted) if the
+//! output was never renamed into place, and rolled forward (inputs deleted,
+//! output re-indexed) if it was.
+
+use crate::index::IndexManager;
+use crate::storage::error::StorageResult;
+use crate::storage::segment::{CompressionType, Segment, SegmentBuilder, FLAG_COMPACTED};
+use crate::storage::types::DataPoint;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Name of the in-progress compaction record inside the segments directory
+const MANIFEST_FILE: &str = "compaction.json";
+
+/// Outcome of a single compaction run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CompactionStats {
+    /// Segments that were merged
+    pub segments_merged: usize,
+    /// Block count across the input segments
+    pub blocks_before: usize,
+    /// Block count of the output segment
+    pub blocks_after: usize,
+    /// Points rewritten
+    pub points: usize,
+    /// Points dropped because a tombstone deleted them
+    pub points_deleted: usize,
+    /// Duplicate points dropped by their metric's duplicate policy
+    pub points_duplicate: usize,
+    /// Input file size in bytes
+    pub bytes_before: u64,
+    /// Output file size in bytes
+    pub bytes_after: u64,
+}
+
+/// Record of a compaction that has started but not yet finished
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CompactionManifest {
+    /// Segments being replaced
+    pub inputs: Vec<u32>,
+    /// Segment being written
+    pub output: u32,
+}
+
+impl CompactionManifest {
+    fn path(segments_dir: &Path) -> PathBuf {
+        segments_dir.join(MANIFEST_FILE)
+    }
+
+    /// Durably write the manifest
+    pub fn write(&self, segments_dir: &Path) -> StorageResult<()> {
+        let path = Self::path(segments_dir);
+        let tmp = path.with_extension("json.tmp");
+
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        sync_dir(segments_dir);
+        Ok(())
+    }
+
+    /// Load a leftover manifest, if any
+    pub fn load(segments_dir: &Path) -> StorageResult<Option<Self>> {
+        let path = Self::path(segments_dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read(&path)?;
+        Ok(Some(serde_json::from_slice(&content)?))
+    }
+
+    /// Mark the compaction as finished
+    pub fn remove(segments_dir: &Path) -> StorageResult<()> {
+        let path = Self::path(segments_dir);
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+            sync_dir(segments_dir);
+        }
+        Ok(())
+    }
+}
+
+/// What the planner needs to know about a loaded segment
+#[derive(Debug, Clone)]
+pub(crate) struct SegmentInfo {
+    pub id: u32,
+    pub size_bytes: u64,
+    pub block_count: usize,
+    pub compacted: bool,
+}
+
+impl SegmentInfo {
+    pub fn from_segment(segment: &Segment) -> Option<Self> {
+        Some(Self {
+            id: segment.id()?,
+            size_bytes: std::fs::metadata(&segment.path).ok()?.len(),
+            block_count: segment.blocks.len(),
+            compacted: segment.header.is_compacted(),
+        })
+    }
+}
+
+/// Choose the next run of segments to merge
+///
+/// `segments` must be in write order. A segment can be merged while it is
+/// smaller than half of `max_segment_size`; the active segment only once it
+/// holds at least `min_blocks` blocks. The first run of adjacent mergeable
+/// segments (capped at `max_segment_size` in total) is returned if it holds
+/// two or more segments, or a single segment that was never compacted and
+/// has more than one block.
+pub(crate) fn plan_compaction(
+    segments: &[SegmentInfo],
+    active_id: u32,
+    max_segment_size: u64,
+    min_blocks: usize,
+) -> Option<Vec<u32>> {
+    let mergeable = |s: &SegmentInfo| {
+        s.size_bytes < max_segment_size / 2 && (s.id != active_id || s.block_count >= min_blocks)
+    };
+    let worth_merging = |run: &[&SegmentInfo]| match run {
+        [] => false,
+        [single] => !single.compacted && single.block_count > 1,
+        _ => true,
+    };
+
+    let mut run: Vec<&SegmentInfo> = Vec::new();
+    let mut run_size = 0u64;
+
+    for segment in segments {
+        let fits = run_size + segment.size_bytes <= max_segment_size;
+
+        if !mergeable(segment) || !fits {
+            if worth_merging(&run) {
+                break;
+            }
+            run.clear();
+            run_size = 0;
+
+            if !mergeable(segment) {
+                continue;
+            }
+        }
+
+        run.push(segment);
+        run_size += segment.size_bytes;
+    }
+
+    if worth_merging(&run) {
+        Some(run.iter().map(|s| s.id).collect())
+    } else {
+        None
+    }
+}
+
+/// Split a run of segments into batches of at most `max_segment_size`
+///
+/// `segments` must be in write order; each batch is an adjacent run of them,
+/// so it can be rewritten on its own. A segment larger than the limit gets
+/// a batch to itself.
+pub(crate) fn batch_segments(segments: &[SegmentInfo], max_segment_size: u64) -> Vec<Vec<u32>> {
+    let mut batches: Vec<Vec<u32>> = Vec::new();
+    let mut batch_size = 0u64;
+
+    for segment in segments {
+        match batches.last_mut() {
+            Some(batch) if batch_size + segment.size_bytes <= max_segment_size => {
+                batch.push(segment.id);
+                batch_size += segment.size_bytes;
+            }
+            _ => {
+                batches.push(vec![segment.id]);
+                batch_size = segment.size_bytes;
+            }
+        }
+    }
+
+    batches
+}
+
+/// File name of a segment
+pub(crate) fn segment_file_name(id: u32) -> String {
+    format!("segment_{:06}.dat", id)
+}
+
+/// Path of a segment file
+pub(crate) fn segment_path(segments_dir: &Path, id: u32) -> PathBuf {
+    segments_dir.join(segment_file_name(id))
+}
+
+/// Path a compaction output is built at before it is renamed into place
+pub(crate) fn temp_segment_path(segments_dir: &Path, id: u32) -> PathBuf {
+    segments_dir.join(format!("segment_{:06}.dat.tmp", id))
+}
+
+/// Fsync a directory so renames and deletes inside it are durable
+///
+/// Not supported on every platform, so failures are ignored.
+pub(crate) fn sync_dir(dir: &Path) {
+    if let Ok(handle) = File::open(dir) {
+        let _ = handle.sync_all();
+    }
+}
+
+/// Write points into a new compacted segment at `path`
+///
+/// Points are clustered by metric and sorted by time within each metric;
+/// the sort is stable, so points sharing a timestamp keep their write order.
+/// The segment is stamped with `sequence` (see `Segment::sequence`).
+/// Returns `None` when there are no points to write.
+pub(crate) fn build_compacted(
+    path: &Path,
+    compression: CompressionType,
+    block_size: usize,
+    sequence: u32,
+    mut points: Vec<DataPoint>,
+) -> StorageResult<Option<Segment>> {
+    points.sort_by_key(|p| (p.metric_id, p.timestamp));
+
+    let mut builder = SegmentBuilder::new(path, compression)
+        .target_block_size(block_size)
+        .flags(FLAG_COMPACTED)
+        .sequence(sequence);
+    builder.add_points(points)?;
+
+    let segment = builder.finish()?;
+    if segment.is_some() {
+        File::open(path)?.sync_all()?;
+    }
+    Ok(segment)
+}
+
+/// Everything the index needs to know about a segment's blocks
+#[derive(Debug, Default)]
+pub(crate) struct SegmentIndexData {
+    /// (block_idx, min_timestamp) per block
+    boundaries: Vec<(u32, i64)>,
+    /// Distinct metrics per block
+    block_metrics: Vec<Vec<u32>>,
+    /// Tag key → (block_idx, value) entries
+    tags: HashMap<String, Vec<(u32, String)>>,
+    /// Total points read
+    pub point_count: usize,
+}
+
+impl SegmentIndexData {
+    /// Read every block of a segment and collect its index entries
+    pub fn scan(segment: &Segment) -> StorageResult<Self> {
+        let mut data = Self::default();
+
+        for idx in 0..segment.blocks.len() {
+            let points = segment.read_block(idx)?;
+            let block_idx = idx as u32;
+
+            data.boundaries
+                .push((block_idx, segment.blocks[idx].min_timestamp));
+            data.point_count += points.len();
+
+            let metrics: BTreeSet<u32> = points.iter().map(|p| p.metric_id).collect();
+            data.block_metrics.push(metrics.into_iter().collect());
+
+            let tag_pairs: HashSet<(&String, &String)> =
+                points.iter().flat_map(|p| p.tags.iter()).collect();
+            for (key, value) in tag_pairs {
+                data.tags
+                    .entry(key.clone())
+                    .or_default()
+                    .push((block_idx, value.clone()));
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Replace whatever the index holds for `segment_id` with this data
+    pub fn apply(&self, index: &mut IndexManager, segment_id: u32) -> StorageResult<()> {
+        let metrics: Vec<u32> = self
+            .block_metrics
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        index.remove_segment(segment_id)?;
+        index.index_segment(segment_id, &self.boundaries, &metrics, Some(&self.tags))?;
+
+        for (block_idx, block_metrics) in self.block_metrics.iter().enumerate() {
+            index.index_block_metrics(segment_id, block_idx as u32, block_metrics);
+        }
+
+        Ok(())
+    }
+}
+
+/// Finish or undo a compaction interrupted by a crash
+///
+/// Must run before segments are loaded, so a half-written output or an
+/// already-replaced input is never served.
+pub(crate) fn recover(segments_dir: &Path, index: &mut IndexManager) -> StorageResult<()> {
+    let manifest = match CompactionManifest::load(segments_dir)? {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+
+    let output = segment_path(segments_dir, manifest.output);
+
+    if output.exists() {
+        tracing::warn!(
+            "Completing interrupted compaction of segments {:?} into {}",
+            manifest.inputs,
+            manifest.output
+        );
+
+        for &id in &manifest.inputs {
+            index.remove_segment(id)?;
+            let path = segment_path(segments_dir, id);
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        let segment = Segment::open(&output)?;
+        SegmentIndexData::scan(&segment)?.apply(index, manifest.output)?;
+        index.persist()?;
+    } else {
+        tracing::warn!(
+            "Rolling back interrupted compaction of segments {:?}",
+            manifest.inputs
+        );
+
+        let tmp = temp_segment_path(segments_dir, manifest.output);
+        if tmp.exists() {
+            std::fs::remove_file(&tmp)?;
+        }
+    }
+
+    sync_dir(segments_dir);
+    CompactionManifest::remove(segments_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn info(id: u32, size_bytes: u64, block_count: usize, compacted: bool) -> SegmentInfo {
+        SegmentInfo {
+            id,
+            size_bytes,
+            block_count,
+            compacted,
+        }
+    }
+
+    #[test]
+    fn test_batch_segments() {
+        let segments = vec![
+            info(1, 400, 1, false),
+            info(2, 500, 1, false),
+            info(3, 200, 1, false),
+            info(4, 1500, 1, true),
+            info(5, 100, 1, false),
+        ];
+        assert_eq!(
+            batch_segments(&segments, 1000),
+            vec![vec![1, 2], vec![3], vec![4], vec![5]]
+        );
+        assert!(batch_segments(&[], 1000).is_empty());
+    }
+
+    #[test]
+    fn test_plan_merges_adjacent_small_segments() {
+        let segments = vec![info(1, 100, 3, false), info(2, 100, 1, true), info(3, 100, 2, false)];
+
+        // Segment 3 is active with fewer than min_blocks blocks
+        let plan = plan_compaction(&segments, 3, 1000, 8);
+        assert_eq!(plan, Some(vec![1, 2]));
+
+        // Once the active segment is fragmented enough, it joins the run
+        let plan = plan_compaction(&segments, 3, 1000, 2);
+        assert_eq!(plan, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_plan_skips_large_and_compacted_segments() {
+        // A lone compacted segment is left alone
+        assert_eq!(plan_compaction(&[info(1, 100, 4, true)], 2, 1000, 8), None);
+
+        // A lone uncompacted segment with several blocks is rewritten
+        assert_eq!(
+            plan_compaction(&[info(1, 100, 4, false)], 2, 1000, 8),
+            Some(vec![1])
+        );
+
+        // Large segments break runs
+        let segments = vec![info(1, 100, 1, true), info(2, 900, 5, false), info(3, 100, 1, true)];
+        assert_eq!(plan_compaction(&segments, 4, 1000, 8), None);
+    }
+
+    #[test]
+    fn test_plan_caps_run_size() {
+        let segments = vec![
+            info(1, 400, 1, true),
+            info(2, 400, 1, true),
+            info(3, 400, 1, true),
+        ];
+        assert_eq!(plan_compaction(&segments, 4, 1000, 8), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_build_compacted_clusters_by_metric() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("segment_000001.dat");
+
+        let points: Vec<DataPoint> = (0..200)
+            .map(|i| DataPoint::with_timestamp(i % 2, i as f64, 1000 - i as i64))
+            .collect();
+
+        let segment = build_compacted(&path, CompressionType::Lz4, 1024, 1, points)
+            .unwrap()
+            .unwrap();
+        assert!(segment.header.is_compacted());
+
+        // Blocks are time-sorted internally
+        for idx in 0..segment.blocks.len() {
+            let block = segment.read_block(idx).unwrap();
+            assert!(block.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        }
+
+        let data = SegmentIndexData::scan(&segment).unwrap();
+        assert_eq!(data.point_count, 200);
+        assert_eq!(data.boundaries.len(), segment.blocks.len());
+        // Only the block where the metrics switch holds both
+        let mixed = data.block_metrics.iter().filter(|m| m.len() > 1).count();
+        assert!(mixed <= 1);
+    }
+
+    #[test]
+    fn test_build_compacted_empty() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("segment_000001.dat");
+
+        let segment = build_compacted(&path, CompressionType::Lz4, 1024, 1, Vec::new()).unwrap();
+        assert!(segment.is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let dir = tempdir().unwrap();
+        assert_eq!(CompactionManifest::load(dir.path()).unwrap(), None);
+
+        let manifest = CompactionManifest {
+            inputs: vec![1, 2],
+            output: 5,
+        };
+        manifest.write(dir.path()).unwrap();
+        assert_eq!(CompactionManifest::load(dir.path()).unwrap(), Some(manifest));
+
+        CompactionManifest::remove(dir.path()).unwrap();
+        assert_eq!(CompactionManifest::load(dir.path()).unwrap(), None);
+    }
+}
@@ -10,8 +10,14 @@
 //! 5. LZ4 compress the result
 //!
 //! Expected compression: ~10x (100 bytes/point → ~10 bytes/point)
+//!
+//! Segments can instead use the columnar codec in
+//! [`gorilla`](crate::storage::gorilla); `compress_block_with` and
+//! `decompress_block_with` pick the codec from a segment's `CompressionType`.
 
 use crate::storage::error::{StorageError, StorageResult};
+use crate::storage::gorilla;
+use crate::storage::segment::CompressionType;
 use crate::storage::types::DataPoint;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -33,7 +39,7 @@ struct EncodedBlock {
 
 /// Space-efficient tag storage with string deduplication
 #[derive(Debug, Serialize, Deserialize)]
-struct EncodedTags {
+pub(crate) struct EncodedTags {
     /// String intern table
     strings: Vec<String>,
     /// For each point: Vec of (key_idx, value_idx) pairs
@@ -41,7 +47,7 @@ struct EncodedTags {
 }
 
 impl EncodedTags {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             strings: Vec::new(),
             point_tags: Vec::new(),
@@ -60,7 +66,7 @@ impl EncodedTags {
     }
 
     /// Add tags for a point
-    fn add_point_tags(&mut self, tags: &HashMap<String, String>) {
+    pub(crate) fn add_point_tags(&mut self, tags: &HashMap<String, String>) {
         let encoded: Vec<(u16, u16)> = tags
             .iter()
             .map(|(k, v)| (self.intern(k), self.intern(v)))
@@ -69,7 +75,7 @@ impl EncodedTags {
     }
 
     /// Decode tags for a point
-    fn decode_point_tags(&self, point_idx: usize) -> HashMap<String, String> {
+    pub(crate) fn decode_point_tags(&self, point_idx: usize) -> HashMap<String, String> {
         self.point_tags
             .get(point_idx)
             .map(|pairs| {
@@ -181,12 +187,37 @@ pub fn decompress_block(data: &[u8]) -> StorageResult<Vec<DataPoint>> {
     Ok(points)
 }
 
+/// Compress a block with the codec a segment was created with
+///
+/// `None` segments have always been written with the LZ4 codec, so they
+/// keep sharing it.
+pub fn compress_block_with(
+    points: &[DataPoint],
+    compression: CompressionType,
+) -> StorageResult<Vec<u8>> {
+    match compression {
+        CompressionType::None | CompressionType::Lz4 => compress_block(points),
+        CompressionType::Gorilla => gorilla::encode_block(points),
+    }
+}
+
+/// Decompress a block written by `compress_block_with`
+pub fn decompress_block_with(
+    data: &[u8],
+    compression: CompressionType,
+) -> StorageResult<Vec<DataPoint>> {
+    match compression {
+        CompressionType::None | CompressionType::Lz4 => decompress_block(data),
+        CompressionType::Gorilla => gorilla::decode_block(data),
+    }
+}
+
 /// Get compression statistics for a block
 #[derive(Debug)]
 pub struct CompressionStats {
     /// Number of points
     pub point_count: usize,
-    /// Original size estimate (bytes)
+    /// Uncompressed size: the points serialized without any encoding (bytes)
     pub original_size: usize,
     /// Compressed size (bytes)
     pub compressed_size: usize,
@@ -194,9 +225,27 @@ pub struct CompressionStats {
     pub ratio: f64,
 }
 
+impl CompressionStats {
+    /// Average compressed bytes per point
+    pub fn bytes_per_point(&self) -> f64 {
+        if self.point_count > 0 {
+            self.compressed_size as f64 / self.point_count as f64
+        } else {
+            0.0
+        }
+    }
+}
+
 /// Calculate compression statistics
+///
+/// The ratio is measured against the points' plain bincode encoding rather
+/// than the in-memory estimate used for buffer management, so it reflects
+/// what the codec actually saves.
 pub fn compression_stats(points: &[DataPoint], compressed: &[u8]) -> CompressionStats {
-    let original_size: usize = points.iter().map(|p| p.estimated_size()).sum();
+    let original_size: usize = points
+        .iter()
+        .map(|p| bincode::serialized_size(p).unwrap_or(0) as usize)
+        .sum();
     let compressed_size = compressed.len();
     let ratio = if compressed_size > 0 {
         original_size as f64 / compressed_size as f64
@@ -0,0 +1,159 @@
+//! Duplicate point handling
+//!
+//! Re-running an integration sync or re-importing an export writes the same
+//! points again. Two points are duplicates when they share metric, timestamp
+//! and tag set; each metric's `DuplicatePolicy` decides which copies survive.
+//!
+//! Duplicates are never rejected on write, since that would need a lookup
+//! per point. Instead they are resolved whenever points are read back: by
+//! queries, and by rewrites (compaction, retention and purges), which drop
+//! the losing copies for good. Resolution needs the points in write order,
+//! oldest first: segments by `Segment::sequence`, then the write buffer.
+
+use crate::storage::types::DataPoint;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+/// Which copies of a duplicated point are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Keep every copy
+    #[default]
+    KeepAll,
+    /// Keep the most recently written copy (upsert)
+    LastWriteWins,
+    /// Keep the first copy and ignore later writes
+    Reject,
+}
+
+impl DuplicatePolicy {
+    /// Check if this is the default policy
+    pub fn is_keep_all(&self) -> bool {
+        *self == DuplicatePolicy::KeepAll
+    }
+}
+
+/// Identity of a point for duplicate detection
+#[derive(Hash, PartialEq, Eq)]
+struct PointKey<'a> {
+    metric_id: u32,
+    timestamp: i64,
+    tags: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> PointKey<'a> {
+    fn of(point: &'a DataPoint) -> Self {
+        let mut tags: Vec<(&str, &str)> = point
+            .tags
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        tags.sort_unstable();
+
+        Self {
+            metric_id: point.metric_id,
+            timestamp: point.timestamp,
+            tags,
+        }
+    }
+}
+
+/// Drop the duplicate copies that a metric's policy discards
+///
+/// `points` must be in write order. The survivors keep their relative order.
+/// Returns the number of points dropped.
+pub fn resolve(points: &mut Vec<DataPoint>, policy: impl Fn(u32) -> DuplicatePolicy) -> usize {
+    let mut keep = vec![true; points.len()];
+    let mut dropped = 0;
+
+    {
+        // Index of the surviving copy of each key seen so far
+        let mut seen: HashMap<PointKey, usize> = HashMap::new();
+        for (idx, point) in points.iter().enumerate() {
+            let policy = policy(point.metric_id);
+            if policy.is_keep_all() {
+                continue;
+            }
+
+            match seen.entry(PointKey::of(point)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(idx);
+                }
+                Entry::Occupied(mut entry) => {
+                    dropped += 1;
+                    if policy == DuplicatePolicy::LastWriteWins {
+                        keep[*entry.get()] = false;
+                        entry.insert(idx);
+                    } else {
+                        keep[idx] = false;
+                    }
+                }
+            }
+        }
+    }
+
+    if dropped > 0 {
+        let mut flags = keep.into_iter();
+        points.retain(|_| flags.next().unwrap_or(true));
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy_by_metric(metric_id: u32) -> DuplicatePolicy {
+        match metric_id {
+            1 => DuplicatePolicy::LastWriteWins,
+            2 => DuplicatePolicy::Reject,
+            _ => DuplicatePolicy::KeepAll,
+        }
+    }
+
+    #[test]
+    fn test_resolve_applies_each_metrics_policy() {
+        let mut points = vec![
+            DataPoint::with_timestamp(1, 1.0, 1000),
+            DataPoint::with_timestamp(2, 1.0, 1000),
+            DataPoint::with_timestamp(3, 1.0, 1000),
+            DataPoint::with_timestamp(1, 2.0, 1000),
+            DataPoint::with_timestamp(2, 2.0, 1000),
+            DataPoint::with_timestamp(3, 2.0, 1000),
+        ];
+
+        assert_eq!(resolve(&mut points, policy_by_metric), 2);
+
+        let values: Vec<(u32, f64)> = points.iter().map(|p| (p.metric_id, p.value)).collect();
+        assert_eq!(values, vec![(2, 1.0), (3, 1.0), (1, 2.0), (3, 2.0)]);
+    }
+
+    #[test]
+    fn test_resolve_keys_on_tag_set() {
+        let mut points = vec![
+            DataPoint::with_timestamp(1, 1.0, 1000)
+                .tag("source", "fitbit")
+                .tag("device", "watch"),
+            DataPoint::with_timestamp(1, 2.0, 1000).tag("source", "manual"),
+            DataPoint::with_timestamp(1, 3.0, 1000)
+                .tag("device", "watch")
+                .tag("source", "fitbit"),
+            DataPoint::with_timestamp(1, 4.0, 2000).tag("source", "manual"),
+        ];
+
+        // Only the two fitbit points share a key, regardless of tag order
+        assert_eq!(resolve(&mut points, |_| DuplicatePolicy::LastWriteWins), 1);
+        let values: Vec<f64> = points.iter().map(|p| p.value).collect();
+        assert_eq!(values, vec![2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_policy_serde_names() {
+        let json = serde_json::to_string(&DuplicatePolicy::LastWriteWins).unwrap();
+        assert_eq!(json, "\"last_write_wins\"");
+        let parsed: DuplicatePolicy = serde_json::from_str("\"reject\"").unwrap();
+        assert_eq!(parsed, DuplicatePolicy::Reject);
+    }
+}
@@ -5,18 +5,66 @@
 //! - Read path: Query → Index → Segment → Decompress → Filter
 //!
 //! Thread-safe via Tokio's async RwLock for concurrent access.
-
-use crate::index::{IndexConfig, IndexManager};
+//!
+//! # Query Planning
+//!
+//! Block metadata in each segment footer prunes by time. When a query is
+//! restricted to a metric or tags, the `IndexManager` is asked which of the
+//! remaining blocks can hold matching points, and only those blocks are
+//! decompressed. Segments the index has never seen are scanned in full.
+//!
+//! # Deletes
+//!
+//! Point deletes are recorded as [`tombstone`](crate::storage::tombstone)s
+//! and hidden at query time until a rewrite removes the points. Metrics can
+//! be soft deleted (hidden, data kept) or hard deleted (data tombstoned and
+//! the name freed).
+//!
+//! # Duplicates
+//!
+//! Points sharing metric, timestamp and tags are resolved by the metric's
+//! [`DuplicatePolicy`] at query time and whenever segments are rewritten.
+//! Both read points in write order: segments by
+//! [`Segment::sequence`], then the write buffer.
+//!
+//! # Flush Notifications
+//!
+//! Every flushed block is announced as a [`FlushedBlock`] to the receivers
+//! of [`StorageEngine::subscribe_flushes`], so derived data such as
+//! continuous queries can be brought up to date incrementally.
+//!
+//! Listeners registered with [`StorageEngine::on_change`] are told about
+//! every [`DataChange`] before the call making it returns, so caches of
+//! query results never serve points that have been overwritten or deleted.
+
+use crate::index::{DataLocation, IndexConfig, IndexManager};
+use crate::storage::compaction::{
+    self, CompactionManifest, CompactionStats, SegmentIndexData, SegmentInfo,
+};
+use crate::storage::duplicates::{self, DuplicatePolicy};
 use crate::storage::error::{StorageError, StorageResult};
+use crate::storage::lock::DirLock;
+use crate::storage::retention::{
+    self, CategoryRetention, ExpiryRule, RetentionPolicy, RetentionStats, RollupTarget,
+};
 use crate::storage::segment::{CompressionType, Segment};
-use crate::storage::types::{Category, DataPoint, Metric, QueryFilter, TimeRange};
-use crate::storage::wal::{WalSyncMode, WriteAheadLog};
+use crate::storage::snapshot::{self, SnapshotManifest};
+use crate::storage::tombstone::{Tombstone, TombstoneSet};
+use crate::storage::types::{Category, DataPoint, DeleteMode, Metric, QueryFilter, TimeRange};
+use crate::storage::wal::{WalEntry, WalSyncMode, WriteAheadLog};
+use chrono::Utc;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::sync::RwLock;
+use std::time::Instant;
+use tokio::sync::{broadcast, RwLock};
 use tokio::time::{interval, Duration};
 
+/// Flush notifications a subscriber may fall behind by before missing some
+const FLUSH_CHANNEL_CAPACITY: usize = 64;
+
 /// Configuration for the storage engine
 #[derive(Debug, Clone)]
 pub struct StorageConfig {
@@ -26,7 +74,7 @@ pub struct StorageConfig {
     pub block_size: usize,
     /// Maximum time before flush in milliseconds (default: 5000)
     pub flush_interval_ms: u64,
-    /// Compression type to use
+    /// Compression type for new segments (existing segments keep theirs)
     pub compression: CompressionType,
     /// WAL sync strategy
     pub wal_sync: WalSyncMode,
@@ -34,6 +82,10 @@ pub struct StorageConfig {
     pub max_segment_size: u64,
     /// Enable tag indexing
     pub enable_tag_index: bool,
+    /// Interval between background compaction runs in milliseconds (default: 600000)
+    pub compaction_interval_ms: u64,
+    /// Blocks the active segment must hold before compaction seals it (default: 8)
+    pub compaction_min_blocks: usize,
 }
 
 impl Default for StorageConfig {
@@ -46,6 +98,8 @@ impl Default for StorageConfig {
             wal_sync: WalSyncMode::Batched,
             max_segment_size: 64 * 1024 * 1024, // 64MB
             enable_tag_index: true,
+            compaction_interval_ms: 10 * 60 * 1000, // 10 minutes
+            compaction_min_blocks: 8,
         }
     }
 }
@@ -73,6 +127,16 @@ impl StorageConfig {
         self.data_dir.join("meta").join("metrics.json")
     }
 
+    /// Get path to category retention policies
+    pub fn retention_path(&self) -> PathBuf {
+        self.data_dir.join("meta").join("retention.json")
+    }
+
+    /// Get path to tombstone checkpoint file
+    pub fn tombstones_path(&self) -> PathBuf {
+        self.data_dir.join("meta").join("tombstones.json")
+    }
+
     /// Get path to config file
     pub fn config_path(&self) -> PathBuf {
         self.data_dir.join("meta").join("config.json")
@@ -104,8 +168,11 @@ impl MetricRegistry {
 
         let mut registry = Self::new();
         for metric in metrics {
-            registry.metrics.push(metric.clone());
-            registry.name_to_id.insert(metric.name.clone(), metric.id);
+            // Hard-deleted metrics keep their slot but give up their name
+            if metric.deleted != Some(DeleteMode::Hard) {
+                registry.name_to_id.insert(metric.name.clone(), metric.id);
+            }
+            registry.metrics.push(metric);
         }
 
         Ok(registry)
@@ -123,9 +190,12 @@ impl MetricRegistry {
     }
 
     /// Register a new metric
+    ///
+    /// Registering the name of a soft-deleted metric restores it.
     pub fn register(&mut self, mut metric: Metric) -> u32 {
         // Check if already exists
         if let Some(&id) = self.name_to_id.get(&metric.name) {
+            self.metrics[id as usize].deleted = None;
             return id;
         }
 
@@ -136,36 +206,71 @@ impl MetricRegistry {
         id
     }
 
-    /// Get metric by name
+    /// Mark a metric as deleted
+    ///
+    /// A hard delete frees the name for a new metric; the old entry keeps
+    /// its slot so IDs stay stable. Returns false if no such metric exists.
+    pub fn delete(&mut self, id: u32, mode: DeleteMode) -> bool {
+        let metric = match self.metrics.get_mut(id as usize) {
+            Some(metric) => metric,
+            None => return false,
+        };
+
+        metric.deleted = Some(mode);
+        if mode == DeleteMode::Hard && self.name_to_id.get(&metric.name) == Some(&id) {
+            self.name_to_id.remove(&metric.name);
+        }
+        true
+    }
+
+    /// Get metric by name (includes soft-deleted metrics)
     pub fn get_by_name(&self, name: &str) -> Option<&Metric> {
         self.name_to_id
             .get(name)
             .and_then(|&id| self.metrics.get(id as usize))
     }
 
-    /// Get metric by ID
+    /// Get metric by ID (includes deleted metrics)
     pub fn get_by_id(&self, id: u32) -> Option<&Metric> {
         self.metrics.get(id as usize)
     }
 
-    /// Get all metrics
+    /// Get all metrics, including deleted ones
     pub fn all(&self) -> &[Metric] {
         &self.metrics
     }
 
+    /// Replace a metric's definition, keeping its ID and name
+    ///
+    /// Returns false if no metric with that ID exists.
+    pub fn update(&mut self, metric: Metric) -> bool {
+        match self.metrics.get_mut(metric.id as usize) {
+            Some(existing) if existing.name == metric.name => {
+                *existing = metric;
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Get metrics by category
     pub fn by_category(&self, category: Category) -> Vec<&Metric> {
         self.metrics
             .iter()
-            .filter(|m| m.category == category)
+            .filter(|m| m.category == category && m.is_active())
             .collect()
     }
+
+    /// Get an active (not deleted) metric by ID
+    pub fn get_active(&self, id: u32) -> Option<&Metric> {
+        self.get_by_id(id).filter(|m| m.is_active())
+    }
 }
 
 /// Internal state for the storage engine
 struct EngineState {
-    /// Loaded segments (sorted by min_timestamp)
-    segments: Vec<Segment>,
+    /// Loaded segments (sorted by min_timestamp), shared with running queries
+    segments: Vec<Arc<Segment>>,
     /// Current segment being written to
     current_segment_id: u32,
 }
@@ -180,12 +285,112 @@ pub struct StorageEngine {
     write_buffer: Arc<RwLock<Vec<DataPoint>>>,
     /// Metric registry
     metrics: Arc<RwLock<MetricRegistry>>,
+    /// Retention policies by category
+    category_retention: RwLock<CategoryRetention>,
+    /// Point deletes not yet purged from segments
+    tombstones: RwLock<TombstoneSet>,
     /// Engine state (segments)
     state: Arc<RwLock<EngineState>>,
     /// Index manager for efficient queries (std::sync::Mutex because SQLite is !Send)
     index: Arc<Mutex<IndexManager>>,
     /// Shutdown signal
     shutdown: Arc<RwLock<bool>>,
+    /// Total blocks decompressed by queries since startup
+    blocks_read: AtomicU64,
+    /// Total blocks skipped by query planning since startup
+    blocks_pruned: AtomicU64,
+    /// Serializes compaction runs
+    compaction_lock: tokio::sync::Mutex<()>,
+    /// Announces flushed blocks
+    flushed: broadcast::Sender<FlushedBlock>,
+    /// Called on every change to stored data
+    change_listeners: Mutex<Vec<ChangeListener>>,
+    /// Keeps other engines and offline tools out of the data directory
+    _lock: DirLock,
+}
+
+/// Callback registered with `StorageEngine::on_change`
+pub type ChangeListener = Box<dyn Fn(&DataChange) + Send + Sync>;
+
+/// Change to stored data that can alter query results
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataChange {
+    /// Points of these metrics were written between the timestamps (inclusive)
+    Written {
+        metric_ids: Vec<u32>,
+        min_timestamp: i64,
+        max_timestamp: i64,
+    },
+    /// Points may have changed anywhere: deletes, expiry, duplicate policies
+    Reset,
+}
+
+/// Points written to segments by one flush
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlushedBlock {
+    /// Metrics with points in the block
+    pub metric_ids: Vec<u32>,
+    /// Oldest point timestamp
+    pub min_timestamp: i64,
+    /// Newest point timestamp
+    pub max_timestamp: i64,
+}
+
+/// Statistics describing how a single storage query was executed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ScanStats {
+    /// Segments that overlap the query range
+    pub segments_scanned: usize,
+    /// Total blocks across all loaded segments
+    pub blocks_total: usize,
+    /// Blocks skipped because their time bounds miss the query range
+    pub blocks_pruned_by_time: usize,
+    /// Blocks skipped because the index ruled out the metric or tags
+    pub blocks_pruned_by_index: usize,
+    /// Blocks actually read and decompressed
+    pub blocks_read: usize,
+    /// Points decompressed from segments (before filtering)
+    pub points_scanned: usize,
+    /// Points taken from the unflushed write buffer
+    pub buffer_points: usize,
+    /// Points hidden by tombstones
+    pub points_deleted: usize,
+    /// Points hidden by their metric's duplicate policy
+    pub points_duplicate: usize,
+    /// Time spent reading and decompressing blocks, in microseconds
+    pub decompress_us: u64,
+    /// Blocks chosen in each segment, in read order
+    pub segments: Vec<SegmentScan>,
+}
+
+/// Blocks of one segment chosen by query planning
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SegmentScan {
+    /// Segment file name
+    pub segment: String,
+    /// Indices of the blocks to read
+    pub blocks: Vec<usize>,
+}
+
+impl ScanStats {
+    /// Total blocks that were not read
+    pub fn blocks_pruned(&self) -> usize {
+        self.blocks_pruned_by_time + self.blocks_pruned_by_index
+    }
+}
+
+/// Describe a block plan by segment file name
+fn segment_scans(segments: &[Arc<Segment>], plan: &[(usize, Vec<usize>)]) -> Vec<SegmentScan> {
+    plan.iter()
+        .map(|(idx, blocks)| SegmentScan {
+            segment: segments[*idx]
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            blocks: blocks.clone(),
+        })
+        .collect()
 }
 
 impl StorageEngine {
@@ -193,44 +398,73 @@ impl StorageEngine {
     pub async fn new(config: StorageConfig) -> StorageResult<Self> {
         // Create directory structure
         std::fs::create_dir_all(&config.data_dir)?;
+        let lock = DirLock::acquire(&config.data_dir)?;
         std::fs::create_dir_all(config.segments_dir())?;
         std::fs::create_dir_all(config.data_dir.join("wal"))?;
         std::fs::create_dir_all(config.data_dir.join("meta"))?;
         std::fs::create_dir_all(config.data_dir.join("index"))?;
 
-        // Load metric registry
+        // Load metric registry and retention policies
         let metrics = MetricRegistry::load(&config.metrics_path())?;
+        let category_retention = CategoryRetention::load(&config.retention_path())?;
+        let mut tombstones = TombstoneSet::load(&config.tombstones_path())?;
 
         // Open WAL
         let wal = WriteAheadLog::open(config.wal_path(), config.wal_sync)?;
 
         // Recover from WAL if needed
-        let recovered_points = wal.recover()?;
+        let mut recovered_points = Vec::new();
+        let mut recovered_tombstones = 0;
+        for entry in wal.recover_entries()? {
+            match entry {
+                WalEntry::Point(point) => recovered_points.push(point),
+                WalEntry::Tombstone(tombstone) => {
+                    if tombstones.replay(tombstone) {
+                        recovered_tombstones += 1;
+                    }
+                }
+            }
+        }
         let has_recovered = !recovered_points.is_empty();
         if has_recovered {
             tracing::info!("Recovered {} points from WAL", recovered_points.len());
         }
-
-        // Load existing segments
-        let (segments, max_segment_id) = Self::load_segments(&config.segments_dir())?;
+        if recovered_tombstones > 0 {
+            tracing::info!("Recovered {} tombstones from WAL", recovered_tombstones);
+            tombstones.checkpoint(&config.tombstones_path())?;
+        }
 
         // Initialize index manager
         let index_config = IndexConfig {
             enable_tags: config.enable_tag_index,
         };
-        let index = IndexManager::with_config(&config.data_dir, index_config)?;
+        let mut index = IndexManager::with_config(&config.data_dir, index_config)?;
+
+        // Finish or undo a compaction interrupted by a crash
+        compaction::recover(&config.segments_dir(), &mut index)?;
+
+        // Load existing segments
+        let (segments, max_segment_id) = Self::load_segments(&config.segments_dir())?;
 
         let engine = Self {
             config: config.clone(),
             wal: Arc::new(RwLock::new(wal)),
             write_buffer: Arc::new(RwLock::new(recovered_points)),
             metrics: Arc::new(RwLock::new(metrics)),
+            category_retention: RwLock::new(category_retention),
+            tombstones: RwLock::new(tombstones),
             state: Arc::new(RwLock::new(EngineState {
                 segments,
                 current_segment_id: max_segment_id + 1,
             })),
             index: Arc::new(Mutex::new(index)),
             shutdown: Arc::new(RwLock::new(false)),
+            blocks_read: AtomicU64::new(0),
+            blocks_pruned: AtomicU64::new(0),
+            compaction_lock: tokio::sync::Mutex::new(()),
+            flushed: broadcast::channel(FLUSH_CHANNEL_CAPACITY).0,
+            change_listeners: Mutex::new(Vec::new()),
+            _lock: lock,
         };
 
         // Flush recovered points
@@ -242,7 +476,7 @@ impl StorageEngine {
     }
 
     /// Load all segments from directory
-    fn load_segments(dir: &Path) -> StorageResult<(Vec<Segment>, u32)> {
+    fn load_segments(dir: &Path) -> StorageResult<(Vec<Arc<Segment>>, u32)> {
         let mut segments = Vec::new();
         let mut max_id = 0u32;
 
@@ -260,7 +494,7 @@ impl StorageEngine {
                         if let Some(id) = segment.id() {
                             max_id = max_id.max(id);
                         }
-                        segments.push(segment);
+                        segments.push(Arc::new(segment));
                     }
                     Err(e) => {
                         tracing::warn!("Failed to open segment {:?}: {}", path, e);
@@ -278,15 +512,10 @@ impl StorageEngine {
 
     /// Write a single data point
     pub async fn write(&self, point: DataPoint) -> StorageResult<()> {
-        // Validate metric exists
+        // Validate metric exists and stores points
         {
             let registry = self.metrics.read().await;
-            if registry.get_by_id(point.metric_id).is_none() {
-                return Err(StorageError::MetricNotFound(format!(
-                    "metric_id {}",
-                    point.metric_id
-                )));
-            }
+            Self::check_writable(registry.get_active(point.metric_id), point.metric_id)?;
         }
 
         // Append to WAL first (durability)
@@ -295,6 +524,12 @@ impl StorageEngine {
             wal.append(&point)?;
         }
 
+        let change = DataChange::Written {
+            metric_ids: vec![point.metric_id],
+            min_timestamp: point.timestamp,
+            max_timestamp: point.timestamp,
+        };
+
         // Add to write buffer
         let should_flush = {
             let mut buffer = self.write_buffer.write().await;
@@ -304,6 +539,7 @@ impl StorageEngine {
             let buffer_size: usize = buffer.iter().map(|p| p.estimated_size()).sum();
             buffer_size >= self.config.block_size
         };
+        self.notify(&change);
 
         if should_flush {
             self.flush().await?;
@@ -318,16 +554,11 @@ impl StorageEngine {
             return Ok(());
         }
 
-        // Validate all metrics exist
+        // Validate all metrics exist and store points
         {
             let registry = self.metrics.read().await;
             for point in &points {
-                if registry.get_by_id(point.metric_id).is_none() {
-                    return Err(StorageError::MetricNotFound(format!(
-                        "metric_id {}",
-                        point.metric_id
-                    )));
-                }
+                Self::check_writable(registry.get_active(point.metric_id), point.metric_id)?;
             }
         }
 
@@ -337,6 +568,15 @@ impl StorageEngine {
             wal.append_batch(&points)?;
         }
 
+        let mut metric_ids: Vec<u32> = points.iter().map(|p| p.metric_id).collect();
+        metric_ids.sort_unstable();
+        metric_ids.dedup();
+        let change = DataChange::Written {
+            metric_ids,
+            min_timestamp: points.iter().map(|p| p.timestamp).min().unwrap_or(0),
+            max_timestamp: points.iter().map(|p| p.timestamp).max().unwrap_or(0),
+        };
+
         // Add to write buffer
         let should_flush = {
             let mut buffer = self.write_buffer.write().await;
@@ -345,6 +585,7 @@ impl StorageEngine {
             let buffer_size: usize = buffer.iter().map(|p| p.estimated_size()).sum();
             buffer_size >= self.config.block_size
         };
+        self.notify(&change);
 
         if should_flush {
             self.flush().await?;
@@ -353,6 +594,21 @@ impl StorageEngine {
         Ok(())
     }
 
+    /// Points can only be written to active, non-computed metrics
+    fn check_writable(metric: Option<&Metric>, metric_id: u32) -> StorageResult<()> {
+        match metric {
+            None => Err(StorageError::MetricNotFound(format!(
+                "metric_id {}",
+                metric_id
+            ))),
+            Some(metric) if metric.is_computed() => Err(StorageError::InvalidPoint(format!(
+                "metric '{}' is computed and cannot be written to",
+                metric.name
+            ))),
+            Some(_) => Ok(()),
+        }
+    }
+
     /// Force flush write buffer to segment
     pub async fn flush(&self) -> StorageResult<()> {
         // Take buffer contents
@@ -369,25 +625,24 @@ impl StorageEngine {
 
         // Collect info for indexing before moving points
         let min_timestamp = points.iter().map(|p| p.timestamp).min().unwrap_or(0);
+        let max_timestamp = points.iter().map(|p| p.timestamp).max().unwrap_or(0);
         let metrics: Vec<u32> = points.iter().map(|p| p.metric_id).collect::<HashSet<_>>().into_iter().collect();
 
-        // Collect all unique tags from the points
-        let mut all_tags: HashMap<String, String> = HashMap::new();
+        // Collect every distinct tag pair from the points
+        let mut all_tags: HashSet<(String, String)> = HashSet::new();
         for point in &points {
             for (k, v) in &point.tags {
-                all_tags.insert(k.clone(), v.clone());
+                all_tags.insert((k.clone(), v.clone()));
             }
         }
 
         // Write to segment
-        let (segment_id, block_idx) = {
+        {
             let mut state = self.state.write().await;
 
             // Get or create current segment
-            let segment_path = self
-                .config
-                .segments_dir()
-                .join(format!("segment_{:06}.dat", state.current_segment_id));
+            let segment_path =
+                compaction::segment_path(&self.config.segments_dir(), state.current_segment_id);
 
             let mut segment = if segment_path.exists() {
                 Segment::open(&segment_path)?
@@ -409,96 +664,269 @@ impl StorageEngine {
                     state.current_segment_id,
                     segment_size
                 );
-                state.segments.push(segment);
+                state.segments.push(Arc::new(segment));
                 state.current_segment_id += 1;
             } else {
                 // Update or add to segments list
                 let existing_idx = state.segments.iter().position(|s| s.path == segment.path);
                 if let Some(idx) = existing_idx {
-                    state.segments[idx] = segment;
+                    state.segments[idx] = Arc::new(segment);
                 } else {
-                    state.segments.push(segment);
+                    state.segments.push(Arc::new(segment));
                 }
             }
 
             // Re-sort segments
             state.segments.sort_by_key(|s| s.header.min_timestamp);
 
-            (segment_id, block_idx)
-        };
-
-        // Update indexes
-        {
+            // Update indexes before releasing the segment lock, so queries
+            // never see a block the index doesn't know about yet
             let mut index = self.index.lock().map_err(|e| {
                 StorageError::Lock(format!("Failed to acquire index lock: {}", e))
             })?;
-            index.index_block(segment_id, block_idx, min_timestamp, &metrics, &all_tags)?;
+            index.index_block(segment_id, block_idx, min_timestamp, &metrics, &HashMap::new())?;
+            index.index_block_tags(segment_id, block_idx, &all_tags);
         }
 
-        // Truncate WAL after successful flush
+        // Nobody may be listening; that's fine
+        let _ = self.flushed.send(FlushedBlock {
+            metric_ids: metrics,
+            min_timestamp,
+            max_timestamp,
+        });
+
+        // Truncate WAL after successful flush. Tombstones only live in the
+        // WAL until checkpointed, so save them first.
         {
             let mut wal = self.wal.write().await;
+            self.tombstones
+                .write()
+                .await
+                .checkpoint(&self.config.tombstones_path())?;
             wal.truncate()?;
         }
 
         Ok(())
     }
 
+    /// Call `listener` on every change to stored data from now on
+    ///
+    /// Listeners run synchronously inside `write`, `write_batch`, deletes
+    /// and retention, so they must be quick and must not call back into the
+    /// engine.
+    pub fn on_change(&self, listener: impl Fn(&DataChange) + Send + Sync + 'static) {
+        self.change_listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(listener));
+    }
+
+    fn notify(&self, change: &DataChange) {
+        for listener in self.change_listeners.lock().unwrap().iter() {
+            listener(change);
+        }
+    }
+
+    /// Receive a [`FlushedBlock`] for every flush from now on
+    ///
+    /// A receiver that falls more than a few dozen flushes behind misses the
+    /// oldest ones and gets `RecvError::Lagged` instead.
+    pub fn subscribe_flushes(&self) -> broadcast::Receiver<FlushedBlock> {
+        self.flushed.subscribe()
+    }
+
     /// Query data points in a time range
     pub async fn query(
         &self,
         range: TimeRange,
         filter: Option<QueryFilter>,
     ) -> StorageResult<Vec<DataPoint>> {
+        let (points, _) = self.query_with_stats(range, filter).await?;
+        Ok(points)
+    }
+
+    /// Query data points and report how many blocks were read or pruned
+    pub async fn query_with_stats(
+        &self,
+        range: TimeRange,
+        filter: Option<QueryFilter>,
+    ) -> StorageResult<(Vec<DataPoint>, ScanStats)> {
         let mut results = Vec::new();
+        let mut stats = ScanStats::default();
+        let registry = self.metrics.read().await;
+
+        let matches = |point: &DataPoint| match filter {
+            Some(ref f) => f.matches(point, registry.get_by_id(point.metric_id)),
+            None => true,
+        };
+
+        // Snapshot, so the tombstone lock is never held with the state lock
+        let tombstones = self.tombstones.read().await.clone();
+
+        // Plan under the state lock, then decode without it so flushes and
+        // compaction aren't held up by a long scan. Each planned segment is
+        // mapped first: a rewrite may delete its file once the lock is gone,
+        // but an existing map stays readable.
+        let plan = {
+            let state = self.state.read().await;
+            let plan = self.plan_blocks(&state.segments, &range, filter.as_ref(), &mut stats)?;
+            stats.segments = segment_scans(&state.segments, &plan);
+            plan.into_iter()
+                .map(|(segment_idx, block_indices)| {
+                    let segment = Arc::clone(&state.segments[segment_idx]);
+                    segment.mapped()?;
+                    Ok((segment, block_indices))
+                })
+                .collect::<StorageResult<Vec<_>>>()?
+        };
+
+        // Query segments in write order
+        for (segment, block_indices) in plan {
+            let deleted = match segment.id() {
+                Some(id) => tombstones.for_segment(id),
+                None => Vec::new(),
+            };
+
+            for block_idx in block_indices {
+                let started = Instant::now();
+                let points = segment.read_block(block_idx)?;
+                stats.decompress_us += started.elapsed().as_micros() as u64;
+                stats.blocks_read += 1;
+                stats.points_scanned += points.len();
+
+                for point in points {
+                    if !range.contains(point.timestamp) || !matches(&point) {
+                        continue;
+                    }
+                    if deleted.iter().any(|t| t.matches(&point)) {
+                        stats.points_deleted += 1;
+                        continue;
+                    }
+                    results.push(point);
+                }
+            }
+        }
 
-        // First check write buffer for unflushed points
+        // Unflushed points are the most recent writes
         {
             let buffer = self.write_buffer.read().await;
             for point in buffer.iter() {
-                if range.contains(point.timestamp) {
-                    if let Some(ref f) = filter {
-                        let registry = self.metrics.read().await;
-                        let metric = registry.get_by_id(point.metric_id);
-                        if f.matches(point, metric) {
-                            results.push(point.clone());
-                        }
-                    } else {
-                        results.push(point.clone());
-                    }
+                if range.contains(point.timestamp) && matches(point) {
+                    stats.buffer_points += 1;
+                    results.push(point.clone());
                 }
             }
         }
 
-        // Query segments
-        {
-            let mut state = self.state.write().await;
+        if registry.all().iter().any(|m| !m.duplicates.is_keep_all()) {
+            stats.points_duplicate = duplicates::resolve(&mut results, |id| {
+                registry.get_by_id(id).map(|m| m.duplicates).unwrap_or_default()
+            });
+        }
 
-            for segment in &mut state.segments {
-                if !segment.overlaps(&range) {
-                    continue;
-                }
+        self.blocks_read
+            .fetch_add(stats.blocks_read as u64, Ordering::Relaxed);
+        self.blocks_pruned
+            .fetch_add(stats.blocks_pruned() as u64, Ordering::Relaxed);
+
+        // Sort by timestamp
+        results.sort_by_key(|p| p.timestamp);
 
-                let points = segment.read_range(&range)?;
+        Ok((results, stats))
+    }
 
-                if let Some(ref f) = filter {
-                    let registry = self.metrics.read().await;
-                    for point in points {
-                        let metric = registry.get_by_id(point.metric_id);
-                        if f.matches(&point, metric) {
-                            results.push(point);
-                        }
-                    }
-                } else {
-                    results.extend(points);
-                }
+    /// Plan a query without reading any blocks
+    ///
+    /// The returned stats describe the segments and blocks `query_with_stats`
+    /// would read; point counts and timings stay zero.
+    pub async fn plan_query(
+        &self,
+        range: TimeRange,
+        filter: Option<QueryFilter>,
+    ) -> StorageResult<ScanStats> {
+        let mut stats = ScanStats::default();
+        let state = self.state.read().await;
+        let plan = self.plan_blocks(&state.segments, &range, filter.as_ref(), &mut stats)?;
+        stats.segments = segment_scans(&state.segments, &plan);
+        Ok(stats)
+    }
+
+    /// Choose the blocks a query has to read
+    ///
+    /// Returns `(segment index, block indices)` pairs in write order. Blocks are first pruned
+    /// by their footer time bounds; if the filter names a metric or tags,
+    /// the index then removes blocks that cannot contain matching points.
+    fn plan_blocks(
+        &self,
+        segments: &[Arc<Segment>],
+        range: &TimeRange,
+        filter: Option<&QueryFilter>,
+        stats: &mut ScanStats,
+    ) -> StorageResult<Vec<(usize, Vec<usize>)>> {
+        let mut plan = Vec::new();
+        let mut earliest_block = i64::MAX;
+
+        for (segment_idx, segment) in segments.iter().enumerate() {
+            stats.blocks_total += segment.blocks.len();
+
+            if !segment.overlaps(range) {
+                stats.blocks_pruned_by_time += segment.blocks.len();
+                continue;
+            }
+            stats.segments_scanned += 1;
+
+            let blocks: Vec<usize> = (0..segment.blocks.len())
+                .filter(|&idx| segment.blocks[idx].overlaps(range))
+                .collect();
+            stats.blocks_pruned_by_time += segment.blocks.len() - blocks.len();
+
+            for &idx in &blocks {
+                earliest_block = earliest_block.min(segment.blocks[idx].min_timestamp);
+            }
+
+            if !blocks.is_empty() {
+                plan.push((segment_idx, blocks));
             }
         }
 
-        // Sort by timestamp
-        results.sort_by_key(|p| p.timestamp);
+        plan.sort_by_key(|(idx, _)| segments[*idx].sequence());
+
+        let metric_id = filter.and_then(|f| f.metric_id);
+        let empty_tags = HashMap::new();
+        let tags = filter.map(|f| &f.tags).unwrap_or(&empty_tags);
+        let empty_tag_values = HashMap::new();
+        let tag_values = filter.map(|f| &f.tag_values).unwrap_or(&empty_tag_values);
+
+        if plan.is_empty() || (metric_id.is_none() && tags.is_empty() && tag_values.is_empty()) {
+            return Ok(plan);
+        }
 
-        Ok(results)
+        let index = self.index.lock().map_err(|e| {
+            StorageError::Lock(format!("Failed to acquire index lock: {}", e))
+        })?;
+
+        // The time index is keyed by each block's first timestamp, so the
+        // lookup has to start at the earliest overlapping block rather than
+        // at range.start to include blocks that straddle the range start.
+        let lookup = TimeRange::new(earliest_block, range.end);
+        let candidates: HashSet<DataLocation> = index
+            .find_by_time_metric_and_tag_values(&lookup, metric_id, tags, tag_values)
+            .into_iter()
+            .collect();
+
+        for (segment_idx, blocks) in &mut plan {
+            let segment_id = match segments[*segment_idx].id() {
+                Some(id) if index.has_segment(id) => id,
+                _ => continue,
+            };
+
+            let before = blocks.len();
+            blocks.retain(|&idx| candidates.contains(&DataLocation::new(segment_id, idx as u32)));
+            stats.blocks_pruned_by_index += before - blocks.len();
+        }
+
+        plan.retain(|(_, blocks)| !blocks.is_empty());
+        Ok(plan)
     }
 
     /// Query with metric name filter (convenience method)
@@ -511,6 +939,7 @@ impl StorageEngine {
             let registry = self.metrics.read().await;
             registry
                 .get_by_name(metric_name)
+                .filter(|m| m.is_active())
                 .map(|m| m.id)
                 .ok_or_else(|| StorageError::MetricNotFound(metric_name.to_string()))?
         };
@@ -521,6 +950,10 @@ impl StorageEngine {
 
     /// Register a new metric
     pub async fn register_metric(&self, metric: Metric) -> StorageResult<u32> {
+        if let Some(policy) = &metric.retention {
+            policy.validate()?;
+        }
+
         let id = {
             let mut registry = self.metrics.write().await;
             let id = registry.register(metric);
@@ -531,16 +964,196 @@ impl StorageEngine {
         Ok(id)
     }
 
-    /// Get all registered metrics
+    /// Get all registered metrics that have not been deleted
     pub async fn get_metrics(&self) -> Vec<Metric> {
         let registry = self.metrics.read().await;
-        registry.all().to_vec()
+        registry.all().iter().filter(|m| m.is_active()).cloned().collect()
     }
 
-    /// Get a specific metric by name
+    /// Get a specific metric by name (None if deleted)
     pub async fn get_metric(&self, name: &str) -> Option<Metric> {
         let registry = self.metrics.read().await;
-        registry.get_by_name(name).cloned()
+        registry.get_by_name(name).filter(|m| m.is_active()).cloned()
+    }
+
+    /// Delete a metric
+    ///
+    /// A soft delete hides the metric but keeps its data, and registering
+    /// the name again restores it. A hard delete also tombstones all of its
+    /// points and frees the name; the points are removed by the next purge
+    /// or rewrite of the segments holding them.
+    pub async fn delete_metric(&self, id: u32, mode: DeleteMode) -> StorageResult<()> {
+        let deleted = {
+            let registry = self.metrics.read().await;
+            registry
+                .get_by_id(id)
+                .ok_or_else(|| StorageError::MetricNotFound(format!("metric_id {}", id)))?
+                .deleted
+        };
+        if deleted == Some(DeleteMode::Hard) {
+            return Ok(());
+        }
+
+        if mode == DeleteMode::Hard {
+            self.delete_points(Some(id), TimeRange::new(i64::MIN, i64::MAX), HashMap::new())
+                .await?;
+        }
+
+        let mut registry = self.metrics.write().await;
+        registry.delete(id, mode);
+        registry.save(&self.config.metrics_path())?;
+        self.notify(&DataChange::Reset);
+        Ok(())
+    }
+
+    /// Delete every point matching a metric, time range and tags
+    ///
+    /// The delete is logged to the WAL as a tombstone and hidden from
+    /// queries immediately. It only covers points written before this call:
+    /// the active segment is sealed, so later writes (e.g. a corrected
+    /// re-import) are unaffected.
+    pub async fn delete_points(
+        &self,
+        metric_id: Option<u32>,
+        range: TimeRange,
+        tags: HashMap<String, String>,
+    ) -> StorageResult<Tombstone> {
+        // Buffered points must be in a sealed segment to be covered
+        self.flush().await?;
+
+        // A rewrite running now would not apply the new tombstone
+        let _guard = self.compaction_lock.lock().await;
+
+        let before_segment = {
+            let mut state = self.state.write().await;
+            Self::seal_active(&mut state);
+            state.current_segment_id
+        };
+
+        let mut wal = self.wal.write().await;
+        let mut tombstones = self.tombstones.write().await;
+        let tombstone = tombstones.add(
+            metric_id,
+            range,
+            tags,
+            before_segment,
+            Utc::now().timestamp_millis(),
+        );
+        if let Err(e) = wal.append_tombstone(&tombstone) {
+            tombstones.retain(|t| t.id != tombstone.id);
+            return Err(e);
+        }
+        self.notify(&DataChange::Reset);
+
+        tracing::info!(
+            "Deleted points of metric {:?} in [{}, {}) (tombstone {})",
+            metric_id,
+            range.start,
+            range.end,
+            tombstone.id
+        );
+
+        Ok(tombstone)
+    }
+
+    /// Get the tombstones that have not been purged yet
+    pub async fn tombstones(&self) -> Vec<Tombstone> {
+        self.tombstones.read().await.all().to_vec()
+    }
+
+    /// Rewrite every segment holding tombstoned points without them
+    ///
+    /// Returns `None` if there was nothing to purge. Tombstones are
+    /// discarded once no segment they apply to remains.
+    pub async fn purge(&self) -> StorageResult<Option<CompactionStats>> {
+        let _guard = self.compaction_lock.lock().await;
+
+        let tombstones = self.tombstones.read().await.clone();
+        if tombstones.is_empty() {
+            return Ok(None);
+        }
+
+        let (inputs, output_id) = {
+            let mut state = self.state.write().await;
+            let inputs = self.segments_with_deleted_data(&state.segments, &tombstones)?;
+            if inputs.is_empty() {
+                drop(state);
+                self.gc_tombstones().await?;
+                return Ok(None);
+            }
+            (
+                Self::contiguous_inputs(&state.segments, &inputs),
+                Self::allocate_output_id(&mut state),
+            )
+        };
+
+        tracing::info!("Purging deleted points from segments {:?}", inputs);
+
+        let stats = self.rewrite_segments(&inputs, output_id, Some).await?;
+        if let Some(stats) = &stats {
+            tracing::info!("Purged {} deleted points", stats.points_deleted);
+        }
+
+        Ok(stats)
+    }
+
+    /// Ids of segments with a block that may hold a tombstoned point
+    fn segments_with_deleted_data(
+        &self,
+        segments: &[Arc<Segment>],
+        tombstones: &TombstoneSet,
+    ) -> StorageResult<Vec<u32>> {
+        let index = self.index.lock().map_err(|e| {
+            StorageError::Lock(format!("Failed to acquire index lock: {}", e))
+        })?;
+
+        let mut ids = Vec::new();
+        for segment in segments {
+            let id = match segment.id() {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let indexed = index.has_segment(id);
+            let deleted = tombstones.for_segment(id).iter().any(|t| {
+                let range = t.range();
+                segment.blocks.iter().enumerate().any(|(idx, block)| {
+                    let location = DataLocation::new(id, idx as u32);
+                    block.overlaps(&range)
+                        && match t.metric_id {
+                            Some(metric_id) if indexed => {
+                                index.may_contain_metric(metric_id, location)
+                            }
+                            _ => true,
+                        }
+                })
+            });
+
+            if deleted {
+                ids.push(id);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Discard tombstones that no longer apply to any segment's data
+    async fn gc_tombstones(&self) -> StorageResult<()> {
+        let state = self.state.read().await;
+        let mut tombstones = self.tombstones.write().await;
+
+        let removed = tombstones.retain(|t| {
+            let range = t.range();
+            state.segments.iter().any(|s| {
+                s.id().map(|id| t.applies_to_segment(id)).unwrap_or(false) && s.overlaps(&range)
+            })
+        });
+
+        if removed > 0 {
+            tracing::info!("Discarded {} purged tombstones", removed);
+            tombstones.checkpoint(&self.config.tombstones_path())?;
+        }
+        Ok(())
     }
 
     /// Get storage statistics
@@ -568,78 +1181,751 @@ impl StorageEngine {
             buffer_points,
             wal_entries,
             storage_size_bytes: storage_size,
+            blocks_read: self.blocks_read.load(Ordering::Relaxed),
+            blocks_pruned: self.blocks_pruned.load(Ordering::Relaxed),
         }
     }
 
-    /// Start background flush task
-    pub fn start_background_flush(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
-        let engine = Arc::clone(self);
-        let flush_interval = Duration::from_millis(engine.config.flush_interval_ms);
+    /// Merge a run of small segments into one compacted segment
+    ///
+    /// Returns `None` if no segments need compacting. See
+    /// [`compaction`](crate::storage::compaction) for the selection policy and
+    /// the crash-safety protocol.
+    pub async fn compact(&self) -> StorageResult<Option<CompactionStats>> {
+        let _guard = self.compaction_lock.lock().await;
 
-        tokio::spawn(async move {
-            let mut ticker = interval(flush_interval);
+        let (inputs, output_id) = {
+            let mut state = self.state.write().await;
 
-            loop {
-                ticker.tick().await;
+            // Only runs adjacent in write order can be merged, so the
+            // output can take their place in it
+            let mut infos: Vec<(u32, SegmentInfo)> = state
+                .segments
+                .iter()
+                .filter_map(|s| Some((s.sequence()?, SegmentInfo::from_segment(s)?)))
+                .collect();
+            infos.sort_by_key(|(sequence, _)| *sequence);
+            let infos: Vec<SegmentInfo> = infos.into_iter().map(|(_, info)| info).collect();
+
+            let inputs = match compaction::plan_compaction(
+                &infos,
+                state.current_segment_id,
+                self.config.max_segment_size,
+                self.config.compaction_min_blocks,
+            ) {
+                Some(inputs) => inputs,
+                None => return Ok(None),
+            };
 
-                // Check shutdown
-                if *engine.shutdown.read().await {
-                    break;
-                }
+            (inputs, Self::allocate_output_id(&mut state))
+        };
 
-                // Flush if buffer has data
-                let has_data = !engine.write_buffer.read().await.is_empty();
-                if has_data {
-                    if let Err(e) = engine.flush().await {
-                        tracing::error!("Background flush failed: {}", e);
-                    }
-                }
-            }
+        tracing::info!("Compacting segments {:?} into {}", inputs, output_id);
 
-            // Final flush on shutdown
-            if let Err(e) = engine.flush().await {
-                tracing::error!("Final flush failed: {}", e);
-            }
-        })
-    }
+        let stats = self
+            .rewrite_segments(&inputs, output_id, Some)
+            .await?
+            .unwrap_or_default();
 
-    /// Shutdown the engine gracefully
-    pub async fn shutdown(&self) -> StorageResult<()> {
-        *self.shutdown.write().await = true;
+        tracing::info!(
+            "Compacted {} segments ({} blocks) into {} blocks",
+            stats.segments_merged,
+            stats.blocks_before,
+            stats.blocks_after
+        );
 
-        // Final flush
-        self.flush().await?;
+        Ok(Some(stats))
+    }
 
-        // Sync WAL
-        {
-            let mut wal = self.wal.write().await;
-            wal.sync()?;
-        }
+    /// Seal the active segment and reserve an id for a rewrite output
+    ///
+    /// Flushes only ever append to the active segment, so once it is sealed
+    /// every existing segment is immutable and safe to rewrite.
+    fn allocate_output_id(state: &mut EngineState) -> u32 {
+        Self::seal_active(state);
+        let output_id = state.current_segment_id;
+        state.current_segment_id += 1;
+        output_id
+    }
 
-        // Persist indexes
-        {
-            let mut index = self.index.lock().map_err(|e| {
-                StorageError::Lock(format!("Failed to acquire index lock: {}", e))
-            })?;
-            index.persist()?;
-        }
+    /// Widen rewrite inputs to every segment written between them
+    ///
+    /// A rewrite output takes the place of its inputs in write order (see
+    /// [`Segment::sequence`]), which is only sound if no other segment falls
+    /// in between. Returns ids in write order.
+    fn contiguous_inputs(segments: &[Arc<Segment>], inputs: &[u32]) -> Vec<u32> {
+        let sequences: Vec<u32> = segments
+            .iter()
+            .filter(|s| s.id().map(|id| inputs.contains(&id)).unwrap_or(false))
+            .filter_map(|s| s.sequence())
+            .collect();
+        let (first, last) = match (sequences.iter().min(), sequences.iter().max()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return inputs.to_vec(),
+        };
 
-        Ok(())
+        let mut run: Vec<(u32, u32)> = segments
+            .iter()
+            .filter_map(|s| Some((s.sequence()?, s.id()?)))
+            .filter(|(sequence, _)| (first..=last).contains(sequence))
+            .collect();
+        run.sort_unstable();
+        run.into_iter().map(|(_, id)| id).collect()
     }
 
-    /// Get the data directory path
-    pub fn data_dir(&self) -> &Path {
-        &self.config.data_dir
+    /// Stop appending to the active segment, if it has been created
+    fn seal_active(state: &mut EngineState) {
+        let active_id = state.current_segment_id;
+        if state.segments.iter().any(|s| s.id() == Some(active_id)) {
+            state.current_segment_id += 1;
+        }
     }
 
-    /// Get index statistics
-    pub fn index_stats(&self) -> crate::index::IndexStats {
-        let index = self.index.lock().unwrap();
-        index.stats()
-    }
+    /// Replace `inputs` with one segment holding `transform(points)`
+    ///
+    /// Shared by compaction, retention and purging. The inputs must be
+    /// sealed (see `allocate_output_id`). Tombstoned points are dropped
+    /// before `transform` sees them. If `transform` returns no points, the
+    /// inputs are dropped without an output; if it returns `None`, nothing
+    /// changes and `None` is returned.
+    async fn rewrite_segments<F>(
+        &self,
+        inputs: &[u32],
+        output_id: u32,
+        transform: F,
+    ) -> StorageResult<Option<CompactionStats>>
+    where
+        F: FnOnce(Vec<DataPoint>) -> Option<Vec<DataPoint>>,
+    {
+        let segments_dir = self.config.segments_dir();
+
+        let manifest = CompactionManifest {
+            inputs: inputs.to_vec(),
+            output: output_id,
+        };
+        manifest.write(&segments_dir)?;
 
-    /// Get time bounds of all indexed data
-    pub fn time_bounds(&self) -> Option<(i64, i64)> {
+        // Callers hold the compaction lock, so no tombstone can be added
+        // while the rewrite runs
+        let tombstones = self.tombstones.read().await.clone();
+        let policies: HashMap<u32, DuplicatePolicy> = {
+            let registry = self.metrics.read().await;
+            registry
+                .all()
+                .iter()
+                .filter(|m| !m.duplicates.is_keep_all())
+                .map(|m| (m.id, m.duplicates))
+                .collect()
+        };
+
+        let tmp_path = compaction::temp_segment_path(&segments_dir, output_id);
+        let built =
+            self.build_rewrite_output(inputs, &tmp_path, &tombstones, &policies, transform);
+        let (mut stats, index_data) = match built {
+            Ok(Some(built)) => built,
+            Ok(None) => {
+                CompactionManifest::remove(&segments_dir)?;
+                return Ok(None);
+            }
+            Err(e) => {
+                // Nothing has been replaced yet, so undo the attempt
+                let _ = std::fs::remove_file(&tmp_path);
+                let _ = CompactionManifest::remove(&segments_dir);
+                return Err(e);
+            }
+        };
+
+        // Commit point: once the output has its final name, recovery rolls
+        // forward instead of back
+        let output_path = compaction::segment_path(&segments_dir, output_id);
+        let output = if index_data.is_some() {
+            std::fs::rename(&tmp_path, &output_path)?;
+            compaction::sync_dir(&segments_dir);
+            stats.bytes_after = std::fs::metadata(&output_path)?.len();
+            Some(Segment::open(&output_path)?)
+        } else {
+            None
+        };
+
+        // Swap segments and indexes together, so queries see either the
+        // inputs or the output but never both
+        {
+            let mut state = self.state.write().await;
+            state
+                .segments
+                .retain(|s| !s.id().map(|id| inputs.contains(&id)).unwrap_or(false));
+            if let Some(output) = output {
+                stats.blocks_after = output.blocks.len();
+                state.segments.push(Arc::new(output));
+            }
+            state.segments.sort_by_key(|s| s.header.min_timestamp);
+
+            let mut index = self.index.lock().map_err(|e| {
+                StorageError::Lock(format!("Failed to acquire index lock: {}", e))
+            })?;
+            for &id in inputs {
+                index.remove_segment(id)?;
+            }
+            if let Some(data) = &index_data {
+                data.apply(&mut index, output_id)?;
+            }
+            index.persist()?;
+        }
+
+        for &id in inputs {
+            std::fs::remove_file(compaction::segment_path(&segments_dir, id))?;
+        }
+        compaction::sync_dir(&segments_dir);
+        CompactionManifest::remove(&segments_dir)?;
+
+        self.gc_tombstones().await?;
+
+        Ok(Some(stats))
+    }
+
+    /// Read the input segments and write the rewritten output to `tmp_path`
+    ///
+    /// Inputs are sealed, so they are read through fresh file handles without
+    /// holding the engine state lock. They are read in write order, so
+    /// duplicates can be resolved, and the output inherits the sequence of
+    /// the newest input.
+    fn build_rewrite_output<F>(
+        &self,
+        inputs: &[u32],
+        tmp_path: &Path,
+        tombstones: &TombstoneSet,
+        policies: &HashMap<u32, DuplicatePolicy>,
+        transform: F,
+    ) -> StorageResult<Option<(CompactionStats, Option<SegmentIndexData>)>>
+    where
+        F: FnOnce(Vec<DataPoint>) -> Option<Vec<DataPoint>>,
+    {
+        let segments_dir = self.config.segments_dir();
+        let mut stats = CompactionStats {
+            segments_merged: inputs.len(),
+            ..Default::default()
+        };
+
+        let mut segments = inputs
+            .iter()
+            .map(|&id| Ok((id, Segment::open(compaction::segment_path(&segments_dir, id))?)))
+            .collect::<StorageResult<Vec<_>>>()?;
+        segments.sort_by_key(|(_, segment)| segment.sequence());
+        let sequence = segments
+            .iter()
+            .filter_map(|(_, segment)| segment.sequence())
+            .max()
+            .unwrap_or_default();
+
+        let mut points = Vec::new();
+        for (id, segment) in &segments {
+            stats.blocks_before += segment.blocks.len();
+            stats.bytes_before += std::fs::metadata(&segment.path)?.len();
+
+            let mut segment_points = segment.read_all()?;
+            stats.points_deleted += tombstones.apply(*id, &mut segment_points);
+            points.extend(segment_points);
+        }
+
+        if !policies.is_empty() {
+            stats.points_duplicate = duplicates::resolve(&mut points, |id| {
+                policies.get(&id).copied().unwrap_or_default()
+            });
+        }
+
+        let points = match transform(points) {
+            Some(points) => points,
+            None => return Ok(None),
+        };
+        stats.points = points.len();
+
+        let output = compaction::build_compacted(
+            tmp_path,
+            self.config.compression,
+            self.config.block_size,
+            sequence,
+            points,
+        )?;
+
+        let index_data = match output {
+            Some(segment) => {
+                // Verify the output before it replaces anything
+                let data = SegmentIndexData::scan(&segment)?;
+                if data.point_count != stats.points {
+                    return Err(StorageError::Corruption(format!(
+                        "Rewritten segment has {} points, expected {}",
+                        data.point_count, stats.points
+                    )));
+                }
+                Some(data)
+            }
+            None => None,
+        };
+
+        Ok(Some((stats, index_data)))
+    }
+
+    /// Set or clear a metric's own retention policy
+    pub async fn set_metric_retention(
+        &self,
+        name: &str,
+        policy: Option<RetentionPolicy>,
+    ) -> StorageResult<()> {
+        if let Some(policy) = &policy {
+            policy.validate()?;
+        }
+
+        let mut registry = self.metrics.write().await;
+        let mut metric = registry
+            .get_by_name(name)
+            .cloned()
+            .ok_or_else(|| StorageError::MetricNotFound(name.to_string()))?;
+
+        metric.retention = policy;
+        registry.update(metric);
+        registry.save(&self.config.metrics_path())
+    }
+
+    /// Set a metric's duplicate policy
+    ///
+    /// Takes effect for queries immediately; existing duplicates are
+    /// dropped the next time their segments are rewritten.
+    pub async fn set_metric_duplicates(
+        &self,
+        name: &str,
+        policy: DuplicatePolicy,
+    ) -> StorageResult<()> {
+        let mut registry = self.metrics.write().await;
+        let mut metric = registry
+            .get_by_name(name)
+            .cloned()
+            .ok_or_else(|| StorageError::MetricNotFound(name.to_string()))?;
+
+        metric.duplicates = policy;
+        registry.update(metric);
+        registry.save(&self.config.metrics_path())?;
+        self.notify(&DataChange::Reset);
+        Ok(())
+    }
+
+    /// Set or clear the retention policy for a whole category
+    pub async fn set_category_retention(
+        &self,
+        category: Category,
+        policy: Option<RetentionPolicy>,
+    ) -> StorageResult<()> {
+        if let Some(policy) = &policy {
+            policy.validate()?;
+        }
+
+        let mut policies = self.category_retention.write().await;
+        match policy {
+            Some(policy) => policies.0.insert(category, policy),
+            None => policies.0.remove(&category),
+        };
+        policies.save(&self.config.retention_path())
+    }
+
+    /// Get the category retention policies
+    pub async fn category_retention(&self) -> CategoryRetention {
+        self.category_retention.read().await.clone()
+    }
+
+    /// Drop or roll up raw points that have outlived their retention policy
+    ///
+    /// Segments holding expired points are rewritten without them, in
+    /// batches of at most `max_segment_size`; rollups replacing a batch's
+    /// points are written into its output, so both change atomically.
+    pub async fn apply_retention(&self) -> StorageResult<RetentionStats> {
+        let now = Utc::now().timestamp_millis();
+
+        // Points still in the write buffer are subject to the policy too
+        self.flush().await?;
+
+        let _guard = self.compaction_lock.lock().await;
+
+        let policies: Vec<(Metric, RetentionPolicy)> = {
+            let registry = self.metrics.read().await;
+            let categories = self.category_retention.read().await;
+            registry
+                .all()
+                .iter()
+                .filter(|m| m.deleted != Some(DeleteMode::Hard))
+                .filter_map(|m| categories.effective(m).map(|p| (m.clone(), p.clone())))
+                .filter(|(metric, policy)| match policy.validate() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        tracing::warn!("Ignoring retention policy of {}: {}", metric.name, e);
+                        false
+                    }
+                })
+                .collect()
+        };
+
+        if policies.is_empty() {
+            return Ok(RetentionStats::default());
+        }
+
+        let cutoffs: HashMap<u32, i64> = policies
+            .iter()
+            .map(|(metric, policy)| (metric.id, policy.cutoff(now)))
+            .collect();
+
+        // Rewrite in batches of at most a segment's size, like compaction,
+        // so a long backlog of expired data isn't loaded all at once
+        let batches: Vec<(Vec<u32>, u32)> = {
+            let mut state = self.state.write().await;
+            let inputs = self.segments_with_expired_data(&state.segments, &cutoffs)?;
+            if inputs.is_empty() {
+                return Ok(RetentionStats::default());
+            }
+            let infos: Vec<SegmentInfo> = Self::contiguous_inputs(&state.segments, &inputs)
+                .into_iter()
+                .filter_map(|id| {
+                    let segment = state.segments.iter().find(|s| s.id() == Some(id))?;
+                    SegmentInfo::from_segment(segment)
+                })
+                .collect();
+            compaction::batch_segments(&infos, self.config.max_segment_size)
+                .into_iter()
+                .map(|batch| (batch, Self::allocate_output_id(&mut state)))
+                .collect()
+        };
+
+        // Register rollup metrics only once there is something to roll up
+        let mut rules = HashMap::new();
+        for (metric, policy) in &policies {
+            let rollup = match &policy.rollup {
+                Some(rollup) => {
+                    let derived = rollup.derived_metric(metric);
+                    if let Some(existing) = self.get_metric(&derived.name).await {
+                        if existing.rollup_of != Some(metric.id) {
+                            tracing::warn!(
+                                "Not rolling up {}: metric {} already exists",
+                                metric.name,
+                                derived.name
+                            );
+                            continue;
+                        }
+                    }
+
+                    Some(RollupTarget {
+                        metric_id: self.register_metric(derived).await?,
+                        interval_ms: rollup.interval_ms,
+                        aggregation: metric.aggregation,
+                    })
+                }
+                None => None,
+            };
+
+            rules.insert(
+                metric.id,
+                ExpiryRule {
+                    cutoff: cutoffs[&metric.id],
+                    rollup,
+                },
+            );
+        }
+
+        // Each batch rolls up its own points, so a bucket whose points are
+        // spread over two batches gets a rollup point from each, as it does
+        // when points are backfilled after their bucket was rolled up
+        let mut stats = RetentionStats::default();
+        for (inputs, output_id) in &batches {
+            tracing::info!("Applying retention to segments {:?}", inputs);
+
+            // A block can be selected because of an old point of another
+            // metric; skip the rewrite if nothing actually expired
+            let mut batch = RetentionStats::default();
+            let rewritten = self
+                .rewrite_segments(inputs, *output_id, |points| {
+                    let kept = retention::apply_rules(points, &rules, &mut batch);
+                    (batch.points_expired > 0).then_some(kept)
+                })
+                .await;
+
+            match rewritten {
+                Ok(Some(_)) => {
+                    stats.segments_rewritten += inputs.len();
+                    stats.points_expired += batch.points_expired;
+                    stats.rollup_points += batch.rollup_points;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // Earlier batches have already changed the data
+                    if stats.segments_rewritten > 0 {
+                        self.notify(&DataChange::Reset);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if stats.segments_rewritten == 0 {
+            return Ok(RetentionStats::default());
+        }
+        self.notify(&DataChange::Reset);
+
+        tracing::info!(
+            "Retention expired {} points, wrote {} rollup points",
+            stats.points_expired,
+            stats.rollup_points
+        );
+
+        Ok(stats)
+    }
+
+    /// Ids of segments with a block that may hold an expired point
+    fn segments_with_expired_data(
+        &self,
+        segments: &[Arc<Segment>],
+        cutoffs: &HashMap<u32, i64>,
+    ) -> StorageResult<Vec<u32>> {
+        let index = self.index.lock().map_err(|e| {
+            StorageError::Lock(format!("Failed to acquire index lock: {}", e))
+        })?;
+
+        let mut ids = Vec::new();
+        for segment in segments {
+            let id = match segment.id() {
+                Some(id) => id,
+                None => continue,
+            };
+
+            // Segments the index doesn't know are checked by time alone
+            let indexed = index.has_segment(id);
+            let expired = segment.blocks.iter().enumerate().any(|(idx, block)| {
+                let location = DataLocation::new(id, idx as u32);
+                cutoffs.iter().any(|(&metric_id, &cutoff)| {
+                    block.min_timestamp < cutoff
+                        && (!indexed || index.may_contain_metric(metric_id, location))
+                })
+            });
+
+            if expired {
+                ids.push(id);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Write a consistent snapshot of the data directory to `dest`
+    ///
+    /// See [`snapshot`](crate::storage::snapshot) for what is captured and
+    /// how to restore it. `dest` must not exist or be empty. Writes and
+    /// queries continue while the snapshot is taken; flushes wait only
+    /// while the indexes are copied.
+    pub async fn snapshot(&self, dest: &Path) -> StorageResult<SnapshotManifest> {
+        if dest.exists() && std::fs::read_dir(dest)?.next().is_some() {
+            return Err(StorageError::Config(format!(
+                "Snapshot destination {:?} is not empty",
+                dest
+            )));
+        }
+
+        self.flush().await?;
+
+        // Keeps rewrites from deleting segments and deletes from adding
+        // tombstones until the snapshot is complete
+        let _guard = self.compaction_lock.lock().await;
+
+        let target = StorageConfig::new(dest);
+        let index_src = self.config.data_dir.join("index");
+        let index_dst = dest.join("index");
+        std::fs::create_dir_all(target.segments_dir())?;
+        std::fs::create_dir_all(&index_dst)?;
+
+        let mut files = Vec::new();
+        let segments: Vec<(u32, PathBuf)> = {
+            let mut state = self.state.write().await;
+            Self::seal_active(&mut state);
+
+            // The indexes must describe exactly the sealed segments, so copy
+            // them before a flush can add a block
+            let mut index = self.index.lock().map_err(|e| {
+                StorageError::Lock(format!("Failed to acquire index lock: {}", e))
+            })?;
+            index.persist()?;
+            for entry in std::fs::read_dir(&index_src)? {
+                let path = entry?.path();
+                let name = match path.file_name().and_then(|n| n.to_str()) {
+                    Some(name) if path.is_file() => name.to_string(),
+                    _ => continue,
+                };
+                // Checkpointed into the database file
+                if name.ends_with("-wal") || name.ends_with("-shm") {
+                    continue;
+                }
+                snapshot::copy_synced(&path, &index_dst.join(&name))?;
+                files.push(format!("index/{}", name));
+            }
+
+            state
+                .segments
+                .iter()
+                .filter_map(|s| Some((s.id()?, s.path.clone())))
+                .collect()
+        };
+
+        // Sealed segments are immutable and the compaction lock keeps them
+        // from being deleted, so they can be linked without the state lock
+        for (id, path) in &segments {
+            let name = compaction::segment_file_name(*id);
+            snapshot::link_or_copy(path, &target.segments_dir().join(&name))?;
+            files.push(format!("segments/{}", name));
+        }
+
+        self.metrics.read().await.save(&target.metrics_path())?;
+        self.category_retention
+            .read()
+            .await
+            .save(&target.retention_path())?;
+        self.tombstones.read().await.save(&target.tombstones_path())?;
+        files.extend(
+            ["metrics.json", "retention.json", "tombstones.json"]
+                .iter()
+                .map(|name| format!("meta/{}", name)),
+        );
+
+        let mut manifest = SnapshotManifest::new(segments.iter().map(|(id, _)| *id).collect());
+        for file in &files {
+            manifest.add_file(dest, file)?;
+        }
+        for dir in [target.segments_dir(), index_dst, dest.join("meta")] {
+            compaction::sync_dir(&dir);
+        }
+        manifest.save(dest)?;
+
+        tracing::info!(
+            "Snapshot of {} segments ({} bytes) written to {:?}",
+            manifest.segments.len(),
+            manifest.total_bytes(),
+            dest
+        );
+
+        Ok(manifest)
+    }
+
+    /// Start background compaction task
+    ///
+    /// Each run applies retention policies first, then compacts.
+    pub fn start_background_compaction(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let engine = Arc::clone(self);
+        let compaction_interval = Duration::from_millis(engine.config.compaction_interval_ms);
+
+        tokio::spawn(async move {
+            let mut ticker = interval(compaction_interval);
+            // The first tick completes immediately; skip it
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                // Check shutdown
+                if *engine.shutdown.read().await {
+                    break;
+                }
+
+                if let Err(e) = engine.apply_retention().await {
+                    tracing::error!("Background retention failed: {}", e);
+                }
+
+                if let Err(e) = engine.compact().await {
+                    tracing::error!("Background compaction failed: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Start background flush task
+    pub fn start_background_flush(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let engine = Arc::clone(self);
+        let flush_interval = Duration::from_millis(engine.config.flush_interval_ms);
+
+        tokio::spawn(async move {
+            let mut ticker = interval(flush_interval);
+
+            loop {
+                ticker.tick().await;
+
+                // Check shutdown
+                if *engine.shutdown.read().await {
+                    break;
+                }
+
+                // Flush if buffer has data
+                let has_data = !engine.write_buffer.read().await.is_empty();
+                if has_data {
+                    if let Err(e) = engine.flush().await {
+                        tracing::error!("Background flush failed: {}", e);
+                    }
+                }
+            }
+
+            // Final flush on shutdown
+            if let Err(e) = engine.flush().await {
+                tracing::error!("Final flush failed: {}", e);
+            }
+        })
+    }
+
+    /// Shutdown the engine gracefully
+    pub async fn shutdown(&self) -> StorageResult<()> {
+        *self.shutdown.write().await = true;
+
+        // Final flush
+        self.flush().await?;
+
+        // Sync WAL
+        {
+            let mut wal = self.wal.write().await;
+            wal.sync()?;
+        }
+
+        // Persist indexes
+        {
+            let mut index = self.index.lock().map_err(|e| {
+                StorageError::Lock(format!("Failed to acquire index lock: {}", e))
+            })?;
+            index.persist()?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the data directory path
+    pub fn data_dir(&self) -> &Path {
+        &self.config.data_dir
+    }
+
+    /// Get index statistics
+    pub fn index_stats(&self) -> crate::index::IndexStats {
+        let index = self.index.lock().unwrap();
+        index.stats()
+    }
+
+    /// Oldest and newest point timestamps in segments and the write buffer
+    ///
+    /// Unlike `time_bounds`, this covers unflushed points and the last point
+    /// of each block; deleted points may still count.
+    pub async fn data_bounds(&self) -> Option<(i64, i64)> {
+        let state = self.state.read().await;
+        let buffer = self.write_buffer.read().await;
+
+        let segments = state
+            .segments
+            .iter()
+            .filter(|s| s.header.block_count > 0)
+            .map(|s| (s.header.min_timestamp, s.header.max_timestamp));
+        let buffered = buffer.iter().map(|p| (p.timestamp, p.timestamp));
+        segments
+            .chain(buffered)
+            .reduce(|(min, max), (lo, hi)| (min.min(lo), max.max(hi)))
+    }
+
+    /// Get time bounds of all indexed data
+    pub fn time_bounds(&self) -> Option<(i64, i64)> {
         let index = self.index.lock().unwrap();
         index.time_bounds()
     }
@@ -653,18 +1939,24 @@ pub struct StorageStats {
     pub buffer_points: usize,
     pub wal_entries: u64,
     pub storage_size_bytes: u64,
+    /// Blocks decompressed by queries since startup
+    pub blocks_read: u64,
+    /// Blocks skipped by query planning since startup
+    pub blocks_pruned: u64,
 }
 
 impl std::fmt::Display for StorageStats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Segments: {}, Points: {}, Buffer: {}, WAL: {}, Size: {:.2} MB",
+            "Segments: {}, Points: {}, Buffer: {}, WAL: {}, Size: {:.2} MB, Blocks read/pruned: {}/{}",
             self.segment_count,
             self.total_points,
             self.buffer_points,
             self.wal_entries,
-            self.storage_size_bytes as f64 / (1024.0 * 1024.0)
+            self.storage_size_bytes as f64 / (1024.0 * 1024.0),
+            self.blocks_read,
+            self.blocks_pruned
         )
     }
 }
@@ -672,6 +1964,7 @@ impl std::fmt::Display for StorageStats {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::retention::RollupPolicy;
     use crate::storage::types::AggregationType;
     use tempfile::tempdir;
 
@@ -949,4 +2242,758 @@ mod tests {
         assert_eq!(stats.buffer_points, 0);
         assert!(stats.storage_size_bytes > 0);
     }
+
+    #[tokio::test]
+    async fn test_query_prunes_blocks_by_metric() {
+        let (engine, _dir) = create_test_engine().await;
+
+        let mood_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+        let energy_id = engine
+            .register_metric(Metric::new("energy", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        // One block per metric, with overlapping time ranges
+        for i in 0..20 {
+            engine
+                .write(DataPoint::with_timestamp(mood_id, i as f64, 1_000 + i))
+                .await
+                .unwrap();
+        }
+        engine.flush().await.unwrap();
+        for i in 0..20 {
+            engine
+                .write(DataPoint::with_timestamp(energy_id, i as f64, 1_000 + i))
+                .await
+                .unwrap();
+        }
+        engine.flush().await.unwrap();
+
+        let range = TimeRange::new(0, 10_000);
+        let (points, stats) = engine
+            .query_with_stats(range, Some(QueryFilter::new().metric_id(mood_id)))
+            .await
+            .unwrap();
+
+        assert_eq!(points.len(), 20);
+        assert!(points.iter().all(|p| p.metric_id == mood_id));
+        assert_eq!(stats.blocks_total, 2);
+        assert_eq!(stats.blocks_read, 1);
+        assert_eq!(stats.blocks_pruned_by_index, 1);
+
+        let totals = engine.stats().await;
+        assert_eq!(totals.blocks_read, 1);
+        assert_eq!(totals.blocks_pruned, 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_includes_block_straddling_range_start() {
+        let (engine, _dir) = create_test_engine().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new("test", "units", Category::Custom, AggregationType::Average))
+            .await
+            .unwrap();
+
+        for ts in [1_000, 2_000, 3_000, 4_000] {
+            engine
+                .write(DataPoint::with_timestamp(metric_id, 1.0, ts))
+                .await
+                .unwrap();
+        }
+        engine.flush().await.unwrap();
+
+        // The block starts at 1000, before the range start
+        let range = TimeRange::new(2_500, 5_000);
+        let (points, stats) = engine
+            .query_with_stats(range, Some(QueryFilter::new().metric_id(metric_id)))
+            .await
+            .unwrap();
+
+        assert_eq!(points.len(), 2);
+        assert_eq!(stats.blocks_read, 1);
+        assert_eq!(stats.blocks_pruned_by_index, 0);
+    }
+
+    #[tokio::test]
+    async fn test_query_prunes_blocks_by_tag() {
+        let (engine, _dir) = create_test_engine().await;
+
+        let metric_id = engine
+            .register_metric(Metric::new("steps", "count", Category::Health, AggregationType::Sum))
+            .await
+            .unwrap();
+
+        // First block mixes two tag values for the same key
+        engine
+            .write(DataPoint::with_timestamp(metric_id, 1.0, 1_000).tag("source", "manual"))
+            .await
+            .unwrap();
+        engine
+            .write(DataPoint::with_timestamp(metric_id, 2.0, 1_001).tag("source", "api"))
+            .await
+            .unwrap();
+        engine.flush().await.unwrap();
+
+        engine
+            .write(DataPoint::with_timestamp(metric_id, 3.0, 1_002).tag("source", "api"))
+            .await
+            .unwrap();
+        engine.flush().await.unwrap();
+
+        let range = TimeRange::new(0, 10_000);
+        let (points, stats) = engine
+            .query_with_stats(range, Some(QueryFilter::new().tag("source", "manual")))
+            .await
+            .unwrap();
+
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].value, 1.0);
+        assert_eq!(stats.blocks_read, 1);
+        assert_eq!(stats.blocks_pruned_by_index, 1);
+    }
+
+    async fn write_flushed_blocks(engine: &StorageEngine, metric_ids: &[u32], blocks: i64) {
+        for block in 0..blocks {
+            for &metric_id in metric_ids {
+                for i in 0..10 {
+                    let ts = 1_000 + block * 100 + i;
+                    engine
+                        .write(DataPoint::with_timestamp(metric_id, ts as f64, ts))
+                        .await
+                        .unwrap();
+                }
+            }
+            engine.flush().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compaction_merges_blocks() {
+        let dir = tempdir().unwrap();
+        let mut config = StorageConfig::new(dir.path());
+        config.compaction_min_blocks = 4;
+        let engine = StorageEngine::new(config.clone()).await.unwrap();
+
+        let mood_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+        let energy_id = engine
+            .register_metric(Metric::new("energy", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        // Too few blocks to seal the active segment
+        write_flushed_blocks(&engine, &[mood_id, energy_id], 3).await;
+        assert!(engine.compact().await.unwrap().is_none());
+
+        write_flushed_blocks(&engine, &[mood_id, energy_id], 3).await;
+        let range = TimeRange::new(0, 10_000);
+        let before = engine.query(range, None).await.unwrap();
+        assert_eq!(before.len(), 120);
+
+        let stats = engine.compact().await.unwrap().unwrap();
+        assert_eq!(stats.segments_merged, 1);
+        assert_eq!(stats.blocks_before, 6);
+        assert_eq!(stats.points, 120);
+        assert!(stats.blocks_after < stats.blocks_before);
+
+        // Nothing left to do
+        assert!(engine.compact().await.unwrap().is_none());
+
+        let after = engine.query(range, None).await.unwrap();
+        assert_eq!(after.len(), before.len());
+
+        // Metric-clustered blocks still prune through the index
+        let (mood, scan) = engine
+            .query_with_stats(range, Some(QueryFilter::new().metric_id(mood_id)))
+            .await
+            .unwrap();
+        assert_eq!(mood.len(), 60);
+        assert!(mood.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert_eq!(scan.blocks_read, 1);
+
+        // New writes go to a fresh segment and survive a restart
+        write_flushed_blocks(&engine, &[mood_id], 1).await;
+        drop(engine);
+
+        let engine = StorageEngine::new(config).await.unwrap();
+        let segments: Vec<_> = std::fs::read_dir(dir.path().join("segments"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(segments.len(), 2, "{:?}", segments);
+        assert_eq!(engine.query(range, None).await.unwrap().len(), 130);
+        assert_eq!(
+            engine.query_metric("energy", range).await.unwrap().len(),
+            60
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compaction_recovery_rolls_back() {
+        let dir = tempdir().unwrap();
+        let config = StorageConfig::new(dir.path());
+
+        {
+            let engine = StorageEngine::new(config.clone()).await.unwrap();
+            let id = engine
+                .register_metric(Metric::new("test", "units", Category::Custom, AggregationType::Average))
+                .await
+                .unwrap();
+            write_flushed_blocks(&engine, &[id], 2).await;
+        }
+
+        // Simulate a crash while the output was still being written
+        let segments_dir = config.segments_dir();
+        let manifest = CompactionManifest {
+            inputs: vec![1],
+            output: 7,
+        };
+        manifest.write(&segments_dir).unwrap();
+        let tmp = compaction::temp_segment_path(&segments_dir, 7);
+        std::fs::write(&tmp, b"partial").unwrap();
+
+        let engine = StorageEngine::new(config).await.unwrap();
+        assert!(!tmp.exists());
+        assert!(CompactionManifest::load(&segments_dir).unwrap().is_none());
+        assert_eq!(engine.query(TimeRange::new(0, 10_000), None).await.unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_recovery_rolls_forward() {
+        let dir = tempdir().unwrap();
+        let config = StorageConfig::new(dir.path());
+        let segments_dir = config.segments_dir();
+        let mut metric_id = 0;
+
+        // Two sessions leave two segments behind
+        for _ in 0..2 {
+            let engine = StorageEngine::new(config.clone()).await.unwrap();
+            metric_id = engine
+                .register_metric(Metric::new("test", "units", Category::Custom, AggregationType::Average))
+                .await
+                .unwrap();
+            write_flushed_blocks(&engine, &[metric_id], 2).await;
+        }
+
+        // Simulate a crash after the output was renamed into place but
+        // before the inputs were deleted
+        let mut points = Vec::new();
+        for id in [1, 2] {
+            let segment = Segment::open(compaction::segment_path(&segments_dir, id)).unwrap();
+            points.extend(segment.read_all().unwrap());
+        }
+        compaction::build_compacted(
+            &compaction::segment_path(&segments_dir, 3),
+            CompressionType::Lz4,
+            64 * 1024,
+            2,
+            points,
+        )
+        .unwrap();
+        CompactionManifest {
+            inputs: vec![1, 2],
+            output: 3,
+        }
+        .write(&segments_dir)
+        .unwrap();
+
+        let engine = StorageEngine::new(config).await.unwrap();
+        assert!(!compaction::segment_path(&segments_dir, 1).exists());
+        assert!(!compaction::segment_path(&segments_dir, 2).exists());
+        assert!(CompactionManifest::load(&segments_dir).unwrap().is_none());
+
+        // Each point is served exactly once, and the index knows the output
+        let range = TimeRange::new(0, 10_000);
+        assert_eq!(engine.query(range, None).await.unwrap().len(), 40);
+        let (points, scan) = engine
+            .query_with_stats(range, Some(QueryFilter::new().metric_id(metric_id)))
+            .await
+            .unwrap();
+        assert_eq!(points.len(), 40);
+        assert_eq!(scan.blocks_read, 1);
+    }
+
+    #[tokio::test]
+    async fn test_retention_rolls_up_expired_points() {
+        let dir = tempdir().unwrap();
+        let config = StorageConfig::new(dir.path());
+        let engine = StorageEngine::new(config.clone()).await.unwrap();
+
+        let hr_id = engine
+            .register_metric(Metric::new("heart_rate", "bpm", Category::Health, AggregationType::Average))
+            .await
+            .unwrap();
+        engine
+            .set_category_retention(
+                Category::Health,
+                Some(RetentionPolicy::raw_days(30).rollup(RollupPolicy::hourly())),
+            )
+            .await
+            .unwrap();
+
+        let hour = 3600 * 1000;
+        let now = Utc::now().timestamp_millis();
+        let old_hour = (now - 40 * 24 * hour) / hour * hour;
+
+        let mut points = vec![
+            DataPoint::with_timestamp(hr_id, 60.0, old_hour + 1_000),
+            DataPoint::with_timestamp(hr_id, 70.0, old_hour + 2_000),
+            DataPoint::with_timestamp(hr_id, 80.0, old_hour + 3_000),
+            DataPoint::with_timestamp(hr_id, 90.0, old_hour + hour + 1_000),
+            DataPoint::with_timestamp(hr_id, 100.0, old_hour + hour + 2_000),
+        ];
+        points.push(DataPoint::with_timestamp(hr_id, 65.0, now - 24 * hour));
+        points.push(DataPoint::with_timestamp(hr_id, 75.0, now - 23 * hour));
+        engine.write_batch(points).await.unwrap();
+
+        let stats = engine.apply_retention().await.unwrap();
+        assert_eq!(stats.points_expired, 5);
+        assert_eq!(stats.rollup_points, 2);
+        assert_eq!(stats.segments_rewritten, 1);
+
+        let all_time = TimeRange::new(0, now + hour);
+        let raw = engine.query_metric("heart_rate", all_time).await.unwrap();
+        assert_eq!(raw.len(), 2);
+
+        let rollup = engine.get_metric("heart_rate_1h").await.unwrap();
+        assert_eq!(rollup.rollup_of, Some(hr_id));
+        let rolled = engine.query_metric("heart_rate_1h", all_time).await.unwrap();
+        assert_eq!(rolled.len(), 2);
+        assert_eq!((rolled[0].timestamp, rolled[0].value), (old_hour, 70.0));
+        assert_eq!((rolled[1].timestamp, rolled[1].value), (old_hour + hour, 95.0));
+
+        // Nothing else has expired, so a second run leaves everything alone
+        assert_eq!(engine.apply_retention().await.unwrap(), RetentionStats::default());
+        assert_eq!(engine.query(all_time, None).await.unwrap().len(), 4);
+
+        // Policies survive a restart
+        drop(engine);
+        let engine = StorageEngine::new(config).await.unwrap();
+        assert!(engine.category_retention().await.0.contains_key(&Category::Health));
+        assert_eq!(engine.query(all_time, None).await.unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_retention_rewrites_in_batches() {
+        let dir = tempdir().unwrap();
+        let mut config = StorageConfig::new(dir.path());
+        // Every flush fills a segment
+        config.max_segment_size = 1;
+        let engine = StorageEngine::new(config).await.unwrap();
+
+        let hr_id = engine
+            .register_metric(Metric::new("heart_rate", "bpm", Category::Health, AggregationType::Average))
+            .await
+            .unwrap();
+        engine
+            .set_category_retention(Category::Health, Some(RetentionPolicy::raw_days(30)))
+            .await
+            .unwrap();
+
+        let day = 24 * 3600 * 1000;
+        let now = Utc::now().timestamp_millis();
+        for i in 0..3 {
+            engine
+                .write_batch(vec![
+                    DataPoint::with_timestamp(hr_id, 60.0, now - (40 + i) * day),
+                    DataPoint::with_timestamp(hr_id, 70.0, now - (1 + i) * day),
+                ])
+                .await
+                .unwrap();
+            engine.flush().await.unwrap();
+        }
+        assert_eq!(engine.stats().await.segment_count, 3);
+
+        // Each segment is its own batch, so none are merged
+        let stats = engine.apply_retention().await.unwrap();
+        assert_eq!((stats.points_expired, stats.segments_rewritten), (3, 3));
+        assert_eq!(engine.stats().await.segment_count, 3);
+
+        let points = engine.query(TimeRange::new(0, now), None).await.unwrap();
+        assert!(points.iter().all(|p| p.value == 70.0));
+        assert_eq!(points.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_metric_retention_overrides_category() {
+        let dir = tempdir().unwrap();
+        let config = StorageConfig::new(dir.path());
+        let engine = StorageEngine::new(config.clone()).await.unwrap();
+
+        let steps_id = engine
+            .register_metric(Metric::new("steps", "count", Category::Health, AggregationType::Sum))
+            .await
+            .unwrap();
+        let mood_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        engine
+            .set_category_retention(Category::Health, Some(RetentionPolicy::raw_days(365)))
+            .await
+            .unwrap();
+        engine
+            .set_metric_retention("steps", Some(RetentionPolicy::raw_days(7)))
+            .await
+            .unwrap();
+
+        let day = 24 * 3600 * 1000;
+        let now = Utc::now().timestamp_millis();
+        engine
+            .write_batch(vec![
+                DataPoint::with_timestamp(steps_id, 1000.0, now - 10 * day),
+                DataPoint::with_timestamp(steps_id, 2000.0, now - day),
+                DataPoint::with_timestamp(mood_id, 7.0, now - 10 * day),
+            ])
+            .await
+            .unwrap();
+
+        let stats = engine.apply_retention().await.unwrap();
+        assert_eq!(stats.points_expired, 1);
+        assert_eq!(stats.rollup_points, 0);
+
+        let range = TimeRange::new(0, now + day);
+        let steps = engine.query_metric("steps", range).await.unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].value, 2000.0);
+        assert_eq!(engine.query_metric("mood", range).await.unwrap().len(), 1);
+        assert_eq!(engine.get_metrics().await.len(), 2);
+
+        drop(engine);
+        let engine = StorageEngine::new(config).await.unwrap();
+        let steps = engine.get_metric("steps").await.unwrap();
+        assert_eq!(steps.retention, Some(RetentionPolicy::raw_days(7)));
+    }
+
+    #[tokio::test]
+    async fn test_delete_points_hides_until_purged() {
+        let (engine, _dir) = create_test_engine().await;
+        let mood_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        // A bad import tagged source=csv alongside manual entries
+        for ts in 1_000..1_010 {
+            engine
+                .write(DataPoint::with_timestamp(mood_id, 1.0, ts).tag("source", "csv"))
+                .await
+                .unwrap();
+            engine
+                .write(DataPoint::with_timestamp(mood_id, 5.0, ts).tag("source", "manual"))
+                .await
+                .unwrap();
+        }
+        engine.flush().await.unwrap();
+
+        let tags: HashMap<String, String> = [("source".to_string(), "csv".to_string())].into();
+        engine
+            .delete_points(Some(mood_id), TimeRange::new(1_000, 1_005), tags)
+            .await
+            .unwrap();
+
+        // Re-imported points in the deleted range stay visible
+        engine
+            .write(DataPoint::with_timestamp(mood_id, 2.0, 1_002).tag("source", "csv"))
+            .await
+            .unwrap();
+
+        let range = TimeRange::new(0, 10_000);
+        let (points, stats) = engine.query_with_stats(range, None).await.unwrap();
+        assert_eq!(points.len(), 16);
+        assert_eq!(stats.points_deleted, 5);
+        assert!(points
+            .iter()
+            .all(|p| p.value != 1.0 || p.timestamp >= 1_005 || !p.has_tag("source", "csv")));
+
+        let purged = engine.purge().await.unwrap().unwrap();
+        assert_eq!(purged.points_deleted, 5);
+        assert!(engine.tombstones().await.is_empty());
+        assert!(engine.purge().await.unwrap().is_none());
+
+        let (after, stats) = engine.query_with_stats(range, None).await.unwrap();
+        assert_eq!(after, points);
+        assert_eq!(stats.points_deleted, 0);
+    }
+
+    #[tokio::test]
+    async fn test_tombstones_survive_restart() {
+        let dir = tempdir().unwrap();
+        let config = StorageConfig::new(dir.path());
+        let range = TimeRange::new(0, 10_000);
+
+        let metric_id;
+        {
+            let engine = StorageEngine::new(config.clone()).await.unwrap();
+            metric_id = engine
+                .register_metric(Metric::new("steps", "steps", Category::Health, AggregationType::Sum))
+                .await
+                .unwrap();
+            for ts in 1_000..1_010 {
+                engine
+                    .write(DataPoint::with_timestamp(metric_id, 1.0, ts))
+                    .await
+                    .unwrap();
+            }
+            engine.flush().await.unwrap();
+
+            // Only in the WAL: dropped without a flush to checkpoint it
+            engine
+                .delete_points(None, TimeRange::new(1_000, 1_004), HashMap::new())
+                .await
+                .unwrap();
+        }
+
+        {
+            let engine = StorageEngine::new(config.clone()).await.unwrap();
+            assert_eq!(engine.query(range, None).await.unwrap().len(), 6);
+
+            // Checkpointed, then the WAL is truncated by the flush
+            engine
+                .write(DataPoint::with_timestamp(metric_id, 1.0, 2_000))
+                .await
+                .unwrap();
+            engine.flush().await.unwrap();
+        }
+
+        let engine = StorageEngine::new(config).await.unwrap();
+        assert_eq!(engine.tombstones().await.len(), 1);
+        assert_eq!(engine.query(range, None).await.unwrap().len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_metric_soft_and_hard_delete() {
+        let (engine, _dir) = create_test_engine().await;
+        let metric = Metric::new("mood", "1-10", Category::Mood, AggregationType::Average);
+        let mood_id = engine.register_metric(metric.clone()).await.unwrap();
+
+        for ts in 1_000..1_005 {
+            engine
+                .write(DataPoint::with_timestamp(mood_id, 7.0, ts))
+                .await
+                .unwrap();
+        }
+        engine.flush().await.unwrap();
+        let range = TimeRange::new(0, 10_000);
+
+        // Soft delete hides the metric and rejects writes, but keeps data
+        engine.delete_metric(mood_id, DeleteMode::Soft).await.unwrap();
+        assert!(engine.get_metric("mood").await.is_none());
+        assert!(engine.get_metrics().await.is_empty());
+        assert!(engine.query_metric("mood", range).await.is_err());
+        assert!(engine.write(DataPoint::with_timestamp(mood_id, 1.0, 2_000)).await.is_err());
+
+        // Registering the name again restores it with its data
+        assert_eq!(engine.register_metric(metric.clone()).await.unwrap(), mood_id);
+        assert_eq!(engine.query_metric("mood", range).await.unwrap().len(), 5);
+
+        // Hard delete drops the data and frees the name
+        engine.delete_metric(mood_id, DeleteMode::Hard).await.unwrap();
+        assert!(engine.query(range, None).await.unwrap().is_empty());
+
+        let new_id = engine.register_metric(metric).await.unwrap();
+        assert_ne!(new_id, mood_id);
+        assert!(engine.query_metric("mood", range).await.unwrap().is_empty());
+
+        // The registry keeps the old slot across a reload
+        let registry = MetricRegistry::load(&engine.config.metrics_path()).unwrap();
+        assert_eq!(registry.get_by_id(mood_id).unwrap().deleted, Some(DeleteMode::Hard));
+        assert_eq!(registry.get_by_name("mood").unwrap().id, new_id);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_policies_in_queries_and_compaction() {
+        let dir = tempdir().unwrap();
+        let mut config = StorageConfig::new(dir.path());
+        config.compaction_min_blocks = 2;
+        let engine = StorageEngine::new(config).await.unwrap();
+        let steps_id = engine
+            .register_metric(
+                Metric::new("steps", "steps", Category::Health, AggregationType::Sum)
+                    .duplicates(DuplicatePolicy::LastWriteWins),
+            )
+            .await
+            .unwrap();
+        let mood_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        // The same sync runs twice, the second time with a corrected value
+        for value in [100.0, 120.0] {
+            for ts in [1_000, 2_000] {
+                engine
+                    .write(DataPoint::with_timestamp(steps_id, value, ts).tag("source", "fitbit"))
+                    .await
+                    .unwrap();
+            }
+            engine
+                .write(DataPoint::with_timestamp(mood_id, value, 1_000))
+                .await
+                .unwrap();
+            engine.flush().await.unwrap();
+        }
+        // Still in the write buffer, so it is the newest copy
+        engine
+            .write(DataPoint::with_timestamp(steps_id, 130.0, 2_000).tag("source", "fitbit"))
+            .await
+            .unwrap();
+
+        let range = TimeRange::new(0, 10_000);
+        let filter = QueryFilter::new().metric_id(steps_id);
+        let (steps, stats) = engine.query_with_stats(range, Some(filter.clone())).await.unwrap();
+        let values: Vec<f64> = steps.iter().map(|p| p.value).collect();
+        assert_eq!(values, vec![120.0, 130.0]);
+        assert_eq!(stats.points_duplicate, 3);
+
+        // Keep-all metrics are untouched
+        let mood = engine.query_metric("mood", range).await.unwrap();
+        assert_eq!(mood.len(), 2);
+
+        engine.flush().await.unwrap();
+        let compacted = engine.compact().await.unwrap().unwrap();
+        assert_eq!(compacted.points_duplicate, 3);
+
+        let (steps, stats) = engine.query_with_stats(range, Some(filter)).await.unwrap();
+        let values: Vec<f64> = steps.iter().map(|p| p.value).collect();
+        assert_eq!(values, vec![120.0, 130.0]);
+        assert_eq!(stats.points_duplicate, 0);
+
+        engine
+            .set_metric_duplicates("mood", DuplicatePolicy::Reject)
+            .await
+            .unwrap();
+        let mood = engine.query_metric("mood", range).await.unwrap();
+        assert_eq!(mood.len(), 1);
+        assert_eq!(mood[0].value, 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_output_keeps_write_order() {
+        let dir = tempdir().unwrap();
+        let (weight_id, noise_id) = {
+            let engine = StorageEngine::new(StorageConfig::new(dir.path())).await.unwrap();
+            let weight_id = engine
+                .register_metric(
+                    Metric::new("weight", "kg", Category::Health, AggregationType::Last)
+                        .duplicates(DuplicatePolicy::LastWriteWins),
+                )
+                .await
+                .unwrap();
+            let noise_id = engine
+                .register_metric(Metric::new("noise", "", Category::Custom, AggregationType::Sum))
+                .await
+                .unwrap();
+
+            engine
+                .write(DataPoint::with_timestamp(weight_id, 80.0, 1_000))
+                .await
+                .unwrap();
+            engine
+                .write(DataPoint::with_timestamp(noise_id, 1.0, 1_000))
+                .await
+                .unwrap();
+            engine
+                .delete_points(Some(noise_id), TimeRange::new(0, 10_000), HashMap::new())
+                .await
+                .unwrap();
+
+            // The correction lands in a newer segment
+            engine
+                .write(DataPoint::with_timestamp(weight_id, 79.5, 1_000))
+                .await
+                .unwrap();
+            engine.flush().await.unwrap();
+
+            // The purge output gets the highest id, but not the newest data
+            let purged = engine.purge().await.unwrap().unwrap();
+            assert_eq!(purged.segments_merged, 1);
+
+            let weight = engine.query_metric("weight", TimeRange::new(0, 10_000)).await.unwrap();
+            assert_eq!(weight.len(), 1);
+            assert_eq!(weight[0].value, 79.5);
+
+            engine.shutdown().await.unwrap();
+            (weight_id, noise_id)
+        };
+
+        let engine = StorageEngine::new(StorageConfig::new(dir.path())).await.unwrap();
+        let points = engine.query(TimeRange::new(0, 10_000), None).await.unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].metric_id, weight_id);
+        assert_eq!(points[0].value, 79.5);
+        assert_ne!(points[0].metric_id, noise_id);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_and_restore() {
+        let dir = tempdir().unwrap();
+        let mut config = StorageConfig::new(dir.path().join("data"));
+        config.compaction_min_blocks = 2;
+        let engine = StorageEngine::new(config).await.unwrap();
+        let mood_id = engine
+            .register_metric(Metric::new("mood", "1-10", Category::Mood, AggregationType::Average))
+            .await
+            .unwrap();
+
+        for ts in [1_000, 2_000] {
+            engine
+                .write(DataPoint::with_timestamp(mood_id, 5.0, ts))
+                .await
+                .unwrap();
+            engine.flush().await.unwrap();
+        }
+        engine
+            .delete_points(Some(mood_id), TimeRange::new(0, 1_500), HashMap::new())
+            .await
+            .unwrap();
+        // Unflushed points are flushed into the snapshot
+        engine
+            .write(DataPoint::with_timestamp(mood_id, 6.0, 3_000))
+            .await
+            .unwrap();
+
+        let snapshot_dir = dir.path().join("snapshot");
+        let manifest = engine.snapshot(&snapshot_dir).await.unwrap();
+        assert!(manifest.files.iter().any(|f| f.path == "index/time_index.db"));
+        assert!(engine.snapshot(&snapshot_dir).await.is_err());
+
+        // Later writes and rewrites don't reach the snapshot
+        engine
+            .write(DataPoint::with_timestamp(mood_id, 7.0, 4_000))
+            .await
+            .unwrap();
+        engine.flush().await.unwrap();
+        engine.purge().await.unwrap().unwrap();
+        engine.compact().await.unwrap();
+
+        // The open engine's directory can't be restored into
+        assert!(matches!(
+            snapshot::restore(&snapshot_dir, &dir.path().join("data")),
+            Err(StorageError::Locked(_))
+        ));
+
+        let data_dir = dir.path().join("restored");
+        let restored = snapshot::restore(&snapshot_dir, &data_dir).unwrap();
+        assert_eq!(restored, manifest);
+
+        let engine = StorageEngine::new(StorageConfig::new(&data_dir)).await.unwrap();
+        let values: Vec<f64> = engine
+            .query_metric("mood", TimeRange::new(0, 10_000))
+            .await
+            .unwrap()
+            .iter()
+            .map(|p| p.value)
+            .collect();
+        assert_eq!(values, vec![5.0, 6.0]);
+        assert_eq!(engine.tombstones().await.len(), 1);
+        assert_eq!(engine.stats().await.segment_count, manifest.segments.len());
+    }
 }
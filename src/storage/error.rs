@@ -46,6 +46,18 @@ pub enum StorageError {
     /// Lock acquisition failed
     #[error("Lock error: {0}")]
     Lock(String),
+
+    /// Data point rejected before writing (empty metric name, NaN value, ...)
+    #[error("Invalid data point: {0}")]
+    InvalidPoint(String),
+
+    /// The handle has been shut down
+    #[error("Storage has been shut down")]
+    Closed,
+
+    /// Another engine or tool has the data directory open
+    #[error("Data directory {0} is in use by another process")]
+    Locked(String),
 }
 
 impl From<bincode::Error> for StorageError {
@@ -0,0 +1,813 @@
+//! Offline integrity checks for a data directory
+//!
+//! `check` reads a data directory without a running engine and reports:
+//!
+//! - segments that cannot be opened, blocks whose checksum fails or that
+//!   do not decompress, and footer metadata that disagrees with the points
+//! - points of metrics missing from `meta/metrics.json`
+//! - an unreadable WAL tail (entries after it are lost on recovery)
+//! - unreadable metadata files and leftovers of interrupted compactions
+//! - time, metric and tag index entries that are missing, or that point at
+//!   blocks that do not exist or do not hold the indexed metric or tag
+//!
+//! The indexes can be trusted when no index issues are reported. Otherwise
+//! `rebuild_indexes` throws them away and re-indexes every readable block.
+//! Neither function should run while an engine has the directory open;
+//! `rebuild_indexes` takes the directory lock and refuses to.
+
+use crate::index::{DataLocation, IndexConfig, IndexManager};
+use crate::storage::compaction::CompactionManifest;
+use crate::storage::engine::{MetricRegistry, StorageConfig};
+use crate::storage::error::StorageResult;
+use crate::storage::lock::DirLock;
+use crate::storage::retention::CategoryRetention;
+use crate::storage::segment::Segment;
+use crate::storage::tombstone::TombstoneSet;
+use crate::storage::wal::{WalScan, WriteAheadLog};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::path::Path;
+
+/// Index files inside `index/`
+const INDEX_FILES: [&str; 5] = [
+    "time_index.db",
+    "time_index.db-wal",
+    "time_index.db-shm",
+    "metric_index.json",
+    "tag_index.json",
+];
+
+/// What to check
+#[derive(Debug, Clone)]
+pub struct FsckOptions {
+    /// Compare the tag index (disable if the engine runs without one)
+    pub check_tags: bool,
+}
+
+impl Default for FsckOptions {
+    fn default() -> Self {
+        Self { check_tags: true }
+    }
+}
+
+/// Part of the data directory an issue was found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    Segments,
+    Wal,
+    Meta,
+    TimeIndex,
+    MetricIndex,
+    TagIndex,
+}
+
+impl Component {
+    /// Check if this is one of the indexes
+    pub fn is_index(&self) -> bool {
+        matches!(
+            self,
+            Component::TimeIndex | Component::MetricIndex | Component::TagIndex
+        )
+    }
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Component::Segments => write!(f, "segments"),
+            Component::Wal => write!(f, "wal"),
+            Component::Meta => write!(f, "meta"),
+            Component::TimeIndex => write!(f, "time_index"),
+            Component::MetricIndex => write!(f, "metric_index"),
+            Component::TagIndex => write!(f, "tag_index"),
+        }
+    }
+}
+
+/// What is wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// Data cannot be read back
+    Corrupt,
+    /// Readable, but disagrees with metadata describing it
+    Mismatch,
+    /// Something that should exist does not
+    Missing,
+    /// Entries referring to data that does not exist
+    Orphaned,
+    /// Left behind by an interrupted operation
+    Leftover,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IssueKind::Corrupt => write!(f, "corrupt"),
+            IssueKind::Mismatch => write!(f, "mismatch"),
+            IssueKind::Missing => write!(f, "missing"),
+            IssueKind::Orphaned => write!(f, "orphaned"),
+            IssueKind::Leftover => write!(f, "leftover"),
+        }
+    }
+}
+
+/// A single problem found by `check`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FsckIssue {
+    pub component: Component,
+    pub kind: IssueKind,
+    /// Segment the issue concerns, if any
+    pub segment: Option<u32>,
+    pub message: String,
+}
+
+impl fmt::Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: ", self.component, self.kind)?;
+        if let Some(segment) = self.segment {
+            write!(f, "segment {}: ", segment)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Result of checking a data directory
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FsckReport {
+    /// Segments opened
+    pub segments: usize,
+    /// Blocks read
+    pub blocks: usize,
+    /// Points decoded
+    pub points: usize,
+    /// Readable WAL entries
+    pub wal_entries: u64,
+    /// Problems found
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    /// Check if no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Check if the indexes match the segments
+    pub fn indexes_consistent(&self) -> bool {
+        !self.issues.iter().any(|i| i.component.is_index())
+    }
+
+    fn push(
+        &mut self,
+        component: Component,
+        kind: IssueKind,
+        segment: Option<u32>,
+        message: impl Into<String>,
+    ) {
+        self.issues.push(FsckIssue {
+            component,
+            kind,
+            segment,
+            message: message.into(),
+        });
+    }
+}
+
+/// Result of `rebuild_indexes`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RebuildStats {
+    /// Segments indexed
+    pub segments: usize,
+    /// Blocks indexed
+    pub blocks: usize,
+    /// Unreadable blocks, indexed by time only
+    pub blocks_skipped: usize,
+}
+
+/// What a block holds, if it could be read
+struct BlockContents {
+    metrics: BTreeSet<u32>,
+    tags: HashSet<(String, String)>,
+}
+
+/// A segment as found on disk
+struct ScannedSegment {
+    id: u32,
+    /// Footer min_timestamp and contents per block
+    blocks: Vec<(i64, Option<BlockContents>)>,
+}
+
+impl ScannedSegment {
+    fn fully_readable(&self) -> bool {
+        self.blocks.iter().all(|(_, contents)| contents.is_some())
+    }
+}
+
+/// Check a data directory
+///
+/// I/O errors reading the directory itself are returned; everything else
+/// is reported as an issue.
+pub fn check(data_dir: &Path, options: &FsckOptions) -> StorageResult<FsckReport> {
+    let config = StorageConfig::new(data_dir);
+    let mut report = FsckReport::default();
+
+    let registry = check_meta(&config, &mut report);
+    let (segments, unopened) = scan_segments(&config, &mut report)?;
+
+    if let Some(registry) = &registry {
+        check_metrics_registered(&segments, registry, &mut report);
+    }
+
+    let wal_path = config.wal_path();
+    if wal_path.exists() {
+        let scan = WriteAheadLog::scan(&wal_path)?;
+        report.wal_entries = scan.points + scan.tombstones;
+        check_wal(&scan, &mut report);
+    }
+
+    check_indexes(&config, options, &segments, &unopened, &mut report)?;
+
+    Ok(report)
+}
+
+/// Throw the indexes away and rebuild them from the segments
+///
+/// Fails with `StorageError::Locked` while an engine has `data_dir` open.
+pub fn rebuild_indexes(data_dir: &Path, options: &FsckOptions) -> StorageResult<RebuildStats> {
+    let _lock = DirLock::acquire(data_dir)?;
+    let config = StorageConfig::new(data_dir);
+    let mut report = FsckReport::default();
+    let (segments, _) = scan_segments(&config, &mut report)?;
+
+    let index_dir = data_dir.join("index");
+    for name in INDEX_FILES {
+        let path = index_dir.join(name);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let mut index = IndexManager::with_config(
+        data_dir,
+        IndexConfig {
+            enable_tags: options.check_tags,
+        },
+    )?;
+
+    let mut stats = RebuildStats::default();
+    for segment in &segments {
+        stats.segments += 1;
+        for (block_idx, (min_timestamp, contents)) in segment.blocks.iter().enumerate() {
+            let block_idx = block_idx as u32;
+            match contents {
+                Some(contents) => {
+                    let metrics: Vec<u32> = contents.metrics.iter().copied().collect();
+                    index.index_block(
+                        segment.id,
+                        block_idx,
+                        *min_timestamp,
+                        &metrics,
+                        &HashMap::new(),
+                    )?;
+                    index.index_block_tags(segment.id, block_idx, &contents.tags);
+                    stats.blocks += 1;
+                }
+                None => {
+                    index.index_block(
+                        segment.id,
+                        block_idx,
+                        *min_timestamp,
+                        &[],
+                        &HashMap::new(),
+                    )?;
+                    stats.blocks_skipped += 1;
+                }
+            }
+        }
+    }
+    index.persist()?;
+
+    Ok(stats)
+}
+
+/// Load the metadata files, reporting any that cannot be parsed
+fn check_meta(config: &StorageConfig, report: &mut FsckReport) -> Option<MetricRegistry> {
+    let registry = match MetricRegistry::load(&config.metrics_path()) {
+        Ok(registry) => Some(registry),
+        Err(e) => {
+            report.push(
+                Component::Meta,
+                IssueKind::Corrupt,
+                None,
+                format!("metrics.json: {}", e),
+            );
+            None
+        }
+    };
+    if let Err(e) = CategoryRetention::load(&config.retention_path()) {
+        report.push(
+            Component::Meta,
+            IssueKind::Corrupt,
+            None,
+            format!("retention.json: {}", e),
+        );
+    }
+    if let Err(e) = TombstoneSet::load(&config.tombstones_path()) {
+        report.push(
+            Component::Meta,
+            IssueKind::Corrupt,
+            None,
+            format!("tombstones.json: {}", e),
+        );
+    }
+    registry
+}
+
+/// Open and read every segment
+///
+/// Returns the segments that could be opened and the ids of those that
+/// could not.
+fn scan_segments(
+    config: &StorageConfig,
+    report: &mut FsckReport,
+) -> StorageResult<(Vec<ScannedSegment>, HashSet<u32>)> {
+    let segments_dir = config.segments_dir();
+    let mut segments = Vec::new();
+    let mut unopened = HashSet::new();
+
+    if !segments_dir.exists() {
+        return Ok((segments, unopened));
+    }
+
+    match CompactionManifest::load(&segments_dir) {
+        Ok(Some(manifest)) => report.push(
+            Component::Segments,
+            IssueKind::Leftover,
+            Some(manifest.output),
+            format!(
+                "interrupted compaction of {:?}; it is recovered on next start",
+                manifest.inputs
+            ),
+        ),
+        Ok(None) => {}
+        Err(e) => report.push(
+            Component::Segments,
+            IssueKind::Corrupt,
+            None,
+            format!("compaction manifest: {}", e),
+        ),
+    }
+
+    let mut paths: Vec<_> = std::fs::read_dir(&segments_dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if name.ends_with(".dat.tmp") {
+            report.push(
+                Component::Segments,
+                IssueKind::Leftover,
+                None,
+                format!("temporary file {}", name),
+            );
+            continue;
+        }
+        if !name.ends_with(".dat") {
+            continue;
+        }
+
+        let segment = match Segment::open(&path) {
+            Ok(segment) => segment,
+            Err(e) => {
+                let id = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.strip_prefix("segment_"))
+                    .and_then(|s| s.parse().ok());
+                if let Some(id) = id {
+                    unopened.insert(id);
+                }
+                report.push(
+                    Component::Segments,
+                    IssueKind::Corrupt,
+                    id,
+                    format!("{}: {}", name, e),
+                );
+                continue;
+            }
+        };
+        let id = match segment.id() {
+            Some(id) => id,
+            None => continue,
+        };
+
+        report.segments += 1;
+        segments.push(scan_segment(id, &segment, report));
+    }
+
+    Ok((segments, unopened))
+}
+
+/// Read every block of a segment and check it against its metadata
+fn scan_segment(id: u32, segment: &Segment, report: &mut FsckReport) -> ScannedSegment {
+    let mut scanned = ScannedSegment {
+        id,
+        blocks: Vec::with_capacity(segment.blocks.len()),
+    };
+
+    for (idx, meta) in segment.blocks.iter().enumerate() {
+        let points = match segment.read_block(idx) {
+            Ok(points) => points,
+            Err(e) => {
+                report.push(
+                    Component::Segments,
+                    IssueKind::Corrupt,
+                    Some(id),
+                    e.to_string(),
+                );
+                scanned.blocks.push((meta.min_timestamp, None));
+                continue;
+            }
+        };
+        report.blocks += 1;
+        report.points += points.len();
+
+        let min = points.iter().map(|p| p.timestamp).min();
+        let max = points.iter().map(|p| p.timestamp).max();
+        if points.len() != meta.point_count as usize
+            || min != Some(meta.min_timestamp)
+            || max != Some(meta.max_timestamp)
+        {
+            report.push(
+                Component::Segments,
+                IssueKind::Mismatch,
+                Some(id),
+                format!(
+                    "block {} footer says {} points in [{}, {}], found {} in [{}, {}]",
+                    idx,
+                    meta.point_count,
+                    meta.min_timestamp,
+                    meta.max_timestamp,
+                    points.len(),
+                    min.unwrap_or_default(),
+                    max.unwrap_or_default()
+                ),
+            );
+        }
+        if meta.min_timestamp < segment.header.min_timestamp
+            || meta.max_timestamp > segment.header.max_timestamp
+        {
+            report.push(
+                Component::Segments,
+                IssueKind::Mismatch,
+                Some(id),
+                format!("block {} lies outside the header's time range", idx),
+            );
+        }
+
+        let contents = BlockContents {
+            metrics: points.iter().map(|p| p.metric_id).collect(),
+            tags: points
+                .iter()
+                .flat_map(|p| p.tags.iter().map(|(k, v)| (k.clone(), v.clone())))
+                .collect(),
+        };
+        scanned.blocks.push((meta.min_timestamp, Some(contents)));
+    }
+
+    scanned
+}
+
+/// Report points of metrics the registry has never seen
+fn check_metrics_registered(
+    segments: &[ScannedSegment],
+    registry: &MetricRegistry,
+    report: &mut FsckReport,
+) {
+    for segment in segments {
+        let unknown: BTreeSet<u32> = segment
+            .blocks
+            .iter()
+            .filter_map(|(_, contents)| contents.as_ref())
+            .flat_map(|contents| contents.metrics.iter().copied())
+            .filter(|&id| registry.get_by_id(id).is_none())
+            .collect();
+
+        if !unknown.is_empty() {
+            report.push(
+                Component::Segments,
+                IssueKind::Orphaned,
+                Some(segment.id),
+                format!("points of unregistered metrics {:?}", unknown),
+            );
+        }
+    }
+}
+
+/// Report an unreadable WAL tail
+fn check_wal(scan: &WalScan, report: &mut FsckReport) {
+    if let Some(error) = &scan.error {
+        report.push(
+            Component::Wal,
+            IssueKind::Corrupt,
+            None,
+            format!(
+                "{} unreadable bytes at offset {} ({}); recovery stops there",
+                scan.file_bytes - scan.valid_bytes,
+                scan.valid_bytes,
+                error
+            ),
+        );
+    }
+}
+
+/// Compare the index contents with what the segments hold
+fn check_indexes(
+    config: &StorageConfig,
+    options: &FsckOptions,
+    segments: &[ScannedSegment],
+    unopened: &HashSet<u32>,
+    report: &mut FsckReport,
+) -> StorageResult<()> {
+    let index_dir = config.data_dir.join("index");
+    if !index_dir.join("time_index.db").exists() {
+        if !segments.is_empty() {
+            report.push(
+                Component::TimeIndex,
+                IssueKind::Missing,
+                None,
+                "index not found",
+            );
+        }
+        return Ok(());
+    }
+
+    let index = IndexManager::with_config(
+        &config.data_dir,
+        IndexConfig {
+            enable_tags: options.check_tags,
+        },
+    )?;
+
+    // Blocks whose contents are unknown can't be judged
+    let unreadable: HashSet<DataLocation> = segments
+        .iter()
+        .flat_map(|s| {
+            s.blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, contents))| contents.is_none())
+                .map(move |(idx, _)| DataLocation::new(s.id, idx as u32))
+        })
+        .collect();
+    let partial: HashSet<u32> = segments
+        .iter()
+        .filter(|s| !s.fully_readable())
+        .map(|s| s.id)
+        .collect();
+
+    let mut time_expected = HashSet::new();
+    let mut segments_expected = HashSet::new();
+    let mut blocks_expected = HashSet::new();
+    let mut tags_expected = HashSet::new();
+    for segment in segments {
+        for (idx, (min_timestamp, contents)) in segment.blocks.iter().enumerate() {
+            let location = DataLocation::new(segment.id, idx as u32);
+            time_expected.insert((location, *min_timestamp));
+
+            if let Some(contents) = contents {
+                for &metric_id in &contents.metrics {
+                    segments_expected.insert((metric_id, segment.id));
+                    blocks_expected.insert((metric_id, location));
+                }
+                for (key, value) in &contents.tags {
+                    tags_expected.insert((key.clone(), value.clone(), location));
+                }
+            }
+        }
+    }
+
+    let time_actual: HashSet<(DataLocation, i64)> = index
+        .time_entries()
+        .into_iter()
+        .map(|e| (e.location, e.timestamp))
+        .filter(|(l, _)| !unopened.contains(&l.segment_id))
+        .collect();
+    diff_entries(
+        report,
+        Component::TimeIndex,
+        &time_expected,
+        &time_actual,
+        |(l, _)| l.segment_id,
+    );
+
+    let segments_actual: HashSet<(u32, u32)> = index
+        .metric_segment_entries()
+        .into_iter()
+        .filter(|(_, s)| !unopened.contains(s) && !partial.contains(s))
+        .collect();
+    let segments_expected: HashSet<(u32, u32)> = segments_expected
+        .into_iter()
+        .filter(|(_, s)| !partial.contains(s))
+        .collect();
+    diff_entries(
+        report,
+        Component::MetricIndex,
+        &segments_expected,
+        &segments_actual,
+        |(_, s)| *s,
+    );
+
+    let blocks_actual: HashSet<(u32, DataLocation)> = index
+        .metric_block_entries()
+        .into_iter()
+        .filter(|(_, l)| !unopened.contains(&l.segment_id) && !unreadable.contains(l))
+        .collect();
+    diff_entries(
+        report,
+        Component::MetricIndex,
+        &blocks_expected,
+        &blocks_actual,
+        |(_, l)| l.segment_id,
+    );
+
+    if options.check_tags {
+        let tags_actual: HashSet<(String, String, DataLocation)> = index
+            .tag_entries()
+            .into_iter()
+            .filter(|(_, _, l)| !unopened.contains(&l.segment_id) && !unreadable.contains(l))
+            .collect();
+        diff_entries(
+            report,
+            Component::TagIndex,
+            &tags_expected,
+            &tags_actual,
+            |(_, _, l)| l.segment_id,
+        );
+    }
+
+    Ok(())
+}
+
+/// Report expected entries the index lacks and entries it should not have,
+/// one issue per segment and direction
+fn diff_entries<T: Eq + Hash>(
+    report: &mut FsckReport,
+    component: Component,
+    expected: &HashSet<T>,
+    actual: &HashSet<T>,
+    segment_of: impl Fn(&T) -> u32,
+) {
+    let count_by_segment = |entries: HashSet<&T>| {
+        let mut counts: BTreeMap<u32, usize> = BTreeMap::new();
+        for entry in entries {
+            *counts.entry(segment_of(entry)).or_default() += 1;
+        }
+        counts
+    };
+
+    for (segment, count) in count_by_segment(expected.difference(actual).collect()) {
+        report.push(
+            component,
+            IssueKind::Missing,
+            Some(segment),
+            format!("{} entries missing", count),
+        );
+    }
+    for (segment, count) in count_by_segment(actual.difference(expected).collect()) {
+        report.push(
+            component,
+            IssueKind::Orphaned,
+            Some(segment),
+            format!("{} entries for data the segment does not hold", count),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::engine::StorageEngine;
+    use crate::storage::error::StorageError;
+    use crate::storage::types::{AggregationType, Category, DataPoint, Metric};
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::tempdir;
+
+    async fn write_data_dir(dir: &Path) {
+        let engine = StorageEngine::new(StorageConfig::new(dir)).await.unwrap();
+        let mood_id = engine
+            .register_metric(Metric::new(
+                "mood",
+                "1-10",
+                Category::Mood,
+                AggregationType::Average,
+            ))
+            .await
+            .unwrap();
+        for block in 0..3 {
+            for i in 0..10 {
+                engine
+                    .write(
+                        DataPoint::with_timestamp(mood_id, 5.0, block * 1_000 + i)
+                            .tag("source", "manual"),
+                    )
+                    .await
+                    .unwrap();
+            }
+            engine.flush().await.unwrap();
+        }
+        engine.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clean_directory() {
+        let dir = tempdir().unwrap();
+        write_data_dir(dir.path()).await;
+
+        let report = check(dir.path(), &FsckOptions::default()).unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+        assert_eq!(report.segments, 1);
+        assert_eq!(report.blocks, 3);
+        assert_eq!(report.points, 30);
+    }
+
+    #[tokio::test]
+    async fn test_detects_corrupt_block_and_wal_tail() {
+        let dir = tempdir().unwrap();
+        write_data_dir(dir.path()).await;
+        let config = StorageConfig::new(dir.path());
+
+        // Flip a byte inside the second block's compressed data
+        let path = crate::storage::compaction::segment_path(&config.segments_dir(), 1);
+        let offset = Segment::open(&path).unwrap().blocks[1].offset + 8;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[0xAB]).unwrap();
+
+        std::fs::write(config.wal_path(), [1, 2, 3]).unwrap();
+
+        let report = check(dir.path(), &FsckOptions::default()).unwrap();
+        let kinds: Vec<(Component, IssueKind)> = report
+            .issues
+            .iter()
+            .map(|i| (i.component, i.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (Component::Segments, IssueKind::Corrupt),
+                (Component::Wal, IssueKind::Corrupt),
+            ]
+        );
+        assert_eq!(report.blocks, 2);
+        assert!(report.indexes_consistent());
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_fixes_stale_indexes() {
+        let dir = tempdir().unwrap();
+        write_data_dir(dir.path()).await;
+
+        // An index entry for a segment that no longer exists
+        {
+            let mut index = IndexManager::new(dir.path()).unwrap();
+            index.index_block(9, 0, 0, &[1], &HashMap::new()).unwrap();
+            index.persist().unwrap();
+        }
+        std::fs::remove_file(dir.path().join("index").join("tag_index.json")).unwrap();
+
+        let report = check(dir.path(), &FsckOptions::default()).unwrap();
+        let issues: Vec<(Component, IssueKind, Option<u32>)> = report
+            .issues
+            .iter()
+            .map(|i| (i.component, i.kind, i.segment))
+            .collect();
+        assert!(issues.contains(&(Component::TimeIndex, IssueKind::Orphaned, Some(9))));
+        assert!(issues.contains(&(Component::MetricIndex, IssueKind::Orphaned, Some(9))));
+        assert!(issues.contains(&(Component::TagIndex, IssueKind::Missing, Some(1))));
+        assert!(!report.indexes_consistent());
+
+        // Not while an engine has the directory open
+        let engine = StorageEngine::new(StorageConfig::new(dir.path())).await.unwrap();
+        assert!(matches!(
+            rebuild_indexes(dir.path(), &FsckOptions::default()),
+            Err(StorageError::Locked(_))
+        ));
+        engine.shutdown().await.unwrap();
+        drop(engine);
+
+        let stats = rebuild_indexes(dir.path(), &FsckOptions::default()).unwrap();
+        assert_eq!(stats.blocks, 3);
+        let report = check(dir.path(), &FsckOptions::default()).unwrap();
+        assert!(report.is_clean(), "{:?}", report.issues);
+    }
+}
@@ -0,0 +1,686 @@
+//! Gorilla-style columnar block codec
+//!
+//! An alternative to the LZ4 codec in `compression`, modelled on Facebook's
+//! Gorilla paper. Points are split into columns, each with its own encoding:
+//!
+//! - **metric ids**: run-length encoded `(metric_id, run_length)` varints
+//! - **timestamps**: first timestamp and first delta, then zigzag varint
+//!   delta-of-deltas (regular sampling encodes as one byte per point)
+//! - **values**: XOR of each value with its predecessor, storing only the
+//!   meaningful bits (unchanged values cost a single bit). XOR does poorly
+//!   on short decimals such as 5.3, so when every value in the block is a
+//!   decimal with at most `MAX_DECIMAL_PLACES` places and that encodes
+//!   smaller, values are instead stored as zigzag varint deltas of the
+//!   value scaled to an integer.
+//! - **tags**: the deduplicated tag table from `compression`, LZ4 compressed
+//!
+//! Points are encoded grouped by metric so runs are long and consecutive
+//! values belong to the same series; decoding restores timestamp order.
+//!
+//! Layout:
+//! ```text
+//! version: u8
+//! point_count: varint
+//! metric_runs: varint, then (metric_id: varint, run_length: varint)*
+//! timestamps: varint* (zigzag: first, first delta, delta-of-deltas)
+//! value_mode: u8 (0 = XOR, 1 = decimal)
+//!   XOR:     values_len: varint, then bitstream [u8; values_len]
+//!   decimal: decimal_places: u8, then varint* (zigzag scaled deltas)
+//! has_tags: u8, then (tags_len: varint, lz4 tag table [u8; tags_len])
+//! ```
+
+use crate::storage::compression::EncodedTags;
+use crate::storage::error::{StorageError, StorageResult};
+use crate::storage::types::DataPoint;
+
+/// Codec format version
+const GORILLA_VERSION: u8 = 1;
+
+/// Value column stored as an XOR bitstream
+const VALUES_XOR: u8 = 0;
+
+/// Value column stored as scaled decimal deltas
+const VALUES_DECIMAL: u8 = 1;
+
+/// Most decimal places the decimal value mode will try
+const MAX_DECIMAL_PLACES: u8 = 4;
+
+/// Encode a block of data points
+pub fn encode_block(points: &[DataPoint]) -> StorageResult<Vec<u8>> {
+    if points.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Group by metric so runs are long and XOR/delta neighbours are related
+    let mut sorted: Vec<&DataPoint> = points.iter().collect();
+    sorted.sort_by_key(|p| (p.metric_id, p.timestamp));
+
+    let mut out = vec![GORILLA_VERSION];
+    write_varint(&mut out, sorted.len() as u64);
+
+    // Metric id runs
+    let mut runs: Vec<(u32, u64)> = Vec::new();
+    for point in &sorted {
+        match runs.last_mut() {
+            Some((id, len)) if *id == point.metric_id => *len += 1,
+            _ => runs.push((point.metric_id, 1)),
+        }
+    }
+    write_varint(&mut out, runs.len() as u64);
+    for (metric_id, len) in runs {
+        write_varint(&mut out, metric_id as u64);
+        write_varint(&mut out, len);
+    }
+
+    // Timestamps: wrapping arithmetic keeps extreme values round-tripping
+    let mut prev_ts = 0i64;
+    let mut prev_delta = 0i64;
+    for (i, point) in sorted.iter().enumerate() {
+        let encoded = match i {
+            0 => point.timestamp,
+            1 => point.timestamp.wrapping_sub(prev_ts),
+            _ => point
+                .timestamp
+                .wrapping_sub(prev_ts)
+                .wrapping_sub(prev_delta),
+        };
+        write_varint(&mut out, zigzag(encoded));
+
+        if i > 0 {
+            prev_delta = point.timestamp.wrapping_sub(prev_ts);
+        }
+        prev_ts = point.timestamp;
+    }
+
+    // Values
+    let values: Vec<f64> = sorted.iter().map(|p| p.value).collect();
+    out.extend_from_slice(&encode_values(&values));
+
+    // Tags
+    if sorted.iter().all(|p| p.tags.is_empty()) {
+        out.push(0);
+    } else {
+        let mut tags = EncodedTags::new();
+        for point in &sorted {
+            tags.add_point_tags(&point.tags);
+        }
+        let serialized = bincode::serialize(&tags)?;
+        let compressed = lz4_flex::compress_prepend_size(&serialized);
+
+        out.push(1);
+        write_varint(&mut out, compressed.len() as u64);
+        out.extend_from_slice(&compressed);
+    }
+
+    Ok(out)
+}
+
+/// Decode a block produced by `encode_block`
+///
+/// Returns points sorted by timestamp.
+pub fn decode_block(data: &[u8]) -> StorageResult<Vec<DataPoint>> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut input = ByteReader::new(data);
+
+    let version = input.read_u8()?;
+    if version != GORILLA_VERSION {
+        return Err(StorageError::Compression(format!(
+            "Unsupported Gorilla block version: {}",
+            version
+        )));
+    }
+
+    let count = input.read_varint()? as usize;
+    // Every point takes at least one timestamp byte
+    if count > data.len() {
+        return Err(corrupt("point count exceeds block size"));
+    }
+
+    // Metric id runs
+    let run_count = input.read_varint()? as usize;
+    let mut metric_ids = Vec::with_capacity(count);
+    for _ in 0..run_count {
+        let metric_id = u32::try_from(input.read_varint()?)
+            .map_err(|_| corrupt("metric id out of range"))?;
+        let end = (input.read_varint()? as usize)
+            .checked_add(metric_ids.len())
+            .filter(|&end| end <= count)
+            .ok_or_else(|| corrupt("metric runs exceed point count"))?;
+        metric_ids.resize(end, metric_id);
+    }
+    if metric_ids.len() != count {
+        return Err(corrupt("metric runs do not cover every point"));
+    }
+
+    // Timestamps
+    let mut timestamps = Vec::with_capacity(count);
+    let mut prev_ts = 0i64;
+    let mut prev_delta = 0i64;
+    for i in 0..count {
+        let encoded = unzigzag(input.read_varint()?);
+        let ts = match i {
+            0 => encoded,
+            1 => prev_ts.wrapping_add(encoded),
+            _ => prev_ts.wrapping_add(prev_delta).wrapping_add(encoded),
+        };
+
+        if i > 0 {
+            prev_delta = ts.wrapping_sub(prev_ts);
+        }
+        prev_ts = ts;
+        timestamps.push(ts);
+    }
+
+    // Values
+    let values = decode_values(&mut input, count)?;
+
+    // Tags
+    let tags = match input.read_u8()? {
+        0 => None,
+        1 => {
+            let len = input.read_varint()? as usize;
+            let decompressed = lz4_flex::decompress_size_prepended(input.read_bytes(len)?)
+                .map_err(|e| {
+                    StorageError::Compression(format!("LZ4 decompression failed: {}", e))
+                })?;
+            let tags: EncodedTags = bincode::deserialize(&decompressed)?;
+            Some(tags)
+        }
+        flag => return Err(corrupt(&format!("invalid tag flag {}", flag))),
+    };
+
+    let mut points: Vec<DataPoint> = (0..count)
+        .map(|i| DataPoint {
+            timestamp: timestamps[i],
+            metric_id: metric_ids[i],
+            value: values[i],
+            tags: tags
+                .as_ref()
+                .map(|t| t.decode_point_tags(i))
+                .unwrap_or_default(),
+        })
+        .collect();
+
+    // Stable, so points sharing a timestamp stay ordered by metric
+    points.sort_by_key(|p| p.timestamp);
+
+    Ok(points)
+}
+
+/// Encode the value column, choosing the smaller of the two modes
+fn encode_values(values: &[f64]) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    let mut xor = XorEncoder::default();
+    for &value in values {
+        xor.encode(&mut bits, value);
+    }
+    let bits = bits.finish();
+
+    let mut out = vec![VALUES_XOR];
+    write_varint(&mut out, bits.len() as u64);
+    out.extend_from_slice(&bits);
+
+    if let Some(decimal) = encode_decimal_values(values) {
+        if decimal.len() < out.len() {
+            return decimal;
+        }
+    }
+    out
+}
+
+/// Encode values as scaled integer deltas, if every value is a short decimal
+fn encode_decimal_values(values: &[f64]) -> Option<Vec<u8>> {
+    let places = (0..=MAX_DECIMAL_PLACES).find(|&places| {
+        let scale = 10f64.powi(places as i32);
+        values.iter().all(|&v| to_scaled(v, scale).is_some())
+    })?;
+    let scale = 10f64.powi(places as i32);
+
+    let mut out = vec![VALUES_DECIMAL, places];
+    let mut prev = 0i64;
+    for &value in values {
+        let scaled = to_scaled(value, scale)?;
+        write_varint(&mut out, zigzag(scaled.wrapping_sub(prev)));
+        prev = scaled;
+    }
+    Some(out)
+}
+
+/// `value * scale` as an integer, if dividing it back restores `value` exactly
+fn to_scaled(value: f64, scale: f64) -> Option<i64> {
+    // Integers beyond 2^53 are not exact in f64
+    const MAX_EXACT: f64 = 9_007_199_254_740_992.0;
+
+    let scaled = (value * scale).round();
+    if !scaled.is_finite() || scaled.abs() > MAX_EXACT {
+        return None;
+    }
+    // Compare what the decoder will produce, bitwise, so -0.0 and NaN fall
+    // back to XOR
+    let scaled = scaled as i64;
+    ((scaled as f64 / scale).to_bits() == value.to_bits()).then_some(scaled)
+}
+
+/// Decode the value column written by `encode_values`
+fn decode_values(input: &mut ByteReader, count: usize) -> StorageResult<Vec<f64>> {
+    let mut values = Vec::with_capacity(count);
+
+    match input.read_u8()? {
+        VALUES_XOR => {
+            let len = input.read_varint()? as usize;
+            let mut bits = BitReader::new(input.read_bytes(len)?);
+            let mut xor = XorDecoder::default();
+            for _ in 0..count {
+                values.push(xor.decode(&mut bits)?);
+            }
+        }
+        VALUES_DECIMAL => {
+            let places = input.read_u8()?;
+            if places > MAX_DECIMAL_PLACES {
+                return Err(corrupt("too many decimal places"));
+            }
+            let scale = 10f64.powi(places as i32);
+
+            let mut prev = 0i64;
+            for _ in 0..count {
+                prev = prev.wrapping_add(unzigzag(input.read_varint()?));
+                values.push(prev as f64 / scale);
+            }
+        }
+        mode => return Err(corrupt(&format!("invalid value mode {}", mode))),
+    }
+
+    Ok(values)
+}
+
+fn corrupt(msg: &str) -> StorageError {
+    StorageError::Compression(format!("Corrupt Gorilla block: {}", msg))
+}
+
+/// Map signed integers to unsigned so small magnitudes stay small
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+/// Append an LEB128 varint
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Cursor over the byte-aligned sections of a block
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_u8(&mut self) -> StorageResult<u8> {
+        let byte = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| corrupt("unexpected end of block"))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> StorageResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| corrupt("unexpected end of block"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_varint(&mut self) -> StorageResult<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(corrupt("varint too long"))
+    }
+}
+
+/// MSB-first bit packer
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte (0 = start a new byte)
+    used: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            used: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    /// Write the low `count` bits of `value`, most significant first
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reader for `BitWriter` output
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> StorageResult<bool> {
+        let byte = self
+            .data
+            .get(self.pos / 8)
+            .ok_or_else(|| corrupt("value stream truncated"))?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> StorageResult<u64> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
+
+/// Gorilla XOR float encoder
+///
+/// Control bits after the first value:
+/// - `0`: same value as before
+/// - `10`: meaningful bits fit the previous leading/trailing zero window
+/// - `11`: 5 bits of leading zeros, 6 bits of length - 1, then the bits
+#[derive(Default)]
+struct XorEncoder {
+    prev: Option<u64>,
+    /// Leading/trailing zeros of the current window
+    window: Option<(u32, u32)>,
+}
+
+impl XorEncoder {
+    fn encode(&mut self, out: &mut BitWriter, value: f64) {
+        let bits = value.to_bits();
+        let prev = match self.prev.replace(bits) {
+            Some(prev) => prev,
+            None => {
+                out.write_bits(bits, 64);
+                return;
+            }
+        };
+
+        let xor = bits ^ prev;
+        if xor == 0 {
+            out.write_bit(false);
+            return;
+        }
+        out.write_bit(true);
+
+        // Leading zeros are stored in 5 bits
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+
+        match self.window {
+            Some((prev_leading, prev_trailing))
+                if leading >= prev_leading && trailing >= prev_trailing =>
+            {
+                out.write_bit(false);
+                let len = 64 - prev_leading - prev_trailing;
+                out.write_bits(xor >> prev_trailing, len);
+            }
+            _ => {
+                out.write_bit(true);
+                let len = 64 - leading - trailing;
+                out.write_bits(leading as u64, 5);
+                out.write_bits((len - 1) as u64, 6);
+                out.write_bits(xor >> trailing, len);
+                self.window = Some((leading, trailing));
+            }
+        }
+    }
+}
+
+/// Decoder for `XorEncoder` output
+#[derive(Default)]
+struct XorDecoder {
+    prev: Option<u64>,
+    window: Option<(u32, u32)>,
+}
+
+impl XorDecoder {
+    fn decode(&mut self, input: &mut BitReader) -> StorageResult<f64> {
+        let prev = match self.prev {
+            Some(prev) => prev,
+            None => {
+                let bits = input.read_bits(64)?;
+                self.prev = Some(bits);
+                return Ok(f64::from_bits(bits));
+            }
+        };
+
+        if !input.read_bit()? {
+            return Ok(f64::from_bits(prev));
+        }
+
+        let (leading, trailing) = if input.read_bit()? {
+            let leading = input.read_bits(5)? as u32;
+            let len = input.read_bits(6)? as u32 + 1;
+            if leading + len > 64 {
+                return Err(corrupt("XOR window out of range"));
+            }
+            let window = (leading, 64 - leading - len);
+            self.window = Some(window);
+            window
+        } else {
+            self.window
+                .ok_or_else(|| corrupt("XOR window reused before being set"))?
+        };
+
+        let len = 64 - leading - trailing;
+        let xor = input.read_bits(len)? << trailing;
+        let bits = prev ^ xor;
+        self.prev = Some(bits);
+        Ok(f64::from_bits(bits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::compression::{compress_block, compression_stats};
+
+    fn assert_round_trip(points: &[DataPoint]) -> Vec<u8> {
+        let encoded = encode_block(points).unwrap();
+        let decoded = decode_block(&encoded).unwrap();
+
+        let mut expected = points.to_vec();
+        expected.sort_by_key(|p| (p.timestamp, p.metric_id));
+        assert_eq!(decoded.len(), expected.len());
+        for (a, b) in expected.iter().zip(decoded.iter()) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.metric_id, b.metric_id);
+            assert_eq!(a.value.to_bits(), b.value.to_bits());
+            assert_eq!(a.tags, b.tags);
+        }
+        encoded
+    }
+
+    #[test]
+    fn test_empty_block() {
+        assert!(encode_block(&[]).unwrap().is_empty());
+        assert!(decode_block(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_round_trip_mixed_metrics_and_tags() {
+        let points: Vec<DataPoint> = (0..500)
+            .map(|i| {
+                let point = DataPoint::with_timestamp(
+                    (i % 3) as u32,
+                    (i as f64 * 0.37).sin() * 100.0,
+                    1_704_067_200_000 + i * 60_000 + (i % 7),
+                );
+                if i % 2 == 0 {
+                    point.tag("source", "watch")
+                } else {
+                    point
+                }
+            })
+            .collect();
+
+        assert_round_trip(&points);
+    }
+
+    #[test]
+    fn test_round_trip_special_values() {
+        let values = [
+            0.0,
+            -0.0,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            1.0,
+            1.0,
+            f64::from_bits(1),
+        ];
+        let points: Vec<DataPoint> = values
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| DataPoint::with_timestamp(1, v, i as i64))
+            .collect();
+        assert_round_trip(&points);
+
+        // NaN payloads survive bit-for-bit
+        let nan = decode_block(&encode_block(&[DataPoint::with_timestamp(1, f64::NAN, 0)]).unwrap())
+            .unwrap();
+        assert!(nan[0].value.is_nan());
+    }
+
+    #[test]
+    fn test_round_trip_extreme_timestamps() {
+        let points = vec![
+            DataPoint::with_timestamp(1, 1.0, i64::MIN),
+            DataPoint::with_timestamp(1, 2.0, 0),
+            DataPoint::with_timestamp(1, 3.0, i64::MAX),
+            DataPoint::with_timestamp(1, 4.0, -5),
+        ];
+        assert_round_trip(&points);
+    }
+
+    #[test]
+    fn test_regular_series_beats_lz4() {
+        // Hourly readings for a week, as in the LZ4 ratio test
+        let points: Vec<DataPoint> = (0..168)
+            .map(|i| {
+                DataPoint::with_timestamp(
+                    1,
+                    ((5.0 + (i as f64 * 0.1).sin() * 2.0) * 10.0).round() / 10.0,
+                    1_704_067_200_000 + i * 3_600_000,
+                )
+                .tag("source", "manual")
+            })
+            .collect();
+
+        let gorilla = assert_round_trip(&points);
+        let lz4 = compress_block(&points).unwrap();
+
+        let gorilla_stats = compression_stats(&points, &gorilla);
+        let lz4_stats = compression_stats(&points, &lz4);
+        assert!(
+            gorilla_stats.ratio > lz4_stats.ratio,
+            "Gorilla {:.1}x should beat LZ4 {:.1}x",
+            gorilla_stats.ratio,
+            lz4_stats.ratio
+        );
+    }
+
+    #[test]
+    fn test_value_mode_selection() {
+        // Short decimals use the decimal mode
+        let decimals = [5.3, 5.4, 5.4, 6.1, -2.25, 1e6];
+        assert_eq!(encode_values(&decimals)[0], VALUES_DECIMAL);
+
+        // Arbitrary floats, -0.0 and NaN fall back to XOR
+        for values in [vec![0.1 + 0.2, 1.0], vec![-0.0, 1.0], vec![f64::NAN, 1.0]] {
+            assert_eq!(encode_values(&values)[0], VALUES_XOR);
+        }
+
+        // Both modes round-trip bit-for-bit
+        for values in [decimals.to_vec(), vec![0.1 + 0.2, -0.0, 1.0 / 3.0]] {
+            let encoded = encode_values(&values);
+            let decoded = decode_values(&mut ByteReader::new(&encoded), values.len()).unwrap();
+            let bits: Vec<u64> = decoded.iter().map(|v| v.to_bits()).collect();
+            let expected: Vec<u64> = values.iter().map(|v| v.to_bits()).collect();
+            assert_eq!(bits, expected);
+        }
+    }
+
+    #[test]
+    fn test_truncated_block_is_an_error() {
+        let points: Vec<DataPoint> = (0..50)
+            .map(|i| DataPoint::with_timestamp(1, i as f64, i * 1000))
+            .collect();
+        let encoded = encode_block(&points).unwrap();
+
+        for len in [1, encoded.len() / 2, encoded.len() - 1] {
+            assert!(decode_block(&encoded[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_varint_and_zigzag() {
+        for v in [0i64, 1, -1, 63, -64, 1 << 40, i64::MIN, i64::MAX] {
+            assert_eq!(unzigzag(zigzag(v)), v);
+
+            let mut buf = Vec::new();
+            write_varint(&mut buf, zigzag(v));
+            assert_eq!(ByteReader::new(&buf).read_varint().unwrap(), zigzag(v));
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! JSON Record Stores
+//!
+//! Definitions kept beside the data, like alert rules and goals, live in a
+//! JSON file of the data directory. The file holds a `StoreState`: a list
+//! of records with IDs assigned on insert, plus whatever else its engine
+//! tracks. Every save rewrites the whole file through a temporary file, so
+//! a crash leaves either the old or the new contents.
+
+use crate::storage::error::StorageResult;
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// A stored record with an ID and a creation time
+pub trait Record: Clone {
+    /// Unique ID
+    fn id(&self) -> u64;
+
+    /// Creation time in milliseconds
+    fn created_at(&self) -> i64;
+
+    /// Set the ID and creation time
+    fn identify(&mut self, id: u64, created_at: i64);
+}
+
+/// Contents of a store file
+pub trait StoreState: Default + Serialize + DeserializeOwned {
+    /// Type of the stored records
+    type Record: Record;
+
+    /// The records, in creation order
+    fn records(&self) -> &[Self::Record];
+
+    /// Mutable access to the records
+    fn records_mut(&mut self) -> &mut Vec<Self::Record>;
+
+    /// Last ID assigned
+    fn last_id(&mut self) -> &mut u64;
+}
+
+/// A `StoreState` kept in a JSON file
+pub struct JsonStore<S> {
+    path: PathBuf,
+    state: Mutex<S>,
+}
+
+impl<S: StoreState> JsonStore<S> {
+    /// Open the file `name` in `dir`, starting empty if it doesn't exist
+    pub fn open(dir: &Path, name: &str) -> StorageResult<Self> {
+        let path = dir.join(name);
+        let state = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            S::default()
+        };
+
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Lock the state, for changes beyond adding and removing records
+    pub async fn lock(&self) -> MutexGuard<'_, S> {
+        self.state.lock().await
+    }
+
+    /// All records, in creation order
+    pub async fn records(&self) -> Vec<S::Record> {
+        self.lock().await.records().to_vec()
+    }
+
+    /// A record by ID
+    pub async fn get(&self, id: u64) -> Option<S::Record> {
+        let state = self.lock().await;
+        state.records().iter().find(|record| record.id() == id).cloned()
+    }
+
+    /// Store a new record under the next ID, created at `now`
+    pub async fn insert(&self, mut record: S::Record, now: i64) -> StorageResult<S::Record> {
+        let mut state = self.lock().await;
+        *state.last_id() += 1;
+        record.identify(*state.last_id(), now);
+        state.records_mut().push(record.clone());
+        self.save(&state)?;
+        Ok(record)
+    }
+
+    /// Replace a record, keeping its ID and creation time
+    ///
+    /// Returns None if there is no record with this ID.
+    pub async fn replace(
+        &self,
+        id: u64,
+        mut record: S::Record,
+    ) -> StorageResult<Option<S::Record>> {
+        let mut state = self.lock().await;
+        let records = state.records_mut();
+        let Some(existing) = records.iter_mut().find(|record| record.id() == id) else {
+            return Ok(None);
+        };
+        record.identify(id, existing.created_at());
+        *existing = record.clone();
+        self.save(&state)?;
+        Ok(Some(record))
+    }
+
+    /// Remove a record; returns false if there is none with this ID
+    pub async fn remove(&self, id: u64) -> StorageResult<bool> {
+        let mut state = self.lock().await;
+        let before = state.records().len();
+        state.records_mut().retain(|record| record.id() != id);
+        if state.records().len() == before {
+            return Ok(false);
+        }
+        self.save(&state)?;
+        Ok(true)
+    }
+
+    /// Write the state to the file
+    pub fn save(&self, state: &S) -> StorageResult<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use tempfile::tempdir;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Note {
+        id: u64,
+        created_at: i64,
+        text: String,
+    }
+
+    impl Record for Note {
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn created_at(&self) -> i64 {
+            self.created_at
+        }
+
+        fn identify(&mut self, id: u64, created_at: i64) {
+            self.id = id;
+            self.created_at = created_at;
+        }
+    }
+
+    #[derive(Default, Serialize, Deserialize)]
+    struct Notes {
+        next_id: u64,
+        notes: Vec<Note>,
+    }
+
+    impl StoreState for Notes {
+        type Record = Note;
+
+        fn records(&self) -> &[Note] {
+            &self.notes
+        }
+
+        fn records_mut(&mut self) -> &mut Vec<Note> {
+            &mut self.notes
+        }
+
+        fn last_id(&mut self) -> &mut u64 {
+            &mut self.next_id
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_store() {
+        let dir = tempdir().unwrap();
+        let store: JsonStore<Notes> = JsonStore::open(dir.path(), "notes.json").unwrap();
+        let note = |text: &str| Note {
+            id: 0,
+            created_at: 0,
+            text: text.to_string(),
+        };
+
+        let first = store.insert(note("first"), 100).await.unwrap();
+        let second = store.insert(note("second"), 200).await.unwrap();
+        assert_eq!((first.id, second.id, second.created_at), (1, 2, 200));
+
+        // Replacing keeps the ID and creation time
+        let replaced = store.replace(1, note("changed")).await.unwrap().unwrap();
+        assert_eq!((replaced.id, replaced.created_at), (1, 100));
+        assert_eq!(store.replace(9, note("missing")).await.unwrap(), None);
+
+        assert!(store.remove(2).await.unwrap());
+        assert!(!store.remove(2).await.unwrap());
+
+        // IDs aren't reused after a restart
+        let reopened: JsonStore<Notes> = JsonStore::open(dir.path(), "notes.json").unwrap();
+        assert_eq!(reopened.records().await, vec![replaced]);
+        assert_eq!(reopened.insert(note("third"), 300).await.unwrap().id, 3);
+    }
+}
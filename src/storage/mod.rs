@@ -4,8 +4,17 @@
 //!
 //! - **types**: Core data structures (DataPoint, Metric, TimeRange)
 //! - **compression**: Delta encoding + LZ4 compression
+//! - **gorilla**: Columnar delta-of-delta/XOR compression
 //! - **wal**: Write-ahead log for durability
 //! - **segment**: Segment file format
+//! - **compaction**: Background merging of small segments
+//! - **retention**: Retention policies and downsampling rollups
+//! - **tombstone**: Point-level deletes hidden until purged
+//! - **duplicates**: Per-metric policies for re-written points
+//! - **snapshot**: Consistent backups and restore
+//! - **fsck**: Offline integrity checks and index rebuilds
+//! - **json_store**: JSON files of records kept beside the data
+//! - **lock**: Exclusive lock on the data directory
 //! - **engine**: Main storage engine orchestrating all components
 //! - **error**: Error types
 //!
@@ -46,17 +55,44 @@
 //! }
 //! ```
 
+pub mod compaction;
 pub mod compression;
+pub mod duplicates;
 pub mod engine;
 pub mod error;
+pub mod fsck;
+pub mod gorilla;
+pub mod json_store;
+pub mod lock;
+pub mod retention;
 pub mod segment;
+pub mod snapshot;
+pub mod tombstone;
 pub mod types;
 pub mod wal;
 
 // Re-export commonly used types
-pub use compression::{compress_block, compression_stats, decompress_block, CompressionStats};
-pub use engine::{MetricRegistry, StorageConfig, StorageEngine, StorageStats};
+pub use compaction::CompactionStats;
+pub use compression::{
+    compress_block, compress_block_with, compression_stats, decompress_block,
+    decompress_block_with, CompressionStats,
+};
+pub use duplicates::DuplicatePolicy;
+pub use engine::{
+    ChangeListener, DataChange, FlushedBlock, MetricRegistry, ScanStats, SegmentScan,
+    StorageConfig, StorageEngine, StorageStats,
+};
+pub use fsck::{Component, FsckIssue, FsckOptions, FsckReport, IssueKind, RebuildStats};
 pub use error::{StorageError, StorageResult};
-pub use segment::{BlockMeta, CompressionType, Segment, SegmentBuilder, SegmentHeader};
-pub use types::{AggregationType, Category, DataPoint, Metric, QueryFilter, TimeRange};
-pub use wal::{WalSyncMode, WriteAheadLog};
+pub use json_store::{JsonStore, Record, StoreState};
+pub use lock::DirLock;
+pub use retention::{CategoryRetention, RetentionPolicy, RetentionStats, RollupPolicy};
+pub use segment::{
+    BlockMeta, CompressionType, Segment, SegmentBuilder, SegmentHeader, FLAG_COMPACTED,
+};
+pub use snapshot::{SnapshotFile, SnapshotManifest};
+pub use tombstone::{Tombstone, TombstoneSet};
+pub use types::{
+    AggregationType, Category, DataPoint, DeleteMode, Metric, QueryFilter, TimeRange,
+};
+pub use wal::{WalEntry, WalScan, WalSyncMode, WriteAheadLog};
@@ -0,0 +1,414 @@
+//! Retention policies and downsampling rollups
+//!
+//! A `RetentionPolicy` limits how long raw points of a metric are kept.
+//! Policies can be attached to a single `Metric` or to a whole `Category`;
+//! a metric's own policy takes precedence.
+//!
+//! When a policy has a `RollupPolicy`, expired raw points are not simply
+//! dropped: they are aggregated into fixed-width buckets using the metric's
+//! `AggregationType` and written to a derived metric (e.g. `heart_rate_1h`
+//! for hourly heart rate). The derived metric carries the rollup's own
+//! retention, so rollups can be kept forever or expire in turn.
+//!
+//! Expired points are removed by rewriting the segments that hold them, using
+//! the same crash-safe swap as compaction. Rollup points are written into the
+//! rewritten segment, so raw data and its replacement change atomically.
+
+use crate::storage::error::{StorageError, StorageResult};
+use crate::storage::types::{AggregationType, Category, DataPoint, Metric};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Milliseconds per day
+const DAY_MS: i64 = 24 * 3600 * 1000;
+
+/// How long raw data is kept, and what replaces it afterwards
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Raw points older than this many days expire
+    pub raw_retention_days: u32,
+    /// Rollup that replaces expired raw points (None = just delete them)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollup: Option<RollupPolicy>,
+}
+
+/// Downsampling applied to expired raw points
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollupPolicy {
+    /// Bucket width in milliseconds
+    pub interval_ms: i64,
+    /// How long rollup points are kept (None = forever)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u32>,
+}
+
+impl RetentionPolicy {
+    /// Keep raw points for `days` days, then delete them
+    pub fn raw_days(days: u32) -> Self {
+        Self {
+            raw_retention_days: days,
+            rollup: None,
+        }
+    }
+
+    /// Builder: roll expired points up into buckets of `interval_ms`
+    pub fn rollup(mut self, rollup: RollupPolicy) -> Self {
+        self.rollup = Some(rollup);
+        self
+    }
+
+    /// Check the retention periods and rollup interval are positive
+    pub fn validate(&self) -> StorageResult<()> {
+        if self.raw_retention_days == 0 {
+            return Err(StorageError::Config(
+                "raw_retention_days must be positive".to_string(),
+            ));
+        }
+        match &self.rollup {
+            Some(rollup) => rollup.validate(),
+            None => Ok(()),
+        }
+    }
+
+    /// Timestamp before which raw points expire
+    ///
+    /// With a rollup the cutoff is aligned down to a bucket boundary, so a
+    /// bucket is only ever rolled up once all of its points have expired.
+    pub fn cutoff(&self, now: i64) -> i64 {
+        let cutoff = now - self.raw_retention_days as i64 * DAY_MS;
+        match &self.rollup {
+            Some(rollup) => bucket_start(cutoff, rollup.interval_ms),
+            None => cutoff,
+        }
+    }
+}
+
+impl RollupPolicy {
+    /// Roll up into buckets of `interval_ms`, kept forever
+    pub fn every(interval_ms: i64) -> Self {
+        assert!(interval_ms > 0, "RollupPolicy: interval must be positive");
+        Self {
+            interval_ms,
+            retention_days: None,
+        }
+    }
+
+    /// Hourly rollups, kept forever
+    pub fn hourly() -> Self {
+        Self::every(3600 * 1000)
+    }
+
+    /// Daily rollups, kept forever
+    pub fn daily() -> Self {
+        Self::every(DAY_MS)
+    }
+
+    /// Builder: expire rollup points after `days` days
+    pub fn keep_days(mut self, days: u32) -> Self {
+        self.retention_days = Some(days);
+        self
+    }
+
+    /// Check the bucket width and rollup retention are positive
+    pub fn validate(&self) -> StorageResult<()> {
+        if self.interval_ms <= 0 {
+            return Err(StorageError::Config(
+                "rollup interval_ms must be positive".to_string(),
+            ));
+        }
+        if self.retention_days == Some(0) {
+            return Err(StorageError::Config(
+                "rollup retention_days must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Short label for the bucket width ("15m", "1h", "1d")
+    pub fn label(&self) -> String {
+        let ms = self.interval_ms;
+        if ms % DAY_MS == 0 {
+            format!("{}d", ms / DAY_MS)
+        } else if ms % (3600 * 1000) == 0 {
+            format!("{}h", ms / (3600 * 1000))
+        } else if ms % (60 * 1000) == 0 {
+            format!("{}m", ms / (60 * 1000))
+        } else if ms % 1000 == 0 {
+            format!("{}s", ms / 1000)
+        } else {
+            format!("{}ms", ms)
+        }
+    }
+
+    /// Definition of the derived metric holding rollups of `source`
+    pub fn derived_metric(&self, source: &Metric) -> Metric {
+        let mut metric = Metric::new(
+            format!("{}_{}", source.name, self.label()),
+            source.unit.clone(),
+            source.category,
+            source.aggregation,
+        )
+        .description(format!("{} rollup of {}", self.label(), source.name));
+        metric.rollup_of = Some(source.id);
+        metric.retention = self.retention_days.map(RetentionPolicy::raw_days);
+        metric
+    }
+}
+
+/// Start of the bucket containing `timestamp`
+fn bucket_start(timestamp: i64, interval_ms: i64) -> i64 {
+    timestamp.div_euclid(interval_ms) * interval_ms
+}
+
+/// Policy for metrics that don't have their own, by category
+///
+/// Stored in `meta/retention.json`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CategoryRetention(pub HashMap<Category, RetentionPolicy>);
+
+impl CategoryRetention {
+    /// Load from JSON file
+    pub fn load(path: &Path) -> StorageResult<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Save to JSON file
+    pub fn save(&self, path: &Path) -> StorageResult<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Policy that applies to a metric
+    ///
+    /// Rollup metrics only follow their own policy; inheriting the source
+    /// category's raw retention would delete the rollups themselves.
+    pub fn effective<'a>(&'a self, metric: &'a Metric) -> Option<&'a RetentionPolicy> {
+        if metric.retention.is_some() || metric.rollup_of.is_some() {
+            return metric.retention.as_ref();
+        }
+        self.0.get(&metric.category)
+    }
+}
+
+/// What retention does to one metric's expired points
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExpiryRule {
+    /// Points strictly before this timestamp expire
+    pub cutoff: i64,
+    /// Where to roll them up, if anywhere
+    pub rollup: Option<RollupTarget>,
+}
+
+/// Derived metric receiving rollups
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RollupTarget {
+    pub metric_id: u32,
+    pub interval_ms: i64,
+    pub aggregation: AggregationType,
+}
+
+/// Outcome of a retention run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RetentionStats {
+    /// Segments rewritten (or dropped when nothing was left in them)
+    pub segments_rewritten: usize,
+    /// Raw points removed
+    pub points_expired: usize,
+    /// Rollup points written in their place
+    pub rollup_points: usize,
+}
+
+/// (source metric, bucket start, sorted tags)
+type BucketKey = (u32, i64, Vec<(String, String)>);
+
+/// Split points into the ones that survive and the rollups replacing the rest
+///
+/// Expired points of a metric with a rollup are grouped by (bucket, tags) and
+/// aggregated; each group becomes one point stamped at the bucket start.
+pub(crate) fn apply_rules(
+    points: Vec<DataPoint>,
+    rules: &HashMap<u32, ExpiryRule>,
+    stats: &mut RetentionStats,
+) -> Vec<DataPoint> {
+    let mut kept = Vec::with_capacity(points.len());
+    let mut buckets: BTreeMap<BucketKey, Vec<(i64, f64)>> = BTreeMap::new();
+
+    for point in points {
+        let rule = match rules.get(&point.metric_id) {
+            Some(rule) if point.timestamp < rule.cutoff => rule,
+            _ => {
+                kept.push(point);
+                continue;
+            }
+        };
+
+        stats.points_expired += 1;
+
+        if let Some(target) = &rule.rollup {
+            let mut tags: Vec<(String, String)> = point.tags.into_iter().collect();
+            tags.sort();
+            let bucket = bucket_start(point.timestamp, target.interval_ms);
+            buckets
+                .entry((point.metric_id, bucket, tags))
+                .or_default()
+                .push((point.timestamp, point.value));
+        }
+    }
+
+    for ((source_id, bucket, tags), mut samples) in buckets {
+        let target = match rules.get(&source_id).and_then(|r| r.rollup.as_ref()) {
+            Some(target) => target,
+            None => continue,
+        };
+
+        // `Last` needs the values in time order
+        samples.sort_by_key(|(ts, _)| *ts);
+        let values: Vec<f64> = samples.into_iter().map(|(_, v)| v).collect();
+
+        if let Some(value) = target.aggregation.aggregate(&values) {
+            stats.rollup_points += 1;
+            kept.push(
+                DataPoint::with_timestamp(target.metric_id, value, bucket)
+                    .tags(tags.into_iter().collect()),
+            );
+        }
+    }
+
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 3600 * 1000;
+
+    #[test]
+    fn test_cutoff_aligns_to_buckets() {
+        let now = 10 * DAY_MS + 90 * 60 * 1000; // day 10, 01:30
+
+        let raw = RetentionPolicy::raw_days(2);
+        assert_eq!(raw.cutoff(now), now - 2 * DAY_MS);
+
+        let hourly = RetentionPolicy::raw_days(2).rollup(RollupPolicy::hourly());
+        assert_eq!(hourly.cutoff(now), 8 * DAY_MS + HOUR);
+    }
+
+    #[test]
+    fn test_rollup_labels() {
+        assert_eq!(RollupPolicy::hourly().label(), "1h");
+        assert_eq!(RollupPolicy::daily().label(), "1d");
+        assert_eq!(RollupPolicy::every(15 * 60 * 1000).label(), "15m");
+        assert_eq!(RollupPolicy::every(6 * HOUR).label(), "6h");
+    }
+
+    #[test]
+    fn test_derived_metric() {
+        let mut source = Metric::new("heart_rate", "bpm", Category::Health, AggregationType::Average);
+        source.id = 3;
+
+        let derived = RollupPolicy::hourly().keep_days(365).derived_metric(&source);
+        assert_eq!(derived.name, "heart_rate_1h");
+        assert_eq!(derived.rollup_of, Some(3));
+        assert_eq!(derived.aggregation, AggregationType::Average);
+        assert_eq!(derived.retention, Some(RetentionPolicy::raw_days(365)));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(RetentionPolicy::raw_days(30).rollup(RollupPolicy::hourly()).validate().is_ok());
+        assert!(RetentionPolicy::raw_days(0).validate().is_err());
+
+        let zero = RollupPolicy {
+            interval_ms: 0,
+            retention_days: None,
+        };
+        assert!(RetentionPolicy::raw_days(30).rollup(zero).validate().is_err());
+        let forgotten = RollupPolicy::daily().keep_days(0);
+        assert!(RetentionPolicy::raw_days(30).rollup(forgotten).validate().is_err());
+    }
+
+    #[test]
+    fn test_effective_policy() {
+        let mut categories = CategoryRetention::default();
+        categories
+            .0
+            .insert(Category::Health, RetentionPolicy::raw_days(90));
+
+        let hr = Metric::new("heart_rate", "bpm", Category::Health, AggregationType::Average);
+        assert_eq!(categories.effective(&hr), Some(&RetentionPolicy::raw_days(90)));
+
+        let own = hr.clone().retention(RetentionPolicy::raw_days(7));
+        assert_eq!(categories.effective(&own), Some(&RetentionPolicy::raw_days(7)));
+
+        // Rollups never inherit the category policy
+        let derived = RollupPolicy::hourly().derived_metric(&hr);
+        assert_eq!(categories.effective(&derived), None);
+
+        let mood = Metric::new("mood", "1-10", Category::Mood, AggregationType::Average);
+        assert_eq!(categories.effective(&mood), None);
+    }
+
+    #[test]
+    fn test_apply_rules_rolls_up_by_bucket_and_tags() {
+        let mut rules = HashMap::new();
+        rules.insert(
+            0,
+            ExpiryRule {
+                cutoff: 2 * HOUR,
+                rollup: Some(RollupTarget {
+                    metric_id: 9,
+                    interval_ms: HOUR,
+                    aggregation: AggregationType::Average,
+                }),
+            },
+        );
+        rules.insert(
+            1,
+            ExpiryRule {
+                cutoff: 2 * HOUR,
+                rollup: None,
+            },
+        );
+
+        let points = vec![
+            DataPoint::with_timestamp(0, 60.0, 10),
+            DataPoint::with_timestamp(0, 80.0, 20),
+            DataPoint::with_timestamp(0, 100.0, 30).tag("source", "watch"),
+            DataPoint::with_timestamp(0, 70.0, HOUR + 5),
+            DataPoint::with_timestamp(0, 75.0, 2 * HOUR), // not expired
+            DataPoint::with_timestamp(1, 1.0, 10),        // expired, no rollup
+            DataPoint::with_timestamp(2, 5.0, 10),        // no policy
+        ];
+
+        let mut stats = RetentionStats::default();
+        let mut kept = apply_rules(points, &rules, &mut stats);
+        kept.sort_by_key(|p| (p.metric_id, p.timestamp, p.tags.len()));
+
+        assert_eq!(stats.points_expired, 5);
+        assert_eq!(stats.rollup_points, 3);
+
+        let rollups: Vec<_> = kept.iter().filter(|p| p.metric_id == 9).collect();
+        assert_eq!(rollups.len(), 3);
+        assert_eq!((rollups[0].timestamp, rollups[0].value), (0, 70.0));
+        assert!(rollups[0].tags.is_empty());
+        assert_eq!((rollups[1].timestamp, rollups[1].value), (0, 100.0));
+        assert!(rollups[1].has_tag("source", "watch"));
+        assert_eq!((rollups[2].timestamp, rollups[2].value), (HOUR, 70.0));
+
+        assert!(kept.iter().any(|p| p.metric_id == 0 && p.timestamp == 2 * HOUR));
+        assert!(kept.iter().any(|p| p.metric_id == 2));
+        assert!(!kept.iter().any(|p| p.metric_id == 1));
+    }
+}
@@ -11,9 +11,11 @@
 //! │   block_count: u32                      │
 //! │   min_timestamp: i64                    │
 //! │   max_timestamp: i64                    │
-//! │   compression: u8                       │
+//! │   compression: u8 (0 none, 1 lz4, 2 gor)│
+//! │   flags: u8                             │
+//! │   sequence: u32                         │
+//! │   reserved: [u8; 28]                    │
 //! │   checksum: u32                         │
-//! │   reserved: [u8; 31]                    │
 //! ├─────────────────────────────────────────┤
 //! │ BLOCKS (variable)                       │
 //! │   For each block:                       │
@@ -27,13 +29,19 @@
 //! │   segment_checksum: u32                 │
 //! └─────────────────────────────────────────┘
 //! ```
+//!
+//! Blocks are read through a read-only memory map, so `read_block` takes
+//! `&self` and any number of readers can share a segment. The map is
+//! created on the first read and dropped whenever a block is appended.
 
-use crate::storage::compression::{compress_block, decompress_block};
+use crate::storage::compression::{compress_block_with, decompress_block_with};
 use crate::storage::error::{StorageError, StorageResult};
 use crate::storage::types::{DataPoint, TimeRange};
+use memmap2::Mmap;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 /// Magic bytes for segment file identification
 const SEGMENT_MAGIC: [u8; 4] = *b"CHRN";
@@ -44,12 +52,48 @@ const SEGMENT_VERSION: u16 = 1;
 /// Header size in bytes
 const HEADER_SIZE: usize = 64;
 
+/// Header flag: segment was written by compaction
+pub const FLAG_COMPACTED: u8 = 0x01;
+
+/// Header flag: the header carries an explicit write sequence
+const FLAG_SEQUENCED: u8 = 0x02;
+
 /// Compression type enum
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum CompressionType {
     None = 0,
+    /// Delta encoding + LZ4 (see `compression`)
     Lz4 = 1,
+    /// Columnar delta-of-delta/XOR encoding (see `gorilla`)
+    Gorilla = 2,
+}
+
+impl CompressionType {
+    /// Name used in configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompressionType::None => "none",
+            CompressionType::Lz4 => "lz4",
+            CompressionType::Gorilla => "gorilla",
+        }
+    }
+}
+
+impl std::str::FromStr for CompressionType {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(CompressionType::None),
+            "lz4" => Ok(CompressionType::Lz4),
+            "gorilla" => Ok(CompressionType::Gorilla),
+            _ => Err(StorageError::Config(format!(
+                "Unknown compression type: {}. Use none, lz4 or gorilla",
+                s
+            ))),
+        }
+    }
 }
 
 impl TryFrom<u8> for CompressionType {
@@ -59,6 +103,7 @@ impl TryFrom<u8> for CompressionType {
         match value {
             0 => Ok(CompressionType::None),
             1 => Ok(CompressionType::Lz4),
+            2 => Ok(CompressionType::Gorilla),
             _ => Err(StorageError::InvalidSegment(format!(
                 "Unknown compression type: {}",
                 value
@@ -82,6 +127,10 @@ pub struct SegmentHeader {
     pub max_timestamp: i64,
     /// Compression type used
     pub compression: CompressionType,
+    /// Segment flags (see `FLAG_COMPACTED`)
+    pub flags: u8,
+    /// Position of the segment's data in write order (None = its id)
+    pub sequence: Option<u32>,
     /// Header checksum
     pub checksum: u32,
 }
@@ -96,10 +145,17 @@ impl SegmentHeader {
             min_timestamp: i64::MAX,
             max_timestamp: i64::MIN,
             compression,
+            flags: 0,
+            sequence: None,
             checksum: 0,
         }
     }
 
+    /// Check whether the segment was produced by compaction
+    pub fn is_compacted(&self) -> bool {
+        self.flags & FLAG_COMPACTED != 0
+    }
+
     /// Serialize header to bytes
     pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
         let mut buf = [0u8; HEADER_SIZE];
@@ -110,7 +166,12 @@ impl SegmentHeader {
         buf[10..18].copy_from_slice(&self.min_timestamp.to_le_bytes());
         buf[18..26].copy_from_slice(&self.max_timestamp.to_le_bytes());
         buf[26] = self.compression as u8;
-        // bytes 27-59 reserved
+        buf[27] = self.flags & !FLAG_SEQUENCED;
+        if let Some(sequence) = self.sequence {
+            buf[27] |= FLAG_SEQUENCED;
+            buf[28..32].copy_from_slice(&sequence.to_le_bytes());
+        }
+        // bytes 32-59 reserved
 
         // Calculate checksum of header (excluding checksum field)
         let checksum = crc32fast::hash(&buf[0..60]);
@@ -159,6 +220,12 @@ impl SegmentHeader {
             buf[18], buf[19], buf[20], buf[21], buf[22], buf[23], buf[24], buf[25],
         ]);
         let compression = CompressionType::try_from(buf[26])?;
+        let flags = buf[27] & !FLAG_SEQUENCED;
+        let sequence = if buf[27] & FLAG_SEQUENCED != 0 {
+            Some(u32::from_le_bytes([buf[28], buf[29], buf[30], buf[31]]))
+        } else {
+            None
+        };
 
         Ok(Self {
             magic,
@@ -167,6 +234,8 @@ impl SegmentHeader {
             min_timestamp,
             max_timestamp,
             compression,
+            flags,
+            sequence,
             checksum: stored_checksum,
         })
     }
@@ -210,8 +279,8 @@ pub struct Segment {
     pub header: SegmentHeader,
     /// Block metadata (for seeking)
     pub blocks: Vec<BlockMeta>,
-    /// File handle for reading
-    reader: Option<BufReader<File>>,
+    /// Read-only map of the file, created on first read
+    map: OnceLock<Mmap>,
 }
 
 impl Segment {
@@ -234,7 +303,7 @@ impl Segment {
             path,
             header,
             blocks: Vec::new(),
-            reader: None,
+            map: OnceLock::new(),
         })
     }
 
@@ -255,7 +324,7 @@ impl Segment {
             path,
             header,
             blocks,
-            reader: Some(file),
+            map: OnceLock::new(),
         })
     }
 
@@ -365,7 +434,7 @@ impl Segment {
         }
 
         // Compress the block
-        let compressed = compress_block(points)?;
+        let compressed = compress_block_with(points, self.header.compression)?;
 
         // Calculate timestamps
         let (min_ts, max_ts) = points.iter().fold((i64::MAX, i64::MIN), |(min, max), p| {
@@ -417,6 +486,9 @@ impl Segment {
 
         writer.flush()?;
 
+        // The file grew; map it again on the next read
+        self.map = OnceLock::new();
+
         Ok(())
     }
 
@@ -443,36 +515,53 @@ impl Segment {
         Ok(())
     }
 
+    /// Map the segment file, or return the existing map
+    pub(crate) fn mapped(&self) -> StorageResult<&Mmap> {
+        if let Some(map) = self.map.get() {
+            return Ok(map);
+        }
+
+        let file = File::open(&self.path)?;
+        // SAFETY: segment files are only changed by `append_block`, which
+        // never rewrites bytes of existing blocks and drops this map, and
+        // rewrites go to a new file that is renamed into place. A file
+        // truncated by another process could still fault on access.
+        let map = unsafe { Mmap::map(&file)? };
+
+        // Another reader may have raced us; either map is equivalent
+        let _ = self.map.set(map);
+        Ok(self.map.get().expect("segment map was just set"))
+    }
+
     /// Read and decompress a specific block
-    pub fn read_block(&mut self, block_idx: usize) -> StorageResult<Vec<DataPoint>> {
+    pub fn read_block(&self, block_idx: usize) -> StorageResult<Vec<DataPoint>> {
         let block_meta = self.blocks.get(block_idx).ok_or_else(|| {
             StorageError::InvalidSegment(format!("Block index out of range: {}", block_idx))
         })?;
 
-        // Ensure reader is available
-        if self.reader.is_none() {
-            self.reader = Some(BufReader::new(File::open(&self.path)?));
-        }
-
-        let reader = self.reader.as_mut().unwrap();
-
-        // Seek to block
-        reader.seek(SeekFrom::Start(block_meta.offset))?;
+        let map = self.mapped()?;
+        let truncated = || {
+            StorageError::Corruption(format!("Block {} extends past end of segment", block_idx))
+        };
 
-        // Read block size
-        let mut size_buf = [0u8; 4];
-        reader.read_exact(&mut size_buf)?;
-        let size = u32::from_le_bytes(size_buf);
+        // Block: size (4) + data (N) + checksum (4)
+        let start = block_meta.offset as usize;
+        let size_buf = map.get(start..start + 4).ok_or_else(truncated)?;
+        let size = u32::from_le_bytes([size_buf[0], size_buf[1], size_buf[2], size_buf[3]]);
 
-        // Read compressed data
-        let mut data = vec![0u8; size as usize];
-        reader.read_exact(&mut data)?;
+        let data_start = start + 4;
+        let data_end = data_start + size as usize;
+        let data = map.get(data_start..data_end).ok_or_else(truncated)?;
 
-        // Read and verify checksum
-        let mut checksum_buf = [0u8; 4];
-        reader.read_exact(&mut checksum_buf)?;
-        let stored_checksum = u32::from_le_bytes(checksum_buf);
-        let computed_checksum = crc32fast::hash(&data);
+        // Verify checksum
+        let checksum_buf = map.get(data_end..data_end + 4).ok_or_else(truncated)?;
+        let stored_checksum = u32::from_le_bytes([
+            checksum_buf[0],
+            checksum_buf[1],
+            checksum_buf[2],
+            checksum_buf[3],
+        ]);
+        let computed_checksum = crc32fast::hash(data);
 
         if stored_checksum != computed_checksum {
             return Err(StorageError::Corruption(format!(
@@ -482,11 +571,20 @@ impl Segment {
         }
 
         // Decompress
-        decompress_block(&data)
+        decompress_block_with(data, self.header.compression)
+    }
+
+    /// Read and decompress every block in the segment
+    pub fn read_all(&self) -> StorageResult<Vec<DataPoint>> {
+        let mut points = Vec::with_capacity(self.point_count() as usize);
+        for idx in 0..self.blocks.len() {
+            points.extend(self.read_block(idx)?);
+        }
+        Ok(points)
     }
 
     /// Read all blocks that overlap with a time range
-    pub fn read_range(&mut self, range: &TimeRange) -> StorageResult<Vec<DataPoint>> {
+    pub fn read_range(&self, range: &TimeRange) -> StorageResult<Vec<DataPoint>> {
         let mut results = Vec::new();
 
         for idx in 0..self.blocks.len() {
@@ -518,6 +616,14 @@ impl Segment {
             .and_then(|s| s.strip_prefix("segment_"))
             .and_then(|s| s.parse().ok())
     }
+
+    /// Position of the segment's data in write order
+    ///
+    /// Segments written by flushes are ordered by id. A rewrite output gets
+    /// a new id but keeps the position of the inputs it replaced.
+    pub fn sequence(&self) -> Option<u32> {
+        self.header.sequence.or_else(|| self.id())
+    }
 }
 
 /// Builder for creating segments with multiple blocks
@@ -525,7 +631,10 @@ pub struct SegmentBuilder {
     path: PathBuf,
     compression: CompressionType,
     target_block_size: usize,
+    flags: u8,
+    sequence: Option<u32>,
     buffer: Vec<DataPoint>,
+    buffer_size: usize,
     segment: Option<Segment>,
 }
 
@@ -535,7 +644,10 @@ impl SegmentBuilder {
             path: path.as_ref().to_path_buf(),
             compression,
             target_block_size: 64 * 1024, // 64KB target
+            flags: 0,
+            sequence: None,
             buffer: Vec::new(),
+            buffer_size: 0,
             segment: None,
         }
     }
@@ -545,15 +657,27 @@ impl SegmentBuilder {
         self
     }
 
-    /// Add points, flushing to blocks as needed
-    pub fn add_points(&mut self, points: Vec<DataPoint>) -> StorageResult<()> {
-        self.buffer.extend(points);
+    /// Set header flags on the built segment
+    pub fn flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set the write sequence of the built segment
+    pub fn sequence(mut self, sequence: u32) -> Self {
+        self.sequence = Some(sequence);
+        self
+    }
 
-        // Estimate buffer size
-        let estimated_size: usize = self.buffer.iter().map(|p| p.estimated_size()).sum();
+    /// Add points, cutting a block each time the target size is reached
+    pub fn add_points(&mut self, points: Vec<DataPoint>) -> StorageResult<()> {
+        for point in points {
+            self.buffer_size += point.estimated_size();
+            self.buffer.push(point);
 
-        if estimated_size >= self.target_block_size {
-            self.flush_buffer()?;
+            if self.buffer_size >= self.target_block_size {
+                self.flush_buffer()?;
+            }
         }
 
         Ok(())
@@ -567,11 +691,15 @@ impl SegmentBuilder {
 
         // Create segment if needed
         if self.segment.is_none() {
-            self.segment = Some(Segment::create(&self.path, self.compression)?);
+            let mut segment = Segment::create(&self.path, self.compression)?;
+            segment.header.flags = self.flags;
+            segment.header.sequence = self.sequence;
+            self.segment = Some(segment);
         }
 
         let segment = self.segment.as_mut().unwrap();
         let points = std::mem::take(&mut self.buffer);
+        self.buffer_size = 0;
         segment.append_block(&points)?;
 
         Ok(())
@@ -626,7 +754,7 @@ mod tests {
 
         // Reopen and verify
         {
-            let mut segment = Segment::open(&path).unwrap();
+            let segment = Segment::open(&path).unwrap();
             assert_eq!(segment.header.block_count, 1);
             assert_eq!(segment.blocks.len(), 1);
 
@@ -665,7 +793,7 @@ mod tests {
 
         // Reopen and read all
         {
-            let mut segment = Segment::open(&path).unwrap();
+            let segment = Segment::open(&path).unwrap();
             assert_eq!(segment.blocks.len(), 5);
 
             let mut all_points = Vec::new();
@@ -694,7 +822,7 @@ mod tests {
         }
 
         {
-            let mut segment = Segment::open(&path).unwrap();
+            let segment = Segment::open(&path).unwrap();
 
             // Query middle range
             let range = TimeRange::new(2000, 5000);
@@ -730,6 +858,132 @@ mod tests {
         assert_eq!(segment.point_count(), 1000);
     }
 
+    #[test]
+    fn test_segment_builder_splits_large_batches() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("segment_001.dat");
+
+        let mut builder = SegmentBuilder::new(&path, CompressionType::Lz4)
+            .target_block_size(1024)
+            .flags(FLAG_COMPACTED);
+
+        // A single batch far larger than the target block size
+        let points: Vec<DataPoint> = (0..1000)
+            .map(|i| DataPoint::with_timestamp(1, i as f64, i * 1000))
+            .collect();
+        builder.add_points(points).unwrap();
+
+        let segment = builder.finish().unwrap().unwrap();
+        assert!(segment.header.block_count > 1);
+        assert_eq!(segment.point_count(), 1000);
+
+        // Flags survive a reopen
+        let reopened = Segment::open(&path).unwrap();
+        assert!(reopened.header.is_compacted());
+        assert_eq!(reopened.point_count(), 1000);
+    }
+
+    #[test]
+    fn test_gorilla_segment_alongside_lz4() {
+        let dir = tempdir().unwrap();
+        let points: Vec<DataPoint> = (0..200)
+            .map(|i| DataPoint::with_timestamp((i % 2) as u32, (i / 10) as f64, i * 1000))
+            .collect();
+
+        for compression in [CompressionType::Lz4, CompressionType::Gorilla] {
+            let path = dir.path().join(format!("segment_{}.dat", compression.as_str()));
+            {
+                let mut segment = Segment::create(&path, compression).unwrap();
+                segment.append_block(&points[..100]).unwrap();
+                segment.append_block(&points[100..]).unwrap();
+            }
+
+            // The codec is read back from the header
+            let segment = Segment::open(&path).unwrap();
+            assert_eq!(segment.header.compression, compression);
+            assert_eq!(segment.read_all().unwrap(), points);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_reads_share_segment() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("segment_001.dat");
+
+        {
+            let mut segment = Segment::create(&path, CompressionType::Lz4).unwrap();
+            for block in 0..4 {
+                let points: Vec<DataPoint> = (0..100)
+                    .map(|i| DataPoint::with_timestamp(1, i as f64, block * 1000 + i))
+                    .collect();
+                segment.append_block(&points).unwrap();
+            }
+        }
+
+        let segment = std::sync::Arc::new(Segment::open(&path).unwrap());
+        let handles: Vec<_> = (0..4)
+            .map(|idx| {
+                let segment = std::sync::Arc::clone(&segment);
+                std::thread::spawn(move || segment.read_block(idx).unwrap())
+            })
+            .collect();
+
+        for (idx, handle) in handles.into_iter().enumerate() {
+            let points = handle.join().unwrap();
+            assert_eq!(points.len(), 100);
+            assert_eq!(points[0].timestamp, idx as i64 * 1000);
+        }
+    }
+
+    #[test]
+    fn test_mapped_segment_outlives_its_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("segment_001.dat");
+
+        let mut segment = Segment::create(&path, CompressionType::Lz4).unwrap();
+        segment
+            .append_block(&[DataPoint::with_timestamp(1, 1.0, 1000)])
+            .unwrap();
+
+        // A rewrite deletes its inputs while queries may still read them
+        let segment = Segment::open(&path).unwrap();
+        segment.mapped().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(segment.read_block(0).unwrap()[0].value, 1.0);
+    }
+
+    #[test]
+    fn test_read_after_append_sees_new_block() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("segment_001.dat");
+
+        let mut segment = Segment::create(&path, CompressionType::Lz4).unwrap();
+        segment
+            .append_block(&[DataPoint::with_timestamp(1, 1.0, 1000)])
+            .unwrap();
+        assert_eq!(segment.read_all().unwrap().len(), 1);
+
+        // The first read mapped the file; the append must not leave it stale
+        segment
+            .append_block(&[DataPoint::with_timestamp(1, 2.0, 2000)])
+            .unwrap();
+        assert_eq!(segment.read_block(1).unwrap()[0].value, 2.0);
+        assert_eq!(segment.read_all().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_compression_type_names() {
+        for compression in [
+            CompressionType::None,
+            CompressionType::Lz4,
+            CompressionType::Gorilla,
+        ] {
+            assert_eq!(compression.as_str().parse::<CompressionType>().unwrap(), compression);
+            assert_eq!(CompressionType::try_from(compression as u8).unwrap(), compression);
+        }
+        assert!("zstd".parse::<CompressionType>().is_err());
+    }
+
     #[test]
     fn test_segment_overlaps() {
         let dir = tempdir().unwrap();
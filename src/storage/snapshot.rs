@@ -0,0 +1,333 @@
+//! Consistent snapshots of a data directory
+//!
+//! `StorageEngine::snapshot` captures a running engine without stopping it:
+//!
+//! ```text
+//! 1. Flush the write buffer and seal the active segment
+//! 2. Hard-link (or copy) every segment; sealed segments never change
+//! 3. Checkpoint the indexes and copy them while no flush can run
+//! 4. Save metrics, retention policies and tombstones from memory
+//! 5. Write snapshot.json listing every file with its size and CRC32
+//! ```
+//!
+//! The manifest is written last, so a snapshot interrupted half way has
+//! none and is refused by `restore`. Points written after the flush are not
+//! part of the snapshot; the WAL is never copied.
+//!
+//! `restore` verifies every file against the manifest, copies them into a
+//! staging directory next to the target and only then moves it into place.
+//! It holds the target's lock, so it fails while an engine has it open.
+
+use crate::storage::compaction::sync_dir;
+use crate::storage::error::{StorageError, StorageResult};
+use crate::storage::lock::{DirLock, LOCK_FILE};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the manifest inside a snapshot directory
+pub const MANIFEST_FILE: &str = "snapshot.json";
+
+/// Current snapshot format version
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Description of a complete snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    /// Format version
+    pub version: u32,
+    /// When the snapshot was taken (ms since epoch)
+    pub created_at: i64,
+    /// Segment ids included
+    pub segments: Vec<u32>,
+    /// Every file in the snapshot, relative to its root
+    pub files: Vec<SnapshotFile>,
+}
+
+/// A file recorded in a snapshot manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// Path relative to the snapshot root, `/`-separated
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// CRC32 of the contents
+    pub crc32: u32,
+}
+
+impl SnapshotManifest {
+    /// Start a manifest for a snapshot taken now
+    pub(crate) fn new(segments: Vec<u32>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            created_at: Utc::now().timestamp_millis(),
+            segments,
+            files: Vec::new(),
+        }
+    }
+
+    /// Load the manifest of a snapshot directory
+    pub fn load(snapshot_dir: &Path) -> StorageResult<Self> {
+        let path = snapshot_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Err(StorageError::Corruption(format!(
+                "{:?} is not a complete snapshot (no {})",
+                snapshot_dir, MANIFEST_FILE
+            )));
+        }
+
+        let manifest: Self = serde_json::from_slice(&std::fs::read(&path)?)?;
+        if manifest.version > SNAPSHOT_VERSION {
+            return Err(StorageError::Config(format!(
+                "Unsupported snapshot version: {}",
+                manifest.version
+            )));
+        }
+        Ok(manifest)
+    }
+
+    /// Durably write the manifest, marking the snapshot complete
+    pub(crate) fn save(&self, snapshot_dir: &Path) -> StorageResult<()> {
+        let path = snapshot_dir.join(MANIFEST_FILE);
+        let tmp = path.with_extension("json.tmp");
+
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        sync_dir(snapshot_dir);
+        Ok(())
+    }
+
+    /// Record a file already present in the snapshot, syncing it to disk
+    pub(crate) fn add_file(&mut self, snapshot_dir: &Path, relative: &str) -> StorageResult<()> {
+        let path = snapshot_dir.join(relative);
+        File::open(&path)?.sync_all()?;
+        let (size, crc32) = checksum_file(&path)?;
+        self.files.push(SnapshotFile {
+            path: relative.to_string(),
+            size,
+            crc32,
+        });
+        Ok(())
+    }
+
+    /// Total size of all files in bytes
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    /// Check every file of the snapshot against the manifest
+    pub fn verify(&self, snapshot_dir: &Path) -> StorageResult<()> {
+        for file in &self.files {
+            // Restores write these paths, so they must stay inside the root
+            let relative = Path::new(&file.path);
+            if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(StorageError::Corruption(format!(
+                    "Snapshot file path {} is not relative",
+                    file.path
+                )));
+            }
+
+            let path = snapshot_dir.join(relative);
+            if !path.exists() {
+                return Err(StorageError::Corruption(format!(
+                    "Snapshot file {} is missing",
+                    file.path
+                )));
+            }
+
+            let (size, crc32) = checksum_file(&path)?;
+            if size != file.size || crc32 != file.crc32 {
+                return Err(StorageError::Corruption(format!(
+                    "Snapshot file {} does not match the manifest",
+                    file.path
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restore a snapshot into `data_dir`
+///
+/// `data_dir` must not exist or be empty, and is locked for the restore.
+/// Nothing is written to it unless the whole snapshot verifies.
+pub fn restore(snapshot_dir: &Path, data_dir: &Path) -> StorageResult<SnapshotManifest> {
+    let created = !data_dir.exists();
+    std::fs::create_dir_all(data_dir)?;
+
+    let restored = DirLock::acquire(data_dir)
+        .and_then(|lock| restore_locked(snapshot_dir, data_dir, &lock));
+    if restored.is_err() && created {
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+    restored
+}
+
+/// `restore` into a data directory whose lock the caller holds
+pub fn restore_locked(
+    snapshot_dir: &Path,
+    data_dir: &Path,
+    _lock: &DirLock,
+) -> StorageResult<SnapshotManifest> {
+    let manifest = SnapshotManifest::load(snapshot_dir)?;
+    manifest.verify(snapshot_dir)?;
+
+    if has_data(data_dir)? {
+        return Err(StorageError::Config(format!(
+            "Cannot restore into {:?}: directory is not empty",
+            data_dir
+        )));
+    }
+
+    let staging = staging_dir(data_dir);
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+
+    let copied = (|| {
+        for file in &manifest.files {
+            let dst = staging.join(&file.path);
+            if let Some(parent) = dst.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            copy_synced(&snapshot_dir.join(&file.path), &dst)?;
+        }
+        std::fs::create_dir_all(staging.join("wal"))?;
+        manifest.save(&staging)
+    })();
+    if let Err(e) = copied {
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    // The data directory keeps its lock file, so the staged entries are
+    // moved into it rather than the staging directory replacing it
+    for entry in std::fs::read_dir(&staging)? {
+        let entry = entry?;
+        std::fs::rename(entry.path(), data_dir.join(entry.file_name()))?;
+    }
+    std::fs::remove_dir(&staging)?;
+    sync_dir(data_dir);
+
+    Ok(manifest)
+}
+
+/// Whether `data_dir` holds anything besides its lock file
+pub fn has_data(data_dir: &Path) -> StorageResult<bool> {
+    if !data_dir.exists() {
+        return Ok(false);
+    }
+    for entry in std::fs::read_dir(data_dir)? {
+        if entry?.file_name() != LOCK_FILE {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Directory a restore is staged in before being moved into place
+fn staging_dir(data_dir: &Path) -> PathBuf {
+    let mut name = data_dir
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".restoring");
+    data_dir.with_file_name(name)
+}
+
+/// Hard-link `src` to `dst`, copying if linking is not possible
+///
+/// Only valid for files that are never modified in place.
+pub(crate) fn link_or_copy(src: &Path, dst: &Path) -> StorageResult<()> {
+    if std::fs::hard_link(src, dst).is_err() {
+        copy_synced(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Copy a file and fsync the copy
+pub(crate) fn copy_synced(src: &Path, dst: &Path) -> StorageResult<()> {
+    std::fs::copy(src, dst)?;
+    File::open(dst)?.sync_all()?;
+    Ok(())
+}
+
+/// Size and CRC32 of a file
+fn checksum_file(path: &Path) -> StorageResult<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+
+    Ok((size, hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write_snapshot(dir: &Path) -> SnapshotManifest {
+        std::fs::create_dir_all(dir.join("meta")).unwrap();
+        std::fs::write(dir.join("meta/metrics.json"), b"{}").unwrap();
+
+        let mut manifest = SnapshotManifest::new(Vec::new());
+        manifest.add_file(dir, "meta/metrics.json").unwrap();
+        manifest.save(dir).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn test_restore_requires_manifest() {
+        let dir = tempdir().unwrap();
+        let snapshot = dir.path().join("snap");
+        std::fs::create_dir_all(&snapshot).unwrap();
+
+        let err = restore(&snapshot, &dir.path().join("data")).unwrap_err();
+        assert!(matches!(err, StorageError::Corruption(_)));
+        assert!(!dir.path().join("data").exists());
+    }
+
+    #[test]
+    fn test_restore_rejects_modified_file() {
+        let dir = tempdir().unwrap();
+        let snapshot = dir.path().join("snap");
+        write_snapshot(&snapshot);
+        std::fs::write(snapshot.join("meta/metrics.json"), b"[]").unwrap();
+
+        let err = restore(&snapshot, &dir.path().join("data")).unwrap_err();
+        assert!(matches!(err, StorageError::Corruption(_)));
+        assert!(!dir.path().join("data").exists());
+        assert!(!dir.path().join("data.restoring").exists());
+    }
+
+    #[test]
+    fn test_restore_refuses_non_empty_target() {
+        let dir = tempdir().unwrap();
+        let snapshot = dir.path().join("snap");
+        let manifest = write_snapshot(&snapshot);
+
+        let data = dir.path().join("data");
+        std::fs::create_dir_all(&data).unwrap();
+        std::fs::write(data.join("keep.txt"), b"mine").unwrap();
+        assert!(restore(&snapshot, &data).is_err());
+        assert_eq!(std::fs::read(data.join("keep.txt")).unwrap(), b"mine");
+
+        std::fs::remove_file(data.join("keep.txt")).unwrap();
+        assert_eq!(restore(&snapshot, &data).unwrap(), manifest);
+        assert_eq!(std::fs::read(data.join("meta/metrics.json")).unwrap(), b"{}");
+        assert!(data.join("wal").is_dir());
+    }
+}
@@ -0,0 +1,276 @@
+//! Tombstones for point-level deletes
+//!
+//! Deleting points never touches segment files directly. Instead a
+//! `Tombstone` describing the deleted points (metric, time range and tag
+//! predicate) is appended to the WAL and kept in memory, and queries hide
+//! every point it matches. Rewrites (compaction, retention or an explicit
+//! purge) then drop the matching points for good, after which the tombstone
+//! is discarded.
+//!
+//! A tombstone only applies to segments that were sealed when it was
+//! created (ids below `before_segment`). Points written afterwards, such as
+//! a corrected re-import of the same range, always land in newer segments
+//! and stay visible.
+//!
+//! The WAL is truncated after every flush, so the live set is checkpointed
+//! to `meta/tombstones.json` before each truncation.
+
+use crate::storage::error::StorageResult;
+use crate::storage::types::{DataPoint, TimeRange};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A deletion of every point matching a predicate
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Unique identifier
+    pub id: u64,
+    /// Metric whose points are deleted (None = all metrics)
+    pub metric_id: Option<u32>,
+    /// Start of the deleted range (inclusive), in milliseconds
+    pub start: i64,
+    /// End of the deleted range (exclusive), in milliseconds
+    pub end: i64,
+    /// Tags a point must all carry to be deleted
+    pub tags: HashMap<String, String>,
+    /// Only segments with a lower id are affected
+    pub before_segment: u32,
+    /// When the delete was issued (ms since epoch)
+    pub created_at: i64,
+}
+
+impl Tombstone {
+    /// Time range covered by this tombstone
+    pub fn range(&self) -> TimeRange {
+        TimeRange::new(self.start, self.end)
+    }
+
+    /// Check if the tombstone applies to points stored in a segment
+    pub fn applies_to_segment(&self, segment_id: u32) -> bool {
+        segment_id < self.before_segment
+    }
+
+    /// Check if a point matches the tombstone's predicate
+    pub fn matches(&self, point: &DataPoint) -> bool {
+        if let Some(metric_id) = self.metric_id {
+            if point.metric_id != metric_id {
+                return false;
+            }
+        }
+
+        if point.timestamp < self.start || point.timestamp >= self.end {
+            return false;
+        }
+
+        self.tags
+            .iter()
+            .all(|(k, v)| point.tags.get(k).map(|pv| pv == v).unwrap_or(false))
+    }
+}
+
+/// The live tombstones of a storage engine
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TombstoneSet {
+    /// Id assigned to the next tombstone
+    next_id: u64,
+    /// Tombstones not yet purged
+    tombstones: Vec<Tombstone>,
+    /// Changed since the last checkpoint
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl TombstoneSet {
+    /// Load from JSON file
+    pub fn load(path: &Path) -> StorageResult<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Save to JSON file
+    pub fn save(&self, path: &Path) -> StorageResult<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Save to JSON file if anything changed since the last checkpoint
+    pub fn checkpoint(&mut self, path: &Path) -> StorageResult<()> {
+        if self.dirty {
+            self.save(path)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Create a tombstone hiding matching points in segments below `before_segment`
+    pub fn add(
+        &mut self,
+        metric_id: Option<u32>,
+        range: TimeRange,
+        tags: HashMap<String, String>,
+        before_segment: u32,
+        created_at: i64,
+    ) -> Tombstone {
+        let tombstone = Tombstone {
+            id: self.next_id,
+            metric_id,
+            start: range.start,
+            end: range.end,
+            tags,
+            before_segment,
+            created_at,
+        };
+        self.next_id += 1;
+        self.tombstones.push(tombstone.clone());
+        self.dirty = true;
+        tombstone
+    }
+
+    /// Re-add a tombstone read back from the WAL
+    ///
+    /// Tombstones with an id below the checkpointed `next_id` were already
+    /// in the set when it was saved (and may have been purged since), so
+    /// they are skipped. Returns true if the tombstone was added.
+    pub fn replay(&mut self, tombstone: Tombstone) -> bool {
+        if tombstone.id < self.next_id {
+            return false;
+        }
+        self.next_id = tombstone.id + 1;
+        self.tombstones.push(tombstone);
+        self.dirty = true;
+        true
+    }
+
+    /// Drop the tombstones for which `keep` returns false
+    ///
+    /// Returns the number removed.
+    pub fn retain(&mut self, keep: impl FnMut(&Tombstone) -> bool) -> usize {
+        let before = self.tombstones.len();
+        self.tombstones.retain(keep);
+        let removed = before - self.tombstones.len();
+        if removed > 0 {
+            self.dirty = true;
+        }
+        removed
+    }
+
+    /// All live tombstones
+    pub fn all(&self) -> &[Tombstone] {
+        &self.tombstones
+    }
+
+    /// Number of live tombstones
+    pub fn len(&self) -> usize {
+        self.tombstones.len()
+    }
+
+    /// Check if there are no live tombstones
+    pub fn is_empty(&self) -> bool {
+        self.tombstones.is_empty()
+    }
+
+    /// Tombstones that apply to a segment
+    pub fn for_segment(&self, segment_id: u32) -> Vec<&Tombstone> {
+        self.tombstones
+            .iter()
+            .filter(|t| t.applies_to_segment(segment_id))
+            .collect()
+    }
+
+    /// Remove points of a segment that are hidden by tombstones
+    ///
+    /// Returns the number of points removed.
+    pub fn apply(&self, segment_id: u32, points: &mut Vec<DataPoint>) -> usize {
+        let tombstones = self.for_segment(segment_id);
+        if tombstones.is_empty() {
+            return 0;
+        }
+
+        let before = points.len();
+        points.retain(|p| !tombstones.iter().any(|t| t.matches(p)));
+        before - points.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn tombstone(id: u64, metric_id: Option<u32>, before_segment: u32) -> Tombstone {
+        Tombstone {
+            id,
+            metric_id,
+            start: 1000,
+            end: 2000,
+            tags: HashMap::new(),
+            before_segment,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_tombstone_matches() {
+        let mut t = tombstone(0, Some(1), 5);
+        t.tags.insert("source".to_string(), "csv".to_string());
+
+        let hit = DataPoint::with_timestamp(1, 1.0, 1500).tag("source", "csv");
+        assert!(t.matches(&hit));
+
+        // Wrong metric, outside the range (end is exclusive), missing tag
+        assert!(!t.matches(&DataPoint::with_timestamp(2, 1.0, 1500).tag("source", "csv")));
+        assert!(!t.matches(&DataPoint::with_timestamp(1, 1.0, 2000).tag("source", "csv")));
+        assert!(!t.matches(&DataPoint::with_timestamp(1, 1.0, 1500)));
+    }
+
+    #[test]
+    fn test_apply_respects_segment_boundary() {
+        let mut set = TombstoneSet::default();
+        set.add(None, TimeRange::new(1000, 2000), HashMap::new(), 5, 0);
+
+        let mut old = vec![
+            DataPoint::with_timestamp(1, 1.0, 1500),
+            DataPoint::with_timestamp(1, 2.0, 2500),
+        ];
+        assert_eq!(set.apply(4, &mut old), 1);
+        assert_eq!(old.len(), 1);
+
+        // Segments created after the delete are untouched
+        let mut new = vec![DataPoint::with_timestamp(1, 1.0, 1500)];
+        assert_eq!(set.apply(5, &mut new), 0);
+        assert_eq!(new.len(), 1);
+    }
+
+    #[test]
+    fn test_replay_skips_checkpointed_tombstones() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("tombstones.json");
+
+        let mut set = TombstoneSet::default();
+        let first = set.add(Some(2), TimeRange::new(1000, 2000), HashMap::new(), 7, 0);
+        set.checkpoint(&path).unwrap();
+
+        // Purged after the checkpoint, but still in the WAL
+        set.retain(|_| false);
+        set.checkpoint(&path).unwrap();
+
+        let mut loaded = TombstoneSet::load(&path).unwrap();
+        assert!(!loaded.replay(first));
+        assert!(loaded.replay(tombstone(1, None, 8)));
+        assert!(!loaded.replay(tombstone(1, None, 8)));
+        assert_eq!(loaded.len(), 1);
+
+        let next = loaded.add(None, TimeRange::new(0, 1), HashMap::new(), 9, 0);
+        assert_eq!(next.id, 2);
+    }
+}
@@ -6,6 +6,8 @@
 //! - `TimeRange`: A time interval for queries
 //! - `Category` and `AggregationType`: Classification enums
 
+use crate::storage::duplicates::DuplicatePolicy;
+use crate::storage::retention::RetentionPolicy;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -183,6 +185,34 @@ pub struct Metric {
     /// Optional max value for validation
     #[serde(default)]
     pub max_value: Option<f64>,
+    /// Retention policy (overrides the category's policy)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+    /// Source metric, if this metric holds rollups of another
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollup_of: Option<u32>,
+    /// Which copies of a re-written point are kept
+    #[serde(default, skip_serializing_if = "DuplicatePolicy::is_keep_all")]
+    pub duplicates: DuplicatePolicy,
+    /// CQL expression this metric is computed from (no points are stored)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expression: Option<String>,
+    /// CQL query whose results this metric stores (a continuous query)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuous_query: Option<String>,
+    /// Set once the metric has been deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<DeleteMode>,
+}
+
+/// How a metric was deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode {
+    /// Hidden but its data is kept; registering the name again restores it
+    Soft,
+    /// Its data is deleted and the name is free for a new metric
+    Hard,
 }
 
 impl Metric {
@@ -202,6 +232,12 @@ impl Metric {
             description: None,
             min_value: None,
             max_value: None,
+            retention: None,
+            rollup_of: None,
+            duplicates: DuplicatePolicy::KeepAll,
+            expression: None,
+            continuous_query: None,
+            deleted: None,
         }
     }
 
@@ -218,6 +254,47 @@ impl Metric {
         self
     }
 
+    /// Builder: set retention policy
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        self.retention = Some(policy);
+        self
+    }
+
+    /// Builder: set duplicate policy
+    pub fn duplicates(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicates = policy;
+        self
+    }
+
+    /// Builder: compute the metric from a CQL expression, e.g.
+    /// `SUM(calories_in) - SUM(calories_out)`
+    pub fn computed(mut self, expression: impl Into<String>) -> Self {
+        self.expression = Some(expression.into());
+        self
+    }
+
+    /// Check if the metric is computed rather than stored
+    pub fn is_computed(&self) -> bool {
+        self.expression.is_some()
+    }
+
+    /// Builder: store the results of a continuous query, e.g.
+    /// `SELECT AVG(mood) GROUP BY day`
+    pub fn materialized(mut self, query: impl Into<String>) -> Self {
+        self.continuous_query = Some(query.into());
+        self
+    }
+
+    /// Check if the metric holds the results of a continuous query
+    pub fn is_materialized(&self) -> bool {
+        self.continuous_query.is_some()
+    }
+
+    /// Check if the metric has not been deleted
+    pub fn is_active(&self) -> bool {
+        self.deleted.is_none()
+    }
+
     /// Validate a value against this metric's constraints
     pub fn validate_value(&self, value: f64) -> bool {
         if let Some(min) = self.min_value {
@@ -338,6 +415,8 @@ pub struct QueryFilter {
     pub metric_name: Option<String>,
     /// Filter by tag values
     pub tags: HashMap<String, String>,
+    /// Filter by tags that must have one of several values
+    pub tag_values: HashMap<String, Vec<String>>,
     /// Filter by category
     pub category: Option<Category>,
 }
@@ -362,6 +441,17 @@ impl QueryFilter {
         self
     }
 
+    /// Require a tag to have one of the given values
+    pub fn tag_in<I, S>(mut self, key: impl Into<String>, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tag_values
+            .insert(key.into(), values.into_iter().map(Into::into).collect());
+        self
+    }
+
     pub fn category(mut self, cat: Category) -> Self {
         self.category = Some(cat);
         self
@@ -382,6 +472,12 @@ impl QueryFilter {
                 return false;
             }
         }
+        for (key, values) in &self.tag_values {
+            match point.tags.get(key) {
+                Some(value) if values.contains(value) => {}
+                _ => return false,
+            }
+        }
 
         // Check category (requires metric info)
         if let Some(cat) = self.category {
@@ -8,11 +8,16 @@
 //! - length: u32 (4 bytes)
 //! - data: [u8; length] (serialized DataPoint)
 //! - crc: u32 (4 bytes, CRC32 of length + data)
+//!
+//! Tombstone entries set the high bit of the length field and hold a
+//! serialized `Tombstone` instead. Point entries are never that large, so
+//! logs written before tombstones existed read back unchanged.
 
 use crate::storage::error::{StorageError, StorageResult};
+use crate::storage::tombstone::Tombstone;
 use crate::storage::types::DataPoint;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 
 /// Sync strategy for WAL writes
@@ -32,6 +37,18 @@ impl Default for WalSyncMode {
     }
 }
 
+/// Length-field bit marking a tombstone entry
+const TOMBSTONE_FLAG: u32 = 0x8000_0000;
+
+/// A record read back from the WAL
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalEntry {
+    /// A written data point
+    Point(DataPoint),
+    /// A point-level delete
+    Tombstone(Tombstone),
+}
+
 /// Write-Ahead Log for durability
 pub struct WriteAheadLog {
     /// File handle for writing
@@ -154,6 +171,27 @@ impl WriteAheadLog {
         Ok(())
     }
 
+    /// Append a tombstone to the WAL
+    ///
+    /// Always synced: a delete that is acknowledged but lost would bring the
+    /// deleted points back after a crash.
+    pub fn append_tombstone(&mut self, tombstone: &Tombstone) -> StorageResult<()> {
+        let data = bincode::serialize(tombstone)?;
+        let len_buf = (data.len() as u32 | TOMBSTONE_FLAG).to_le_bytes();
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&len_buf);
+        hasher.update(&data);
+        let crc = hasher.finalize();
+
+        self.writer.write_all(&len_buf)?;
+        self.writer.write_all(&data)?;
+        self.writer.write_all(&crc.to_le_bytes())?;
+
+        self.entry_count += 1;
+        self.sync()
+    }
+
     /// Conditionally sync based on mode and threshold
     fn maybe_sync(&mut self) -> StorageResult<()> {
         match self.sync_mode {
@@ -181,28 +219,73 @@ impl WriteAheadLog {
         Ok(())
     }
 
-    /// Read all entries for recovery
+    /// Read all data points for recovery (tombstones are skipped)
     pub fn recover(&self) -> StorageResult<Vec<DataPoint>> {
+        Ok(self
+            .recover_entries()?
+            .into_iter()
+            .filter_map(|entry| match entry {
+                WalEntry::Point(point) => Some(point),
+                WalEntry::Tombstone(_) => None,
+            })
+            .collect())
+    }
+
+    /// Read all entries, points and tombstones, in write order
+    pub fn recover_entries(&self) -> StorageResult<Vec<WalEntry>> {
         let file = File::open(&self.path)?;
         let mut reader = BufReader::new(file);
-        let mut points = Vec::new();
+        let mut entries = Vec::new();
 
         loop {
             match Self::read_entry_from(&mut reader) {
-                Ok(Some(point)) => points.push(point),
+                Ok(Some(entry)) => entries.push(entry),
                 Ok(None) => break, // EOF
                 Err(e) => {
-                    tracing::warn!("WAL recovery stopped at entry {}: {}", points.len(), e);
+                    tracing::warn!("WAL recovery stopped at entry {}: {}", entries.len(), e);
+                    break;
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Read a WAL file from start to end without opening it for writing
+    ///
+    /// Stops at the first entry that cannot be read and reports where the
+    /// readable prefix ends.
+    pub fn scan(path: impl AsRef<Path>) -> StorageResult<WalScan> {
+        let file = File::open(path.as_ref())?;
+        let mut scan = WalScan {
+            file_bytes: file.metadata()?.len(),
+            ..Default::default()
+        };
+        let mut reader = BufReader::new(file);
+
+        loop {
+            match Self::read_entry_from(&mut reader) {
+                Ok(Some(WalEntry::Point(_))) => scan.points += 1,
+                Ok(Some(WalEntry::Tombstone(_))) => scan.tombstones += 1,
+                Ok(None) => break,
+                Err(e) => {
+                    scan.error = Some(e.to_string());
                     break;
                 }
             }
+            scan.valid_bytes = reader.stream_position()?;
+        }
+
+        // A torn length prefix reads as a clean end of the log
+        if scan.error.is_none() && scan.valid_bytes < scan.file_bytes {
+            scan.error = Some("truncated entry".to_string());
         }
 
-        Ok(points)
+        Ok(scan)
     }
 
     /// Read a single entry from a reader
-    fn read_entry_from<R: Read>(reader: &mut R) -> StorageResult<Option<DataPoint>> {
+    fn read_entry_from<R: Read>(reader: &mut R) -> StorageResult<Option<WalEntry>> {
         // Read length
         let mut len_buf = [0u8; 4];
         match reader.read_exact(&mut len_buf) {
@@ -210,7 +293,9 @@ impl WriteAheadLog {
             Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
             Err(e) => return Err(e.into()),
         }
-        let len = u32::from_le_bytes(len_buf) as usize;
+        let raw_len = u32::from_le_bytes(len_buf);
+        let is_tombstone = raw_len & TOMBSTONE_FLAG != 0;
+        let len = (raw_len & !TOMBSTONE_FLAG) as usize;
 
         // Sanity check on length (max 1MB per entry)
         if len > 1_000_000 {
@@ -243,8 +328,11 @@ impl WriteAheadLog {
         }
 
         // Deserialize
-        let point: DataPoint = bincode::deserialize(&data)?;
-        Ok(Some(point))
+        if is_tombstone {
+            Ok(Some(WalEntry::Tombstone(bincode::deserialize(&data)?)))
+        } else {
+            Ok(Some(WalEntry::Point(bincode::deserialize(&data)?)))
+        }
     }
 
     /// Truncate the WAL (after successful flush to segment)
@@ -295,7 +383,24 @@ impl WriteAheadLog {
     }
 }
 
+/// Outcome of `WriteAheadLog::scan`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalScan {
+    /// Readable point entries
+    pub points: u64,
+    /// Readable tombstone entries
+    pub tombstones: u64,
+    /// Length of the readable prefix in bytes
+    pub valid_bytes: u64,
+    /// Size of the file in bytes
+    pub file_bytes: u64,
+    /// Why reading stopped before the end of the file, if it did
+    pub error: Option<String>,
+}
+
 /// WAL entry iterator for streaming recovery
+///
+/// Yields data points only; tombstones are skipped.
 pub struct WalIterator {
     reader: BufReader<File>,
     entries_read: u64,
@@ -315,13 +420,16 @@ impl Iterator for WalIterator {
     type Item = StorageResult<DataPoint>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match WriteAheadLog::read_entry_from(&mut self.reader) {
-            Ok(Some(point)) => {
-                self.entries_read += 1;
-                Some(Ok(point))
+        loop {
+            match WriteAheadLog::read_entry_from(&mut self.reader) {
+                Ok(Some(WalEntry::Point(point))) => {
+                    self.entries_read += 1;
+                    return Some(Ok(point));
+                }
+                Ok(Some(WalEntry::Tombstone(_))) => self.entries_read += 1,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
             }
-            Ok(None) => None,
-            Err(e) => Some(Err(e)),
         }
     }
 }
@@ -515,4 +623,66 @@ mod tests {
             assert_eq!(recovered.len(), 10);
         }
     }
+
+    #[test]
+    fn test_wal_tombstone_entries() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("test.wal");
+
+        let tombstone = Tombstone {
+            id: 7,
+            metric_id: Some(1),
+            start: 0,
+            end: 5000,
+            tags: [("source".to_string(), "csv".to_string())].into(),
+            before_segment: 3,
+            created_at: 1234,
+        };
+
+        {
+            let mut wal = WriteAheadLog::open(&wal_path, WalSyncMode::Batched).unwrap();
+            wal.append(&DataPoint::with_timestamp(1, 1.0, 1000)).unwrap();
+            wal.append_tombstone(&tombstone).unwrap();
+            wal.append(&DataPoint::with_timestamp(1, 2.0, 2000)).unwrap();
+            wal.sync().unwrap();
+        }
+
+        let wal = WriteAheadLog::open(&wal_path, WalSyncMode::Batched).unwrap();
+        assert_eq!(wal.entry_count(), 3);
+
+        let entries = wal.recover_entries().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1], WalEntry::Tombstone(tombstone));
+
+        // Point-only readers skip the tombstone
+        assert_eq!(wal.recover().unwrap().len(), 2);
+        assert_eq!(WalIterator::new(&wal_path).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_wal_scan_reports_torn_tail() {
+        let dir = tempdir().unwrap();
+        let wal_path = dir.path().join("test.wal");
+
+        {
+            let mut wal = WriteAheadLog::open(&wal_path, WalSyncMode::EveryWrite).unwrap();
+            for i in 0..3 {
+                wal.append(&DataPoint::with_timestamp(1, 1.0, i * 1000)).unwrap();
+            }
+        }
+        let clean = WriteAheadLog::scan(&wal_path).unwrap();
+        assert_eq!(clean.points, 3);
+        assert_eq!(clean.valid_bytes, clean.file_bytes);
+        assert_eq!(clean.error, None);
+
+        // Half of a length prefix, as left by a crash mid-append
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(&[7, 0]).unwrap();
+
+        let torn = WriteAheadLog::scan(&wal_path).unwrap();
+        assert_eq!(torn.points, 3);
+        assert_eq!(torn.valid_bytes, clean.file_bytes);
+        assert_eq!(torn.file_bytes, clean.file_bytes + 2);
+        assert!(torn.error.is_some());
+    }
 }
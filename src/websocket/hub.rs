@@ -273,10 +273,14 @@ impl ConnectionHub {
         // - metrics.{name} (specific metric)
         // - category.{cat} (all metrics in category)
         // - insights (insight updates)
+        // - alerts (fired alert rules)
+        // - goals (goal progress)
         // - system (system events)
         topic.starts_with("metrics.")
             || topic.starts_with("category.")
             || topic == "insights"
+            || topic == "alerts"
+            || topic == "goals"
             || topic == "system"
     }
 
@@ -368,6 +372,8 @@ mod tests {
         assert!(hub.is_valid_topic("metrics.*"));
         assert!(hub.is_valid_topic("category.health"));
         assert!(hub.is_valid_topic("insights"));
+        assert!(hub.is_valid_topic("alerts"));
+        assert!(hub.is_valid_topic("goals"));
         assert!(hub.is_valid_topic("system"));
 
         assert!(!hub.is_valid_topic("invalid"));
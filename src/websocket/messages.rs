@@ -3,6 +3,8 @@
 //! Defines all message types for WebSocket communication between
 //! clients (dashboards) and the Chronicle server.
 
+use crate::alerts::Alert;
+use crate::goals::{Goal, GoalProgress};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -40,6 +42,42 @@ pub enum ServerMessage {
         #[serde(skip_serializing_if = "HashMap::is_empty")]
         tags: HashMap<String, String>,
     },
+    /// An alert rule fired
+    Alert {
+        /// Alert ID
+        id: u64,
+        /// ID of the rule that fired
+        rule_id: u64,
+        /// Name of the rule that fired
+        rule: String,
+        /// Metric the rule watches
+        metric: String,
+        /// What happened
+        message: String,
+        /// Value of the point that fired the rule
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<f64>,
+        /// Timestamp in milliseconds
+        timestamp: i64,
+    },
+    /// A goal's progress changed
+    GoalProgress {
+        /// Goal ID
+        goal_id: u64,
+        /// Goal name
+        goal: String,
+        /// Metric the goal tracks
+        metric: String,
+        /// Consecutive periods that met the goal
+        current_streak: u32,
+        /// Longest run of periods that met the goal
+        best_streak: u32,
+        /// Whether the current period meets the goal already
+        period_met: bool,
+        /// Today's aggregate
+        #[serde(skip_serializing_if = "Option::is_none")]
+        today: Option<f64>,
+    },
     /// Subscription confirmed
     Subscribed {
         /// Topics successfully subscribed to
@@ -92,6 +130,38 @@ impl WsEvent {
         }
     }
 
+    /// Create an alert event
+    pub fn alert(alert: &Alert) -> Self {
+        Self {
+            topic: "alerts".to_string(),
+            message: ServerMessage::Alert {
+                id: alert.id,
+                rule_id: alert.rule_id,
+                rule: alert.rule.clone(),
+                metric: alert.metric.clone(),
+                message: alert.message.clone(),
+                value: alert.value,
+                timestamp: alert.timestamp,
+            },
+        }
+    }
+
+    /// Create a goal progress event
+    pub fn goal_progress(goal: &Goal, progress: &GoalProgress) -> Self {
+        Self {
+            topic: "goals".to_string(),
+            message: ServerMessage::GoalProgress {
+                goal_id: goal.id,
+                goal: goal.name.clone(),
+                metric: goal.metric.clone(),
+                current_streak: progress.current_streak,
+                best_streak: progress.best_streak,
+                period_met: progress.period_met,
+                today: progress.today,
+            },
+        }
+    }
+
     /// Create an insight event
     pub fn insight(content: &str) -> Self {
         Self {
@@ -173,4 +243,23 @@ mod tests {
             _ => panic!("Expected DataPoint"),
         }
     }
+
+    #[test]
+    fn test_ws_event_alert() {
+        let alert = Alert {
+            id: 1,
+            rule_id: 2,
+            rule: "No mood".to_string(),
+            metric: "mood".to_string(),
+            message: "No mood data for 2d".to_string(),
+            value: None,
+            timestamp: 1699000000000,
+            triggered_at: 1699000000000,
+        };
+        let event = WsEvent::alert(&alert);
+        assert_eq!(event.topic, "alerts");
+        let json = serde_json::to_string(&event.message).unwrap();
+        assert!(json.contains("\"type\":\"alert\""));
+        assert!(!json.contains("\"value\""));
+    }
 }
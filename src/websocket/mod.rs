@@ -15,6 +15,8 @@
 //! - `metrics.{name}` - Specific metric (e.g., `metrics.mood`)
 //! - `category.{cat}` - All metrics in category
 //! - `insights` - Insight updates
+//! - `alerts` - Fired alert rules
+//! - `goals` - Goal progress
 //! - `system` - System events
 //!
 //! ## Example